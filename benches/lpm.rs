@@ -0,0 +1,33 @@
+#![feature(test)]
+
+extern crate test;
+extern crate yaavre;
+
+use test::Bencher;
+use yaavre::progmem::ProgramMemory;
+
+
+const TABLE_SIZE : u32 = 0x8000;
+
+
+// a 32K string table
+fn string_table() -> ProgramMemory {
+    let image: Vec<u8> = (0..TABLE_SIZE).map(|i| i as u8).collect();
+    let mut prog_mem = ProgramMemory::new();
+    prog_mem.set_bytes(&image).unwrap();
+    prog_mem
+}
+
+// read a byte at a time like LPM Z+ in a loop
+#[bench]
+fn lpm_string_table(b: &mut Bencher) {
+    let prog_mem = string_table();
+
+    b.iter(|| {
+        let mut sum = 0u32;
+        for addr in 0..TABLE_SIZE {
+            sum += prog_mem.get_prog_mem_byte(addr, &"", 0) as u32;
+        }
+        sum
+    });
+}
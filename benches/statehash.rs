@@ -0,0 +1,42 @@
+#![feature(test)]
+
+extern crate test;
+extern crate yaavre;
+
+use test::Bencher;
+use yaavre::Emulator;
+use yaavre::device::ATXMEGA128A4U;
+use yaavre::statehash::{MemHasher, StateHasher};
+
+
+// a few stack and variable writes between samples, as in a typical interval
+fn scribble(emu: &mut Emulator, n: u8) {
+    let top = emu.io_mem.data_mem.len() - 1;
+    emu.io_mem.data_mem[0x2010] = n;
+    emu.io_mem.data_mem[top - 4] = n;
+}
+
+#[bench]
+fn hash_whole_data_space(b: &mut Bencher) {
+    let mut emu = Emulator::for_device(&ATXMEGA128A4U);
+    let mut n = 0;
+    b.iter(|| {
+        n += 1;
+        scribble(&mut emu, n);
+        let mut hasher = StateHasher::new();
+        hasher.write(&emu.io_mem.data_mem);
+        hasher.finish()
+    });
+}
+
+#[bench]
+fn hash_changed_pages(b: &mut Bencher) {
+    let mut emu = Emulator::for_device(&ATXMEGA128A4U);
+    let mut mem_hasher = MemHasher::new();
+    let mut n = 0;
+    b.iter(|| {
+        n += 1;
+        scribble(&mut emu, n);
+        mem_hasher.hash(&emu.io_mem.data_mem)
+    });
+}
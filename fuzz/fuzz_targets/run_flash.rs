@@ -0,0 +1,31 @@
+// Runs arbitrary bytes as the flash image, for a bounded number of
+// instructions. Faults (bad opcodes, stack under/overflows, unimplemented
+// instructions...) are fine; panics aren't.
+//
+//     cargo +nightly fuzz run run_flash
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::stopcond::StopCondition;
+
+
+const MAX_INSNS : u64 = 10000;
+// sleeping with interrupts enabled doesn't count instructions
+const MAX_CYCLES : u64 = 100000;
+
+
+fuzz_target!(|data: &[u8]| {
+    let mut emu = Emulator::new();
+    if emu.prog_mem.set_bytes(data).is_err() {
+        return;
+    }
+    emu.reset();
+
+    emu.run_until_any(&[
+        StopCondition::InsnLimit(MAX_INSNS),
+        StopCondition::CycleLimit(MAX_CYCLES),
+    ]);
+});
@@ -0,0 +1,584 @@
+// XMEGA ADC
+//
+// Input values come from the host: set directly with `set_input`, from a
+// stimulus file, or from a callback. Values are raw 12-bit unsigned results.
+//
+// The stimulus file is CSV with one value change per line:
+//   <cycle>,<input pin>,<value>
+
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const CTRLA : u32 = 0x00;
+const CTRLB : u32 = 0x01;
+const REFCTRL : u32 = 0x02;
+const EVCTRL : u32 = 0x03;
+const PRESCALER : u32 = 0x04;
+const INTFLAGS : u32 = 0x06;
+const TEMP : u32 = 0x07;
+const CH0RES : u32 = 0x10;
+const CH3RES_H : u32 = 0x17;
+const CMP : u32 = 0x18;
+const CMP_H : u32 = 0x19;
+const CH0 : u32 = 0x20;
+const CH3_END : u32 = 0x3F;
+
+// channel register offsets
+const CH_CTRL : u32 = 0x00;
+const CH_MUXCTRL : u32 = 0x01;
+const CH_INTCTRL : u32 = 0x02;
+const CH_INTFLAGS : u32 = 0x03;
+const CH_RES : u32 = 0x04;
+const CH_RES_H : u32 = 0x05;
+const CH_SCAN : u32 = 0x06;
+
+// CTRLA bits
+const ENABLE : u8 = 0x01;
+const FLUSH : u8 = 0x02;
+const CH0START : u8 = 0x04;
+
+// CTRLB bits
+const FREERUN : u8 = 0x08;
+const RESOLUTION_MASK : u8 = 0x06;
+const RESOLUTION_8BIT : u8 = 0x04;
+const RESOLUTION_LEFT12BIT : u8 = 0x06;
+
+// CHn.CTRL bits
+const CH_START : u8 = 0x80;
+
+// CHn.INTCTRL interrupt modes
+const INTMODE_COMPLETE : u8 = 0;
+const INTMODE_BELOW : u8 = 1;
+const INTMODE_ABOVE : u8 = 3;
+
+/// ADC clocks per conversion
+const CONVERSION_CLOCKS : u64 = 7;
+
+
+/// returns the value of input pin `pin` at cycle `now`
+pub type AdcCallback = Arc<dyn Fn(u8, u64) -> u16 + Send + Sync>;
+
+#[derive(Clone, Copy, Default)]
+pub struct AdcChannel {
+    pub ctrl: u8,
+    pub muxctrl: u8,
+    pub intctrl: u8,
+    pub intflags: u8,
+    pub res: u16,
+    pub scan: u8,
+
+    /// cycle at which the current conversion completes
+    done_at: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Adc {
+    name: String,
+    base: u32,
+    /// vector of CH0; CH1-CH3 follow
+    ch0_vector: u8,
+    /// DMA trigger source of CH0; CH1-CH3 follow
+    dma_trigger: u8,
+    /// EVSYS source of CH0; CH1-CH3 follow
+    ev_source: u8,
+
+    pub ctrla: u8,
+    pub ctrlb: u8,
+    pub refctrl: u8,
+    pub evctrl: u8,
+    pub prescaler: u8,
+    pub temp: u8,
+    pub cmp: u16,
+    pub channels: [AdcChannel; 4],
+
+    /// current value of each input pin
+    pub inputs: [u16; 16],
+    /// (cycle, pin, value), in cycle order
+    stimulus: Vec<(u64, u8, u16)>,
+    next_stimulus: usize,
+    /// overrides `inputs` when set
+    callback: Option<AdcCallback>,
+
+    now: u64,
+    /// conversion complete events not yet routed
+    events: Vec<u8>,
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad ADC stimulus line {}", line_num + 1))
+}
+
+/// read a stimulus file, see above
+pub fn load_stimulus(path: &str) -> io::Result<Vec<(u64, u8, u16)>> {
+    let r = BufReader::new(File::open(path)?);
+    let mut stimulus = vec![];
+
+    for (line_num, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        if parts.len() != 3 {
+            return Err(bad_line(line_num));
+        }
+
+        let cycle = u64::from_str(parts[0]).map_err(|_| bad_line(line_num))?;
+        let pin = u8::from_str(parts[1]).map_err(|_| bad_line(line_num))?;
+        let val = u16::from_str(parts[2]).map_err(|_| bad_line(line_num))?;
+        if pin >= 16 {
+            return Err(bad_line(line_num));
+        }
+
+        stimulus.push((cycle, pin, val));
+    }
+
+    stimulus.sort_by_key(|&(cycle, _, _)| cycle);
+    Ok(stimulus)
+}
+
+impl Adc {
+    pub fn new(name: &str, base: u32, ch0_vector: u8, dma_trigger: u8,
+               ev_source: u8) -> Adc {
+
+        Adc {
+            name: name.to_string(),
+            base,
+            ch0_vector,
+            dma_trigger,
+            ev_source,
+
+            ctrla: 0,
+            ctrlb: 0,
+            refctrl: 0,
+            evctrl: 0,
+            prescaler: 0,
+            temp: 0,
+            cmp: 0,
+            channels: [AdcChannel::default(); 4],
+
+            inputs: [0; 16],
+            stimulus: vec![],
+            next_stimulus: 0,
+            callback: None,
+
+            now: 0,
+            events: vec![],
+        }
+    }
+
+    pub fn set_input(&mut self, pin: u8, val: u16) {
+        self.inputs[pin as usize] = val & 0xfff;
+    }
+
+    pub fn set_stimulus(&mut self, stimulus: Vec<(u64, u8, u16)>) {
+        self.stimulus = stimulus;
+        self.next_stimulus = 0;
+    }
+
+    pub fn set_callback(&mut self, callback: AdcCallback) {
+        self.callback = Some(callback);
+    }
+
+    fn conversion_cycles(&self) -> u64 {
+        let div = 4 << (self.prescaler & 0x7);
+        CONVERSION_CLOCKS * div
+    }
+
+    fn start_conversion(&mut self, ch: usize) {
+        if (self.ctrla & ENABLE) == 0 {
+            return;
+        }
+
+        let done_at = self.now + self.conversion_cycles();
+        self.channels[ch].done_at = Some(done_at);
+    }
+
+    fn sample(&self, pin: u8) -> u16 {
+        let val = match self.callback {
+            Some(ref callback) => callback(pin, self.now),
+            None => self.inputs[pin as usize],
+        };
+
+        let val = val & 0xfff;
+        match self.ctrlb & RESOLUTION_MASK {
+            RESOLUTION_8BIT => val >> 4,
+            RESOLUTION_LEFT12BIT => val << 4,
+            _ => val,
+        }
+    }
+
+    fn complete_conversion(&mut self, ch: usize) {
+        let pin = (self.channels[ch].muxctrl >> 3) & 0xf;
+        let res = self.sample(pin);
+        let cmp = self.cmp;
+
+        self.events.push(self.ev_source + ch as u8);
+
+        let c = &mut self.channels[ch];
+        c.done_at = None;
+        c.res = res;
+
+        let flag = match (c.intctrl >> 2) & 0x3 {
+            INTMODE_COMPLETE => true,
+            INTMODE_BELOW => res < cmp,
+            INTMODE_ABOVE => res > cmp,
+            _ => false,
+        };
+        if flag {
+            c.intflags |= 1;
+        }
+    }
+
+    fn read_ch(&mut self, ch: usize, ofs: u32) -> u8 {
+        let c = self.channels[ch];
+        match ofs {
+            CH_CTRL =>
+                (c.ctrl & !CH_START)
+                    | (if c.done_at.is_some() { CH_START } else { 0 }),
+            CH_MUXCTRL => c.muxctrl,
+            CH_INTCTRL => c.intctrl,
+            CH_INTFLAGS => c.intflags,
+            CH_RES..=CH_RES_H => self.read16(ofs, c.res),
+            CH_SCAN => c.scan,
+            _ => 0,
+        }
+    }
+
+    fn write_ch(&mut self, ch: usize, ofs: u32, val: u8) {
+        match ofs {
+            CH_CTRL => {
+                self.channels[ch].ctrl = val & !CH_START;
+                if (val & CH_START) != 0 {
+                    self.start_conversion(ch);
+                }
+            },
+            CH_MUXCTRL => self.channels[ch].muxctrl = val,
+            CH_INTCTRL => self.channels[ch].intctrl = val,
+            // write 1 to clear
+            CH_INTFLAGS => self.channels[ch].intflags &= !val,
+            CH_RES..=CH_RES_H => {
+                if let Some(res) = self.write16(ofs, val) {
+                    self.channels[ch].res = res;
+                }
+            },
+            CH_SCAN => self.channels[ch].scan = val,
+            _ => {},
+        }
+    }
+
+    fn read16(&mut self, ofs: u32, val: u16) -> u8 {
+        if (ofs & 1) == 0 {
+            self.temp = (val >> 8) as u8;
+            val as u8
+        } else {
+            self.temp
+        }
+    }
+
+    /// returns the new value once the high byte is written
+    fn write16(&mut self, ofs: u32, val: u8) -> Option<u16> {
+        if (ofs & 1) == 0 {
+            self.temp = val;
+            None
+        } else {
+            Some(((val as u16) << 8) | (self.temp as u16))
+        }
+    }
+}
+
+impl Peripheral for Adc {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x40)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRLA => {
+                let mut val = self.ctrla;
+                for ch in 0..4 {
+                    if self.channels[ch].done_at.is_some() {
+                        val |= CH0START << ch;
+                    }
+                }
+                val
+            },
+            CTRLB => self.ctrlb,
+            REFCTRL => self.refctrl,
+            EVCTRL => self.evctrl,
+            PRESCALER => self.prescaler,
+            INTFLAGS => {
+                let mut val = 0;
+                for ch in 0..4 {
+                    val |= (self.channels[ch].intflags & 1) << ch;
+                }
+                val
+            },
+            TEMP => self.temp,
+            CH0RES..=CH3RES_H => {
+                let res = self.channels[((ofs - CH0RES) / 2) as usize].res;
+                self.read16(ofs, res)
+            },
+            CMP..=CMP_H => {
+                let cmp = self.cmp;
+                self.read16(ofs, cmp)
+            },
+            CH0..=CH3_END => {
+                let ch = ((ofs - CH0) / 8) as usize;
+                self.read_ch(ch, (ofs - CH0) % 8)
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRLA => {
+                self.ctrla = val & (ENABLE | 0xc0);
+
+                if (val & FLUSH) != 0 {
+                    for ch in 0..4 {
+                        self.channels[ch].done_at = None;
+                    }
+                }
+
+                for ch in 0..4 {
+                    if (val & (CH0START << ch)) != 0 {
+                        self.start_conversion(ch);
+                    }
+                }
+            },
+            CTRLB => self.ctrlb = val,
+            REFCTRL => self.refctrl = val,
+            EVCTRL => self.evctrl = val,
+            PRESCALER => self.prescaler = val,
+            // write 1 to clear
+            INTFLAGS => {
+                for ch in 0..4 {
+                    if (val & (1 << ch)) != 0 {
+                        self.channels[ch].intflags &= !1;
+                    }
+                }
+            },
+            TEMP => self.temp = val,
+            CMP..=CMP_H => {
+                if let Some(cmp) = self.write16(ofs, val) {
+                    self.cmp = cmp;
+                }
+            },
+            CH0..=CH3_END => {
+                let ch = ((ofs - CH0) / 8) as usize;
+                self.write_ch(ch, (ofs - CH0) % 8, val);
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut adc = Adc::new(
+            &self.name, self.base, self.ch0_vector, self.dma_trigger,
+            self.ev_source);
+
+        adc.inputs = self.inputs;
+        adc.stimulus = mem::take(&mut self.stimulus);
+        adc.next_stimulus = self.next_stimulus;
+        adc.callback = self.callback.take();
+        adc.now = self.now;
+
+        *self = adc;
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.now = now;
+
+        while self.next_stimulus < self.stimulus.len()
+                && self.stimulus[self.next_stimulus].0 <= now {
+
+            let (_, pin, val) = self.stimulus[self.next_stimulus];
+            self.set_input(pin, val);
+            self.next_stimulus += 1;
+        }
+
+        for ch in 0..4 {
+            match self.channels[ch].done_at {
+                Some(done_at) if done_at <= now => {
+                    self.complete_conversion(ch);
+                    if (self.ctrlb & FREERUN) != 0 {
+                        self.start_conversion(ch);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        let slept = now.saturating_sub(self.now);
+        self.now = now;
+        for c in self.channels.iter_mut() {
+            if let Some(ref mut done_at) = c.done_at {
+                *done_at += slept;
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let stimulus = self.stimulus.get(self.next_stimulus).map(|s| s.0);
+        self.channels.iter()
+            .filter_map(|c| c.done_at)
+            .chain(stimulus)
+            .min()
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        // highest level, then lowest vector
+        (0..4)
+            .filter(|&ch| (self.channels[ch].intflags & 1) != 0)
+            .map(|ch| (self.ch0_vector + ch as u8,
+                       self.channels[ch].intctrl & 0x3))
+            .filter(|&(_, level)| level != 0)
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        for ch in 0..4 {
+            if (self.channels[ch].intctrl & 0x3) != 0 {
+                out.push(self.ch0_vector + ch as u8);
+            }
+        }
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        if vector >= self.ch0_vector && vector < self.ch0_vector + 4 {
+            self.channels[(vector - self.ch0_vector) as usize].intflags &= !1;
+        }
+    }
+
+    fn poll_events(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.events);
+    }
+
+    fn event(&mut self, channel: u8) {
+        // EVACT 1-4 triggers the first 1-4 ADC channels from event channels
+        // EVSEL, EVSEL+1...
+        let evsel = (self.evctrl >> 3) & 0x7;
+        let num_triggered = self.evctrl & 0x7;
+        if num_triggered > 4 || channel < evsel {
+            return;
+        }
+
+        let ch = channel - evsel;
+        if ch < num_triggered {
+            self.start_conversion(ch as usize);
+        }
+    }
+
+    fn dma_request(&self, trigsrc: u8) -> bool {
+        trigsrc >= self.dma_trigger && trigsrc < self.dma_trigger + 4
+            && (self.channels[(trigsrc - self.dma_trigger) as usize].intflags & 1) != 0
+    }
+
+    fn dma_ack(&mut self, trigsrc: u8) {
+        if trigsrc >= self.dma_trigger && trigsrc < self.dma_trigger + 4 {
+            self.channels[(trigsrc - self.dma_trigger) as usize].intflags &= !1;
+        }
+    }
+
+    fn powered(&self) -> bool {
+        (self.ctrla & ENABLE) != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.ctrla, self.ctrlb, self.refctrl, self.evctrl,
+            self.prescaler, self.temp,
+        ];
+        state.write_u16::<LittleEndian>(self.cmp).unwrap();
+
+        for c in self.channels.iter() {
+            state.extend_from_slice(
+                &[c.ctrl, c.muxctrl, c.intctrl, c.intflags, c.scan]);
+            state.write_u16::<LittleEndian>(c.res).unwrap();
+            state.write_u64::<LittleEndian>(c.done_at.unwrap_or(u64::MAX))
+                .unwrap();
+        }
+
+        for &val in self.inputs.iter() {
+            state.write_u16::<LittleEndian>(val).unwrap();
+        }
+
+        state.write_u64::<LittleEndian>(self.next_stimulus as u64).unwrap();
+        state.write_u64::<LittleEndian>(self.now).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        // 6 bytes, cmp, 4 channels of 15 bytes, 16 inputs, 2 u64s
+        if state.len() != 6 + 2 + 4 * 15 + 32 + 16 {
+            return;
+        }
+
+        self.ctrla = state[0];
+        self.ctrlb = state[1];
+        self.refctrl = state[2];
+        self.evctrl = state[3];
+        self.prescaler = state[4];
+        self.temp = state[5];
+
+        let mut r = &state[6..];
+        self.cmp = r.read_u16::<LittleEndian>().unwrap();
+
+        for c in self.channels.iter_mut() {
+            c.ctrl = r.read_u8().unwrap();
+            c.muxctrl = r.read_u8().unwrap();
+            c.intctrl = r.read_u8().unwrap();
+            c.intflags = r.read_u8().unwrap();
+            c.scan = r.read_u8().unwrap();
+            c.res = r.read_u16::<LittleEndian>().unwrap();
+            let done_at = r.read_u64::<LittleEndian>().unwrap();
+            c.done_at =
+                if done_at == u64::MAX { None } else { Some(done_at) };
+        }
+
+        for val in self.inputs.iter_mut() {
+            *val = r.read_u16::<LittleEndian>().unwrap();
+        }
+
+        self.next_stimulus = r.read_u64::<LittleEndian>().unwrap() as usize;
+        self.now = r.read_u64::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,416 @@
+// XMEGA AES crypto module
+//
+// AES-128, one 16-byte block at a time. The firmware loads the state and the
+// key byte by byte through the STATE and KEY registers, starts the module,
+// and reads the result back through STATE. Like on the chip, an encryption
+// leaves the last round key in the key memory, and a decryption has to be
+// started with that last round key loaded, and leaves the original key.
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const CTRL : u32 = 0x00;
+const STATUS : u32 = 0x01;
+const STATE : u32 = 0x02;
+const KEY : u32 = 0x03;
+const INTCTRL : u32 = 0x04;
+
+// CTRL bits
+const START : u8 = 0x80;
+const AUTO : u8 = 0x40;
+const RESET : u8 = 0x20;
+const DECRYPT : u8 = 0x10;
+const XOR : u8 = 0x04;
+
+// STATUS bits
+const ERROR : u8 = 0x80;
+const SRIF : u8 = 0x01;
+
+/// how long an encryption or decryption takes
+const AES_CYCLES : u64 = 375;
+
+const BLOCK_SIZE : usize = 16;
+const ROUNDS : usize = 10;
+
+const SBOX : [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const INV_SBOX : [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+const RCON : [u8; ROUNDS] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+type Block = [u8; BLOCK_SIZE];
+
+
+/// multiply by x in GF(2^8)
+fn xtime(a: u8) -> u8 {
+    (a << 1) ^ if (a & 0x80) != 0 { 0x1b } else { 0 }
+}
+
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if (b & 1) != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+/// round key `round + 1` from round key `round`
+fn next_round_key(key: &Block, round: usize) -> Block {
+    let mut next = [0; BLOCK_SIZE];
+    let t = [SBOX[key[13] as usize] ^ RCON[round], SBOX[key[14] as usize],
+             SBOX[key[15] as usize], SBOX[key[12] as usize]];
+    for i in 0..4 {
+        next[i] = key[i] ^ t[i];
+    }
+    for i in 4..BLOCK_SIZE {
+        next[i] = key[i] ^ next[i - 4];
+    }
+    next
+}
+
+/// round key `round` from round key `round + 1`
+fn prev_round_key(key: &Block, round: usize) -> Block {
+    let mut prev = [0; BLOCK_SIZE];
+    for i in 4..BLOCK_SIZE {
+        prev[i] = key[i] ^ key[i - 4];
+    }
+    let t = [SBOX[prev[13] as usize] ^ RCON[round], SBOX[prev[14] as usize],
+             SBOX[prev[15] as usize], SBOX[prev[12] as usize]];
+    for i in 0..4 {
+        prev[i] = key[i] ^ t[i];
+    }
+    prev
+}
+
+fn add_round_key(state: &mut Block, key: &Block) {
+    for (s, k) in state.iter_mut().zip(key) {
+        *s ^= k;
+    }
+}
+
+/// row r moves r columns left, or right if `inverse`
+fn shift_rows(state: &mut Block, inverse: bool) {
+    let old = *state;
+    for col in 0..4 {
+        for row in 1..4 {
+            let from = if inverse { (col + 4 - row) % 4 } else { (col + row) % 4 };
+            state[col * 4 + row] = old[from * 4 + row];
+        }
+    }
+}
+
+fn mix_columns(state: &mut Block, inverse: bool) {
+    let m = if inverse { [14, 11, 13, 9] } else { [2, 3, 1, 1] };
+    for col in state.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        for row in 0..4 {
+            col[row] = (0..4).fold(0, |acc, i| acc ^ gmul(a[(row + i) % 4], m[i]));
+        }
+    }
+}
+
+/// encrypt `state` with `key`, and leave the last round key in `key`
+pub fn encrypt(state: &mut Block, key: &mut Block) {
+    add_round_key(state, key);
+    for round in 0..ROUNDS {
+        for s in state.iter_mut() {
+            *s = SBOX[*s as usize];
+        }
+        shift_rows(state, false);
+        if round != ROUNDS - 1 {
+            mix_columns(state, false);
+        }
+        *key = next_round_key(key, round);
+        add_round_key(state, key);
+    }
+}
+
+/// decrypt `state` with the last round key in `key`, and leave the
+/// original key in `key`
+pub fn decrypt(state: &mut Block, key: &mut Block) {
+    add_round_key(state, key);
+    for round in (0..ROUNDS).rev() {
+        if round != ROUNDS - 1 {
+            mix_columns(state, true);
+        }
+        shift_rows(state, true);
+        for s in state.iter_mut() {
+            *s = INV_SBOX[*s as usize];
+        }
+        *key = prev_round_key(key, round);
+        add_round_key(state, key);
+    }
+}
+
+#[derive(Clone)]
+pub struct Aes {
+    base: u32,
+    vector: u8,
+
+    pub ctrl: u8,
+    pub status: u8,
+    pub intctrl: u8,
+    pub state: Block,
+    pub key: Block,
+    state_write: usize,
+    state_read: usize,
+    key_write: usize,
+    key_read: usize,
+
+    /// when the running operation finishes
+    done_at: Option<u64>,
+    now: u64,
+}
+
+impl Aes {
+    pub fn new(base: u32, vector: u8) -> Aes {
+        Aes {
+            base,
+            vector,
+
+            ctrl: 0,
+            status: 0,
+            intctrl: 0,
+            state: [0; BLOCK_SIZE],
+            key: [0; BLOCK_SIZE],
+            state_write: 0,
+            state_read: 0,
+            key_write: 0,
+            key_read: 0,
+
+            done_at: None,
+            now: 0,
+        }
+    }
+
+    fn start(&mut self) {
+        self.ctrl |= START;
+        self.status &= !SRIF;
+        self.done_at = Some(self.now + AES_CYCLES);
+    }
+
+    fn finish(&mut self) {
+        if (self.ctrl & DECRYPT) != 0 {
+            decrypt(&mut self.state, &mut self.key);
+        } else {
+            encrypt(&mut self.state, &mut self.key);
+        }
+
+        self.ctrl &= !START;
+        self.status |= SRIF;
+        self.done_at = None;
+        self.state_write = 0;
+        self.state_read = 0;
+        self.key_write = 0;
+        self.key_read = 0;
+    }
+}
+
+impl Peripheral for Aes {
+    fn name(&self) -> &str {
+        "AES"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x08)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            STATUS => self.status,
+            // the memories can't be accessed while the module is running
+            STATE | KEY if self.done_at.is_some() => {
+                self.status |= ERROR;
+                0
+            },
+            STATE => {
+                let val = self.state[self.state_read];
+                self.state_read = (self.state_read + 1) % BLOCK_SIZE;
+                val
+            },
+            KEY => {
+                let val = self.key[self.key_read];
+                self.key_read = (self.key_read + 1) % BLOCK_SIZE;
+                val
+            },
+            INTCTRL => self.intctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL if (val & RESET) != 0 => {
+                let (base, vector, now) = (self.base, self.vector, self.now);
+                *self = Aes::new(base, vector);
+                self.now = now;
+            },
+            CTRL => {
+                let running = self.done_at.is_some();
+                self.ctrl = (val & !START) | (self.ctrl & START);
+                if (val & START) != 0 && !running {
+                    self.start();
+                }
+            },
+            STATUS => self.status &= !(val & (ERROR | SRIF)),
+            STATE | KEY if self.done_at.is_some() => self.status |= ERROR,
+            STATE => {
+                if (self.ctrl & XOR) != 0 {
+                    self.state[self.state_write] ^= val;
+                } else {
+                    self.state[self.state_write] = val;
+                }
+                self.state_write = (self.state_write + 1) % BLOCK_SIZE;
+                if self.state_write == 0 && (self.ctrl & AUTO) != 0 {
+                    self.start();
+                }
+            },
+            KEY => {
+                self.key[self.key_write] = val;
+                self.key_write = (self.key_write + 1) % BLOCK_SIZE;
+            },
+            INTCTRL => self.intctrl = val & 0x03,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let now = self.now;
+        *self = Aes::new(self.base, self.vector);
+        self.now = now;
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.now = now;
+        if self.done_at.is_some_and(|done_at| done_at <= now) {
+            self.finish();
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        let slept = now.saturating_sub(self.now);
+        self.now = now;
+        if let Some(ref mut done_at) = self.done_at {
+            *done_at += slept;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.done_at
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let level = self.intctrl & 0x3;
+        if (self.status & SRIF) != 0 && level != 0 {
+            Some((self.vector, level))
+        } else {
+            None
+        }
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        if (self.intctrl & 0x3) != 0 {
+            out.push(self.vector);
+        }
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        if vector == self.vector {
+            self.status &= !SRIF;
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.done_at.is_some()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.ctrl, self.status, self.intctrl];
+        state.extend_from_slice(&self.state);
+        state.extend_from_slice(&self.key);
+        state.extend_from_slice(&[self.state_write as u8, self.state_read as u8,
+                                  self.key_write as u8, self.key_read as u8]);
+        state.write_u64::<LittleEndian>(self.done_at.unwrap_or(u64::MAX)).unwrap();
+        state.write_u64::<LittleEndian>(self.now).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 55 {
+            return;
+        }
+
+        self.ctrl = state[0];
+        self.status = state[1];
+        self.intctrl = state[2];
+        self.state.copy_from_slice(&state[3..19]);
+        self.key.copy_from_slice(&state[19..35]);
+        self.state_write = state[35] as usize % BLOCK_SIZE;
+        self.state_read = state[36] as usize % BLOCK_SIZE;
+        self.key_write = state[37] as usize % BLOCK_SIZE;
+        self.key_read = state[38] as usize % BLOCK_SIZE;
+        let mut r = &state[39..];
+        let done_at = r.read_u64::<LittleEndian>().unwrap();
+        self.done_at = if done_at == u64::MAX { None } else { Some(done_at) };
+        self.now = r.read_u64::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,273 @@
+// A virtual board with LEDs and push buttons on GPIO pins
+//
+// An LED is lit while its pin is at its active level, and every change is
+// logged with its cycle. A button connects its pin to its active level while
+// pressed and leaves it floating otherwise, so active-low buttons need the
+// firmware to turn on the pin's pull-up, as on a real board. Buttons are
+// pressed by the host, from the debugger, or by a script.
+//
+// The script is CSV with one button change per line:
+//   <cycle>,<button>,<press|release>
+
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use gpio::PinState;
+use iomem::IOMemory;
+use wiring::PinDevice;
+
+
+pub const BOARD_NAME : &str = "board";
+
+/// an LED or a button, and the pin it's on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+    pub name: String,
+    pub port: String,
+    pub pin: u8,
+    pub active_low: bool,
+}
+
+impl Binding {
+    /// "NAME=PORT.PIN", optionally followed by ":low" or ":high" for the
+    /// active level, e.g. "led1=PORTR.0:low"
+    pub fn parse(spec: &str, active_low: bool) -> Option<Binding> {
+        let mut parts = spec.splitn(2, '=');
+        let name = parts.next()?;
+        let mut parts = parts.next()?.splitn(2, ':');
+        let mut pin_parts = parts.next()?.splitn(2, '.');
+        let port = pin_parts.next()?;
+        let pin = pin_parts.next()?.parse().ok().filter(|&pin| pin < 8)?;
+        let active_low = match parts.next() {
+            Some("low") => true,
+            Some("high") => false,
+            Some(_) => return None,
+            None => active_low,
+        };
+
+        if name.is_empty() {
+            return None;
+        }
+        Some(Binding { name: name.to_string(), port: port.to_string(), pin, active_low })
+    }
+
+    fn active_state(&self) -> PinState {
+        if self.active_low { PinState::Low } else { PinState::High }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Button {
+    binding: Binding,
+    pressed: bool,
+    /// what the pin was last driven for, if anything
+    driven: Option<bool>,
+}
+
+#[derive(Clone, Debug)]
+struct Led {
+    binding: Binding,
+    on: bool,
+}
+
+#[derive(Clone)]
+pub struct Board {
+    leds: Vec<Led>,
+    buttons: Vec<Button>,
+    /// (cycle, button, pressed), in cycle order
+    script: Vec<(u64, usize, bool)>,
+    next_script: usize,
+    /// (cycle, LED, on) for every change
+    pub led_events: Vec<(u64, usize, bool)>,
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad button script line {}", line_num + 1))
+}
+
+impl Default for Board {
+    fn default() -> Board {
+        Board::new()
+    }
+}
+
+impl Board {
+    pub fn new() -> Board {
+        Board {
+            leds: vec![],
+            buttons: vec![],
+            script: vec![],
+            next_script: 0,
+            led_events: vec![],
+        }
+    }
+
+    pub fn add_led(&mut self, binding: Binding) {
+        self.leds.push(Led { binding, on: false });
+    }
+
+    pub fn add_button(&mut self, binding: Binding) {
+        self.buttons.push(Button { binding, pressed: false, driven: None });
+    }
+
+    pub fn led_on(&self, name: &str) -> Option<bool> {
+        self.leds.iter().find(|led| led.binding.name == name).map(|led| led.on)
+    }
+
+    /// (name, on) for each LED
+    pub fn leds(&self) -> Vec<(&str, bool)> {
+        self.leds.iter().map(|led| (led.binding.name.as_str(), led.on)).collect()
+    }
+
+    /// (name, pressed) for each button
+    pub fn buttons(&self) -> Vec<(&str, bool)> {
+        self.buttons.iter().map(|b| (b.binding.name.as_str(), b.pressed)).collect()
+    }
+
+    /// press or release button `name`; the pin changes before the next
+    /// instruction. returns false if there's no such button.
+    pub fn set_button(&mut self, name: &str, pressed: bool) -> bool {
+        match self.buttons.iter_mut().find(|b| b.binding.name == name) {
+            Some(b) => {
+                b.pressed = pressed;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// read a script of button presses, see above
+    pub fn load_script(&mut self, path: &str) -> io::Result<()> {
+        let r = BufReader::new(File::open(path)?);
+        let mut script = vec![];
+
+        for (line_num, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+            if parts.len() != 3 {
+                return Err(bad_line(line_num));
+            }
+
+            let cycle = u64::from_str(parts[0]).map_err(|_| bad_line(line_num))?;
+            let button = self.buttons.iter().position(|b| b.binding.name == parts[1])
+                .ok_or_else(|| bad_line(line_num))?;
+            let pressed = match parts[2] {
+                "press" => true,
+                "release" => false,
+                _ => return Err(bad_line(line_num)),
+            };
+            script.push((cycle, button, pressed));
+        }
+
+        script.sort_by_key(|&(cycle, _, _)| cycle);
+        self.script = script;
+        self.next_script = 0;
+        Ok(())
+    }
+
+    /// LEDs and buttons, one line
+    pub fn fmt_status(&self) -> String {
+        let leds: Vec<String> = self.leds.iter()
+            .map(|led| format!("{} {}", led.binding.name, if led.on { "*" } else { "." }))
+            .collect();
+        let buttons: Vec<String> = self.buttons.iter()
+            .map(|b| format!("{} {}", b.binding.name, if b.pressed { "down" } else { "up" }))
+            .collect();
+        format!("LEDs: {}  buttons: {}\n", leds.join(", "), buttons.join(", "))
+    }
+
+    /// every LED change, one per line
+    pub fn fmt_led_events(&self) -> String {
+        self.led_events.iter()
+            .map(|&(cycle, i, on)| format!("{} {} {}\n",
+                cycle, self.leds[i].binding.name, if on { "on" } else { "off" }))
+            .collect()
+    }
+}
+
+impl PinDevice for Board {
+    fn name(&self) -> &str {
+        BOARD_NAME
+    }
+
+    fn update(&mut self, io_mem: &mut IOMemory, now: u64) {
+        while self.next_script < self.script.len() && self.script[self.next_script].0 <= now {
+            let (_, i, pressed) = self.script[self.next_script];
+            self.buttons[i].pressed = pressed;
+            self.next_script += 1;
+        }
+
+        for b in self.buttons.iter_mut() {
+            if b.driven == Some(b.pressed) {
+                continue;
+            }
+            if let Some(port) = io_mem.port_mut(&b.binding.port) {
+                let state = if b.pressed { b.binding.active_state() } else { PinState::Floating };
+                port.drive_pin(b.binding.pin, state);
+            }
+            b.driven = Some(b.pressed);
+        }
+
+        for (i, led) in self.leds.iter_mut().enumerate() {
+            let on = io_mem.port(&led.binding.port)
+                .is_some_and(|p| p.pin_state(led.binding.pin) == led.binding.active_state());
+            if on != led.on {
+                led.on = on;
+                self.led_events.push((now, i, on));
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.script.get(self.next_script).map(|&(cycle, _, _)| cycle)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        state.write_u64::<LittleEndian>(self.next_script as u64).unwrap();
+        state.extend(self.leds.iter().map(|led| led.on as u8));
+        state.extend(self.buttons.iter().map(|b| b.pressed as u8));
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() != 8 + self.leds.len() + self.buttons.len() {
+            return;
+        }
+
+        let mut r = state;
+        self.next_script = r.read_u64::<LittleEndian>().unwrap() as usize;
+        for led in self.leds.iter_mut() {
+            led.on = r[0] != 0;
+            r = &r[1..];
+        }
+        for b in self.buttons.iter_mut() {
+            b.pressed = r[0] != 0;
+            // drive the pin again, in case it changed
+            b.driven = None;
+            r = &r[1..];
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn PinDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,35 @@
+// Breakpoints, optionally with a condition (see expr.rs)
+
+use expr::Expr;
+
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub id: usize,
+    /// byte address
+    pub addr: u32,
+    /// the condition's source text and parsed form
+    pub condition: Option<(String, Expr)>,
+    pub enabled: bool,
+    /// times execution stopped here
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(id: usize, addr: u32, condition: Option<&str>)
+            -> Result<Breakpoint, String> {
+
+        let condition = match condition {
+            Some(text) => Some((text.to_string(), Expr::parse(text)?)),
+            None => None,
+        };
+
+        Ok(Breakpoint {
+            id,
+            addr,
+            condition,
+            enabled: true,
+            hits: 0,
+        })
+    }
+}
@@ -0,0 +1,74 @@
+// Calling guest functions from the host, per the avr-gcc calling convention
+//
+// Each argument takes an even number of registers, going down from r25:r24;
+// an argument's bytes are little-endian, starting at its lowest register.
+// Arguments that don't fit in r8-r25 would go on the stack, which isn't
+// supported. Return values come back in the same registers as a first
+// argument of the same size would be passed in.
+//
+// Going the other way, stubs replace guest functions with host code.
+
+use iomem::IOMemory;
+
+
+/// first register after the argument registers
+const ARG_REGS_END : usize = 26;
+/// lowest argument register
+const ARG_REGS_START : usize = 8;
+
+
+/// host code run instead of a guest function. it gets the arguments from
+/// the registers, puts the return value there, and returns how many cycles
+/// the call should take (on top of the RET).
+pub type Stub = Box<dyn FnMut(&mut IOMemory) -> u64 + Send>;
+
+
+/// what a call returned and what it changed
+#[derive(Clone, Debug)]
+pub struct CallResult {
+    /// r24, or r25:r24
+    pub ret: u16,
+    /// all registers after the call, for larger return values
+    pub regs: [u8; 32],
+    /// data space bytes the call changed, as (address, old, new)
+    pub changed_mem: Vec<(u32, u8, u8)>,
+    pub cycles: u64,
+}
+
+impl CallResult {
+    /// a `size`-byte return value, e.g. 4 for a uint32_t, little-endian
+    pub fn ret_bytes(&self, size: usize) -> &[u8] {
+        let start = ARG_REGS_END - ((size + 1) & !1);
+        &self.regs[start..start + size]
+    }
+}
+
+/// (register, value) pairs to pass `args`, or None if they don't all fit in
+/// registers
+pub fn arg_registers(args: &[&[u8]]) -> Option<Vec<(usize, u8)>> {
+    let mut regs = vec![];
+    let mut next = ARG_REGS_END;
+
+    for arg in args {
+        let size = (arg.len() + 1) & !1;
+        if next < ARG_REGS_START + size {
+            return None;
+        }
+        next -= size;
+
+        for (i, &b) in arg.iter().enumerate() {
+            regs.push((next + i, b));
+        }
+    }
+
+    Some(regs)
+}
+
+/// data space bytes that differ between `before` and `after`
+pub fn mem_changes(before: &[u8], after: &[u8]) -> Vec<(u32, u8, u8)> {
+    before.iter().zip(after)
+        .enumerate()
+        .filter(|&(_, (old, new))| old != new)
+        .map(|(addr, (&old, &new))| (addr as u32, old, new))
+        .collect()
+}
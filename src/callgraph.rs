@@ -0,0 +1,127 @@
+// Call, return and interrupt entry events, and a call graph built from them
+//
+// The emulator already tracks calls for Emulator::call_stack; with call
+// events enabled, it also hands each call, interrupt entry and return to
+// listeners as it happens. CallGraph is one such listener: it counts who
+// calls whom, for a DOT graph, and keeps function entries and exits over
+// time, for chrome://tracing or Perfetto.
+//
+// "rcall .+0", which only pushes the pc or makes stack space, isn't a call.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+use json;
+use symbols::SymbolTable;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallKind {
+    /// CALL, RCALL, ICALL or EICALL
+    Call,
+    /// the CPU took the interrupt with this vector number
+    Interrupt(u8),
+    /// RET or RETI, or a stub returning
+    Return,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallEvent {
+    pub kind: CallKind,
+    /// the function or handler called, or returning
+    pub target: u32,
+    /// the call or return instruction, or the interrupted one
+    pub from: u32,
+    pub cycle: u64,
+    /// emulated time since power-on
+    pub time: Duration,
+    /// frames on the call stack after the event
+    pub depth: usize,
+}
+
+/// gets call events as they happen
+pub type CallListener = Box<dyn FnMut(&CallEvent) + Send>;
+
+/// name of the caller for calls from outside of any function
+const TOP_FRAME : &str = "[top]";
+
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+    /// (caller, callee, through an interrupt) -> count. the caller is None
+    /// outside of any call.
+    edges: BTreeMap<(Option<u32>, u32, bool), u64>,
+    /// callees, outermost first
+    stack: Vec<u32>,
+    /// (time, entry or exit, function)
+    events: Vec<(Duration, bool, u32)>,
+}
+
+fn name(symbols: &SymbolTable, func: Option<u32>) -> String {
+    match func {
+        Some(addr) => symbols.fmt_addr(addr),
+        None => TOP_FRAME.to_string(),
+    }
+}
+
+fn micros(t: Duration) -> f64 {
+    t.as_secs() as f64 * 1e6 + t.subsec_nanos() as f64 / 1e3
+}
+
+impl CallGraph {
+    pub fn new() -> CallGraph {
+        Default::default()
+    }
+
+    pub fn record(&mut self, event: &CallEvent) {
+        match event.kind {
+            CallKind::Call | CallKind::Interrupt(_) => {
+                let irq = event.kind != CallKind::Call;
+                let caller = self.stack.last().cloned();
+                *self.edges.entry((caller, event.target, irq)).or_insert(0) += 1;
+                self.stack.push(event.target);
+                self.events.push((event.time, true, event.target));
+            },
+
+            CallKind::Return => {
+                // returns from calls made before recording started have
+                // no entry to match
+                if let Some(func) = self.stack.pop() {
+                    self.events.push((event.time, false, func));
+                }
+            },
+        }
+    }
+
+    /// the graph in Graphviz DOT, with call counts on the edges and
+    /// interrupts dashed
+    pub fn dot(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph calls {{").unwrap();
+        writeln!(out, "    node [shape=box];").unwrap();
+
+        for (&(caller, callee, irq), count) in &self.edges {
+            writeln!(out, "    {} -> {} [label=\"{}\"{}];",
+                json::string(&name(symbols, caller)),
+                json::string(&name(symbols, Some(callee))),
+                count,
+                if irq { ", style=dashed" } else { "" }).unwrap();
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// function entries and exits as Chrome trace event JSON
+    pub fn chrome_trace(&self, symbols: &SymbolTable) -> String {
+        let events: Vec<String> = self.events
+            .iter()
+            .map(|&(time, entry, func)| format!(
+                "{{\"name\":{},\"ph\":\"{}\",\"ts\":{:.3},\"pid\":1,\"tid\":1}}",
+                json::string(&name(symbols, Some(func))),
+                if entry { "B" } else { "E" },
+                micros(time)))
+            .collect();
+
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"))
+    }
+}
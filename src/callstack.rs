@@ -0,0 +1,65 @@
+// Call stack frames, as tracked by the emulator for backtraces
+//
+// Frames are pushed on calls and interrupt entries, and popped by matching
+// the return address and SP that RET/RETI leave behind against them, so a
+// return that skips frames (e.g. after longjmp(), or when "rcall .+0" made
+// stack space) still lands on the right one. When nothing matches, frames
+// whose return address is no longer on the stack are dropped instead.
+//
+// A jump from inside a function to the start of another one is most likely
+// a tail call: the new function returns to the original caller. The top
+// frame then takes the new function, and remembers where it came from.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FrameKind {
+    /// CALL, RCALL, ICALL or EICALL
+    Call,
+    /// entered through this interrupt vector
+    Interrupt(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Frame {
+    /// SP before the return address was pushed, and after it's popped
+    pub sp: u16,
+    /// the call instruction, or the interrupted one
+    pub from: u32,
+    /// the function called, or the interrupt vector
+    pub to: u32,
+    /// byte address
+    pub ret_addr: u32,
+    pub kind: FrameKind,
+    /// the function that jumped to `to` instead of calling it, if this
+    /// looks like a tail call
+    pub tail_call_from: Option<u32>,
+}
+
+impl Frame {
+    /// "rcall .+0", which only pushes the pc or makes stack space
+    pub fn is_fake(&self) -> bool {
+        self.kind == FrameKind::Call && self.to == self.ret_addr
+            && self.tail_call_from.is_none()
+    }
+
+    /// the return address is off the stack at `sp`, so the frame is gone
+    pub fn is_dead(&self, sp: u16) -> bool {
+        sp >= self.sp
+    }
+}
+
+/// pop the frame that a return to `ret_addr`, leaving SP at `sp`, returns
+/// from, and any above it. returns the popped frames, innermost first.
+pub fn pop_frames(frames: &mut Vec<Frame>, ret_addr: u32, sp: u16) -> Vec<Frame> {
+    let start = match frames.iter().rposition(|f| f.sp == sp && f.ret_addr == ret_addr) {
+        Some(i) => i,
+        // the return address was pushed by hand or changed; go by SP alone
+        None => frames.iter().position(|f| f.is_dead(sp)).unwrap_or(frames.len()),
+    };
+
+    let mut popped = frames.split_off(start);
+    popped.reverse();
+    popped
+}
@@ -0,0 +1,401 @@
+// XMEGA clock system: the oscillators (OSC) and the system clock selection
+// and prescalers (CLK)
+//
+// Oscillators become ready as soon as they're enabled. Everything is counted
+// in CPU cycles, which run at clkPER, so the selected frequency only matters
+// for converting cycles to emulated time.
+
+use std::any::Any;
+use std::cmp;
+use std::time::Duration;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+
+
+pub const CLK_BASE : u32 = 0x0040;
+pub const OSC_BASE : u32 = 0x0050;
+
+// register offsets, relative to CLK_BASE
+const CLK_CTRL : u32 = 0x00;
+const CLK_PSCTRL : u32 = 0x01;
+const CLK_LOCK : u32 = 0x02;
+const CLK_RTCCTRL : u32 = 0x03;
+const CLK_USBCTRL : u32 = 0x04;
+const OSC_CTRL : u32 = 0x10;
+const OSC_STATUS : u32 = 0x11;
+const OSC_XOSCCTRL : u32 = 0x12;
+const OSC_XOSCFAIL : u32 = 0x13;
+const OSC_RC32KCAL : u32 = 0x14;
+const OSC_PLLCTRL : u32 = 0x15;
+const OSC_DFLLCTRL : u32 = 0x16;
+
+// CLK.CTRL system clock sources
+const SCLKSEL_MASK : u8 = 0x07;
+const SCLKSEL_RC2M : u8 = 0x00;
+const SCLKSEL_RC32M : u8 = 0x01;
+const SCLKSEL_RC32K : u8 = 0x02;
+const SCLKSEL_XOSC : u8 = 0x03;
+const SCLKSEL_PLL : u8 = 0x04;
+
+// CLK.PSCTRL fields
+const PSADIV_MASK : u8 = 0x7C;
+const PSBCDIV_MASK : u8 = 0x03;
+
+// CLK.LOCK bits
+const LOCK : u8 = 0x01;
+
+// OSC.CTRL enable bits, and the matching OSC.STATUS ready bits
+const RC2MEN : u8 = 0x01;
+const RC32MEN : u8 = 0x02;
+const RC32KEN : u8 = 0x04;
+const XOSCEN : u8 = 0x08;
+const PLLEN : u8 = 0x10;
+
+// CLK.RTCCTRL fields
+const RTCSRC_MASK : u8 = 0x0E;
+const RTCEN : u8 = 0x01;
+
+// OSC.PLLCTRL fields
+const PLLSRC_MASK : u8 = 0xC0;
+const PLLSRC_RC2M : u8 = 0x00;
+const PLLSRC_RC32M : u8 = 0x80;
+const PLLSRC_XOSC : u8 = 0xC0;
+const PLLFAC_MASK : u8 = 0x1F;
+
+const RC2M_FREQ : u32 = 2_000_000;
+const RC32M_FREQ : u32 = 32_000_000;
+const RC32K_FREQ : u32 = 32_768;
+
+const NANOS_PER_SEC : u64 = 1_000_000_000;
+
+
+#[derive(Clone)]
+pub struct Clock {
+    pub ctrl: u8,
+    pub psctrl: u8,
+    pub lock: u8,
+    pub rtcctrl: u8,
+    pub usbctrl: u8,
+    pub osc_ctrl: u8,
+    pub xoscctrl: u8,
+    pub xoscfail: u8,
+    pub rc32kcal: u8,
+    pub pllctrl: u8,
+    pub dfllctrl: u8,
+
+    /// frequency of the external crystal or clock on XTAL1/XTAL2, or None
+    /// if there isn't one. set by the host; survives resets.
+    pub xosc_freq: Option<u32>,
+
+    /// emulated time at `base_cycle`, when the CPU frequency last changed
+    base_nanos: u64,
+    base_cycle: u64,
+    base_freq: u32,
+    last_tick: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            ctrl: SCLKSEL_RC2M,
+            psctrl: 0,
+            lock: 0,
+            rtcctrl: 0,
+            usbctrl: 0,
+            osc_ctrl: RC2MEN,
+            xoscctrl: 0,
+            xoscfail: 0,
+            rc32kcal: 0,
+            pllctrl: 0,
+            dfllctrl: 0,
+
+            xosc_freq: Some(16_000_000),
+
+            base_nanos: 0,
+            base_cycle: 0,
+            base_freq: RC2M_FREQ,
+            last_tick: 0,
+        }
+    }
+
+    /// OSC.STATUS: enabled oscillators are ready at once, but the PLL also
+    /// needs its source
+    pub fn status(&self) -> u8 {
+        let mut ready = self.osc_ctrl & (RC2MEN | RC32MEN | RC32KEN);
+
+        if (self.osc_ctrl & XOSCEN) != 0 && self.xosc_freq.is_some() {
+            ready |= XOSCEN;
+        }
+
+        if (self.osc_ctrl & PLLEN) != 0 && (ready & self.pll_source()) != 0 {
+            ready |= PLLEN;
+        }
+
+        ready
+    }
+
+    /// the OSC.CTRL bit of the PLL's source oscillator
+    fn pll_source(&self) -> u8 {
+        match self.pllctrl & PLLSRC_MASK {
+            PLLSRC_RC2M => RC2MEN,
+            PLLSRC_RC32M => RC32MEN,
+            PLLSRC_XOSC => XOSCEN,
+            _ => 0,
+        }
+    }
+
+    fn pll_freq(&self) -> u32 {
+        let source = match self.pllctrl & PLLSRC_MASK {
+            PLLSRC_RC2M => RC2M_FREQ,
+            // the 32 MHz oscillator goes through a /4 divider first
+            PLLSRC_RC32M => RC32M_FREQ / 4,
+            PLLSRC_XOSC => self.xosc_freq.unwrap_or(0),
+            _ => 0,
+        };
+        source * ((self.pllctrl & PLLFAC_MASK) as u32)
+    }
+
+    /// clkSYS, the selected oscillator's frequency
+    pub fn sys_freq(&self) -> u32 {
+        match self.ctrl & SCLKSEL_MASK {
+            SCLKSEL_RC2M => RC2M_FREQ,
+            SCLKSEL_RC32M => RC32M_FREQ,
+            SCLKSEL_RC32K => RC32K_FREQ,
+            SCLKSEL_XOSC => self.xosc_freq.unwrap_or(0),
+            SCLKSEL_PLL => self.pll_freq(),
+            _ => RC2M_FREQ,
+        }
+    }
+
+    /// clkPER4, after prescaler A
+    pub fn per4_freq(&self) -> u32 {
+        let psadiv = (self.psctrl & PSADIV_MASK) >> 2;
+        // odd values from 1 to 17 divide by 2 to 512; anything else is /1
+        match psadiv {
+            1..=17 if psadiv % 2 == 1 => self.sys_freq() >> psadiv.div_ceil(2),
+            _ => self.sys_freq(),
+        }
+    }
+
+    /// clkPER2, after prescaler B
+    pub fn per2_freq(&self) -> u32 {
+        match self.psctrl & PSBCDIV_MASK {
+            2 => self.per4_freq() / 4,
+            3 => self.per4_freq() / 2,
+            _ => self.per4_freq(),
+        }
+    }
+
+    /// clkPER, which is also clkCPU, after prescaler C
+    pub fn cpu_freq(&self) -> u32 {
+        match self.psctrl & PSBCDIV_MASK {
+            1 | 3 => self.per2_freq() / 2,
+            _ => self.per2_freq(),
+        }
+    }
+
+    /// emulated time since power-on at cycle `now`
+    pub fn time_at(&self, now: u64) -> Duration {
+        let cycles = now.saturating_sub(self.base_cycle) as u128;
+        let nanos = cycles * (NANOS_PER_SEC as u128)
+            / (cmp::max(self.base_freq, 1) as u128);
+        let nanos = self.base_nanos + nanos as u64;
+        Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32)
+    }
+
+    /// the RTC's clock, or None if it's off. the external clock on TOSC1
+    /// isn't emulated.
+    pub fn rtc_freq(&self) -> Option<u32> {
+        if (self.rtcctrl & RTCEN) == 0 {
+            return None;
+        }
+
+        match (self.rtcctrl & RTCSRC_MASK) >> 1 {
+            // 1.024 kHz from the ULP, TOSC or RC oscillators
+            0..=2 => Some(1024),
+            // 32.768 kHz from TOSC or the RC oscillator
+            5 | 6 => Some(RC32K_FREQ),
+            _ => None,
+        }
+    }
+
+    /// emulated time since power-on, as of the last tick
+    pub fn elapsed(&self) -> Duration {
+        self.time_at(self.last_tick)
+    }
+
+    /// emulated time since power-on at cycle `now`, in nanoseconds
+    pub fn nanos_at(&self, now: u64) -> u64 {
+        let t = self.time_at(now);
+        t.as_secs() * NANOS_PER_SEC + t.subsec_nanos() as u64
+    }
+
+    /// switch the system clock, if the new source is ready
+    fn write_ctrl(&mut self, val: u8) {
+        if (self.status() & sclk_source(val)) != 0 {
+            self.ctrl = val & SCLKSEL_MASK;
+        }
+    }
+
+    /// enable or disable oscillators. the one driving the system clock
+    /// can't be disabled, and the PLL's source can't be disabled while it
+    /// runs.
+    fn write_osc_ctrl(&mut self, val: u8) {
+        let mut keep = sclk_source(self.ctrl);
+        if ((val | keep) & PLLEN) != 0 {
+            keep |= self.pll_source();
+        }
+
+        self.osc_ctrl = (val & (RC2MEN | RC32MEN | RC32KEN | XOSCEN | PLLEN))
+            | (self.osc_ctrl & keep);
+    }
+}
+
+/// the OSC.CTRL bit of the oscillator selected by CLK.CTRL value `ctrl`
+fn sclk_source(ctrl: u8) -> u8 {
+    match ctrl & SCLKSEL_MASK {
+        SCLKSEL_RC2M => RC2MEN,
+        SCLKSEL_RC32M => RC32MEN,
+        SCLKSEL_RC32K => RC32KEN,
+        SCLKSEL_XOSC => XOSCEN,
+        SCLKSEL_PLL => PLLEN,
+        _ => 0,
+    }
+}
+
+impl Peripheral for Clock {
+    fn name(&self) -> &str {
+        "CLK"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        // CLK and OSC, with the gap between them
+        (CLK_BASE, OSC_BASE + 0x08 - CLK_BASE)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CLK_CTRL => self.ctrl,
+            CLK_PSCTRL => self.psctrl,
+            CLK_LOCK => self.lock,
+            CLK_RTCCTRL => self.rtcctrl,
+            CLK_USBCTRL => self.usbctrl,
+            OSC_CTRL => self.osc_ctrl,
+            OSC_STATUS => self.status(),
+            OSC_XOSCCTRL => self.xoscctrl,
+            OSC_XOSCFAIL => self.xoscfail,
+            OSC_RC32KCAL => self.rc32kcal,
+            OSC_PLLCTRL => self.pllctrl,
+            OSC_DFLLCTRL => self.dfllctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        let locked = (self.lock & LOCK) != 0;
+
+        match ofs {
+            CLK_CTRL if !locked => self.write_ctrl(val),
+            CLK_PSCTRL if !locked =>
+                self.psctrl = val & (PSADIV_MASK | PSBCDIV_MASK),
+            // can only be set, until the next reset
+            CLK_LOCK => self.lock |= val & LOCK,
+            CLK_RTCCTRL => self.rtcctrl = val,
+            CLK_USBCTRL => self.usbctrl = val,
+            OSC_CTRL => self.write_osc_ctrl(val),
+            OSC_XOSCCTRL => self.xoscctrl = val,
+            OSC_XOSCFAIL => self.xoscfail = val,
+            OSC_RC32KCAL => self.rc32kcal = val,
+            // can't be changed while the PLL is enabled
+            OSC_PLLCTRL if (self.osc_ctrl & PLLEN) == 0 => self.pllctrl = val,
+            OSC_DFLLCTRL => self.dfllctrl = val,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let xosc_freq = self.xosc_freq;
+        let base_nanos = self.nanos_at(self.last_tick);
+        let last_tick = self.last_tick;
+
+        *self = Clock::new();
+        self.xosc_freq = xosc_freq;
+
+        // time goes on, at the reset frequency
+        self.base_nanos = base_nanos;
+        self.base_cycle = last_tick;
+        self.last_tick = last_tick;
+    }
+
+    fn tick(&mut self, now: u64) {
+        if now < self.last_tick {
+            // the cycle counter went back to 0, so this is a power-on reset
+            self.base_nanos = 0;
+            self.base_cycle = 0;
+        }
+
+        let freq = self.cpu_freq();
+        if freq != self.base_freq {
+            self.base_nanos = self.nanos_at(now);
+            self.base_cycle = now;
+            self.base_freq = freq;
+        }
+
+        self.last_tick = now;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.ctrl, self.psctrl, self.lock, self.rtcctrl, self.usbctrl,
+            self.osc_ctrl, self.xoscctrl, self.xoscfail, self.rc32kcal,
+            self.pllctrl, self.dfllctrl,
+        ];
+        state.write_u64::<LittleEndian>(self.base_nanos).unwrap();
+        state.write_u64::<LittleEndian>(self.base_cycle).unwrap();
+        state.write_u32::<LittleEndian>(self.base_freq).unwrap();
+        state.write_u64::<LittleEndian>(self.last_tick).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() != 11 + 8 + 8 + 4 + 8 {
+            return;
+        }
+
+        self.ctrl = state[0];
+        self.psctrl = state[1];
+        self.lock = state[2];
+        self.rtcctrl = state[3];
+        self.usbctrl = state[4];
+        self.osc_ctrl = state[5];
+        self.xoscctrl = state[6];
+        self.xoscfail = state[7];
+        self.rc32kcal = state[8];
+        self.pllctrl = state[9];
+        self.dfllctrl = state[10];
+
+        let mut r = &state[11..];
+        self.base_nanos = r.read_u64::<LittleEndian>().unwrap();
+        self.base_cycle = r.read_u64::<LittleEndian>().unwrap();
+        self.base_freq = r.read_u32::<LittleEndian>().unwrap();
+        self.last_tick = r.read_u64::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
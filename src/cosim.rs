@@ -0,0 +1,149 @@
+// Two emulators side by side, with their USARTs cross-connected
+//
+// For boards with two MCUs talking over a UART: what either instance's USART
+// transmits is what the other one receives. The instances share one timeline
+// of emulated time, and whichever is behind steps next, so neither gets ahead
+// of a byte the other is about to send. Each keeps its own clock settings.
+//
+// A byte reaches the other side once its frame has been sent, and then takes
+// a frame there to arrive, so the link adds a frame of latency. Mismatched
+// baud rates aren't detected.
+
+use std::any::Any;
+use std::time::Duration;
+use emulator::{Emulator, RunResult, StopReason};
+use iomem::IOMemory;
+use wiring::PinDevice;
+
+
+const LINK_NAME : &str = "cosim link";
+const NANOS_PER_SEC : u64 = 1_000_000_000;
+
+/// keeps a sleeping CPU from skipping past the other instance's present
+#[derive(Clone)]
+struct Horizon {
+    cycle: Option<u64>,
+}
+
+impl PinDevice for Horizon {
+    fn name(&self) -> &str {
+        LINK_NAME
+    }
+
+    fn update(&mut self, _io_mem: &mut IOMemory, _now: u64) {}
+
+    fn next_event(&self) -> Option<u64> {
+        self.cycle
+    }
+
+    fn box_clone(&self) -> Box<dyn PinDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn nanos(emu: &Emulator) -> u64 {
+    let t = emu.emulated_time();
+    t.as_secs() * NANOS_PER_SEC + t.subsec_nanos() as u64
+}
+
+/// how many of `emu`'s CPU cycles `nanos` takes, at its current clock
+fn cycles_in(emu: &Emulator, nanos: u64) -> u64 {
+    let freq = emu.io_mem.clock().map_or(emu.device.cpu_freq, |clk| clk.cpu_freq());
+    (nanos as u128 * freq as u128 / NANOS_PER_SEC as u128) as u64
+}
+
+/// why a run stopped: which instance stopped (0 for `a`, 1 for `b`), and how
+#[derive(Debug)]
+pub struct CosimStop {
+    pub instance: usize,
+    pub result: RunResult,
+}
+
+pub struct Cosim {
+    pub a: Emulator,
+    pub b: Emulator,
+    /// how much of each instance's USART output was passed on
+    sent: [usize; 2],
+}
+
+impl Cosim {
+    /// connect `a` and `b`, which should be loaded and reset already
+    pub fn new(mut a: Emulator, mut b: Emulator) -> Cosim {
+        a.io_mem.attach_pin_device(Box::new(Horizon { cycle: None }));
+        b.io_mem.attach_pin_device(Box::new(Horizon { cycle: None }));
+        let sent = [a.io_mem.usart_output_log.len(), b.io_mem.usart_output_log.len()];
+        Cosim { a, b, sent }
+    }
+
+    /// instance `i`, and the other one
+    fn pair(&mut self, i: usize) -> (&mut Emulator, &mut Emulator) {
+        if i == 0 { (&mut self.a, &mut self.b) } else { (&mut self.b, &mut self.a) }
+    }
+
+    /// how far both instances have got
+    pub fn emulated_time(&self) -> Duration {
+        let nanos = nanos(&self.a).min(nanos(&self.b));
+        Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32)
+    }
+
+    /// step whichever instance is behind, and pass on what it transmitted.
+    /// returns which instance it was.
+    pub fn step(&mut self) -> (usize, RunResult) {
+        let i = if nanos(&self.a) <= nanos(&self.b) { 0 } else { 1 };
+        let sent = self.sent[i];
+
+        let (emu, other) = self.pair(i);
+        let ahead = nanos(other).saturating_sub(nanos(emu));
+        let horizon = emu.cycle_count + cycles_in(emu, ahead);
+        emu.io_mem.pin_device_mut::<Horizon>(LINK_NAME).unwrap().cycle = Some(horizon);
+
+        let result = emu.step();
+
+        // a power-on reset clears the log
+        let log = &emu.io_mem.usart_output_log;
+        let start = if log.len() < sent { 0 } else { sent };
+        if log.len() > start {
+            other.queue_uart_input(&log[start..]);
+        }
+        let sent = log.len();
+
+        self.sent[i] = sent;
+        (i, result)
+    }
+
+    /// run until `time` (emulated time since power-on) or until either
+    /// instance stops
+    pub fn run_until(&mut self, time: Duration) -> Option<CosimStop> {
+        while self.emulated_time() < time {
+            if let Some(stop) = self.step_checked() {
+                return Some(stop);
+            }
+        }
+        None
+    }
+
+    /// run until either instance stops
+    pub fn run(&mut self) -> CosimStop {
+        loop {
+            if let Some(stop) = self.step_checked() {
+                return stop;
+            }
+        }
+    }
+
+    fn step_checked(&mut self) -> Option<CosimStop> {
+        let (instance, result) = self.step();
+        match result.reason {
+            StopReason::InsnLimit => None,
+            _ => Some(CosimStop { instance, result }),
+        }
+    }
+}
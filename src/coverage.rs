@@ -0,0 +1,168 @@
+// Code coverage: which flash addresses executed, and how often
+//
+// Reports are per function when symbols are loaded. `addresses` lists the
+// executed instruction addresses in hex, one per line, which can be fed to
+// `avr-addr2line -e firmware.elf`. `lcov` needs a way to map addresses to
+// source lines.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use progmem::ProgramMemory;
+use symbols::SymbolTable;
+
+
+#[derive(Clone)]
+pub struct Coverage {
+    /// execution counts per flash word
+    hits: Vec<u32>,
+}
+
+impl Coverage {
+    /// for `flash_size` bytes of flash
+    pub fn new(flash_size: u32) -> Coverage {
+        Coverage { hits: vec![0; (flash_size / 2) as usize] }
+    }
+
+    /// the instruction at byte address `pc` was executed
+    pub fn hit(&mut self, pc: u32) {
+        if let Some(count) = self.hits.get_mut((pc / 2) as usize) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    pub fn hits(&self, pc: u32) -> u32 {
+        self.hits.get((pc / 2) as usize).cloned().unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        for count in self.hits.iter_mut() {
+            *count = 0;
+        }
+    }
+
+    /// byte addresses of executed instructions, in order
+    pub fn executed(&self) -> Vec<u32> {
+        self.hits
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count != 0)
+            .map(|(i, _)| (i * 2) as u32)
+            .collect()
+    }
+
+    /// instruction addresses in [start, end), following instruction sizes
+    fn insns_in(prog_mem: &ProgramMemory, start: u32, end: u32) -> Vec<u32> {
+        let mut addrs = vec![];
+        let mut addr = start;
+        while addr < end {
+            addrs.push(addr);
+            addr += prog_mem.get_insn_at(addr).map_or(2, |insn| insn.byte_size() as u32);
+        }
+        addrs
+    }
+
+    /// (name, covered instructions, instructions) for each function
+    pub fn functions(&self, prog_mem: &ProgramMemory, symbols: &SymbolTable)
+            -> Vec<(String, usize, usize)> {
+
+        symbols.symbols()
+            .iter()
+            .filter(|sym| sym.is_code())
+            .filter_map(|sym| {
+                let (start, end) = symbols.range(&sym.name)?;
+                let insns = Coverage::insns_in(prog_mem, start, end);
+                let covered = insns.iter().filter(|&&a| self.hits(a) != 0).count();
+                Some((sym.name.clone(), covered, insns.len()))
+            })
+            .collect()
+    }
+
+    pub fn report(&self, prog_mem: &ProgramMemory, symbols: &SymbolTable)
+            -> String {
+
+        let mut out = String::new();
+        let functions = self.functions(prog_mem, symbols);
+
+        if functions.is_empty() {
+            writeln!(out, "{} instruction addresses executed",
+                self.executed().len()).unwrap();
+            return out;
+        }
+
+        let percent = |covered: usize, total: usize| {
+            if total == 0 { 0.0 } else { 100.0 * covered as f64 / total as f64 }
+        };
+
+        let mut covered_funcs = 0;
+        let mut covered_insns = 0;
+        let mut total_insns = 0;
+
+        writeln!(out, "{:<32} {:>8} {:>8} {:>7}", "function", "covered", "insns", "%")
+            .unwrap();
+        for &(ref name, covered, total) in functions.iter() {
+            writeln!(out, "{:<32} {:>8} {:>8} {:>7.2}",
+                name, covered, total, percent(covered, total)).unwrap();
+
+            if covered != 0 {
+                covered_funcs += 1;
+            }
+            covered_insns += covered;
+            total_insns += total;
+        }
+
+        writeln!(out, "functions: {}/{} ({:.2}%), instructions: {}/{} ({:.2}%)",
+            covered_funcs, functions.len(), percent(covered_funcs, functions.len()),
+            covered_insns, total_insns, percent(covered_insns, total_insns))
+            .unwrap();
+        out
+    }
+
+    pub fn addresses(&self) -> String {
+        let mut out = String::new();
+        for addr in self.executed() {
+            writeln!(out, "{:#x}", addr).unwrap();
+        }
+        out
+    }
+
+    /// an lcov tracefile. `line_of` maps a byte address to (source file,
+    /// line). instructions in functions that didn't execute count as
+    /// uncovered lines.
+    pub fn lcov<F>(&self, prog_mem: &ProgramMemory, symbols: &SymbolTable,
+                   line_of: F) -> String
+            where F: Fn(u32) -> Option<(String, u32)> {
+
+        // file -> line -> hits
+        let mut files: BTreeMap<String, BTreeMap<u32, u32>> = BTreeMap::new();
+        let mut add = |addr: u32, hits: u32| {
+            if let Some((file, line)) = line_of(addr) {
+                let count = files.entry(file).or_default()
+                    .entry(line).or_insert(0);
+                *count = (*count).max(hits);
+            }
+        };
+
+        for sym in symbols.symbols().iter().filter(|sym| sym.is_code()) {
+            if let Some((start, end)) = symbols.range(&sym.name) {
+                for addr in Coverage::insns_in(prog_mem, start, end) {
+                    add(addr, self.hits(addr));
+                }
+            }
+        }
+        for addr in self.executed() {
+            add(addr, self.hits(addr));
+        }
+
+        let mut out = String::new();
+        for (file, lines) in files.iter() {
+            writeln!(out, "SF:{}", file).unwrap();
+            for (line, hits) in lines.iter() {
+                writeln!(out, "DA:{},{}", line, hits).unwrap();
+            }
+            writeln!(out, "LH:{}", lines.values().filter(|&&h| h != 0).count()).unwrap();
+            writeln!(out, "LF:{}", lines.len()).unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+}
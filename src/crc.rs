@@ -0,0 +1,187 @@
+// XMEGA CRC module
+//
+// CRC-16 is CCITT (polynomial 0x1021, most significant bit first). CRC-32 is
+// the IEEE 802.3 one, least significant bit first, and complemented when the
+// computation ends. Data comes from writes to DATAIN, or from flash through
+// the NVM controller's CRC commands. DMA channel sources aren't emulated.
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+
+
+// register offsets
+const CTRL : u32 = 0x00;
+const STATUS : u32 = 0x01;
+const DATAIN : u32 = 0x03;
+const CHECKSUM0 : u32 = 0x04;
+const CHECKSUM3 : u32 = 0x07;
+
+// CTRL bits
+const RESET_MASK : u8 = 0xC0;
+const RESET0 : u8 = 0x80;
+const RESET1 : u8 = 0xC0;
+const CRC32 : u8 = 0x20;
+const SOURCE_MASK : u8 = 0x0F;
+
+// CTRL.SOURCE values
+const SOURCE_DISABLE : u8 = 0x0;
+const SOURCE_IO : u8 = 0x1;
+const SOURCE_FLASH : u8 = 0x2;
+
+// STATUS bits
+const ZERO : u8 = 0x02;
+const BUSY : u8 = 0x01;
+
+const CRC16_POLY : u16 = 0x1021;
+/// reversed
+const CRC32_POLY : u32 = 0xEDB8_8320;
+
+
+#[derive(Clone)]
+pub struct Crc {
+    base: u32,
+    pub ctrl: u8,
+    pub status: u8,
+    pub checksum: u32,
+}
+
+impl Crc {
+    pub fn new(base: u32) -> Crc {
+        Crc {
+            base,
+            ctrl: 0,
+            status: 0,
+            checksum: 0,
+        }
+    }
+
+    fn is_crc32(&self) -> bool {
+        (self.ctrl & CRC32) != 0
+    }
+
+    fn update(&mut self, val: u8) {
+        if self.is_crc32() {
+            let mut crc = self.checksum ^ val as u32;
+            for _ in 0..8 {
+                crc = if (crc & 1) != 0 { (crc >> 1) ^ CRC32_POLY } else { crc >> 1 };
+            }
+            self.checksum = crc;
+        } else {
+            let mut crc = self.checksum as u16 ^ (val as u16) << 8;
+            for _ in 0..8 {
+                crc = if (crc & 0x8000) != 0 { (crc << 1) ^ CRC16_POLY } else { crc << 1 };
+            }
+            self.checksum = crc as u32;
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.is_crc32() {
+            self.checksum = !self.checksum;
+        }
+        self.status &= !BUSY;
+        if self.checksum == 0 {
+            self.status |= ZERO;
+        }
+    }
+
+    /// run `data`, read from flash by an NVM CRC command, through the
+    /// module. returns false if the module isn't set up for flash.
+    pub fn flash_checksum(&mut self, data: &[u8]) -> bool {
+        if (self.ctrl & SOURCE_MASK) != SOURCE_FLASH || (self.status & BUSY) == 0 {
+            return false;
+        }
+
+        for &val in data {
+            self.update(val);
+        }
+        self.finish();
+        true
+    }
+}
+
+impl Peripheral for Crc {
+    fn name(&self) -> &str {
+        "CRC"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x08)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            STATUS => self.status,
+            CHECKSUM0..=CHECKSUM3 => (self.checksum >> (8 * (ofs - CHECKSUM0))) as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL => {
+                let reset = val & RESET_MASK;
+                if reset == RESET0 || reset == RESET1 {
+                    self.checksum = if reset == RESET1 { 0xFFFF_FFFF } else { 0 };
+                    self.status = 0;
+                }
+
+                self.ctrl = val & !RESET_MASK;
+                if !self.is_crc32() {
+                    self.checksum &= 0xFFFF;
+                }
+                if (val & SOURCE_MASK) != SOURCE_DISABLE {
+                    self.status = BUSY;
+                }
+            },
+            // writing BUSY ends the computation
+            STATUS if (val & BUSY) != 0 && (self.status & BUSY) != 0 => self.finish(),
+            DATAIN if (self.status & BUSY) != 0 && (self.ctrl & SOURCE_MASK) == SOURCE_IO =>
+                self.update(val),
+            // the checksum can be preset while the module's idle
+            CHECKSUM0..=CHECKSUM3 if (self.status & BUSY) == 0 => {
+                let shift = 8 * (ofs - CHECKSUM0);
+                self.checksum = (self.checksum & !(0xFF << shift)) | (val as u32) << shift;
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Crc::new(self.base);
+    }
+
+    fn powered(&self) -> bool {
+        (self.ctrl & SOURCE_MASK) != SOURCE_DISABLE
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.ctrl, self.status];
+        state.write_u32::<LittleEndian>(self.checksum).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 6 {
+            return;
+        }
+
+        self.ctrl = state[0];
+        self.status = state[1];
+        self.checksum = (&state[2..]).read_u32::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,59 @@
+// Instruction timings, from the AVR instruction set manual. They depend on
+// the core (XMEGA or classic) and on whether the PC is 16 or 22 bits wide.
+
+use disa::{AvrInsn, MemAccess, MemRegUpdate};
+use device::Device;
+use isa::Isa;
+
+
+/// cycles taken by `insn` on `device`. `taken` says whether a conditional
+/// branch was taken; skips are accounted for by the skipped instruction
+/// itself.
+pub fn insn_cycles(insn: &AvrInsn, taken: bool, device: &Device) -> u64 {
+    let xmega = device.isa == Isa::Xmega;
+    // an extra cycle for the third byte of a return address
+    let wide = device.has_22bit_addrs as u64;
+
+    match insn {
+        &AvrInsn::Breq(_) | &AvrInsn::Brne(_)
+        | &AvrInsn::Brcc(_) | &AvrInsn::Brcs(_)
+        | &AvrInsn::Brge(_) | &AvrInsn::Brlt(_)
+        | &AvrInsn::Brmi(_) | &AvrInsn::Brpl(_)
+        | &AvrInsn::Brtc(_) | &AvrInsn::Brts(_)
+        | &AvrInsn::Brhc(_) | &AvrInsn::Brhs(_)
+        | &AvrInsn::Brvc(_) | &AvrInsn::Brvs(_)
+        | &AvrInsn::Brid(_) | &AvrInsn::Brie(_)
+        | &AvrInsn::Brbc(_, _) | &AvrInsn::Brbs(_, _) if taken => 2,
+
+        &AvrInsn::Rjmp(_) | &AvrInsn::Ijmp | &AvrInsn::Eijmp => 2,
+        &AvrInsn::Jmp(_) => 3,
+
+        &AvrInsn::Rcall(_) | &AvrInsn::Icall =>
+            if xmega { 2 + wide } else { 3 + wide },
+        &AvrInsn::Call(_) =>
+            if xmega { 3 + wide } else { 4 + wide },
+        &AvrInsn::Eicall => if xmega { 3 } else { 4 },
+        &AvrInsn::Ret | &AvrInsn::Reti => 4 + wide,
+
+        &AvrInsn::Adiw(_, _) | &AvrInsn::Sbiw(_, _) => 2,
+        &AvrInsn::Mul(_, _) | &AvrInsn::Muls(_, _) | &AvrInsn::Mulsu(_, _)
+        | &AvrInsn::Fmul(_, _) | &AvrInsn::Fmuls(_, _)
+        | &AvrInsn::Fmulsu(_, _) => 2,
+
+        &AvrInsn::Pop(_) => 2,
+        &AvrInsn::Xch(_) | &AvrInsn::Las(_) | &AvrInsn::Lac(_)
+        | &AvrInsn::Lat(_) => 2,
+        &AvrInsn::Ldd(_, _) => 2,
+        &AvrInsn::Lds(_, _) | &AvrInsn::Sts(_, _) => 2,
+        &AvrInsn::LpmZ(_, _) | &AvrInsn::ElpmZ(_, _) => 3,
+
+        // classic cores take longer to get at data space through a pointer,
+        // and to set and clear IO bits
+        &AvrInsn::Ld(_, MemAccess { update: MemRegUpdate::PreDec, .. }) if !xmega => 3,
+        &AvrInsn::Ld(_, _) if !xmega => 2,
+        &AvrInsn::St(_, _) | &AvrInsn::Std(_, _) | &AvrInsn::Push(_)
+        | &AvrInsn::Sbi(_, _) | &AvrInsn::Cbi(_, _) if !xmega => 2,
+
+        _ => 1,
+    }
+}
@@ -0,0 +1,541 @@
+// Interactive debugger prompt
+
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::result;
+use board::{Board, BOARD_NAME};
+use emulator::Emulator;
+use error::Result;
+use expr::Expr;
+use lcd;
+use sreg::parse_flags;
+use symbols::{SymbolTable, DATA_OFFSET};
+
+
+/// longest data symbol a watch shows, in bytes
+const MAX_WATCH_BYTES : usize = 16;
+
+#[derive(Clone, Debug)]
+enum WatchValue {
+    Expr(Expr),
+    /// a data symbol's contents
+    Bytes { addr: u32, len: usize },
+}
+
+/// shown after every command that runs the program
+#[derive(Clone, Debug)]
+pub struct Watch {
+    pub text: String,
+    value: WatchValue,
+    /// the value last shown, to point out changes
+    last: Option<String>,
+}
+
+impl Watch {
+    /// a data symbol's name, for its contents, or an expression (see
+    /// expr.rs), where symbols stand for their addresses
+    pub fn parse(text: &str, symbols: &SymbolTable) -> result::Result<Watch, String> {
+        let text = text.trim();
+        let value = match symbols.find(text) {
+            Some(sym) if sym.addr >= DATA_OFFSET => WatchValue::Bytes {
+                addr: sym.addr - DATA_OFFSET,
+                len: (sym.size as usize).clamp(1, MAX_WATCH_BYTES),
+            },
+            _ => WatchValue::Expr(Expr::parse_with_symbols(text, symbols)?),
+        };
+
+        Ok(Watch { text: text.to_string(), value, last: None })
+    }
+
+    fn eval(&self, emu: &Emulator) -> String {
+        match self.value {
+            WatchValue::Expr(ref e) => {
+                let val = e.eval(emu);
+                format!("{} ({:#x})", val, val)
+            },
+
+            // little-endian, so 1, 2 and 4 byte variables read as numbers
+            WatchValue::Bytes { addr, len } => {
+                let mut buf = vec![0; len];
+                if emu.read_data(addr, &mut buf).is_err() {
+                    return "<out of range>".to_string();
+                }
+                match len {
+                    1 | 2 | 4 => {
+                        let val = buf.iter().rev().fold(0u32, |acc, &b| (acc << 8) | b as u32);
+                        format!("{} ({:#x})", val, val)
+                    },
+                    _ => buf.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+                }
+            },
+        }
+    }
+}
+
+pub struct Debugger<'a> {
+    pub emu: &'a mut Emulator,
+    pub watches: Vec<Watch>,
+    /// show the state and watches after commands that run the program.
+    /// front-ends that show them all the time turn it off.
+    pub show_state: bool,
+}
+
+const HELP: &str = "\
+commands:
+  s, step [n]         execute n instructions (default 1)
+  n, next             execute one instruction, stepping over calls
+  fin, finish         run until the current function returns
+  bs, back [n]        step back n instructions (needs time travel)
+  c, continue         run until the program halts, or Ctrl-C
+  u, until <addr>     run until pc == addr
+  b, break <addr> [if <cond>]
+                      add a breakpoint, e.g. b 0x1234 if r24 == 0x42
+  bl, breakpoints     list breakpoints
+  d, delete <id>      remove a breakpoint
+  r, regs             show the current state
+  w, watch <expr>     show a data symbol, or an expression like
+                      mem16[counter] + 1, after every command that runs
+                      the program; changed values are marked with *
+  wl, watches         list watches
+  unwatch <n>         remove watch n
+  x, dump <addr> [len]
+                      show len bytes (default 64, or a symbol's size) of
+                      data memory at an address or data symbol
+  dumpmem [flash] <start> <end> <file>
+                      save data memory, or flash, from start up to end to
+                      a file, as Intel HEX if it ends with .hex, otherwise
+                      raw
+  set mem <addr> <bytes...>
+                      store bytes in data memory, e.g. set mem buf 0x41 0
+  set reg rN <val>    set a register
+  set sreg <flags>    set SREG to the named flags, e.g. set sreg ZC
+  json                show the current state as JSON
+  lcd                 show what attached LCDs display
+  board               show the board's LEDs and buttons
+  press <button>      press a button on the board, until released
+  release <button>    release a button on the board
+  coverage            show coverage so far (needs --coverage)
+  stats               show runtime statistics, starting them if needed
+  stack               show stack usage, starting to track it if needed
+  heatmap [n]         show the n busiest IO registers and memory pages,
+                      starting to count if needed
+  profile             show cycles per function (needs --profile)
+  q, quit             exit the debugger
+";
+
+fn parse_num(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// the data space address of a data symbol, or a number
+fn parse_data_addr(spec: &str, symbols: &SymbolTable) -> Option<(u32, Option<usize>)> {
+    match symbols.find(spec) {
+        Some(sym) if sym.addr >= DATA_OFFSET =>
+            Some((sym.addr - DATA_OFFSET, Some(sym.size as usize).filter(|&n| n > 0))),
+        _ => parse_num(spec).map(|addr| (addr as u32, None)),
+    }
+}
+
+/// parse "<addr> [if <cond>]"
+pub fn parse_breakpoint(spec: &str) -> Option<(u32, Option<&str>)> {
+    let spec = spec.trim();
+    let (addr, cond) = match spec.find(" if ") {
+        Some(i) => (&spec[..i], Some(spec[i + 4..].trim())),
+        None => (spec, None),
+    };
+
+    parse_num(addr.trim()).map(|addr| (addr as u32, cond))
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(emu: &'a mut Emulator) -> Debugger<'a> {
+        Debugger { emu, watches: vec![], show_state: true }
+    }
+
+    /// the watches' values, marking the ones that changed since last time
+    pub fn fmt_watches(&mut self) -> String {
+        let mut out = String::new();
+        for (i, watch) in self.watches.iter_mut().enumerate() {
+            let val = watch.eval(self.emu);
+            let changed = watch.last.as_ref().is_some_and(|last| *last != val);
+            out.push_str(&format!("{}{}: {} = {}\n",
+                if changed { "*" } else { " " }, i + 1, watch.text, val));
+            watch.last = Some(val);
+        }
+        out
+    }
+
+    /// "mem <addr> <bytes...>", "reg rN <val>" or "sreg <flags>"
+    fn set(&mut self, args: &[&str]) -> result::Result<(), String> {
+        match args.first().copied() {
+            Some("mem") => {
+                let addr = args.get(1).and_then(|s| parse_data_addr(s, &self.emu.symbols))
+                    .map(|(addr, _)| addr);
+                let bytes: Option<Vec<u8>> = args.iter().skip(2)
+                    .map(|s| parse_num(s).filter(|&b| b <= 0xff).map(|b| b as u8))
+                    .collect();
+                match (addr, bytes) {
+                    (Some(addr), Some(ref bytes)) if !bytes.is_empty() =>
+                        self.emu.write_data(addr, bytes).map_err(|e| format!("error: {}", e)),
+                    _ => Err("usage: set mem <addr|symbol> <bytes...>".to_string()),
+                }
+            },
+
+            Some("reg") => {
+                let reg = args.get(1)
+                    .filter(|s| s.starts_with('r'))
+                    .and_then(|s| s[1..].parse::<u8>().ok())
+                    .filter(|&r| r < 32);
+                let val = args.get(2).and_then(|s| parse_num(s)).filter(|&v| v <= 0xff);
+                match (reg, val) {
+                    (Some(r), Some(val)) => {
+                        self.emu.set_reg8(r, val as u8);
+                        Ok(())
+                    },
+                    _ => Err("usage: set reg rN <val>".to_string()),
+                }
+            },
+
+            Some("sreg") => {
+                // no flags clears them all
+                match parse_flags(args.get(1).unwrap_or(&"")) {
+                    Some(val) => {
+                        self.emu.set_sreg(val);
+                        Ok(())
+                    },
+                    None => Err("usage: set sreg <flags>, e.g. set sreg ZC".to_string()),
+                }
+            },
+
+            _ => Err("usage: set mem|reg|sreg ...".to_string()),
+        }
+    }
+
+    fn run_until(&mut self, pc: Option<u32>) -> Result<()> {
+        self.emu.halted = false;
+        while !self.emu.halted {
+            self.emu.step_checked()?;
+            if !self.emu.halted && Some(self.emu.pc) == pc {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// runs a single command line, returns false if the user asked to quit
+    pub fn run_command<W: Write>(&mut self, line: &str, out: &mut W)
+            -> io::Result<bool> {
+
+        self.emu.discard_signals();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let count = words.get(1).and_then(|s| parse_num(s)).unwrap_or(1);
+
+        let result = match words.first().copied() {
+            None => return Ok(true),
+
+            Some("q") | Some("quit") => return Ok(false),
+
+            Some("h") | Some("help") => {
+                write!(out, "{}", HELP)?;
+                return Ok(true);
+            },
+
+            Some("s") | Some("step") => self.emu.run_insns(count).map(|_| ()),
+
+            Some("n") | Some("next") => self.emu.step_over().map(|_| ()),
+
+            Some("fin") | Some("finish") =>
+                self.emu.run_until_return().map(|_| ()),
+
+            Some("bs") | Some("back") => {
+                if self.emu.time_travel.is_none() {
+                    writeln!(out, "time travel isn't enabled")?;
+                    return Ok(true);
+                }
+
+                self.emu.step_back(count).map(|_| ())
+            },
+
+            Some("c") | Some("continue") => self.run_until(None),
+
+            Some("u") | Some("until") => {
+                match words.get(1).and_then(|s| parse_num(s)) {
+                    Some(pc) => self.run_until(Some(pc as u32)),
+                    None => {
+                        writeln!(out, "usage: until <addr>")?;
+                        return Ok(true);
+                    },
+                }
+            },
+
+            Some("b") | Some("break") => {
+                let spec = line.trim().split_once(' ').map_or("", |(_, rest)| rest);
+                match parse_breakpoint(spec) {
+                    Some((addr, cond)) => match self.emu.add_breakpoint(addr, cond) {
+                        Ok(id) => writeln!(out, "breakpoint {} at {:#x}", id, addr)?,
+                        Err(e) => writeln!(out, "bad condition: {}", e)?,
+                    },
+                    None => writeln!(out, "usage: break <addr> [if <cond>]")?,
+                }
+                return Ok(true);
+            },
+
+            Some("bl") | Some("breakpoints") => {
+                for bp in self.emu.breakpoints.iter() {
+                    write!(out, "{}: {:#x}, {} hits", bp.id, bp.addr, bp.hits)?;
+                    if let Some((ref text, _)) = bp.condition {
+                        write!(out, ", if {}", text)?;
+                    }
+                    writeln!(out)?;
+                }
+                return Ok(true);
+            },
+
+            Some("d") | Some("delete") => {
+                match words.get(1).and_then(|s| parse_num(s)) {
+                    Some(id) if self.emu.remove_breakpoint(id as usize) => {},
+                    Some(id) => writeln!(out, "no breakpoint {}", id)?,
+                    None => writeln!(out, "usage: delete <id>")?,
+                }
+                return Ok(true);
+            },
+
+            Some("r") | Some("regs") => Ok(()),
+
+            Some("w") | Some("watch") => {
+                let text = line.trim().split_once(' ').map_or("", |(_, rest)| rest);
+                if text.trim().is_empty() {
+                    writeln!(out, "usage: watch <expr>")?;
+                    return Ok(true);
+                }
+                match Watch::parse(text, &self.emu.symbols) {
+                    Ok(mut watch) => {
+                        let val = watch.eval(self.emu);
+                        writeln!(out, "{}: {} = {}", self.watches.len() + 1, watch.text, val)?;
+                        watch.last = Some(val);
+                        self.watches.push(watch);
+                    },
+                    Err(e) => writeln!(out, "bad watch: {}", e)?,
+                }
+                return Ok(true);
+            },
+
+            Some("wl") | Some("watches") => {
+                for (i, watch) in self.watches.iter().enumerate() {
+                    writeln!(out, "{}: {}", i + 1, watch.text)?;
+                }
+                return Ok(true);
+            },
+
+            Some("unwatch") => {
+                match words.get(1).and_then(|s| parse_num(s)) {
+                    Some(n) if n >= 1 && (n as usize) <= self.watches.len() => {
+                        self.watches.remove(n as usize - 1);
+                    },
+                    Some(n) => writeln!(out, "no watch {}", n)?,
+                    None => writeln!(out, "usage: unwatch <n>")?,
+                }
+                return Ok(true);
+            },
+
+            Some("x") | Some("dump") => {
+                let addr = words.get(1).and_then(|s| parse_data_addr(s, &self.emu.symbols));
+                // a bad length is None, no length is Some(None)
+                let len = match words.get(2) {
+                    Some(s) => parse_num(s).map(Some),
+                    None => Some(None),
+                };
+                match (addr, len) {
+                    (Some((addr, size)), Some(len)) => {
+                        let len = len.map(|n| n as usize).or(size).unwrap_or(64);
+                        match self.emu.dump_data(addr, len) {
+                            Ok(dump) => write!(out, "{}", dump)?,
+                            Err(e) => writeln!(out, "error: {}", e)?,
+                        }
+                    },
+                    _ => writeln!(out, "usage: dump <addr|symbol> [len]")?,
+                }
+                return Ok(true);
+            },
+
+            Some("dumpmem") => {
+                let flash = words.get(1) == Some(&"flash");
+                let args = &words[if flash { 2 } else { 1 }..];
+                let range = match args {
+                    [start, end, path] => {
+                        // code symbols for flash, data symbols otherwise
+                        let start = if flash {
+                            self.emu.symbols.find(start).map(|sym| sym.addr)
+                                .or_else(|| parse_num(start).map(|addr| addr as u32))
+                        } else {
+                            parse_data_addr(start, &self.emu.symbols).map(|(addr, _)| addr)
+                        };
+                        match (start, parse_num(end)) {
+                            (Some(start), Some(end)) => Some((start, end as u32, path)),
+                            _ => None,
+                        }
+                    },
+                    _ => None,
+                };
+
+                match range {
+                    Some((start, end, path)) => {
+                        match self.emu.save_memory(flash, start, end, Path::new(path)) {
+                            Ok(()) => writeln!(out, "saved {} bytes to {}",
+                                end.saturating_sub(start), path)?,
+                            Err(e) => writeln!(out, "error: {}", e)?,
+                        }
+                    },
+                    None => writeln!(out, "usage: dumpmem [flash] <start> <end> <file>")?,
+                }
+                return Ok(true);
+            },
+
+            Some("set") => {
+                if let Err(e) = self.set(&words[1..]) {
+                    writeln!(out, "{}", e)?;
+                }
+                return Ok(true);
+            },
+
+            Some("profile") => {
+                match self.emu.profile_report() {
+                    Some(report) => write!(out, "{}", report)?,
+                    None => writeln!(out, "profiling isn't enabled")?,
+                }
+                return Ok(true);
+            },
+
+            Some("coverage") => {
+                match self.emu.coverage_report() {
+                    Some(report) => write!(out, "{}", report)?,
+                    None => writeln!(out, "coverage isn't enabled")?,
+                }
+                return Ok(true);
+            },
+
+            Some("stats") => {
+                match self.emu.stats_report() {
+                    Some(report) => write!(out, "{}", report)?,
+                    None => {
+                        self.emu.enable_stats();
+                        writeln!(out, "collecting statistics from now on")?;
+                    },
+                }
+                return Ok(true);
+            },
+
+            Some("stack") => {
+                match self.emu.stack_report() {
+                    Some(report) => write!(out, "{}", report)?,
+                    None => {
+                        self.emu.enable_stack_monitor(None);
+                        writeln!(out, "tracking stack usage from now on")?;
+                    },
+                }
+                return Ok(true);
+            },
+
+            Some("heatmap") => {
+                let limit = words.get(1).and_then(|s| parse_num(s)).unwrap_or(20);
+                match self.emu.heatmap_report(limit as usize) {
+                    Some(report) => write!(out, "{}", report)?,
+                    None => {
+                        self.emu.enable_heatmap(256);
+                        writeln!(out, "counting accesses from now on")?;
+                    },
+                }
+                return Ok(true);
+            },
+
+            Some("lcd") => {
+                let lcds = lcd::attached(&self.emu.io_mem);
+                if lcds.is_empty() {
+                    writeln!(out, "no LCD attached")?;
+                }
+                for lcd in lcds {
+                    write!(out, "{}", lcd.fmt_display())?;
+                }
+                return Ok(true);
+            },
+
+            Some("board") => {
+                match self.emu.io_mem.pin_device::<Board>(BOARD_NAME) {
+                    Some(board) => write!(out, "{}", board.fmt_status())?,
+                    None => writeln!(out, "no board attached")?,
+                }
+                return Ok(true);
+            },
+
+            Some(cmd @ "press") | Some(cmd @ "release") => {
+                let name = match words.get(1) {
+                    Some(name) => name,
+                    None => {
+                        writeln!(out, "usage: {} <button>", cmd)?;
+                        return Ok(true);
+                    },
+                };
+                let found = self.emu.io_mem.pin_device_mut::<Board>(BOARD_NAME)
+                    .is_some_and(|board| board.set_button(name, cmd == "press"));
+                if !found {
+                    writeln!(out, "no button {:?}", name)?;
+                }
+                return Ok(true);
+            },
+
+            Some("json") => {
+                writeln!(out, "{}", self.emu.state_json())?;
+                return Ok(true);
+            },
+
+            Some(cmd) => {
+                writeln!(out, "unknown command {:?}, try 'help'", cmd)?;
+                return Ok(true);
+            },
+        };
+
+        match result {
+            Ok(()) if self.emu.halted =>
+                writeln!(out, "stopped: {:?}", self.emu.stop_reason)?,
+            Ok(()) => {},
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+
+        if self.show_state {
+            write!(out, "{}", self.emu.fmt_state())?;
+            let watches = self.fmt_watches();
+            write!(out, "{}", watches)?;
+        }
+        Ok(true)
+    }
+
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut out: W)
+            -> io::Result<()> {
+
+        write!(out, "{}", self.emu.fmt_state())?;
+
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(yaavre) ")?;
+            out.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+
+            if !self.run_command(&line, &mut out)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
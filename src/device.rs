@@ -0,0 +1,156 @@
+// Device profiles: the memory sizes and layout of the emulated chip
+//
+// Memories are allocated to the profile's sizes, so accesses past the end of
+// SRAM are faults rather than silently landing in a huge buffer.
+
+use progmem::{FLASH_SIZE, FLASH_PAGE_SIZE};
+use isa::Isa;
+use regmap::{IoBlock, ATMEGA328P_IO, ATTINY85_IO, ATXMEGA128A4U_IO};
+use iomem::{atmega328p_peripherals, attiny85_peripherals, default_peripherals};
+use iomem::{RAMPD, RAMPX, RAMPY, RAMPZ, EIND};
+use peripheral::Peripheral;
+
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub name: &'static str,
+    /// bytes, including the boot section
+    pub flash_size: u32,
+    pub flash_page_size: u32,
+    /// internal SRAM in data space
+    pub sram_start: u32,
+    pub sram_size: u32,
+    pub eeprom_size: u32,
+    /// instructions the core has
+    pub isa: Isa,
+    /// data space address of IO address 0, which is where IN/OUT port
+    /// numbers start. classic AVRs map the register file below it, at 0.
+    pub io_offset: u32,
+    /// the PC is wider than 16 bits, so return addresses take 3 bytes on the
+    /// stack instead of 2
+    pub has_22bit_addrs: bool,
+    /// bytes per interrupt vector: 4 for a JMP, or 2 on devices without JMP
+    /// where each vector is an RJMP
+    pub vector_size: u32,
+    /// the extended addressing registers the device has, of RAMPD, RAMPX,
+    /// RAMPY, RAMPZ and EIND
+    pub ramp_regs: &'static [u32],
+    /// IO register names and bit fields, for describing IO accesses
+    pub io_regs: &'static [IoBlock],
+    /// the built-in peripherals
+    pub peripherals: fn() -> Vec<Box<dyn Peripheral>>,
+    /// the USART the host's serial input and output go through, if any
+    pub host_usart: Option<&'static str>,
+    /// CPU clock in Hz when there's no CLK peripheral to say: the reset
+    /// default, or the usual board's crystal
+    pub cpu_freq: u32,
+}
+
+pub const ATXMEGA128A4U : Device = Device {
+    name: "atxmega128a4u",
+    flash_size: FLASH_SIZE,
+    flash_page_size: FLASH_PAGE_SIZE,
+    sram_start: 0x2000,
+    sram_size: 0x2000,
+    eeprom_size: 0x800,
+    isa: Isa::Xmega,
+    io_offset: 0,
+    has_22bit_addrs: true,
+    vector_size: 4,
+    ramp_regs: &[RAMPD, RAMPX, RAMPY, RAMPZ, EIND],
+    io_regs: ATXMEGA128A4U_IO,
+    peripherals: default_peripherals,
+    host_usart: Some("USARTC0"),
+    cpu_freq: 2_000_000,
+};
+
+/// the Arduino Uno's chip
+pub const ATMEGA328P : Device = Device {
+    name: "atmega328p",
+    flash_size: 0x8000,
+    flash_page_size: 128,
+    sram_start: 0x0100,
+    sram_size: 0x0800,
+    eeprom_size: 0x400,
+    isa: Isa::Avr5,
+    io_offset: 0x20,
+    has_22bit_addrs: false,
+    vector_size: 4,
+    ramp_regs: &[],
+    io_regs: ATMEGA328P_IO,
+    peripherals: atmega328p_peripherals,
+    host_usart: Some("USART0"),
+    cpu_freq: 16_000_000,
+};
+
+/// the smallest core here: no MUL, JMP or CALL, and a single port
+pub const ATTINY85 : Device = Device {
+    name: "attiny85",
+    flash_size: 0x2000,
+    flash_page_size: 64,
+    sram_start: 0x0060,
+    sram_size: 0x0200,
+    eeprom_size: 0x200,
+    isa: Isa::Avr25,
+    io_offset: 0x20,
+    has_22bit_addrs: false,
+    vector_size: 2,
+    ramp_regs: &[],
+    io_regs: ATTINY85_IO,
+    peripherals: attiny85_peripherals,
+    host_usart: None,
+    // the internal 8 MHz oscillator, divided by 8 as shipped
+    cpu_freq: 1_000_000,
+};
+
+pub const DEVICES : &[&Device] = &[&ATXMEGA128A4U, &ATMEGA328P, &ATTINY85];
+
+
+impl Device {
+    pub fn by_name(name: &str) -> Option<&'static Device> {
+        DEVICES.iter().find(|d| d.name == name).copied()
+    }
+
+    /// last byte of internal SRAM
+    pub fn ramend(&self) -> u32 {
+        self.sram_start + self.sram_size - 1
+    }
+
+    /// `pc` wrapped around the end of flash, as the PC's width does when
+    /// flash is a power of 2. linkers rely on it to reach the far end of 8K
+    /// parts with RJMP and RCALL.
+    pub fn wrap_pc(&self, pc: u32) -> u32 {
+        if self.flash_size.is_power_of_two() {
+            pc & (self.flash_size - 1)
+        } else {
+            pc
+        }
+    }
+
+    /// bytes a call or interrupt pushes
+    pub fn ret_addr_size(&self) -> u16 {
+        if self.has_22bit_addrs { 3 } else { 2 }
+    }
+
+    /// cycles from taking an interrupt to the first instruction of its
+    /// vector, while pushing the return address
+    pub fn interrupt_cycles(&self) -> u64 {
+        match self.isa {
+            Isa::Xmega => 5,
+            _ => if self.has_22bit_addrs { 5 } else { 4 },
+        }
+    }
+
+    /// extra cycles an interrupt takes to respond when it wakes the CPU
+    pub fn wake_cycles(&self) -> u64 {
+        match self.isa {
+            Isa::Xmega => 5,
+            _ => 4,
+        }
+    }
+
+    /// bytes of data space to allocate: IO, mapped EEPROM and SRAM
+    pub fn data_size(&self) -> usize {
+        (self.sram_start + self.sram_size) as usize
+    }
+}
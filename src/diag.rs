@@ -0,0 +1,51 @@
+// Diagnostics output, so that library users can decide where (and whether)
+// warnings, USART output and state dumps get printed. The library itself
+// never prints.
+
+use std::sync::Arc;
+
+
+pub trait DiagnosticsSink: Send + Sync {
+    /// something the emulator doesn't handle properly, e.g. an access to an
+    /// unimplemented IO register
+    fn warning(&self, msg: &str);
+
+    /// a byte transmitted by the USART
+    fn uart_output(&self, val: u8);
+
+    /// the CPU state, as formatted by Emulator::fmt_state, when asked for
+    /// from outside (SIGUSR1, or a replayed dump)
+    fn state_dump(&self, _state: &str) {}
+
+    /// bytes the firmware wrote to stderr through a host call
+    fn guest_stderr(&self, _data: &[u8]) {}
+}
+
+pub type SharedSink = Arc<dyn DiagnosticsSink>;
+
+
+/// discards everything; this is the default
+pub struct NullSink;
+
+impl DiagnosticsSink for NullSink {
+    fn warning(&self, _msg: &str) {}
+
+    fn uart_output(&self, _val: u8) {}
+}
+
+
+/// passes only the firmware's output, from the USART and host calls, on to
+/// another sink
+pub struct QuietSink(pub SharedSink);
+
+impl DiagnosticsSink for QuietSink {
+    fn warning(&self, _msg: &str) {}
+
+    fn uart_output(&self, val: u8) {
+        self.0.uart_output(val);
+    }
+
+    fn guest_stderr(&self, data: &[u8]) {
+        self.0.guest_stderr(data);
+    }
+}
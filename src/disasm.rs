@@ -0,0 +1,75 @@
+// objdump-style disassembly listings
+
+use std::fmt::Write;
+use disa::AvrInsn;
+use progmem::ProgramMemory;
+use symbols::SymbolTable;
+use lines::LineTable;
+
+
+/// the byte address `insn` at `addr` jumps, branches or calls to, if it's
+/// known statically
+pub fn branch_target(addr: u32, insn: &AvrInsn) -> Option<u32> {
+    let seq_pc = addr + (insn.byte_size() as u32);
+
+    match *insn {
+        AvrInsn::Jmp(tgt) | AvrInsn::Call(tgt) => Some(tgt),
+
+        AvrInsn::Rjmp(ofs) | AvrInsn::Rcall(ofs) =>
+            Some(AvrInsn::get_rel_jmp_target(seq_pc, ofs)),
+
+        AvrInsn::Breq(ofs) | AvrInsn::Brne(ofs)
+        | AvrInsn::Brcc(ofs) | AvrInsn::Brcs(ofs)
+        | AvrInsn::Brge(ofs) | AvrInsn::Brlt(ofs)
+        | AvrInsn::Brmi(ofs) | AvrInsn::Brpl(ofs)
+        | AvrInsn::Brtc(ofs) | AvrInsn::Brts(ofs)
+        | AvrInsn::Brhc(ofs) | AvrInsn::Brhs(ofs)
+        | AvrInsn::Brvc(ofs) | AvrInsn::Brvs(ofs)
+        | AvrInsn::Brid(ofs) | AvrInsn::Brie(ofs)
+        | AvrInsn::Brbc(_, ofs) | AvrInsn::Brbs(_, ofs) =>
+            Some(AvrInsn::get_rel_jmp_target(seq_pc, ofs.into())),
+
+        _ => None,
+    }
+}
+
+/// list the instructions in `insns`, with a header at each function start,
+/// source lines when they change, and branch targets' symbols
+pub fn listing<I>(prog_mem: &ProgramMemory, symbols: &SymbolTable,
+                  lines: &LineTable, insns: I) -> String
+        where I: Iterator<Item=(u32, AvrInsn)> {
+
+    let mut out = String::new();
+    let mut last_line = None;
+
+    for (addr, insn) in insns {
+        if let Some((sym, 0)) = symbols.lookup(addr) {
+            writeln!(out).unwrap();
+            writeln!(out, "{:08x} <{}>:", addr, sym.name).unwrap();
+        }
+
+        let line = lines.fmt_line(addr);
+        if let Some(line) = line.filter(|l| Some(l) != last_line.as_ref()) {
+            writeln!(out, "{}", line).unwrap();
+            last_line = Some(line);
+        }
+
+        let mut bytes = String::new();
+        for a in addr..addr + (insn.byte_size() as u32) {
+            if let Some(b) = prog_mem.read_byte(a) {
+                write!(bytes, "{:02x} ", b).unwrap();
+            }
+        }
+
+        write!(out, "{:>8x}:\t{:<12}\t{:?}", addr, bytes, insn).unwrap();
+        if let Some(tgt) = branch_target(addr, &insn) {
+            write!(out, "\t; {:#x}", tgt).unwrap();
+            if symbols.lookup(tgt).is_some() {
+                write!(out, " <{}>", symbols.fmt_addr(tgt)).unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+    }
+
+    out
+}
@@ -0,0 +1,466 @@
+// XMEGA DMA controller
+//
+// The registers live here like any other peripheral, but transfers need the
+// whole data space, so IOMemory calls `Dma::run` after ticking everything
+// else. One burst per channel is transferred per CPU step.
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use iomem::IOMemory;
+
+
+pub const DMA_BASE : u32 = 0x0100;
+
+// register offsets
+const CTRL : u32 = 0x00;
+const INTFLAGS : u32 = 0x03;
+const STATUS : u32 = 0x04;
+const TEMP : u32 = 0x06;
+const TEMP_H : u32 = 0x07;
+const CH0 : u32 = 0x10;
+const CH3_END : u32 = 0x4F;
+
+// channel register offsets
+const CH_CTRLA : u32 = 0x00;
+const CH_CTRLB : u32 = 0x01;
+const CH_ADDRCTRL : u32 = 0x02;
+const CH_TRIGSRC : u32 = 0x03;
+const CH_TRFCNT : u32 = 0x04;
+const CH_TRFCNT_H : u32 = 0x05;
+const CH_REPCNT : u32 = 0x06;
+const CH_SRCADDR0 : u32 = 0x08;
+const CH_SRCADDR2 : u32 = 0x0A;
+const CH_DESTADDR0 : u32 = 0x0C;
+const CH_DESTADDR2 : u32 = 0x0E;
+
+// CTRL bits
+const ENABLE : u8 = 0x80;
+const RESET : u8 = 0x40;
+
+// CHn.CTRLA bits
+const CH_ENABLE : u8 = 0x80;
+const CH_RESET : u8 = 0x40;
+const CH_REPEAT : u8 = 0x20;
+const CH_TRFREQ : u8 = 0x10;
+const CH_SINGLE : u8 = 0x04;
+
+// CHn.CTRLB bits
+const CH_ERRIF : u8 = 0x20;
+const CH_TRNIF : u8 = 0x10;
+
+// CHn.ADDRCTRL reload and direction modes
+const RELOAD_BLOCK : u8 = 1;
+const RELOAD_BURST : u8 = 2;
+const RELOAD_TRANSACTION : u8 = 3;
+const DIR_INC : u8 = 1;
+const DIR_DEC : u8 = 2;
+
+/// first interrupt vector; one per channel
+const CH0_VECTOR : u8 = 6;
+
+
+#[derive(Clone, Copy, Default)]
+pub struct DmaChannel {
+    pub ctrla: u8,
+    pub ctrlb: u8,
+    pub addrctrl: u8,
+    pub trigsrc: u8,
+    pub trfcnt: u16,
+    pub repcnt: u8,
+    pub srcaddr: u32,
+    pub destaddr: u32,
+
+    /// register values latched when the channel was enabled, for reloads
+    trfcnt_reload: u16,
+    srcaddr_reload: u32,
+    destaddr_reload: u32,
+    /// a trigger was received and the block (or burst, in single-shot mode)
+    /// isn't done yet
+    pending: bool,
+    /// in the middle of a block
+    busy: bool,
+}
+
+impl DmaChannel {
+    fn enabled(&self) -> bool {
+        (self.ctrla & CH_ENABLE) != 0
+    }
+
+    fn burst_len(&self) -> u16 {
+        1 << (self.ctrla & 0x3)
+    }
+
+    fn latch(&mut self) {
+        self.trfcnt_reload = self.trfcnt;
+        self.srcaddr_reload = self.srcaddr;
+        self.destaddr_reload = self.destaddr;
+    }
+
+    fn reload(&mut self, when: u8) {
+        if (self.addrctrl >> 6) == when {
+            self.srcaddr = self.srcaddr_reload;
+        }
+        if ((self.addrctrl >> 2) & 0x3) == when {
+            self.destaddr = self.destaddr_reload;
+        }
+    }
+
+    fn read(&self, ofs: u32) -> u8 {
+        match ofs {
+            CH_CTRLA => self.ctrla,
+            CH_CTRLB =>
+                self.ctrlb
+                    | (if self.busy { 0x80 } else { 0 })
+                    | (if self.pending { 0x40 } else { 0 }),
+            CH_ADDRCTRL => self.addrctrl,
+            CH_TRIGSRC => self.trigsrc,
+            CH_TRFCNT => self.trfcnt as u8,
+            CH_TRFCNT_H => (self.trfcnt >> 8) as u8,
+            CH_REPCNT => self.repcnt,
+            CH_SRCADDR0..=CH_SRCADDR2 =>
+                (self.srcaddr >> (8 * (ofs - CH_SRCADDR0))) as u8,
+            CH_DESTADDR0..=CH_DESTADDR2 =>
+                (self.destaddr >> (8 * (ofs - CH_DESTADDR0))) as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CH_CTRLA => {
+                if (val & CH_RESET) != 0 {
+                    *self = DmaChannel::default();
+                    return;
+                }
+
+                if (val & CH_ENABLE) != 0 && !self.enabled() {
+                    self.latch();
+                }
+                if (val & CH_ENABLE) == 0 {
+                    self.pending = false;
+                    self.busy = false;
+                }
+
+                self.ctrla = val & !CH_TRFREQ;
+                if (val & CH_TRFREQ) != 0 && self.enabled() {
+                    self.pending = true;
+                }
+            },
+            CH_CTRLB => {
+                // write 1 to clear the flags
+                self.ctrlb = (val & 0x0f)
+                    | (self.ctrlb & (CH_ERRIF | CH_TRNIF) & !val);
+            },
+            CH_ADDRCTRL => self.addrctrl = val,
+            CH_TRIGSRC => self.trigsrc = val,
+            CH_TRFCNT => self.trfcnt = (self.trfcnt & 0xff00) | (val as u16),
+            CH_TRFCNT_H =>
+                self.trfcnt = (self.trfcnt & 0x00ff) | ((val as u16) << 8),
+            CH_REPCNT => self.repcnt = val,
+            CH_SRCADDR0..=CH_SRCADDR2 => {
+                let shift = 8 * (ofs - CH_SRCADDR0);
+                self.srcaddr = (self.srcaddr & !(0xff << shift))
+                    | ((val as u32) << shift);
+            },
+            CH_DESTADDR0..=CH_DESTADDR2 => {
+                let shift = 8 * (ofs - CH_DESTADDR0);
+                self.destaddr = (self.destaddr & !(0xff << shift))
+                    | ((val as u32) << shift);
+            },
+            _ => {},
+        }
+    }
+}
+
+fn step_addr(addr: u32, dir: u8) -> u32 {
+    match dir {
+        DIR_INC => addr.wrapping_add(1) & 0xffffff,
+        DIR_DEC => addr.wrapping_sub(1) & 0xffffff,
+        _ => addr,
+    }
+}
+
+#[derive(Clone)]
+pub struct Dma {
+    pub ctrl: u8,
+    pub temp: u16,
+    pub channels: [DmaChannel; 4],
+}
+
+impl Default for Dma {
+    fn default() -> Dma {
+        Dma::new()
+    }
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            ctrl: 0,
+            temp: 0,
+            channels: [DmaChannel::default(); 4],
+        }
+    }
+
+    /// whether `run` has anything to do
+    pub fn active(&self) -> bool {
+        (self.ctrl & ENABLE) != 0 && self.channels.iter().any(|c| c.enabled())
+    }
+
+    /// accept triggers and transfer one burst on each channel that has work.
+    /// channel 0 has the highest priority.
+    pub fn run(&mut self, io: &mut IOMemory) {
+        for i in 0..4 {
+            let ch = self.channels[i];
+            if !ch.enabled() {
+                continue;
+            }
+
+            if ch.trigsrc != 0 && !ch.pending && io.dma_request(ch.trigsrc) {
+                io.dma_ack(ch.trigsrc);
+                self.channels[i].pending = true;
+            }
+
+            if self.channels[i].pending {
+                self.do_burst(i, io);
+            }
+        }
+    }
+
+    fn do_burst(&mut self, i: usize, io: &mut IOMemory) {
+        let mut ch = self.channels[i];
+        ch.busy = true;
+
+        let src_dir = (ch.addrctrl >> 4) & 0x3;
+        let dest_dir = ch.addrctrl & 0x3;
+
+        for _ in 0..ch.burst_len() {
+            let res = io.get8(ch.srcaddr, &"<dma>", 0)
+                .and_then(|val| io.set8(ch.destaddr, val, &"<dma>", 0));
+            if res.is_err() {
+                ch.ctrlb |= CH_ERRIF;
+                ch.ctrla &= !CH_ENABLE;
+                ch.pending = false;
+                ch.busy = false;
+                self.channels[i] = ch;
+                return;
+            }
+
+            ch.srcaddr = step_addr(ch.srcaddr, src_dir);
+            ch.destaddr = step_addr(ch.destaddr, dest_dir);
+
+            // 0 means 64K
+            ch.trfcnt = ch.trfcnt.wrapping_sub(1);
+            if ch.trfcnt == 0 {
+                break;
+            }
+        }
+
+        ch.reload(RELOAD_BURST);
+        if (ch.ctrla & CH_SINGLE) != 0 {
+            ch.pending = false;
+        }
+
+        if ch.trfcnt == 0 {
+            // block done
+            ch.busy = false;
+            ch.pending = false;
+            ch.trfcnt = ch.trfcnt_reload;
+            ch.reload(RELOAD_BLOCK);
+
+            let more_blocks =
+                if (ch.ctrla & CH_REPEAT) == 0 {
+                    false
+                } else if ch.repcnt == 0 {
+                    // repeat forever
+                    true
+                } else {
+                    ch.repcnt -= 1;
+                    ch.repcnt != 0
+                };
+
+            if !more_blocks {
+                ch.reload(RELOAD_TRANSACTION);
+                ch.ctrla &= !(CH_ENABLE | CH_REPEAT);
+                ch.ctrlb |= CH_TRNIF;
+            }
+        }
+
+        self.channels[i] = ch;
+    }
+}
+
+impl Peripheral for Dma {
+    fn name(&self) -> &str {
+        "DMA"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (DMA_BASE, 0x50)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            INTFLAGS => {
+                let mut val = 0;
+                for (i, ch) in self.channels.iter().enumerate() {
+                    if (ch.ctrlb & CH_TRNIF) != 0 {
+                        val |= 1 << i;
+                    }
+                    if (ch.ctrlb & CH_ERRIF) != 0 {
+                        val |= 0x10 << i;
+                    }
+                }
+                val
+            },
+            STATUS => {
+                let mut val = 0;
+                for (i, ch) in self.channels.iter().enumerate() {
+                    if ch.pending {
+                        val |= 1 << i;
+                    }
+                    if ch.busy {
+                        val |= 0x10 << i;
+                    }
+                }
+                val
+            },
+            TEMP => self.temp as u8,
+            TEMP_H => (self.temp >> 8) as u8,
+            CH0..=CH3_END => {
+                let i = ((ofs - CH0) / 0x10) as usize;
+                self.channels[i].read((ofs - CH0) % 0x10)
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL => {
+                if (val & RESET) != 0 {
+                    *self = Dma::new();
+                    return;
+                }
+                self.ctrl = val;
+            },
+            INTFLAGS => {
+                // write 1 to clear
+                for (i, ch) in self.channels.iter_mut().enumerate() {
+                    if (val & (1 << i)) != 0 {
+                        ch.ctrlb &= !CH_TRNIF;
+                    }
+                    if (val & (0x10 << i)) != 0 {
+                        ch.ctrlb &= !CH_ERRIF;
+                    }
+                }
+            },
+            TEMP => self.temp = (self.temp & 0xff00) | (val as u16),
+            TEMP_H => self.temp = (self.temp & 0x00ff) | ((val as u16) << 8),
+            CH0..=CH3_END => {
+                let i = ((ofs - CH0) / 0x10) as usize;
+                self.channels[i].write((ofs - CH0) % 0x10, val);
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Dma::new();
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        // highest level, then lowest vector
+        self.channels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, ch)| {
+                let trn_level = ch.ctrlb & 0x3;
+                let err_level = (ch.ctrlb >> 2) & 0x3;
+                let trn = if (ch.ctrlb & CH_TRNIF) != 0 { trn_level } else { 0 };
+                let err = if (ch.ctrlb & CH_ERRIF) != 0 { err_level } else { 0 };
+                let level = trn.max(err);
+                if level != 0 {
+                    Some((CH0_VECTOR + i as u8, level))
+                } else {
+                    None
+                }
+            })
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        for (i, ch) in self.channels.iter().enumerate() {
+            if (ch.ctrlb & 0xf) != 0 {
+                out.push(CH0_VECTOR + i as u8);
+            }
+        }
+    }
+
+    fn powered(&self) -> bool {
+        (self.ctrl & ENABLE) != 0
+    }
+
+    // the flags aren't cleared by taking the interrupt
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.ctrl];
+        state.write_u16::<LittleEndian>(self.temp).unwrap();
+
+        for ch in self.channels.iter() {
+            state.extend_from_slice(&[
+                ch.ctrla, ch.ctrlb, ch.addrctrl, ch.trigsrc, ch.repcnt,
+                ch.pending as u8, ch.busy as u8,
+            ]);
+            state.write_u16::<LittleEndian>(ch.trfcnt).unwrap();
+            state.write_u16::<LittleEndian>(ch.trfcnt_reload).unwrap();
+            for &addr in &[ch.srcaddr, ch.destaddr, ch.srcaddr_reload,
+                           ch.destaddr_reload] {
+                state.write_u32::<LittleEndian>(addr).unwrap();
+            }
+        }
+
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        // ctrl, temp, 4 channels of 7 bytes, 2 words and 4 addresses
+        if state.len() != 3 + 4 * (7 + 4 + 16) {
+            return;
+        }
+
+        self.ctrl = state[0];
+        let mut r = &state[1..];
+        self.temp = r.read_u16::<LittleEndian>().unwrap();
+
+        for ch in self.channels.iter_mut() {
+            ch.ctrla = r.read_u8().unwrap();
+            ch.ctrlb = r.read_u8().unwrap();
+            ch.addrctrl = r.read_u8().unwrap();
+            ch.trigsrc = r.read_u8().unwrap();
+            ch.repcnt = r.read_u8().unwrap();
+            ch.pending = r.read_u8().unwrap() != 0;
+            ch.busy = r.read_u8().unwrap() != 0;
+            ch.trfcnt = r.read_u16::<LittleEndian>().unwrap();
+            ch.trfcnt_reload = r.read_u16::<LittleEndian>().unwrap();
+            ch.srcaddr = r.read_u32::<LittleEndian>().unwrap();
+            ch.destaddr = r.read_u32::<LittleEndian>().unwrap();
+            ch.srcaddr_reload = r.read_u32::<LittleEndian>().unwrap();
+            ch.destaddr_reload = r.read_u32::<LittleEndian>().unwrap();
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,128 @@
+// Loading firmware from ELF files, as produced by avr-gcc
+//
+// Flash contents come from the PT_LOAD segments' physical (load) addresses,
+// so initialized data ends up where the startup code copies it from.
+// Segments for EEPROM, fuses etc. are above DATA_OFFSET and are skipped.
+// The symbol table and DWARF line info are loaded too, if present.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use addr2line;
+use addr2line::object::{Endianness, Object, ObjectSymbol, SymbolKind};
+use addr2line::object::elf::PT_LOAD;
+use addr2line::object::read::elf::{ElfFile32, ProgramHeader};
+use symbols::{Symbol, SymbolTable, DATA_OFFSET};
+use lines::LineTable;
+
+
+pub const ELF_MAGIC : &[u8] = b"\x7fELF";
+
+
+pub struct ElfImage {
+    pub flash: Vec<u8>,
+    pub symbols: SymbolTable,
+    pub lines: LineTable,
+}
+
+fn bad_elf<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn load_flash(elf: &ElfFile32<Endianness>, data: &[u8], flash_size: u32)
+        -> io::Result<Vec<u8>> {
+
+    let endian = elf.endian();
+    let mut flash = vec![];
+
+    for ph in elf.raw_segments() {
+        let addr = ph.p_paddr(endian);
+        if ph.p_type(endian) != PT_LOAD || addr >= DATA_OFFSET {
+            continue;
+        }
+
+        let bytes = ph.data(endian, data)
+            .map_err(|_| bad_elf("bad segment"))?;
+        let start = addr as usize;
+        let end = start + bytes.len();
+        if end > flash_size as usize {
+            return Err(bad_elf(format!("segment at {:#x} doesn't fit in flash", addr)));
+        }
+
+        if flash.len() < end {
+            flash.resize(end, 0xff);
+        }
+        flash[start..end].copy_from_slice(bytes);
+    }
+
+    // flash is read in words
+    if flash.len() % 2 != 0 {
+        flash.push(0xff);
+    }
+    Ok(flash)
+}
+
+fn load_symbols(elf: &ElfFile32<Endianness>) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+
+    for sym in elf.symbols() {
+        let name = match sym.name() {
+            Ok(name) if !name.is_empty() && sym.is_definition() => name,
+            _ => continue,
+        };
+
+        // same letters as nm
+        let kind = match (sym.kind(), sym.is_global()) {
+            (SymbolKind::Text, true) => 'T',
+            (SymbolKind::Text, false) => 't',
+            (SymbolKind::Data, true) => 'D',
+            (SymbolKind::Data, false) => 'd',
+            _ => continue,
+        };
+
+        symbols.add(Symbol {
+            name: name.to_string(),
+            addr: sym.address() as u32,
+            size: sym.size() as u32,
+            kind,
+        });
+    }
+
+    symbols
+}
+
+fn load_lines(elf: &ElfFile32<Endianness>, flash_size: u32) -> io::Result<LineTable> {
+    let ctx = addr2line::Context::new(elf).map_err(bad_elf)?;
+    let mut lines = LineTable::new();
+
+    let locations = ctx.find_location_range(0, flash_size as u64)
+        .map_err(bad_elf)?;
+    for (addr, len, loc) in locations {
+        if let (Some(file), Some(line)) = (loc.file, loc.line) {
+            lines.add(addr as u32, (addr + len) as u32, file, line);
+        }
+    }
+
+    Ok(lines)
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+/// `flash_size` is the device's, in bytes
+pub fn parse(data: &[u8], flash_size: u32) -> io::Result<ElfImage> {
+    let elf = ElfFile32::<Endianness>::parse(data).map_err(bad_elf)?;
+
+    Ok(ElfImage {
+        flash: load_flash(&elf, data, flash_size)?,
+        symbols: load_symbols(&elf),
+        lines: load_lines(&elf, flash_size)?,
+    })
+}
+
+pub fn load(path: &str, flash_size: u32) -> io::Result<ElfImage> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    parse(&data, flash_size)
+}
@@ -1,199 +1,2532 @@
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::mem;
+use std::cmp;
+use std::fmt;
+use std::fmt::Write;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use hex;
 use progmem::ProgramMemory;
-use iomem::IOMemory;
+use iomem::{IOMemory, IoAccess, PtrReg};
+use device::{Device, ATXMEGA128A4U};
+use isa::min_isa;
+use regmap;
 use std::sync::mpsc;
+#[cfg(all(unix, feature = "signals"))]
 use signal_notify::{notify, Signal};
-use disa::{AvrInsn, Reg, RegPair, MemAccess, MemRegUpdate};
+use disa::{AvrInsn, Reg, RegPair, MemAccess, MemRegUpdate, Z_L};
+use error::{Error, Result};
+use diag::SharedSink;
+use cycles::insn_cycles;
+use des::des_round;
+use snapshot::Snapshot;
+use replay::{InputEvent, InputLog, InputMode};
+use timetravel::TimeTravel;
+use breakpoint::Breakpoint;
+use symbols::SymbolTable;
+use lines::LineTable;
+use elf;
+use json;
+use profile::{Cost, Profiler};
+use callgraph::{CallEvent, CallGraph, CallKind, CallListener};
+use callstack::{pop_frames, Frame, FrameKind};
+use coverage::Coverage;
+use warp;
+use warp::{LoopState, Warp};
+use disasm::branch_target;
+use hostcall::HostCalls;
+use crc::Crc;
+use call;
+use call::{CallResult, Stub};
+use stopcond::{ResolvedCondition, StopCondition};
+use remote::{Command, EmulatorHandle};
+use std::collections::HashMap;
+use stats::{Counters, Stats};
+use energy::{CurrentTable, Energy};
+use statehash::{HashLog, MemHasher, StateHasher};
+use heatmap::Heatmap;
+use stack::StackMonitor;
+use hexdump;
+use vcd::{Source, Vcd};
+use hexdump::hexdump;
+use history::{History, HistoryEntry};
+use taint::{Taint, TaintReport};
+use faultinject::{FaultPoint, FaultTarget, FaultTime};
+use irqstress::IrqStress;
+#[cfg(feature = "scripting")]
+use script::{Machine, MachineState, ScriptCall, ScriptEvent, Scripts};
+use usart::Usart;
+use trace::{TraceEntry, TraceFilter, Tracer};
+use gpio::PinState;
+use rst::ResetCause;
+use pmic::BOOT_VECTORS_BASE;
+use sleep::SleepMode;
+
+
+// a CallStack for `$emu`, borrowing only the fields it needs, so it can be
+// passed to io_mem methods
+macro_rules! call_stack {
+    ($emu:expr) => {
+        CallStack {
+            frames: &$emu.call_stack,
+            symbols: &$emu.symbols,
+            lines: &$emu.lines,
+        }
+    }
+}
+
+fn fmt_location(symbols: &SymbolTable, lines: &LineTable, addr: u32) -> String {
+    let name = symbols.fmt_addr(addr);
+    match lines.fmt_line(addr) {
+        Some(line) => format!("{} ({})", name, line),
+        None => name,
+    }
+}
+
+/// the call stack, formatted only when displayed, so that passing it along
+/// in case of a warning costs nothing
+pub struct CallStack<'a> {
+    frames: &'a [Frame],
+    symbols: &'a SymbolTable,
+    lines: &'a LineTable,
+}
+
+impl<'a> fmt::Display for CallStack<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbolic = !self.symbols.is_empty() || !self.lines.is_empty();
+
+        write!(f, "[")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+
+            if symbolic {
+                // the callee is the function of the next call site, or of
+                // the pc
+                write!(f, "{}", fmt_location(self.symbols, self.lines, frame.from))?;
+            } else {
+                write!(f, "{:#x}->{:#x}", frame.from, frame.to)?;
+            }
+
+            if let FrameKind::Interrupt(vector) = frame.kind {
+                write!(f, " <irq {}>", vector)?;
+            }
+            if let Some(func) = frame.tail_call_from {
+                if symbolic {
+                    write!(f, " <tail call from {}>", self.symbols.fmt_addr(func))?;
+                } else {
+                    write!(f, " <tail call from {:#x}>", func)?;
+                }
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// the program stopped itself, e.g. at the "rjmp .-2" in __stop_program
+    Halted,
+    /// a BREAK instruction was executed
+    Break,
+    /// SLEEP with interrupts disabled, so nothing can ever wake the CPU
+    SleepForever,
+    /// reached the address passed to until()
+    ReachedPc,
+    /// executed the number of instructions passed to run_insns()
+    InsnLimit,
+    /// step_over() finished the instruction, or the call it stepped over
+    SteppedOver,
+    /// the function run_until_return() was called in returned
+    Returned,
+    /// hit the breakpoint with this id
+    Breakpoint(usize),
+    /// the firmware asked to exit with this code
+    Exit(u8),
+    /// reached the cycle count passed to run_until_cycle()
+    CycleLimit,
+    /// the run_until_any() condition with this index was met
+    Condition(usize),
+    /// the state hash at this instruction count differed from the
+    /// reference's; see enable_state_hashing()
+    Diverged(u64),
+    /// an error stopped execution; see RunResult::fault
+    Fault,
+    /// SIGINT (Ctrl-C), or the stop_handle() flag was set
+    Interrupted,
+    /// a script called emu.stop()
+    Script,
+    /// an instruction that doesn't decode or isn't implemented, with
+    /// UnimplementedPolicy::Halt
+    Unimplemented,
+}
+
+
+/// host code for an instruction the emulator doesn't know. it gets the
+/// words at pc, and returns the instruction's size in words and how many
+/// cycles it took, or None to fault after all.
+pub type InsnHandler = Box<dyn FnMut(&mut IOMemory, u32, &[u16]) -> Option<(u32, u64)> + Send>;
+
+/// what to do with instructions that don't decode or aren't implemented,
+/// e.g. vendor-specific opcodes
+#[derive(Clone)]
+pub enum UnimplementedPolicy {
+    /// stop with an error
+    Fault,
+    /// stop with StopReason::Unimplemented, after sending a crash report to
+    /// the diagnostics sink
+    Halt,
+    /// warn and carry on as if it were a one-word NOP (or the decoded
+    /// instruction's size)
+    Skip,
+    /// let host code emulate it, shared with clones
+    Emulate(Arc<Mutex<InsnHandler>>),
+}
+
+
+/// how a run went
+#[derive(Debug)]
+pub struct RunResult {
+    pub reason: StopReason,
+    /// the error, when the reason is Fault
+    pub fault: Option<Error>,
+    /// instructions executed and cycles taken during the run
+    pub insns: u64,
+    pub cycles: u64,
+    /// where execution stopped
+    pub pc: u32,
+}
+
+impl RunResult {
+    pub fn is_fault(&self) -> bool {
+        self.fault.is_some()
+    }
+}
 
 
 pub struct Emulator {
+    pub device: Device,
     pub prog_mem: ProgramMemory,
     pub io_mem: IOMemory,
     pub pc: u32,
 
-    pub call_stack: Vec<(u16, u32, u32)>,
+    pub call_stack: Vec<Frame>,
 
     pub skip_next_insn: bool,
 
     pub insn_count: u64,
-    // TODO: cycle_count
+    pub cycle_count: u64,
+
+    pub halted: bool,
+    pub stop_reason: StopReason,
+    /// executed SLEEP, waiting for a wake-up event
+    pub sleeping: bool,
+    /// stop with exit code r24 when execution gets here
+    pub exit_pc: Option<u32>,
+    /// host code replacing the guest functions at these addresses
+    /// shared with clones, as closures can't be cloned
+    stubs: HashMap<u32, Arc<Mutex<Stub>>>,
+    pub unimplemented_policy: UnimplementedPolicy,
+
+    pub input_mode: InputMode,
+    pub time_travel: Option<TimeTravel>,
+
+    pub breakpoints: Vec<Breakpoint>,
+    next_breakpoint_id: usize,
+
+    pub symbols: SymbolTable,
+    /// source lines, from an ELF file's debug info
+    pub lines: LineTable,
+    tracer: Option<(Box<dyn Tracer>, TraceFilter)>,
+    /// where IO register accesses are described, when enabled
+    io_trace: Option<Box<dyn io::Write + Send>>,
+    /// selected signals' changes, when enabled
+    vcd: Option<Vcd>,
+    pub profiler: Option<Profiler>,
+    /// who calls whom, when enabled
+    pub call_graph: Option<CallGraph>,
+    /// get call, return and interrupt entry events
+    call_listeners: Vec<CallListener>,
+    pub coverage: Option<Coverage>,
+    pub stats: Option<Stats>,
+    /// time per power state, when enabled
+    pub energy: Option<Energy>,
+    pub stack_monitor: Option<StackMonitor>,
+    /// the last few instructions, for crash reports, when enabled
+    pub history: Option<History>,
+    /// skip busy-wait loops, when enabled
+    pub warp: Option<Warp>,
+    /// state hashes every so often, when enabled
+    pub state_hashes: Option<HashLog>,
+    /// where input ends up, when enabled
+    pub taint: Option<Taint>,
+    /// injected faults that haven't happened yet
+    pub pending_faults: Vec<FaultPoint>,
+    /// enabled interrupts fired at random cycles, when enabled
+    pub irq_stress: Option<IrqStress>,
+    /// scripts attached to events
+    #[cfg(feature = "scripting")]
+    pub scripts: Option<Scripts>,
+
+    /// where flash is mirrored in data space, and the flash version last
+    /// copied there
+    flash_map: Option<u32>,
+    flash_map_version: Option<u64>,
+
+    /// only the original emulator handles signals, not its clones
+    #[cfg(all(unix, feature = "signals"))]
+    sig_chan: Option<mpsc::Receiver<Signal>>,
+    /// where SIGUSR2 dumps the state and data memory to
+    pub dump_path: PathBuf,
+    /// set from anywhere to stop execution before the next instruction
+    stop_flag: Arc<AtomicBool>,
+    /// commands from EmulatorHandles
+    commands_tx: mpsc::Sender<Command>,
+    commands_rx: mpsc::Receiver<Command>,
+    /// a handle asked serve() to return
+    quitting: bool,
+    /// USART input queued by the host, possibly from other threads
+    uart_input_tx: mpsc::Sender<Vec<u8>>,
+    uart_input_rx: mpsc::Receiver<Vec<u8>>,
+}
+
+/// a fork of the machine, e.g. to explore both sides of a branch, possibly
+/// on other threads. the clone has its own handles and stop flag, and no
+/// tracer, IO trace, VCD output, call listeners or signal handling; commands
+/// and USART input still queued by handles stay with the original. stubs are
+/// shared.
+impl Clone for Emulator {
+    fn clone(&self) -> Emulator {
+        let (uart_input_tx, uart_input_rx) = mpsc::channel();
+        let (commands_tx, commands_rx) = mpsc::channel();
+
+        Emulator {
+            device: self.device.clone(),
+            prog_mem: self.prog_mem.clone(),
+            io_mem: self.io_mem.clone(),
+            pc: self.pc,
+
+            call_stack: self.call_stack.clone(),
+
+            skip_next_insn: self.skip_next_insn,
+
+            insn_count: self.insn_count,
+            cycle_count: self.cycle_count,
+
+            halted: self.halted,
+            stop_reason: self.stop_reason,
+            sleeping: self.sleeping,
+            exit_pc: self.exit_pc,
+            stubs: self.stubs.clone(),
+            unimplemented_policy: self.unimplemented_policy.clone(),
+
+            input_mode: self.input_mode.clone(),
+            time_travel: self.time_travel.clone(),
+
+            breakpoints: self.breakpoints.clone(),
+            next_breakpoint_id: self.next_breakpoint_id,
+
+            symbols: self.symbols.clone(),
+            lines: self.lines.clone(),
+            tracer: None,
+            io_trace: None,
+            vcd: None,
+            profiler: self.profiler.clone(),
+            call_graph: self.call_graph.clone(),
+            call_listeners: vec![],
+            coverage: self.coverage.clone(),
+            stats: self.stats.clone(),
+            energy: self.energy.clone(),
+            stack_monitor: self.stack_monitor.clone(),
+            history: self.history.clone(),
+            warp: self.warp.clone(),
+            state_hashes: self.state_hashes.clone(),
+            taint: self.taint.clone(),
+            pending_faults: self.pending_faults.clone(),
+            irq_stress: self.irq_stress.clone(),
+            #[cfg(feature = "scripting")]
+            scripts: self.scripts.clone(),
+
+            flash_map: self.flash_map,
+            flash_map_version: self.flash_map_version,
+
+            #[cfg(all(unix, feature = "signals"))]
+            sig_chan: None,
+            dump_path: self.dump_path.clone(),
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            commands_tx,
+            commands_rx,
+            quitting: false,
+            uart_input_tx,
+            uart_input_rx,
+        }
+    }
+}
+
+// forks are run on other threads
+#[allow(dead_code)]
+fn assert_clone_send() {
+    fn check<T: Clone + Send>() {}
+    check::<Emulator>();
+}
+
+impl Default for Emulator {
+    fn default() -> Emulator {
+        Emulator::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        Emulator::for_device(&ATXMEGA128A4U)
+    }
+
+    /// with memories sized for `device`
+    pub fn for_device(device: &Device) -> Emulator {
+        let (uart_input_tx, uart_input_rx) = mpsc::channel();
+        let (commands_tx, commands_rx) = mpsc::channel();
+
+        Emulator {
+            device: device.clone(),
+            prog_mem: ProgramMemory::for_device(device),
+
+            io_mem: IOMemory::for_device(device),
+            pc: 0,
+
+            call_stack: vec![],
+
+            skip_next_insn: false,
+
+            insn_count: 0,
+            cycle_count: 0,
+
+            halted: false,
+            stop_reason: StopReason::Halted,
+            sleeping: false,
+            exit_pc: None,
+            stubs: HashMap::new(),
+            unimplemented_policy: UnimplementedPolicy::Fault,
+
+            input_mode: InputMode::Live,
+            time_travel: None,
+
+            breakpoints: vec![],
+            next_breakpoint_id: 1,
+
+            symbols: SymbolTable::new(),
+            lines: LineTable::new(),
+            tracer: None,
+            io_trace: None,
+            vcd: None,
+            profiler: None,
+            call_graph: None,
+            call_listeners: vec![],
+            coverage: None,
+            stats: None,
+            energy: None,
+            stack_monitor: None,
+            history: None,
+            warp: None,
+            state_hashes: None,
+            taint: None,
+            pending_faults: vec![],
+            irq_stress: None,
+            #[cfg(feature = "scripting")]
+            scripts: None,
+
+            flash_map: None,
+            flash_map_version: None,
+
+            #[cfg(all(unix, feature = "signals"))]
+            sig_chan: Some(notify(&[Signal::USR1, Signal::USR2, Signal::INT])),
+            dump_path: PathBuf::from("yaavre-dump.txt"),
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            commands_tx,
+            commands_rx,
+            quitting: false,
+            uart_input_tx,
+            uart_input_rx,
+        }
+    }
+
+    /// route warnings and USART output to `sink`; by default they are
+    /// discarded
+    pub fn set_diagnostics_sink(&mut self, sink: SharedSink) {
+        self.prog_mem.diag = sink.clone();
+        self.io_mem.diag = sink;
+    }
+
+    /// power-on reset
+    pub fn reset(&mut self) {
+        self.reset_with_cause(ResetCause::PowerOn);
+    }
+
+    /// reset the CPU and peripherals, keeping flash. other than power-on
+    /// resets keep the register file and SRAM, and don't reset the
+    /// instruction and cycle counters.
+    pub fn reset_with_cause(&mut self, cause: ResetCause) {
+        let power_on = cause == ResetCause::PowerOn;
+
+        self.io_mem.reset(power_on);
+        if let Some(rst) = self.io_mem.reset_controller_mut() {
+            rst.record(cause);
+        }
+
+        self.pc =
+            if self.io_mem.nvm.config.boot_reset() {
+                BOOT_VECTORS_BASE
+            } else {
+                0
+            };
+        self.call_stack = vec![];
+        self.skip_next_insn = false;
+        self.halted = false;
+        self.sleeping = false;
+        // a power-on reset cleared the flash window
+        self.flash_map_version = None;
+        self.forget_warp_loop();
+
+        if power_on {
+            self.insn_count = 0;
+            self.cycle_count = 0;
+        }
+    }
+
+    /// captures everything needed to later continue execution from the
+    /// current point with `restore`
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            call_stack: self.call_stack.clone(),
+            skip_next_insn: self.skip_next_insn,
+            sleeping: self.sleeping,
+            insn_count: self.insn_count,
+            cycle_count: self.cycle_count,
+
+            regs: self.io_mem.regs.r,
+            sreg: self.io_mem.sreg.as_u8(),
+            data_mem: self.io_mem.data_mem.clone(),
+            flash: self.prog_mem.words().to_vec(),
+
+            usart_input: self.io_mem.usart_input.iter().cloned().collect(),
+            usart_output_log: self.io_mem.usart_output_log.clone(),
+            nvm: self.io_mem.nvm.clone(),
+            // devices wired to the pins go with the peripherals
+            peripheral_state: self.io_mem.peripherals
+                .iter()
+                .map(|p| (p.name().to_string(), p.save_state()))
+                .chain(self.io_mem.pin_devices
+                    .iter()
+                    .map(|d| (d.name().to_string(), d.save_state())))
+                .collect(),
+            injected_interrupts: self.io_mem.injected_interrupts.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snap: &Snapshot) {
+        self.pc = snap.pc;
+        self.call_stack = snap.call_stack.clone();
+        self.skip_next_insn = snap.skip_next_insn;
+        self.sleeping = snap.sleeping;
+        self.insn_count = snap.insn_count;
+        self.cycle_count = snap.cycle_count;
+        self.halted = false;
+
+        self.io_mem.regs.r = snap.regs;
+        self.io_mem.sreg.set_u8(snap.sreg);
+        self.io_mem.data_mem = snap.data_mem.clone();
+        self.prog_mem.set_words(snap.flash.clone());
+        self.flash_map_version = None;
+
+        self.io_mem.usart_input = snap.usart_input.iter().cloned().collect();
+        self.io_mem.usart_output_log = snap.usart_output_log.clone();
+        // the device config isn't part of the machine state
+        let config = self.io_mem.nvm.config.clone();
+        self.io_mem.nvm = snap.nvm.clone();
+        self.io_mem.nvm.config = config;
+        for (name, state) in &snap.peripheral_state {
+            let p = self.io_mem.peripherals.iter_mut().find(|p| p.name() == name);
+            if let Some(p) = p {
+                p.load_state(state);
+            } else if let Some(d) = self.io_mem.pin_devices.iter_mut().find(|d| d.name() == name) {
+                d.load_state(state);
+            }
+        }
+        self.io_mem.injected_interrupts = snap.injected_interrupts.clone();
+        // SLEEP.CTRL can't have changed since the CPU went to sleep
+        self.io_mem.sleep_mode = if self.sleeping { self.sleep_mode() } else { None };
+        self.io_mem.peripherals_restored(self.cycle_count);
+        self.forget_warp_loop();
+    }
+
+    /// e.g. "main+0x12 (main.c:40)", with as much as is known about `addr`
+    pub fn fmt_location(&self, addr: u32) -> String {
+        fmt_location(&self.symbols, &self.lines, addr)
+    }
+
+    pub fn fmt_call_stack(&self) -> String {
+        call_stack!(self).to_string()
+    }
+
+    // LPM/ELPM, which read signature rows etc. instead of flash for some
+    // NVM commands
+    fn lpm_byte(&self, addr: u32) -> u8 {
+        match self.io_mem.nvm.read_lpm(addr) {
+            Some(val) => val,
+            None => {
+                let call_stack = call_stack!(self);
+                self.prog_mem.get_prog_mem_byte(addr, &call_stack, self.pc)
+            },
+        }
+    }
+
+    fn get_cur_insn(&self) -> Option<AvrInsn> {
+        self.prog_mem.get_insn_at(self.pc)
+    }
+
+    pub fn fmt_state(&self) -> String {
+        let mut out = String::new();
+        let insn = self.get_cur_insn();
+
+        if self.symbols.is_empty() && self.lines.is_empty() {
+            writeln!(out, "{:#06x}:  {:?}", self.pc, insn).unwrap();
+        } else {
+            writeln!(out, "{:#06x} <{}>:  {:?}",
+                self.pc, self.fmt_location(self.pc), insn).unwrap();
+        }
+        writeln!(out).unwrap();
+
+        let sreg_chars = [
+            if self.io_mem.sreg.c { "C" } else { "." },
+            if self.io_mem.sreg.z { "Z" } else { "." },
+            if self.io_mem.sreg.n { "N" } else { "." },
+            if self.io_mem.sreg.v { "V" } else { "." },
+            if self.io_mem.sreg.s { "S" } else { "." },
+            if self.io_mem.sreg.h { "H" } else { "." },
+            if self.io_mem.sreg.t { "T" } else { "." },
+            if self.io_mem.sreg.i { "I" } else { "." },
+        ];
+        let sreg_str = sreg_chars.join("");
+
+        writeln!(out, "sp={:#06x}, sreg: {}", self.io_mem.get_sp(), sreg_str)
+            .unwrap();
+        let t = self.emulated_time();
+        writeln!(out, "cycles: {}, time: {}.{:06}s",
+            self.cycle_count, t.as_secs(), t.subsec_micros()).unwrap();
+        writeln!(out).unwrap();
+
+        for line_num in 0..32 / 8 {
+            let i = line_num * 8;
+            write!(out, "{:>3}:", format!("r{}", i)).unwrap();
+
+            for j in i..i + 8 {
+                if j % 2 == 0 {
+                    out.push(' ');
+                } else {
+                    out.push(':');
+                }
+
+                write!(out, "{:02x}", self.io_mem.regs.get8(j)).unwrap();
+            }
+
+            writeln!(out).unwrap();
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out,
+            "X: {:06x} Y: {:06x} Z: {:06x}",
+            self.io_mem.get_full_x(),
+            self.io_mem.get_full_y(),
+            self.io_mem.get_full_z()).unwrap();
+
+        writeln!(out).unwrap();
+        writeln!(out, "call stack: {}", self.fmt_call_stack()).unwrap();
+
+        let sp = self.io_mem.get_sp() as usize;
+        let stack_end = cmp::min(sp + 16, self.io_mem.data_mem.len());
+        let stack = self.io_mem.data_mem.get(sp..stack_end).unwrap_or(&[]);
+        writeln!(out, "some stack bytes: {}", hex::encode(stack)).unwrap();
+
+        out
+    }
+
+    /// the same information as fmt_state, as a JSON object
+    pub fn state_json(&self) -> String {
+        let insn = self.get_cur_insn().map(|insn| format!("{:?}", insn));
+        let sp = self.io_mem.get_sp();
+        let t = self.emulated_time();
+
+        let call_stack: Vec<String> = self.call_stack
+            .iter()
+            .map(|f| format!(
+                "{{\"sp\":{},\"from\":{},\"to\":{},\"ret\":{},\"irq\":{},\"tail_call_from\":{}}}",
+                f.sp, f.from, f.to, f.ret_addr,
+                match f.kind {
+                    FrameKind::Interrupt(vector) => vector.to_string(),
+                    FrameKind::Call => "null".to_string(),
+                },
+                f.tail_call_from.map_or("null".to_string(), |a| a.to_string())))
+            .collect();
+
+        // SP points at the next free byte
+        let stack_start = sp as usize + 1;
+        let stack_end = cmp::min(stack_start + 16, self.io_mem.data_mem.len());
+        let stack = self.io_mem.data_mem.get(stack_start..stack_end)
+            .unwrap_or(&[]);
+
+        format!("{{\"pc\":{},\"insn\":{},\"sp\":{},\"sreg\":{},\
+                 \"regs\":{},\"x\":{},\"y\":{},\"z\":{},\
+                 \"cycles\":{},\"insns\":{},\"time_ns\":{},\
+                 \"call_stack\":[{}],\"stack\":{}}}",
+            self.pc,
+            insn.map_or("null".to_string(), |s| json::string(&s)),
+            sp,
+            self.io_mem.sreg.as_u8(),
+            json::array(&self.io_mem.regs.r),
+            self.io_mem.get_full_x(),
+            self.io_mem.get_full_y(),
+            self.io_mem.get_full_z(),
+            self.cycle_count,
+            self.insn_count,
+            t.as_secs() * 1_000_000_000 + t.subsec_nanos() as u64,
+            call_stack.join(","),
+            json::array(stack))
+    }
+
+    /// emulated time since power-on, according to the clock settings the
+    /// firmware chose
+    pub fn emulated_time(&self) -> Duration {
+        match self.io_mem.clock() {
+            Some(clk) => clk.time_at(self.cycle_count),
+            None => Duration::from_nanos(
+                (self.cycle_count as u128 * 1_000_000_000 / self.device.cpu_freq as u128) as u64),
+        }
+    }
+
+    /// hand the state to the diagnostics sink
+    fn dump_state(&self) {
+        self.io_mem.diag.state_dump(&self.fmt_state());
+    }
+
+    /// the data space as a hex dump, 16 bytes per line
+    pub fn fmt_data_mem(&self) -> String {
+        let mut s = String::new();
+        for (i, line) in self.io_mem.data_mem.chunks(16).enumerate() {
+            write!(s, "{:06x}:", i * 16).unwrap();
+            for b in line {
+                write!(s, " {:02x}", b).unwrap();
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    /// write the state and data memory to `path`
+    pub fn dump_to_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, format!("{}\n{}", self.fmt_state(), self.fmt_data_mem()))
+    }
+
+    /// a flag that stops execution with StopReason::Interrupted when set,
+    /// e.g. from a Ctrl-C handler or another thread
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop_flag.clone()
+    }
+
+    /// a handle for controlling the emulator from other threads, while
+    /// this one is in serve()
+    pub fn handle(&self) -> EmulatorHandle {
+        EmulatorHandle::new(self.commands_tx.clone(), self.stop_flag.clone(),
+                            self.uart_input_tx.clone())
+    }
+
+    /// run, controlled through handles: when paused or stopped, carry out
+    /// commands until a handle resumes. returns how the last run went once
+    /// a handle asks to quit.
+    pub fn serve(&mut self) -> RunResult {
+        self.quitting = false;
+        loop {
+            let res = self.run();
+            if self.quitting {
+                return res;
+            }
+
+            loop {
+                match self.commands_rx.recv() {
+                    Ok(Command::Run(f)) => f(self),
+                    Ok(Command::Resume) => {
+                        // a pause while paused shouldn't stop this run
+                        self.stop_flag.store(false, Ordering::SeqCst);
+                        break;
+                    },
+                    // self has a sender, so recv() can't fail
+                    Ok(Command::Quit) | Err(_) => return res,
+                }
+            }
+        }
+    }
+
+    /// commands that arrived while running
+    fn run_commands(&mut self) {
+        while let Ok(cmd) = self.commands_rx.try_recv() {
+            match cmd {
+                Command::Run(f) => f(self),
+                Command::Resume => {},
+                Command::Quit => {
+                    self.quitting = true;
+                    self.stop_flag.store(true, Ordering::SeqCst);
+                },
+            }
+        }
+    }
+
+    /// drop stop requests and signals that arrived while not running, e.g.
+    /// Ctrl-C at the debugger prompt, so they don't stop the next run right
+    /// away
+    pub fn discard_signals(&self) {
+        #[cfg(all(unix, feature = "signals"))]
+        {
+            if let Some(ref chan) = self.sig_chan {
+                while chan.try_recv().is_ok() {}
+            }
+        }
+        self.stop_flag.store(false, Ordering::SeqCst);
+    }
+
+    #[cfg(all(unix, feature = "signals"))]
+    fn handle_signals(&mut self) {
+        let sig = match self.sig_chan {
+            Some(ref chan) => chan.try_recv().ok(),
+            None => None,
+        };
+
+        match sig {
+            Some(Signal::USR1) => {
+                let cycle = self.cycle_count;
+                if let InputMode::Record(ref mut log) = self.input_mode {
+                    log.push(cycle, InputEvent::StateDump);
+                }
+
+                // when replaying, the log decides when to dump
+                match self.input_mode {
+                    InputMode::Replay { .. } => {},
+                    _ => self.dump_state(),
+                }
+            },
+            Some(Signal::USR2) => {
+                let msg = match self.dump_to_file(&self.dump_path) {
+                    Ok(()) => format!("dumped state to {}", self.dump_path.display()),
+                    Err(e) => format!("can't dump state to {}: {}",
+                                      self.dump_path.display(), e),
+                };
+                self.io_mem.diag.warning(&msg);
+            },
+            Some(Signal::INT) => self.stop_flag.store(true, Ordering::SeqCst),
+            _ => (),
+        }
+    }
+
+    #[cfg(not(all(unix, feature = "signals")))]
+    fn handle_signals(&mut self) {}
+
+    /// queue bytes for the firmware to receive on the USART. they arrive
+    /// at the start of the next step.
+    pub fn queue_uart_input(&self, data: &[u8]) {
+        // the receiver lives as long as self
+        self.uart_input_tx.send(data.to_vec()).unwrap();
+    }
+
+    /// everything the USART transmitted since the last call or power-on
+    /// reset
+    pub fn take_uart_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.io_mem.usart_output_log)
+    }
+
+    /// a handle for queueing USART input from another thread while the
+    /// emulator runs
+    pub fn uart_input_sender(&self) -> mpsc::Sender<Vec<u8>> {
+        self.uart_input_tx.clone()
+    }
+
+    // move queued host input into the USART. ignored when replaying, as the
+    // log has it already.
+    fn drain_uart_input(&mut self) {
+        while let Ok(data) = self.uart_input_rx.try_recv() {
+            if let InputMode::Replay { .. } = self.input_mode {
+                continue;
+            }
+
+            self.io_mem.usart_input.extend(data.iter());
+            self.io_mem.uart_rx_received.extend(data);
+        }
+    }
+
+    /// raise interrupt `vector` from the host side. it stays pending until
+    /// the CPU takes it. ignored when replaying, as the log has it already.
+    pub fn raise_interrupt(&mut self, vector: u8) {
+        let cycle = self.cycle_count;
+        match self.input_mode {
+            InputMode::Replay { .. } => return,
+            InputMode::Record(ref mut log) =>
+                log.push(cycle, InputEvent::Interrupt(vector)),
+            InputMode::Live => {},
+        }
+
+        self.io_mem.injected_interrupts.push(vector);
+    }
+
+    /// raise interrupts the firmware has enabled at pseudo-random cycles,
+    /// `mean_gap` cycles apart on average, to shake out critical section
+    /// bugs. with the same `seed`, the same interrupts fire at the same
+    /// cycles.
+    pub fn enable_irq_stress(&mut self, seed: u64, mean_gap: u64) {
+        self.irq_stress = Some(IrqStress::new(seed, mean_gap));
+    }
+
+    pub fn irq_stress_report(&self) -> Option<String> {
+        self.irq_stress.as_ref().map(|s| s.report())
+    }
+
+    // fire a random enabled interrupt, if it's time to. through
+    // raise_interrupt(), so recordings have it, and replays don't fire
+    // twice.
+    fn stress_interrupts(&mut self) {
+        let vector = match self.irq_stress {
+            Some(ref mut stress) => {
+                if self.cycle_count < stress.next_cycle() {
+                    return;
+                }
+                let injected = &self.io_mem.injected_interrupts;
+                let enabled: Vec<u8> = self.io_mem.enabled_interrupts()
+                    .into_iter()
+                    .filter(|v| !injected.contains(v))
+                    .collect();
+                stress.fire(self.cycle_count, &enabled)
+            },
+            None => return,
+        };
+
+        if let Some(vector) = vector {
+            self.raise_interrupt(vector);
+        }
+    }
+
+    pub fn pin_state(&self, port: &str, pin: u8) -> Option<PinState> {
+        self.io_mem.port(port).map(|p| p.pin_state(pin))
+    }
+
+    /// drive an input pin from the host side; returns false if there's no
+    /// such port
+    pub fn drive_pin(&mut self, port: &str, pin: u8, state: PinState) -> bool {
+        match self.io_mem.port_mut(port) {
+            Some(p) => {
+                p.drive_pin(pin, state);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// start logging external inputs
+    pub fn start_recording(&mut self) {
+        self.io_mem.uart_live_input = true;
+        self.input_mode = InputMode::Record(InputLog::new());
+    }
+
+    /// stop logging external inputs, and return the log if we were recording
+    pub fn stop_recording(&mut self) -> Option<InputLog> {
+        match mem::replace(&mut self.input_mode, InputMode::Live) {
+            InputMode::Record(log) => Some(log),
+            // re-executing after step_back(); the log is complete regardless
+            InputMode::Replay { log, then_record: true, .. } => {
+                self.io_mem.uart_live_input = true;
+                Some(log)
+            },
+            other => {
+                self.input_mode = other;
+                None
+            }
+        }
+    }
+
+    /// ignore external inputs, and feed the ones from `log` instead, at the
+    /// same cycles they were recorded at
+    pub fn start_replay(&mut self, log: InputLog) {
+        self.io_mem.uart_live_input = false;
+        self.input_mode =
+            InputMode::Replay { log, next: 0, then_record: false };
+    }
+
+    /// take a checkpoint every `interval` instructions, keeping at most
+    /// `max_checkpoints` of them, so that `step_back` can be used. this also
+    /// starts recording inputs if we aren't replaying them already, as
+    /// re-executing needs them.
+    pub fn enable_time_travel(&mut self, interval: u64, max_checkpoints: usize) {
+        if let InputMode::Live = self.input_mode {
+            self.start_recording();
+        }
+
+        self.time_travel = Some(TimeTravel::new(interval, max_checkpoints));
+    }
+
+    /// go back `n` instructions, by restoring the nearest earlier checkpoint
+    /// and re-executing from there. returns the number of instructions
+    /// actually stepped back, which is less than `n` if there aren't old
+    /// enough checkpoints.
+    pub fn step_back(&mut self, n: u64) -> Result<u64> {
+        let start = self.insn_count;
+        let target = start.saturating_sub(n);
+
+        let snap = match self.time_travel {
+            Some(ref tt) => tt.checkpoint_before(target).cloned(),
+            None => None,
+        };
+        let snap = match snap {
+            Some(snap) => snap,
+            None => return Ok(0),
+        };
+        let target = cmp::max(target, snap.insn_count);
+        self.time_travel.as_mut().unwrap().rewind_to(&snap);
+
+        // re-execute with the same inputs as the first time around
+        let (log, then_record) =
+            match mem::replace(&mut self.input_mode, InputMode::Live) {
+                InputMode::Record(log) => (log, true),
+                InputMode::Replay { log, then_record, .. } => (log, then_record),
+                InputMode::Live => (InputLog::new(), false),
+            };
+
+        self.restore(&snap);
+
+        let next = log.events
+            .iter()
+            .position(|&(cycle, _)| cycle >= snap.cycle_count)
+            .unwrap_or(log.events.len());
+        self.io_mem.uart_live_input = false;
+        self.input_mode = InputMode::Replay { log, next, then_record };
+
+        // warp skips loop iterations in batches, which could overshoot
+        let warp = self.warp.take();
+        let result = self.run_to_insn(target);
+        self.warp = warp;
+        self.forget_warp_loop();
+        result?;
+
+        Ok(start.saturating_sub(self.insn_count))
+    }
+
+    fn run_to_insn(&mut self, target: u64) -> Result<()> {
+        while self.insn_count < target {
+            self._step()?;
+        }
+        Ok(())
+    }
+
+    fn record_inputs(&mut self, cycle: u64) {
+        if self.io_mem.uart_rx_received.is_empty() {
+            return;
+        }
+
+        let received = mem::take(&mut self.io_mem.uart_rx_received);
+        if let InputMode::Record(ref mut log) = self.input_mode {
+            for val in received {
+                log.push(cycle, InputEvent::UartByte(val));
+            }
+        }
+    }
+
+    fn replay_inputs(&mut self) {
+        let mut dump = false;
+        let mut done = false;
+
+        if let InputMode::Replay { ref log, ref mut next, then_record }
+                = self.input_mode {
+
+            while *next < log.events.len()
+                    && log.events[*next].0 <= self.cycle_count {
+
+                match log.events[*next].1 {
+                    InputEvent::UartByte(val) =>
+                        self.io_mem.usart_input.push_back(val),
+                    InputEvent::Interrupt(vector) =>
+                        self.io_mem.injected_interrupts.push(vector),
+                    InputEvent::StateDump => dump = true,
+                }
+
+                *next += 1;
+            }
+
+            done = then_record && *next >= log.events.len();
+        }
+
+        if done {
+            if let InputMode::Replay { log, .. } =
+                    mem::replace(&mut self.input_mode, InputMode::Live) {
+
+                self.io_mem.uart_live_input = true;
+                self.input_mode = InputMode::Record(log);
+            }
+        }
+
+        if dump {
+            self.dump_state();
+        }
+    }
+
+    /// load an ELF file, with its symbols and line info, or a raw binary
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let mut f = File::open(path)?;
+        let mut buffer = vec![];
+        f.read_to_end(&mut buffer)?;
+
+        if elf::is_elf(&buffer) {
+            let image = elf::parse(&buffer, self.device.flash_size)?;
+            self.set_flash(&image.flash)?;
+            self.symbols = image.symbols;
+            self.lines = image.lines;
+        } else {
+            self.set_flash(&buffer)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load_bin(&mut self, path: &str) -> io::Result<()> {
+        let mut f = File::open(path)?;
+        let mut buffer = vec![];
+        f.read_to_end(&mut buffer)?;
+
+        self.set_flash(&buffer)
+    }
+
+    fn set_flash(&mut self, image: &[u8]) -> io::Result<()> {
+        if image.len() > self.device.flash_size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} byte image doesn't fit in {}'s flash",
+                    image.len(), self.device.name)));
+        }
+
+        self.prog_mem.set_bytes(image)
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        self.halted = true;
+        self.stop_reason = reason;
+    }
+
+    /// start attributing cycles to functions, discarding any earlier profile
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    /// the profile so far, by function, if profiling is enabled
+    pub fn profile_report(&self) -> Option<String> {
+        self.profiler.as_ref().map(|p| p.report(&self.symbols))
+    }
+
+    /// start recording calls for call_graph_dot() and call_trace_json(),
+    /// discarding earlier ones
+    pub fn enable_call_graph(&mut self) {
+        self.call_graph = Some(CallGraph::new());
+    }
+
+    pub fn call_graph_dot(&self) -> Option<String> {
+        self.call_graph.as_ref().map(|g| g.dot(&self.symbols))
+    }
+
+    /// function entries and exits, for chrome://tracing or Perfetto
+    pub fn call_trace_json(&self) -> Option<String> {
+        self.call_graph.as_ref().map(|g| g.chrome_trace(&self.symbols))
+    }
+
+    /// have `listener` called on every call, return and interrupt entry
+    pub fn add_call_listener(&mut self, listener: CallListener) {
+        self.call_listeners.push(listener);
+    }
+
+    fn call_event(&mut self, kind: CallKind, target: u32, from: u32) {
+        if self.call_graph.is_none() && self.call_listeners.is_empty() {
+            return;
+        }
+
+        let event = CallEvent {
+            kind,
+            target,
+            from,
+            cycle: self.cycle_count,
+            time: self.emulated_time(),
+            depth: self.call_stack.len(),
+        };
+        if let Some(ref mut graph) = self.call_graph {
+            graph.record(&event);
+        }
+        for listener in self.call_listeners.iter_mut() {
+            listener(&event);
+        }
+    }
+
+    fn counters(&self) -> Counters {
+        Counters {
+            insns: self.insn_count,
+            cycles: self.cycle_count,
+            reads: self.io_mem.data_reads,
+            writes: self.io_mem.data_writes,
+            emulated_time: self.emulated_time(),
+        }
+    }
+
+    /// start collecting statistics, discarding earlier ones. run() prints
+    /// them when it finishes.
+    pub fn enable_stats(&mut self) {
+        let counters = self.counters();
+        let ramend = self.device.ramend() as u16;
+        self.stats = Some(Stats::new(&counters, self.io_mem.get_sp(), ramend));
+    }
+
+    pub fn stats_report(&self) -> Option<String> {
+        self.stats.as_ref().map(|stats| stats.report(&self.counters()))
+    }
+
+    /// start counting time per power state, discarding earlier counts
+    pub fn enable_energy(&mut self) {
+        self.energy = Some(Energy::new());
+    }
+
+    /// duty cycles and, given `table`, estimated consumption
+    pub fn energy_report(&self, table: Option<&CurrentTable>) -> Option<String> {
+        self.energy.as_ref().map(|energy| energy.report(table))
+    }
+
+    /// start counting accesses per IO register and per `page_size` bytes
+    /// of memory, discarding earlier counts
+    pub fn enable_heatmap(&mut self, page_size: u32) {
+        self.io_mem.heatmap = Some(Heatmap::new(page_size));
+    }
+
+    /// the `limit` busiest IO registers and pages, if enabled
+    pub fn heatmap_report(&self, limit: usize) -> Option<String> {
+        self.io_mem.heatmap.as_ref().map(|h| h.report(limit))
+    }
+
+    /// start tracking stack usage. if `guard` is set, stop with an error
+    /// when SP enters that [start, end) region.
+    pub fn enable_stack_monitor(&mut self, guard: Option<(u16, u16)>) {
+        let mut monitor = StackMonitor::new(
+            self.device.sram_start as u16, self.device.ramend() as u16);
+        monitor.guard = guard;
+        self.stack_monitor = Some(monitor);
+    }
+
+    /// keep the last `size` instructions for crash_report()
+    pub fn enable_history(&mut self, size: usize) {
+        self.history = Some(History::new(size));
+    }
+
+    /// what to tell the user when a run stops with `fault`: the error, the
+    /// last instructions (if history is enabled), the state, and the code
+    /// around pc
+    pub fn crash_report(&self, fault: &Error) -> String {
+        let mut out = format!("error: {}\n\n", fault);
+
+        if let Some(report) = self.irq_stress_report() {
+            writeln!(out, "{}", report).unwrap();
+        }
+
+        if let Some(ref history) = self.history {
+            writeln!(out, "last instructions:").unwrap();
+            out.push_str(&history.fmt(&self.symbols));
+            writeln!(out).unwrap();
+        }
+
+        out.push_str(&self.fmt_state());
+        writeln!(out).unwrap();
+
+        // a few instructions either side
+        let (start, end) = (self.pc.saturating_sub(16), self.pc + 16);
+        for (addr, insn) in self.prog_mem.get_insns_at(start, end) {
+            let marker = if addr == self.pc { "=>" } else { "  " };
+            writeln!(out, "{} {:>8x}:\t{:?}", marker, addr, insn).unwrap();
+        }
+
+        out
+    }
+
+    pub fn stack_report(&self) -> Option<String> {
+        self.stack_monitor.as_ref().map(|m| m.report(&self.symbols))
+    }
+
+    /// flip the `mask` bits of `target` at time `at`
+    pub fn inject_fault(&mut self, at: FaultTime, target: FaultTarget, mask: u8) {
+        self.pending_faults.push(FaultPoint { at, target, mask });
+    }
+
+    fn apply_faults(&mut self) {
+        if self.pending_faults.is_empty() {
+            return;
+        }
+
+        let faults = mem::take(&mut self.pending_faults);
+        let (due, pending): (Vec<_>, Vec<_>) =
+            faults.into_iter().partition(|f| f.is_due(self));
+        for f in due {
+            f.apply(self);
+        }
+        self.pending_faults = pending;
+    }
+
+    /// run `source` as a Rhai script on `event`; see script.rs
+    #[cfg(feature = "scripting")]
+    pub fn add_script(&mut self, event: ScriptEvent, source: &str) -> Result<()> {
+        let scripts = self.scripts.get_or_insert_with(Scripts::new);
+        scripts.add(event, source).map_err(|msg| Error::Script { msg })?;
+        if scripts.wants_io_accesses() && self.io_mem.io_accesses.is_none() {
+            self.io_mem.io_accesses = Some(vec![]);
+        }
+        Ok(())
+    }
+
+    /// run `calls` with the machine state moved into a Machine for them.
+    /// returns false if any script returned false.
+    #[cfg(feature = "scripting")]
+    fn call_scripts(&mut self, calls: Vec<ScriptCall>) -> Result<bool> {
+        if calls.is_empty() {
+            return Ok(true);
+        }
+
+        let machine = Machine::new(MachineState {
+            regs: self.io_mem.regs.r,
+            sreg: self.io_mem.sreg.as_u8(),
+            pc: self.pc,
+            data_mem: mem::take(&mut self.io_mem.data_mem),
+            insn_count: self.insn_count,
+            cycle_count: self.cycle_count,
+            io_offset: self.io_mem.io_offset,
+            stop: false,
+        });
+        let res = self.scripts.as_ref().unwrap().run(&calls, &machine);
+
+        let state = machine.take_state();
+        self.io_mem.regs.r = state.regs;
+        self.io_mem.sreg.set_u8(state.sreg);
+        self.pc = state.pc;
+        self.io_mem.data_mem = state.data_mem;
+        for line in self.scripts.as_ref().unwrap().take_output() {
+            self.io_mem.diag.warning(&line);
+        }
+        if state.stop {
+            self.stop(StopReason::Script);
+        }
+
+        res.map_err(|msg| Error::Script { msg })
+    }
+
+    #[cfg(feature = "scripting")]
+    fn insn_scripts(&mut self, accesses: &[IoAccess]) -> Result<()> {
+        let insn_count = self.insn_count;
+        let calls = match self.scripts {
+            Some(ref mut scripts) => scripts.after_insn(insn_count, accesses),
+            None => return Ok(()),
+        };
+        self.call_scripts(calls)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn insn_scripts(&mut self, _accesses: &[IoAccess]) -> Result<()> {
+        Ok(())
+    }
+
+    /// whether to stop at breakpoint `id`
+    #[cfg(feature = "scripting")]
+    fn breakpoint_scripts(&mut self, id: usize) -> Result<bool> {
+        let calls = match self.scripts {
+            Some(ref scripts) => scripts.on_breakpoint(id),
+            None => return Ok(true),
+        };
+        self.call_scripts(calls)
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn breakpoint_scripts(&mut self, _id: usize) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// start tracking taint from USART input, forgetting earlier taint and
+    /// reports. if `trap` is set, stop with an error when tainted data
+    /// reaches PC or an SPM address.
+    pub fn enable_taint(&mut self, trap: bool) {
+        let sources = self.io_mem.peripherals.iter()
+            .filter_map(|p| p.as_any().downcast_ref::<Usart>())
+            .map(|usart| usart.data_addr())
+            .collect();
+
+        let mut taint = Taint::new(sources);
+        taint.trap = trap;
+        taint.ret_addr_size = self.device.ret_addr_size();
+        self.taint = Some(taint);
+    }
+
+    pub fn taint_report(&self) -> Option<String> {
+        self.taint.as_ref().map(|taint| {
+            let mut out = format!("{} taint reports\n", taint.reports.len());
+            for r in &taint.reports {
+                writeln!(out, "{} @ {} after {} instructions",
+                         r.sink, self.fmt_location(r.pc), r.insn_count).unwrap();
+            }
+            out
+        })
+    }
+
+    /// make flash readable, but not writable, at `base` in data space. SPM
+    /// still works, and the window follows changes to flash. data space
+    /// grows to fit the window if needed.
+    pub fn map_flash(&mut self, base: u32) {
+        let size = self.prog_mem.size();
+        self.io_mem.write_protect.remove("flash");
+        self.io_mem.write_protect.add(base, base + size, "flash");
+        self.flash_map = Some(base);
+        self.flash_map_version = None;
+        self.sync_flash_map();
+    }
+
+    // whether the firmware signalled that it's done, through a host call, a
+    // write to exit_addr or reaching exit_pc; returns the exit code
+    fn exit_requested(&mut self) -> Option<u8> {
+        let code = self.io_mem.host_calls_mut().and_then(|h| h.take_exit());
+        if code.is_some() {
+            return code;
+        }
+
+        if let Some(code) = self.io_mem.exit_request.take() {
+            return Some(code);
+        }
+
+        // e.g. avr-libc's _exit, with exit()'s argument still in r24
+        if Some(self.pc) == self.exit_pc && !self.sleeping {
+            return Some(self.io_mem.regs.r[24]);
+        }
+
+        None
+    }
+
+    /// set exit_pc to avr-libc's `_exit` (or `exit`), if the symbols have
+    /// it. returns whether they do.
+    pub fn exit_at_exit_symbol(&mut self) -> bool {
+        let addr = self.symbols.find("_exit").or_else(|| self.symbols.find("exit"))
+            .map(|sym| sym.addr);
+        if addr.is_some() {
+            self.exit_pc = addr;
+        }
+        addr.is_some()
+    }
+
+    /// the exit code the program stopped with, if it exited. halting at
+    /// __stop_program counts, with the code still in r24.
+    pub fn exit_code(&self) -> Option<u8> {
+        match self.stop_reason {
+            StopReason::Exit(code) => Some(code),
+            StopReason::Halted if self.halted => Some(self.io_mem.regs.r[24]),
+            _ => None,
+        }
+    }
+
+    /// let the firmware make host calls through registers at `base` (see
+    /// hostcall.rs). READ_FILE can read files under `files_root`, if given.
+    pub fn enable_host_calls(&mut self, base: u32, files_root: Option<PathBuf>) {
+        let mut host = HostCalls::new(base);
+        host.files_root = files_root;
+
+        self.io_mem.peripherals.retain(|p| !p.as_any().is::<HostCalls>());
+        self.io_mem.add_peripheral(Box::new(host));
+    }
+
+    /// the cycle of the next scheduled peripheral event or replayed input
+    pub fn next_event_cycle(&mut self) -> Option<u64> {
+        let replayed = match self.input_mode {
+            InputMode::Replay { ref log, next, .. } =>
+                log.events.get(next).map(|e| e.0),
+            _ => None,
+        };
+
+        self.io_mem.next_event_cycle().into_iter().chain(replayed).min()
+    }
+
+    /// a hash of the registers, SREG, PC, SP and the whole data space. the
+    /// same state always gives the same hash.
+    pub fn state_hash(&self) -> u64 {
+        self.state_hash_with(&mut MemHasher::new())
+    }
+
+    // the same, reusing `mem_hasher`'s page hashes from earlier
+    fn state_hash_with(&self, mem_hasher: &mut MemHasher) -> u64 {
+        let mut hasher = StateHasher::new();
+        hasher.write(&self.pc.to_le_bytes());
+        hasher.write(&self.io_mem.get_sp().to_le_bytes());
+        hasher.write(&[self.io_mem.sreg.as_u8()]);
+        hasher.write(&self.io_mem.regs.r);
+        hasher.write(&mem_hasher.hash(&self.io_mem.data_mem).to_le_bytes());
+        hasher.finish()
+    }
+
+    /// record state_hash() every `interval` instructions, in state_hashes
+    pub fn enable_state_hashing(&mut self, interval: u64) {
+        self.state_hashes = Some(HashLog::new(interval));
+    }
+
+    fn record_state_hash(&mut self) {
+        let insn_count = self.insn_count;
+        let due = self.state_hashes.as_ref().is_some_and(|log| log.due(insn_count));
+        if !due {
+            return;
+        }
+
+        let mut log = self.state_hashes.take().unwrap();
+        let hash = self.state_hash_with(&mut log.mem_hasher);
+        let matches = log.record(insn_count, hash);
+        self.state_hashes = Some(log);
+        if !matches {
+            self.stop(StopReason::Diverged(insn_count));
+        }
+    }
+
+    /// skip over busy-wait loops (see warp.rs)
+    pub fn enable_warp(&mut self) {
+        self.warp = Some(Warp::new());
+    }
+
+    // the polling loop warp saw last is no longer where execution is
+    fn forget_warp_loop(&mut self) {
+        if let Some(ref mut warp) = self.warp {
+            warp.forget();
+        }
+    }
+
+    // fast-forward through the loop whose start execution just jumped back to
+    fn warp_loop(&mut self) {
+        let head = self.pc;
+
+        let limit = self.next_event_cycle();
+
+        if let Some(delay) = warp::delay_loop(&self.prog_mem, head, &self.device) {
+            let n = delay.iterations(&self.io_mem.regs.r);
+            // leave the last iteration to run
+            let skip = match limit {
+                Some(limit) =>
+                    (n - 1).min(limit.saturating_sub(self.cycle_count) / delay.cycles),
+                None => n - 1,
+            };
+
+            if skip > 0 {
+                delay.set_iterations(&mut self.io_mem.regs.r, n - skip);
+
+                let skipped = skip * delay.cycles;
+                self.cycle_count += skipped;
+                self.insn_count += skip * delay.insns;
+
+                let warp = self.warp.as_mut().unwrap();
+                warp.delay_loops += 1;
+                warp.skipped_cycles += skipped;
+            }
+            return;
+        }
+
+        let state = LoopState {
+            head,
+            regs: self.io_mem.regs.r,
+            sreg: self.io_mem.sreg.as_u8(),
+            sp: self.io_mem.get_sp(),
+            depth: self.call_stack.len(),
+            data_writes: self.io_mem.data_writes,
+        };
+        let (cycle, insn_count) = (self.cycle_count, self.insn_count);
+        let skip = self.warp.as_mut().unwrap()
+            .poll_loop(state, cycle, insn_count, limit);
+        if let Some((n, cycles, insns)) = skip {
+            self.cycle_count += n * cycles;
+            self.insn_count += n * insns;
+        }
+    }
+
+    // run flash through the CRC module for an NVM CRC command
+    fn run_flash_crc(&mut self) {
+        let (start, end) = match self.io_mem.nvm.crc_request.take() {
+            Some(range) => range,
+            None => return,
+        };
+
+        let end = cmp::min(end, self.prog_mem.size() - 1);
+        let data: Vec<u8> = (start..end + 1)
+            .map(|addr| self.prog_mem.read_byte(addr).unwrap_or(0xff))
+            .collect();
+        let done = self.io_mem.peripheral_mut::<Crc>("CRC")
+            .is_some_and(|crc| crc.flash_checksum(&data));
+        if !done {
+            self.io_mem.diag.warning(&format!(
+                "NVM CRC command with the CRC module not set to flash @ {:#x}",
+                self.pc));
+        }
+    }
+
+    // copy flash to its data space window if it changed since the last copy
+    fn sync_flash_map(&mut self) {
+        let base = match self.flash_map {
+            Some(base) => base as usize,
+            None => return,
+        };
+
+        let version = self.prog_mem.version();
+        if self.flash_map_version == Some(version) {
+            return;
+        }
+        self.flash_map_version = Some(version);
+
+        let end = base + self.prog_mem.size() as usize;
+        if self.io_mem.data_mem.len() < end {
+            self.io_mem.data_mem.resize(end, 0);
+        }
+
+        let window = &mut self.io_mem.data_mem[base..end];
+        for (addr, b) in window.iter_mut().enumerate() {
+            // past the image is erased
+            *b = self.prog_mem.read_byte(addr as u32).unwrap_or(0xff);
+        }
+    }
+
+    /// start recording executed addresses, discarding earlier coverage
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.prog_mem.size()));
+    }
+
+    pub fn coverage_report(&self) -> Option<String> {
+        self.coverage.as_ref().map(|c| c.report(&self.prog_mem, &self.symbols))
+    }
+
+    /// coverage as an lcov tracefile, if coverage is enabled and there's
+    /// line info
+    pub fn coverage_lcov(&self) -> Option<String> {
+        if self.lines.is_empty() {
+            return None;
+        }
+
+        let lines = &self.lines;
+        self.coverage.as_ref().map(|c| {
+            c.lcov(&self.prog_mem, &self.symbols,
+                |addr| lines.lookup(addr).map(|(file, line)| (file.to_string(), line)))
+        })
+    }
+
+    /// report instructions in `filter`'s ranges to `tracer`
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>, filter: TraceFilter) {
+        self.clear_tracer();
+        self.tracer = Some((tracer, filter));
+    }
+
+    /// stop tracing, flush the tracer and return it
+    pub fn clear_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take().map(|(mut tracer, _)| {
+            tracer.flush();
+            tracer
+        })
+    }
+
+    /// describe every IO register access to `out`, one line each, see
+    /// regmap::fmt_access
+    pub fn set_io_trace(&mut self, out: Box<dyn io::Write + Send>) {
+        self.clear_io_trace();
+        self.io_trace = Some(out);
+        if self.io_mem.io_accesses.is_none() {
+            self.io_mem.io_accesses = Some(vec![]);
+        }
+    }
+
+    /// stop describing IO accesses, and flush the output
+    pub fn clear_io_trace(&mut self) {
+        if let Some(mut out) = self.io_trace.take() {
+            let _ = out.flush();
+        }
+    }
+
+    /// write changes of `signals` (see vcd::Source::parse) to `out` as a
+    /// VCD file, named as given
+    pub fn start_vcd(&mut self, out: Box<dyn io::Write + Send>, signals: &[&str])
+            -> Result<()> {
+
+        let mut sources = vec![];
+        for &spec in signals {
+            let source = Source::parse(spec, &self.io_mem, self.device.io_regs)
+                .ok_or_else(|| Error::UnknownSymbol { name: spec.to_string() })?;
+            sources.push((spec.to_string(), source));
+        }
+
+        self.stop_vcd();
+        let mut vcd = Vcd::new(out, self.device.name, sources);
+        if vcd.has_io_regs() && self.io_mem.io_accesses.is_none() {
+            self.io_mem.io_accesses = Some(vec![]);
+        }
+        vcd.sample(self.emulated_nanos(), &self.io_mem);
+        self.vcd = Some(vcd);
+        Ok(())
+    }
+
+    /// stop recording signals, and flush the VCD output
+    pub fn stop_vcd(&mut self) {
+        if let Some(mut vcd) = self.vcd.take() {
+            vcd.flush();
+        }
+    }
+
+    fn emulated_nanos(&self) -> u64 {
+        let t = self.emulated_time();
+        t.as_secs() * 1_000_000_000 + t.subsec_nanos() as u64
+    }
+
+    fn vcd_io(&mut self, accesses: &[IoAccess]) {
+        let time = self.emulated_nanos();
+        if let Some(ref mut vcd) = self.vcd {
+            for access in accesses {
+                vcd.io_access(time, access.addr, access.val);
+            }
+        }
+    }
+
+    fn sample_vcd(&mut self) {
+        let time = self.emulated_nanos();
+        if let Some(ref mut vcd) = self.vcd {
+            vcd.sample(time, &self.io_mem);
+        }
+    }
+
+    fn trace_io(&mut self, pc: u32, accesses: &[IoAccess]) {
+        if let Some(ref mut out) = self.io_trace {
+            for access in accesses {
+                let line = regmap::fmt_access(self.device.io_regs, self.insn_count, pc,
+                                              access);
+                let _ = writeln!(out, "{}", line);
+            }
+        }
+    }
+
+    fn trace_insn(&mut self, pc: u32, insn: AvrInsn, skipped: bool,
+                  regs_before: &[u8; 32], sreg_before: u8, cycle: u64) {
+
+        let symbols = &self.symbols;
+        let (tracer, filter) = match self.tracer {
+            Some((ref mut tracer, ref filter)) => (tracer, filter),
+            None => return,
+        };
+        if !filter.matches(pc) {
+            return;
+        }
+
+        let regs = &self.io_mem.regs.r;
+        let reg_changes = (0..32)
+            .filter(|&r| regs[r] != regs_before[r])
+            .map(|r| (r as u8, regs[r]))
+            .collect();
+
+        tracer.trace(&TraceEntry {
+            pc,
+            insn,
+            skipped,
+            cycle,
+            insn_count: self.insn_count,
+            sp: self.io_mem.get_sp(),
+            reg_changes,
+            sreg_before,
+            sreg_after: self.io_mem.sreg.as_u8(),
+            symbol: symbols.lookup(pc).map(|(sym, ofs)| (sym.name.as_str(), ofs)),
+        });
+    }
+
+    /// stop at `addr` when `condition` (if any) is true. returns the
+    /// breakpoint's id, or an error if the condition doesn't parse.
+    pub fn add_breakpoint(&mut self, addr: u32, condition: Option<&str>)
+            -> ::std::result::Result<usize, String> {
+
+        let id = self.next_breakpoint_id;
+        self.breakpoints.push(Breakpoint::new(id, addr, condition)?);
+        self.next_breakpoint_id += 1;
+        Ok(id)
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|bp| bp.id != id);
+        self.breakpoints.len() != len
+    }
+
+    /// if an enabled breakpoint at pc has its condition met, count the hit
+    /// and return its id
+    fn check_breakpoints(&mut self) -> Option<usize> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+
+        let i = self.breakpoints.iter().position(|bp| {
+            bp.enabled && bp.addr == self.pc
+                && bp.condition.as_ref().is_none_or(|c| c.1.eval(self) != 0)
+        })?;
+
+        let bp = &mut self.breakpoints[i];
+        bp.hits += 1;
+        Some(bp.id)
+    }
+
+    /// one step, stopping if that lands on a breakpoint
+    pub(crate) fn step_checked(&mut self) -> Result<()> {
+        self._step()?;
+
+        if !self.halted {
+            if let Some(id) = self.check_breakpoints() {
+                if self.breakpoint_scripts(id)? {
+                    self.stop(StopReason::Breakpoint(id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // run `f`, and sum up how it went
+    fn run_result<F>(&mut self, f: F) -> RunResult
+            where F: FnOnce(&mut Emulator) -> Result<StopReason> {
+
+        let (insn_count, cycle_count) = (self.insn_count, self.cycle_count);
+
+        let (reason, fault) = match f(self) {
+            Ok(reason) => (reason, None),
+            Err(e) => (StopReason::Fault, Some(e)),
+        };
+        if fault.is_some() {
+            self.stop(StopReason::Fault);
+        }
+
+        RunResult {
+            reason,
+            fault,
+            // a reset during the run restarts the counts
+            insns: self.insn_count.saturating_sub(insn_count),
+            cycles: self.cycle_count.saturating_sub(cycle_count),
+            pc: self.pc,
+        }
+    }
+
+    /// run until the program stops
+    pub fn run(&mut self) -> RunResult {
+        self.run_result(|emu| {
+            emu.halted = false;
+            while !emu.halted {
+                emu.step_checked()?;
+            }
+            Ok(emu.stop_reason)
+        })
+    }
+
+    /// run until execution reaches `pc`, or the program stops
+    pub fn until(&mut self, pc: u32) -> RunResult {
+        let mut result = self.run_until_any(&[StopCondition::Pc(pc)]);
+        if let StopReason::Condition(_) = result.reason {
+            result.reason = StopReason::ReachedPc;
+            self.stop_reason = StopReason::ReachedPc;
+        }
+        result
+    }
+
+    /// run until one of `conds` is met, or the program stops. the stop
+    /// reason says which condition it was, by index.
+    pub fn run_until_any(&mut self, conds: &[StopCondition]) -> RunResult {
+        self.run_result(|emu| emu.run_until_any_inner(conds))
+    }
+
+    fn run_until_any_inner(&mut self, conds: &[StopCondition]) -> Result<StopReason> {
+        let mut resolved = vec![];
+        for cond in conds {
+            resolved.push(match *cond {
+                StopCondition::Pc(addr) => ResolvedCondition::Pc(addr),
+                StopCondition::Symbol(ref name) => {
+                    let sym = self.symbols.find(name).ok_or_else(||
+                        Error::UnknownSymbol { name: name.clone() })?;
+                    ResolvedCondition::Pc(sym.addr)
+                },
+                StopCondition::InsnLimit(n) =>
+                    ResolvedCondition::InsnCount(self.insn_count + n),
+                StopCondition::CycleLimit(n) =>
+                    ResolvedCondition::CycleCount(self.cycle_count + n),
+                StopCondition::UartOutput =>
+                    ResolvedCondition::UartOutput(self.io_mem.usart_output_log.len()),
+            });
+        }
+
+        let mut met = None;
+        self.run_until_cond(|emu| {
+            met = resolved.iter().position(|cond| match *cond {
+                ResolvedCondition::Pc(addr) => emu.pc == addr,
+                ResolvedCondition::InsnCount(n) => emu.insn_count >= n,
+                ResolvedCondition::CycleCount(n) => emu.cycle_count >= n,
+                ResolvedCondition::UartOutput(len) =>
+                    emu.io_mem.usart_output_log.len() > len,
+            });
+            met.is_some()
+        }, StopReason::Halted)?;
+
+        if let Some(i) = met {
+            self.stop_reason = StopReason::Condition(i);
+        }
+
+        Ok(self.stop_reason)
+    }
+
+    /// step until `done` returns true after a step, or the program stops
+    fn run_until_cond<F>(&mut self, mut done: F, reason: StopReason)
+            -> Result<StopReason>
+            where F: FnMut(&Emulator) -> bool {
+
+        self.halted = false;
+        while !self.halted {
+            self.step_checked()?;
+            if !self.halted && done(self) {
+                self.stop_reason = reason;
+                break;
+            }
+        }
+
+        Ok(self.stop_reason)
+    }
+
+    /// execute `n` instructions. interrupt entry and sleeping don't count.
+    pub fn run_insns(&mut self, n: u64) -> Result<StopReason> {
+        if n == 0 {
+            return Ok(StopReason::InsnLimit);
+        }
+
+        let end = self.insn_count + n;
+        self.run_until_cond(|emu| emu.insn_count >= end, StopReason::InsnLimit)
+    }
+
+    /// run until the cycle count reaches `cycle`, or the program stops
+    pub fn run_until_cycle(&mut self, cycle: u64) -> Result<StopReason> {
+        if self.cycle_count >= cycle {
+            return Ok(StopReason::CycleLimit);
+        }
+
+        self.run_until_cond(|emu| emu.cycle_count >= cycle, StopReason::CycleLimit)
+    }
+
+    /// the address of code symbol `spec`, or `spec` as a hex byte address
+    pub fn resolve_code_addr(&self, spec: &str) -> Option<u32> {
+        self.symbols.find(spec).map(|sym| sym.addr)
+            .or_else(|| u32::from_str_radix(spec, 16).ok())
+    }
+
+    /// start executing at `func` (see resolve_code_addr) instead of wherever
+    /// the reset left off, with an empty call stack
+    pub fn set_entry(&mut self, func: &str) -> Result<()> {
+        let addr = self.resolve_code_addr(func).ok_or_else(||
+            Error::BadCall { msg: format!("unknown function {:?}", func) })?;
+
+        self.pc = addr;
+        self.call_stack.clear();
+        self.skip_next_insn = false;
+        self.halted = false;
+        self.wake();
+        self.forget_warp_loop();
+        Ok(())
+    }
+
+    /// write `data` to data space starting at `addr`, as the CPU would, so
+    /// IO register writes reach their peripherals. see write_data() for
+    /// plain memory.
+    pub fn poke(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let pc = self.pc;
+        for (i, &val) in data.iter().enumerate() {
+            self.io_mem.set8(addr + i as u32, val, &"<host>", pc)?;
+        }
+        Ok(())
+    }
+
+    /// call guest function `func` (see resolve_code_addr) with `args`, each
+    /// little-endian (see call.rs), and run until it returns. the pc, SP and
+    /// call stack go back to where they were, even if the call fails;
+    /// registers and memory keep the call's changes.
+    pub fn call_function(&mut self, func: &str, args: &[&[u8]])
+            -> Result<CallResult> {
+
+        let addr = self.resolve_code_addr(func).ok_or_else(||
+            Error::BadCall { msg: format!("unknown function {:?}", func) })?;
+        let arg_regs = call::arg_registers(args).ok_or_else(||
+            Error::BadCall { msg: "arguments don't fit in registers".to_string() })?;
+
+        for (reg, val) in arg_regs {
+            self.io_mem.regs.r[reg] = val;
+        }
+        // the zero register
+        self.io_mem.regs.r[1] = 0;
+
+        let mem_before = self.io_mem.data_mem.clone();
+        let start_cycle = self.cycle_count;
+        let saved_pc = self.pc;
+        let saved_sp = self.io_mem.get_sp();
+        let saved_call_stack = self.call_stack.clone();
+        let saved_skip = self.skip_next_insn;
+
+        let res = self.run_call(func, addr);
+
+        self.pc = saved_pc;
+        self.io_mem.set_sp(saved_sp);
+        self.call_stack = saved_call_stack;
+        self.skip_next_insn = saved_skip;
+        res?;
+
+        Ok(CallResult {
+            ret: self.io_mem.regs.get16(24),
+            regs: self.io_mem.regs.r,
+            changed_mem: call::mem_changes(&mem_before, &self.io_mem.data_mem),
+            cycles: self.cycle_count - start_cycle,
+        })
+    }
+
+    // run from `addr` until it returns, for call_function
+    fn run_call(&mut self, func: &str, addr: u32) -> Result<()> {
+        // returning here means the call is done; there's no code there
+        let sentinel = self.device.flash_size;
+        self.push_ret_addr(sentinel, addr, FrameKind::Call)?;
+        self.pc = addr;
+        self.wake();
+        self.skip_next_insn = false;
+
+        let reason = self.run_until_cond(
+            |emu| emu.pc == sentinel, StopReason::Returned)?;
+        if reason != StopReason::Returned {
+            return Err(Error::BadCall {
+                msg: format!("{} didn't return: {:?}", func, reason),
+            });
+        }
+        Ok(())
+    }
+
+    /// run `stub` on the host instead of guest function `func` (see
+    /// resolve_code_addr), replacing any earlier stub for it
+    pub fn stub_function(&mut self, func: &str, stub: Stub) -> Result<()> {
+        let addr = self.resolve_code_addr(func).ok_or_else(||
+            Error::BadCall { msg: format!("unknown function {:?}", func) })?;
+        self.stubs.insert(addr, Arc::new(Mutex::new(stub)));
+        Ok(())
+    }
+
+    /// go back to running the guest's own `func`
+    pub fn remove_stub(&mut self, func: &str) -> bool {
+        match self.resolve_code_addr(func) {
+            Some(addr) => self.stubs.remove(&addr).is_some(),
+            None => false,
+        }
+    }
+
+    // run the stub for the function at pc, then return from the function
+    fn run_stub(&mut self, start_cycle: u64) -> Result<()> {
+        let pc = self.pc;
+        let cycles = match self.stubs.get(&pc) {
+            Some(stub) => (*stub.lock().unwrap())(&mut self.io_mem),
+            None => return Ok(()),
+        };
+
+        self.pc = self.pop_ret_addr()?;
+        self.cycle_count += cycles + insn_cycles(&AvrInsn::Ret, true, &self.device);
+        self.insn_count += 1;
+        self.record_inputs(start_cycle);
+        Ok(())
+    }
+
+    /// run `handler` for instructions the emulator doesn't know, instead of
+    /// faulting
+    pub fn emulate_unimplemented(&mut self, handler: InsnHandler) {
+        self.unimplemented_policy = UnimplementedPolicy::Emulate(Arc::new(Mutex::new(handler)));
+    }
+
+    // apply the unimplemented instruction policy to `err`, about the
+    // instruction at pc, which takes `words` words if it decoded
+    fn unimplemented(&mut self, err: Error, words: u32, start_cycle: u64) -> Result<()> {
+        let pc = self.pc;
+        let (words, cycles) = match self.unimplemented_policy.clone() {
+            UnimplementedPolicy::Fault => return Err(err),
+            UnimplementedPolicy::Halt => {
+                let report = self.crash_report(&err);
+                self.io_mem.diag.warning(&report);
+                self.stop(StopReason::Unimplemented);
+                return Ok(());
+            },
+            UnimplementedPolicy::Skip => {
+                self.io_mem.diag.warning(&format!("skipped: {}", err));
+                (words, 1)
+            },
+            UnimplementedPolicy::Emulate(handler) => {
+                // enough for the longest instruction
+                let opcode = [
+                    self.prog_mem.read_word(pc).unwrap_or(0),
+                    self.prog_mem.read_word(pc + 2).unwrap_or(0),
+                ];
+                let res = (*handler.lock().unwrap())(&mut self.io_mem, pc, &opcode);
+                match res {
+                    Some(res) => res,
+                    None => return Err(err),
+                }
+            },
+        };
+
+        self.pc = pc + 2 * words;
+        self.cycle_count += cycles;
+        self.insn_count += 1;
+        self.record_inputs(start_cycle);
+        Ok(())
+    }
+
+    /// execute one instruction, but run calls until they return
+    pub fn step_over(&mut self) -> Result<StopReason> {
+        let insn = match self.get_cur_insn() {
+            Some(insn) => insn,
+            None => return Err(Error::DecodeError { pc: self.pc }),
+        };
+        let seq_pc = self.pc + (insn.byte_size() as u32);
+
+        let is_call = match insn {
+            // "rcall .+0" is for pushing the pc or making stack space
+            AvrInsn::Rcall(ofs) =>
+                AvrInsn::get_rel_jmp_target(seq_pc, ofs) != seq_pc,
+            AvrInsn::Call(_) | AvrInsn::Icall | AvrInsn::Eicall => true,
+            _ => false,
+        };
+
+        if !is_call || self.skip_next_insn {
+            let end = self.insn_count + 1;
+            return self.run_until_cond(
+                |emu| emu.insn_count >= end, StopReason::SteppedOver);
+        }
+
+        // back at the return address with the return address popped, so
+        // recursive calls don't stop early
+        let sp = self.io_mem.get_sp();
+        self.run_until_cond(
+            |emu| emu.pc == seq_pc && emu.io_mem.get_sp() >= sp,
+            StopReason::SteppedOver)
+    }
+
+    /// run until the current function (or interrupt handler) returns. with
+    /// an empty call stack, this runs until the program stops.
+    pub fn run_until_return(&mut self) -> Result<StopReason> {
+        let depth = self.call_stack.len();
+        self.run_until_cond(
+            |emu| emu.call_stack.len() < depth, StopReason::Returned)
+    }
+
+    /// one instruction (or interrupt entry, or idle cycle)
+    pub fn step(&mut self) -> RunResult {
+        self.run_result(|emu| {
+            emu.halted = false;
+            emu.step_checked()?;
+            Ok(if emu.halted { emu.stop_reason } else { StopReason::InsnLimit })
+        })
+    }
+
+    pub fn get_reg8(&self, r: u8) -> u8 {
+        self.io_mem.regs.get8(r)
+    }
+
+    pub fn set_reg8(&mut self, r: u8, val: u8) {
+        self.io_mem.regs.set8(r, val);
+    }
+
+    pub fn get_reg16(&self, r: u8) -> u16 {
+        self.io_mem.regs.get16(r)
+    }
+
+    pub fn set_reg16(&mut self, r: u8, val: u16) {
+        self.io_mem.regs.set16(r, val);
+    }
+
+    /// fill `buf` from data space at `addr`, as stored, with no side
+    /// effects. IO registers live in their peripherals; see read_io().
+    pub fn read_data(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        let start = addr as usize;
+        match self.io_mem.data_mem.get(start..start + buf.len()) {
+            Some(mem) => {
+                buf.copy_from_slice(mem);
+                Ok(())
+            },
+            None => Err(Error::BadIoAccess { addr: self.bad_data_addr(addr), pc: self.pc }),
+        }
+    }
+
+    /// store `data` in data space at `addr`, bypassing write protection
+    /// and watchpoints
+    pub fn write_data(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let start = addr as usize;
+        if start + data.len() > self.io_mem.data_mem.len() {
+            return Err(Error::BadIoAccess { addr: self.bad_data_addr(addr), pc: self.pc });
+        }
+        self.io_mem.data_mem[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// `len` bytes of data space from `addr`, in hex and ASCII
+    pub fn dump_data(&self, addr: u32, len: usize) -> Result<String> {
+        let mut buf = vec![0; len];
+        self.read_data(addr, &mut buf)?;
+        Ok(hexdump(addr, &buf))
+    }
+
+    /// save data space, or flash if `flash`, from `start` up to `end` to
+    /// `path`, as raw bytes or Intel HEX (see hexdump::save())
+    pub fn save_memory(&self, flash: bool, start: u32, end: u32, path: &Path) -> io::Result<()> {
+        let mut buf = vec![0; end.saturating_sub(start) as usize];
+        let result = if flash {
+            self.read_flash(start, &mut buf)
+        } else {
+            self.read_data(start, &mut buf)
+        };
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        hexdump::save(path, start, &buf)
+    }
+
+    pub fn get_sreg(&self) -> u8 {
+        self.io_mem.sreg.as_u8()
+    }
 
-    pub halted: bool,
+    pub fn set_sreg(&mut self, val: u8) {
+        self.io_mem.sreg.set_u8(val);
+    }
 
-    sig_chan: mpsc::Receiver<Signal>,
-}
+    // the first address from `addr` on that's outside of data space
+    fn bad_data_addr(&self, addr: u32) -> u32 {
+        cmp::max(addr, self.io_mem.data_mem.len() as u32)
+    }
 
-impl Emulator {
-    pub fn new() -> Emulator {
-        let sig_chan = notify(&[Signal::USR1]);
+    /// fill `buf` with the flash words from byte address `addr`. erased
+    /// flash past the end of the image reads as 0xffff.
+    pub fn read_flash_words(&self, addr: u32, buf: &mut [u16]) -> Result<()> {
+        for (i, word) in buf.iter_mut().enumerate() {
+            let word_addr = addr + 2 * i as u32;
+            if word_addr >= self.device.flash_size {
+                return Err(Error::BadFlashAccess { addr: word_addr });
+            }
+            *word = self.prog_mem.read_word(word_addr).unwrap_or(0xffff);
+        }
+        Ok(())
+    }
 
-        Emulator {
-            prog_mem: ProgramMemory::new(),
+    /// fill `buf` with flash bytes from `addr`, reading erased flash
+    /// past the end of the image as 0xff
+    pub fn read_flash(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            let byte_addr = addr + i as u32;
+            if byte_addr >= self.device.flash_size {
+                return Err(Error::BadFlashAccess { addr: byte_addr });
+            }
+            *b = self.prog_mem.read_byte(byte_addr).unwrap_or(0xff);
+        }
+        Ok(())
+    }
 
-            io_mem: IOMemory::new(),
-            pc: 0,
+    /// store `words` in flash from byte address `addr`, as if programmed
+    pub fn write_flash_words(&mut self, addr: u32, words: &[u16]) -> Result<()> {
+        let end = addr + 2 * words.len() as u32;
+        if end > self.device.flash_size {
+            return Err(Error::BadFlashAccess { addr: cmp::max(addr, self.device.flash_size) });
+        }
 
-            call_stack: vec![],
+        for (i, &word) in words.iter().enumerate() {
+            self.prog_mem.write_word(addr + 2 * i as u32, word);
+        }
+        Ok(())
+    }
 
-            skip_next_insn: false,
+    /// read the IO register called `reg`, e.g. "USARTC0.STATUS", like the
+    /// firmware would; reading some registers clears flags
+    pub fn read_io(&mut self, reg: &str) -> Result<u8> {
+        let addr = regmap::find(self.device.io_regs, reg).ok_or_else(||
+            Error::UnknownSymbol { name: reg.to_string() })?;
+        let call_stack = call_stack!(self);
+        self.io_mem.get8(addr, &call_stack, self.pc)
+    }
 
-            insn_count: 0,
+    pub(crate) fn _step(&mut self) -> Result<()> {
+        // charged to the state the step started in
+        let energy_start = match self.energy {
+            Some(ref mut energy) => {
+                let state = match self.io_mem.sleep_mode {
+                    Some(mode) if self.sleeping => mode.name(),
+                    _ => "active",
+                };
+                energy.start(state, &self.io_mem);
+                Some(self.emulated_time())
+            },
+            None => None,
+        };
 
-            halted: false,
+        let result = self.profiled_step();
 
-            sig_chan: sig_chan,
+        if let Some(start) = energy_start {
+            // a power-on reset takes time back to 0
+            let nanos = self.emulated_time().checked_sub(start).map_or(0, |d| d.as_nanos() as u64);
+            self.energy.as_mut().unwrap().finish(nanos, &self.io_mem);
         }
+        // after interrupt entries and idle cycles too
+        if self.vcd.is_some() {
+            self.sample_vcd();
+        }
+        result
     }
 
-    pub fn reset(&mut self) {
-        self.pc = 0;
-        self.io_mem = IOMemory::new();
-        self.call_stack = vec![];
-        self.skip_next_insn = false;
-        self.insn_count = 0;
-        self.halted = false;
-    }
+    fn profiled_step(&mut self) -> Result<()> {
+        match self.profiler {
+            // the stack from before the step, so calls and returns are
+            // charged to the caller
+            Some(ref mut profiler) => profiler.set_stack(&self.call_stack),
+            None => return self.step_insn(),
+        }
 
-    pub fn fmt_call_stack(&self) -> String {
-        let frame_strings : Vec<String> =
-            self.call_stack
-                .iter()
-                .map(|&(_, from, to)| format!("{:#x}->{:#x}", from, to))
-                .collect();
+        let insn_count = self.insn_count;
+        let cycle_count = self.cycle_count;
 
-        format!("[{}]", frame_strings.join(", "))
-    }
+        let result = self.step_insn();
 
-    fn get_cur_insn(&self) -> Option<AvrInsn> {
-        self.prog_mem.get_insn_at(self.pc)
+        let cost = Cost {
+            insns: self.insn_count.saturating_sub(insn_count),
+            cycles: self.cycle_count.saturating_sub(cycle_count),
+        };
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.charge(cost);
+        }
+
+        result
     }
 
-    pub fn print_state(&self) {
-        let insn = self.get_cur_insn();
+    /// one instruction, interrupt entry or idle cycle
+    fn step_insn(&mut self) -> Result<()> {
+        let need_checkpoint = match self.time_travel {
+            Some(ref tt) => tt.needs_checkpoint(self.insn_count),
+            None => false,
+        };
+        if need_checkpoint {
+            let snap = self.snapshot();
+            self.time_travel.as_mut().unwrap().add_checkpoint(snap);
+        }
 
-        println!("{:#06x}:  {:?}", self.pc, insn);
-        println!();
+        self.handle_signals();
+        self.run_commands();
+        if self.stop_flag.swap(false, Ordering::SeqCst) {
+            // before the instruction, so continuing picks up from here
+            self.stop(StopReason::Interrupted);
+            return Ok(());
+        }
 
-        let sreg_chars = [
-            if self.io_mem.sreg.c { "C" } else { "." },
-            if self.io_mem.sreg.z { "Z" } else { "." },
-            if self.io_mem.sreg.n { "N" } else { "." },
-            if self.io_mem.sreg.v { "V" } else { "." },
-            if self.io_mem.sreg.s { "S" } else { "." },
-            if self.io_mem.sreg.h { "H" } else { "." },
-            if self.io_mem.sreg.t { "T" } else { "." },
-            if self.io_mem.sreg.i { "I" } else { "." },
-        ];
-        let sreg_str = sreg_chars.join("");
+        self.sync_flash_map();
+        self.run_flash_crc();
+        self.drain_uart_input();
+        self.replay_inputs();
+        self.stress_interrupts();
+        let start_cycle = self.cycle_count;
 
-        println!("sp={:#06x}, sreg: {}", self.io_mem.get_sp(), sreg_str);
-        println!();
+        self.io_mem.tick_peripherals(start_cycle);
 
-        for line_num in 0..32 / 8 {
-            let i = line_num * 8;
-            print!("{:>3}:", format!("r{}", i));
+        if let Some(code) = self.exit_requested() {
+            self.stop(StopReason::Exit(code));
+            self.record_inputs(start_cycle);
+            return Ok(());
+        }
 
-            for j in i..i + 8 {
-                if j % 2 == 0 {
-                    print!(" ");
-                } else {
-                    print!(":");
-                }
+        self.apply_faults();
 
-                print!("{:02x}", self.io_mem.regs.get8(j));
+        if self.io_mem.sreg.i && !self.skip_next_insn {
+            if let Some((vector, level)) = self.io_mem.pending_interrupt() {
+                if self.sleeping {
+                    self.wake();
+                    // waking up adds to the interrupt response
+                    self.cycle_count += self.device.wake_cycles();
+                }
+                self.enter_interrupt(vector, level)?;
+                self.record_inputs(start_cycle);
+                return Ok(());
             }
+        }
 
-            println!();
+        if self.sleeping {
+            // only interrupts wake the CPU up, so skip to the next thing that
+            // could raise one
+            let next = self.next_event_cycle().unwrap_or(0);
+            self.cycle_count = cmp::max(self.cycle_count + 1, next);
+            self.record_inputs(start_cycle);
+            return Ok(());
         }
 
-        println!();
-        println!(
-            "X: {:06x} Y: {:06x} Z: {:06x}",
-            self.io_mem.get_full_x(),
-            self.io_mem.get_full_y(),
-            self.io_mem.get_full_z());
+        if !self.skip_next_insn && self.stubs.contains_key(&self.pc) {
+            return self.run_stub(start_cycle);
+        }
 
-        println!();
-        println!("call stack: {}", self.fmt_call_stack());
+        let insn = match self.get_cur_insn() {
+            Some(insn) => insn,
+            None => {
+                let err = Error::DecodeError { pc: self.pc };
+                return self.unimplemented(err, 1, start_cycle);
+            },
+        };
+        let seq_pc = self.pc + (insn.byte_size() as u32);
+        let mut next_pc = seq_pc;
+
+        let insn_pc = self.pc;
+        let trace_before = match self.tracer {
+            Some(_) => Some((self.io_mem.regs.r, self.io_mem.sreg.as_u8(),
+                             self.cycle_count)),
+            None => None,
+        };
+        let skipped = self.skip_next_insn;
 
-        let sp = self.io_mem.get_sp() as usize;
-        println!("some stack bytes: {}",
-            hex::encode(&self.io_mem.data_mem[sp..sp + 16]));
-    }
+        if self.skip_next_insn {
+            self.skip_next_insn = false;
+            // skipping costs a cycle per word of the skipped instruction
+            self.cycle_count += (insn.byte_size() / 2) as u64;
+        } else {
+            if let Some(ref mut coverage) = self.coverage {
+                coverage.hit(self.pc);
+            }
+            if let Some(ref mut stats) = self.stats {
+                stats.count_insn(&insn);
+            }
+            if !self.device.isa.has(&insn) {
+                return Err(Error::UnsupportedInsn {
+                    insn: format!("{:?}", insn),
+                    pc: self.pc,
+                    device: self.device.name,
+                    isa: self.device.isa,
+                    needs: min_isa(&insn),
+                });
+            }
+            self.propagate_taint(&insn)?;
 
-    pub fn load_bin(&mut self, path: &str) -> io::Result<()> {
-        let mut f = File::open(path)?;
-        let mut buffer = vec![];
-        f.read_to_end(&mut buffer)?;
+            match self.do_opcode(&insn, &mut next_pc) {
+                Err(err @ Error::UnimplementedInsn { .. }) => {
+                    let words = (insn.byte_size() / 2) as u32;
+                    return self.unimplemented(err, words, start_cycle);
+                },
+                res => res?,
+            }
+            self.cycle_count += insn_cycles(&insn, next_pc != seq_pc, &self.device);
+        }
 
-        self.prog_mem.set_bytes(&buffer)?;
+        if let Some((regs, sreg, cycle)) = trace_before {
+            let pc = self.pc;
+            self.trace_insn(pc, insn, skipped, &regs, sreg, cycle);
+        }
 
-        Ok(())
-    }
+        if let Some(ref mut history) = self.history {
+            history.push(HistoryEntry {
+                insn_count: self.insn_count,
+                pc: insn_pc,
+                insn,
+                skipped,
+                regs: self.io_mem.regs.r,
+                sp: self.io_mem.get_sp(),
+                sreg: self.io_mem.sreg.as_u8(),
+            });
+        }
 
-    pub fn run(&mut self) {
-        self.halted = false;
-        while !self.halted {
-            self._step();
+        let io_accesses = match self.io_mem.io_accesses {
+            Some(ref mut accesses) => mem::take(accesses),
+            None => vec![],
+        };
+        self.trace_io(insn_pc, &io_accesses);
+        self.vcd_io(&io_accesses);
+
+        self.pc = next_pc;
+        self.insn_count += 1;
+
+        let jumped_back = next_pc <= insn_pc
+            && branch_target(insn_pc, &insn) == Some(next_pc);
+        if self.warp.is_some() && !skipped && jumped_back {
+            self.warp_loop();
         }
 
-        self.print_state();
-    }
+        if let Some(ref mut stats) = self.stats {
+            stats.note_stack(self.io_mem.get_sp(), self.call_stack.len());
+        }
 
-    pub fn until(&mut self, pc: u32) {
-        self.halted = false;
-        while !self.halted {
-            self._step();
-            if self.pc == pc {
-                break;
-            }
+        let sw_reset = self.io_mem.reset_controller_mut()
+            .is_some_and(|rst| rst.take_reset_request());
+        if sw_reset {
+            self.reset_with_cause(ResetCause::Software);
         }
 
-        self.print_state();
-    }
+        self.record_inputs(start_cycle);
+        self.record_state_hash();
+        self.insn_scripts(&io_accesses)?;
 
-    pub fn step(&mut self) {
-        self._step();
-        self.print_state();
-    }
+        if let Some(ref mut monitor) = self.stack_monitor {
+            let sp = self.io_mem.get_sp();
+            if !monitor.check(sp, &self.call_stack) {
+                return Err(Error::StackOverflow { sp, pc: insn_pc });
+            }
+        }
 
-    pub fn get_reg8(&self, r: u8) -> u8 {
-        self.io_mem.regs.get8(r)
+        Ok(())
     }
 
-    pub fn set_reg8(&mut self, r: u8, val: u8) {
-        self.io_mem.regs.set8(r, val);
+    fn propagate_taint(&mut self, insn: &AvrInsn) -> Result<()> {
+        let taint = match self.taint {
+            Some(ref mut taint) => taint,
+            None => return Ok(()),
+        };
+
+        if let Some(sink) = taint.propagate(insn, &self.io_mem) {
+            taint.reports.push(TaintReport {
+                sink,
+                pc: self.pc,
+                insn_count: self.insn_count,
+            });
+            if taint.trap {
+                return Err(Error::Tainted { sink, pc: self.pc });
+            }
+        }
+        Ok(())
     }
 
-    pub fn get_reg16(&self, r: u8) -> u16 {
-        self.io_mem.regs.get16(r)
+    /// the mode SLEEP enters, or None if SLEEP.CTRL.SEN is clear
+    fn sleep_mode(&self) -> Option<SleepMode> {
+        match self.io_mem.sleep_controller() {
+            Some(sleep) => sleep.mode(),
+            // nothing to enable sleep with, so it always idles
+            None => Some(SleepMode::Idle),
+        }
     }
 
-    pub fn set_reg16(&mut self, r: u8, val: u16) {
-        self.io_mem.regs.set16(r, val);
+    fn wake(&mut self) {
+        self.sleeping = false;
+        self.io_mem.wake(self.cycle_count);
     }
 
-    fn _step(&mut self) {
-        match self.sig_chan.try_recv() {
-            Ok(_) => self.print_state(),
-            _ => (),
+    fn enter_interrupt(&mut self, vector: u8, level: u8) -> Result<()> {
+        let vectors_base = self.io_mem.pmic().map_or(0, |pmic| pmic.vectors_base());
+        let tgt = vectors_base + (vector as u32) * self.device.vector_size;
+        let ret_addr = self.pc;
+        if let Some(ref mut taint) = self.taint {
+            taint.push_ret_addr(self.io_mem.get_sp());
         }
+        self.push_ret_addr(ret_addr, tgt, FrameKind::Interrupt(vector))?;
+        self.call_event(CallKind::Interrupt(vector), tgt, ret_addr);
 
-        let insn = self.get_cur_insn().unwrap();
-        let mut next_pc = self.pc + (insn.byte_size() as u32);
-
-        if self.skip_next_insn {
-            self.skip_next_insn = false;
-        } else {
-            self.do_opcode(&insn, &mut next_pc);
+        // the PMIC blocks lower levels instead of clearing I
+        match self.io_mem.pmic_mut() {
+            Some(pmic) => pmic.enter(vector, level),
+            None => self.io_mem.sreg.i = false,
         }
+        self.io_mem.interrupt_taken(vector);
 
-        self.pc = next_pc;
-        // TODO
-        self.insn_count += 1;
+        self.pc = tgt;
+        self.cycle_count += self.device.interrupt_cycles();
+        Ok(())
     }
 
     /// set SReg for logical bit operations
@@ -218,6 +2551,8 @@ impl Emulator {
     }
 
     /// set SReg for addition operations
+    // the flag formulas are as given in the instruction set manual
+    #[allow(clippy::nonminimal_bool)]
     fn set_sreg_for_add(&mut self, rd_val: u8, rr_val: u8, r_val: u8)
     {
         let sreg = &mut self.io_mem.sreg;
@@ -241,6 +2576,8 @@ impl Emulator {
     }
 
     /// set SReg for subtraction operations
+    // the flag formulas are as given in the instruction set manual
+    #[allow(clippy::nonminimal_bool)]
     fn set_sreg_for_sub(&mut self, rd_val: u8, rr_val: u8, r_val: u8,
             use_prev: bool)
     {
@@ -268,134 +2605,267 @@ impl Emulator {
         sreg.s = sreg.n ^ sreg.v;
     }
 
+    /// store a multiplication result in R1:R0 and set SReg. `fractional`
+    /// is for the FMUL* variants, which shift the result left by one.
+    fn set_mul_result(&mut self, product: u16, fractional: bool) {
+        let r_val = if fractional { product << 1 } else { product };
+        self.set_reg16(0, r_val);
+
+        let sreg = &mut self.io_mem.sreg;
+        // C is bit 15 of the product before shifting
+        sreg.c = (product & 0x8000) != 0;
+        sreg.z = r_val == 0;
+    }
+
     fn get_carry(&self) -> u8 {
         if self.io_mem.sreg.c { 1 } else { 0 }
     }
 
-    fn push_ret_addr(&mut self, ret_addr: u32, call_tgt: u32) {
-        self.call_stack.push((self.io_mem.get_sp(), self.pc, call_tgt));
+    fn push_ret_addr(&mut self, ret_addr: u32, call_tgt: u32, kind: FrameKind) -> Result<()> {
+        // frames longjmp() left behind
+        let sp = self.io_mem.get_sp();
+        while self.call_stack.last().is_some_and(|f| f.is_dead(sp)) {
+            self.call_stack.pop();
+        }
+
+        self.call_stack.push(Frame {
+            sp,
+            from: self.pc,
+            to: call_tgt,
+            ret_addr,
+            kind,
+            tail_call_from: None,
+        });
 
         let ret_addr = ret_addr >> 1;
 
-        // TODO: if !has_22bit_addrs, push16
-        self.io_mem.push24(ret_addr);
+        let call_stack = call_stack!(self);
+        if self.device.has_22bit_addrs {
+            self.io_mem.push24(ret_addr, &call_stack, self.pc)
+        } else {
+            self.io_mem.push16(ret_addr as u16, &call_stack, self.pc)
+        }
     }
 
-    fn pop_ret_addr(&mut self) -> u32 {
-        // TODO: if !has_22bit_addrs, pop16
-        let mut ret_addr = self.io_mem.pop24();
+    fn pop_ret_addr(&mut self) -> Result<u32> {
+        let call_stack = call_stack!(self);
+        let mut ret_addr = if self.device.has_22bit_addrs {
+            self.io_mem.pop24(&call_stack, self.pc)?
+        } else {
+            self.io_mem.pop16(&call_stack, self.pc)? as u32
+        };
 
         ret_addr <<= 1;
 
-        // remove return address from call stack but also any extra "return
-        // addresses" pushed by "rcall .+0" instructions just to get the
-        // current address or allocate stack space
-        while !self.call_stack.is_empty() &&
-               self.call_stack.last().unwrap().0 <= self.io_mem.get_sp() {
+        // the frame this returns from, but also any extra "return addresses"
+        // pushed by "rcall .+0" instructions just to get the current address
+        // or allocate stack space, or left behind by longjmp()
+        let sp = self.io_mem.get_sp();
+        for frame in pop_frames(&mut self.call_stack, ret_addr, sp) {
+            if !frame.is_fake() {
+                let pc = self.pc;
+                self.call_event(CallKind::Return, frame.to, pc);
+            }
+        }
+
+        Ok(ret_addr)
+    }
 
-            self.call_stack.pop();
+    // a jump from pc to `tgt`. from a vector to its handler, it names the
+    // handler in the interrupt's frame; from inside a function to the start
+    // of another one, it's probably a tail call, which returns for the
+    // current frame.
+    fn note_jump(&mut self, tgt: u32) {
+        let is_func_start = match self.symbols.lookup(tgt) {
+            Some((sym, 0)) => sym.is_code(),
+            _ => false,
+        };
+        if !is_func_start {
+            return;
         }
 
-        ret_addr
+        let cur_func = self.symbols.lookup(self.pc).map(|(sym, _)| sym.addr);
+        let frame = match self.call_stack.last_mut() {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        match frame.kind {
+            FrameKind::Interrupt(_) if frame.to == self.pc => frame.to = tgt,
+            _ if cur_func != Some(tgt) => {
+                frame.tail_call_from = Some(cur_func.unwrap_or(self.pc));
+                frame.to = tgt;
+            },
+            _ => {},
+        }
     }
 
-    fn do_call(&mut self, next_pc: &mut u32, call_tgt: u32) {
+    fn do_call(&mut self, next_pc: &mut u32, call_tgt: u32) -> Result<()> {
         let ret_addr = *next_pc;
-        self.push_ret_addr(ret_addr, call_tgt);
+        self.push_ret_addr(ret_addr, call_tgt, FrameKind::Call)?;
+        if call_tgt != ret_addr {
+            let pc = self.pc;
+            self.call_event(CallKind::Call, call_tgt, pc);
+        }
         *next_pc = call_tgt;
+        Ok(())
     }
 
-    // does the pre-update and returns the address
-    fn do_pre_mem_access(&mut self, mema: MemAccess, full_reg: bool) -> u32 {
-        let MemAccess { reg_pair, ofs, update } = mema;
+    // the X, Y or Z an instruction accesses memory through
+    fn ptr_reg(&self, mema: &MemAccess) -> Result<PtrReg> {
+        PtrReg::from_reg(mema.reg_pair.0).ok_or(Error::DecodeError { pc: self.pc })
+    }
 
-        let base_addr =
-            if full_reg {
-                let mut val = self.io_mem.get_full_reg(reg_pair.0);
+    // X, Y or Z, with its RAMP register if `full_reg`
+    fn get_ptr_reg(&self, reg: PtrReg, full_reg: bool) -> u32 {
+        if full_reg {
+            self.io_mem.get_full_reg(reg)
+        } else {
+            self.get_reg16(reg.low()) as u32
+        }
+    }
 
-                if update == MemRegUpdate::PreDec {
-                    // TODO: incorrect overflow handling
-                    val -= 1;
-                    self.io_mem.set_full_reg(reg_pair.0, val);
-                }
+    fn set_ptr_reg(&mut self, reg: PtrReg, val: u32, full_reg: bool) {
+        if full_reg {
+            self.io_mem.set_full_reg(reg, val);
+        } else {
+            self.set_reg16(reg.low(), val as u16);
+        }
+    }
 
-                val
-            } else {
-                let mut val = self.get_reg16(reg_pair.0);
+    fn ptr_reg_mask(&self, reg: PtrReg, full_reg: bool) -> u32 {
+        if full_reg { self.io_mem.full_reg_mask(reg) } else { 0xffff }
+    }
 
-                if update == MemRegUpdate::PreDec {
-                    // TODO: incorrect overflow handling
-                    val -= 1;
-                    self.set_reg16(reg_pair.0, val);
-                }
+    // does the pre-update and returns the address. X, Y and Z wrap around
+    // like on the real CPU: at 64 KiB, or at 16 MiB, carrying into and out
+    // of the RAMP register, on devices that have it.
+    fn do_pre_mem_access(&mut self, mema: MemAccess, full_reg: bool) -> Result<u32> {
+        let reg = self.ptr_reg(&mema)?;
+        let MemAccess { reg_pair: _, ofs, update } = mema;
+        let mask = self.ptr_reg_mask(reg, full_reg);
+
+        let mut val = self.get_ptr_reg(reg, full_reg);
+        if update == MemRegUpdate::PreDec {
+            val = val.wrapping_sub(1) & mask;
+            self.set_ptr_reg(reg, val, full_reg);
+        }
 
-                val as u32
-            };
+        Ok(val.wrapping_add(ofs as u32) & mask)
+    }
 
-        // TODO: incorrect overflow handling
-        base_addr + (ofs as u32)
+    fn do_post_mem_access(&mut self, mema: MemAccess, full_reg: bool) -> Result<()> {
+        let reg = self.ptr_reg(&mema)?;
+        if mema.update != MemRegUpdate::PostInc {
+            return Ok(());
+        }
+
+        let mask = self.ptr_reg_mask(reg, full_reg);
+        let val = self.get_ptr_reg(reg, full_reg);
+        self.set_ptr_reg(reg, val.wrapping_add(1) & mask, full_reg);
+        Ok(())
     }
 
-    fn do_post_mem_access(&mut self, mema: MemAccess, full_reg: bool) {
-        let MemAccess { reg_pair, ofs: _, update } = mema;
+    /// xmega XCH/LAS/LAC/LAT: writes op(mem_val, rd_val) to (Z), and the
+    /// old value of (Z) to Rd
+    fn do_atomic_rmw(&mut self, rd: u8, op: fn(u8, u8) -> u8) -> Result<()> {
+        let addr = self.io_mem.get_full_z();
+        let call_stack = call_stack!(self);
 
-        if full_reg {
-            if update == MemRegUpdate::PostInc {
-                let val = self.get_reg16(reg_pair.0);
-                self.set_reg16(reg_pair.0, val + 1);
-            }
-        } else {
-            if update == MemRegUpdate::PostInc {
-                let val = self.io_mem.get_full_reg(reg_pair.0);
-                self.io_mem.set_full_reg(reg_pair.0, val + 1);
-            }
-        }
+        let mem_val = self.io_mem.get8(addr, &call_stack, self.pc)?;
+        let rd_val = self.get_reg8(rd);
+        self.io_mem.set8(addr, op(mem_val, rd_val), &call_stack, self.pc)?;
+        self.set_reg8(rd, mem_val);
+
+        Ok(())
     }
 
-    fn do_opcode(&mut self, insn: &AvrInsn, next_pc: &mut u32) {
+    fn do_opcode(&mut self, insn: &AvrInsn, next_pc: &mut u32) -> Result<()> {
         match insn {
             &AvrInsn::Nop => {},
 
-            &AvrInsn::Jmp(tgt) => *next_pc = tgt,
+            &AvrInsn::Jmp(tgt) => {
+                *next_pc = tgt;
+                self.note_jump(tgt);
+            },
 
             &AvrInsn::Rjmp(ofs) => {
                 // catch "__stop_program"
                 if ofs == -2 && !self.io_mem.sreg.i {
-                    self.halted = true;
+                    self.stop(StopReason::Halted);
                 }
 
-                *next_pc = AvrInsn::get_rel_jmp_target(*next_pc, ofs);
+                *next_pc = self.device.wrap_pc(AvrInsn::get_rel_jmp_target(*next_pc, ofs));
+                let tgt = *next_pc;
+                self.note_jump(tgt);
             }
 
-            &AvrInsn::Eijmp => *next_pc = self.io_mem.get_full_ind() << 1,
+            &AvrInsn::Ijmp => {
+                *next_pc = self.device.wrap_pc((self.get_reg16(Z_L.0) as u32) << 1);
+                let tgt = *next_pc;
+                self.note_jump(tgt);
+            },
+
+            &AvrInsn::Eijmp => {
+                *next_pc = self.io_mem.get_full_ind() << 1;
+                let tgt = *next_pc;
+                self.note_jump(tgt);
+            },
 
             &AvrInsn::Call(tgt) =>
-                self.do_call(next_pc, tgt),
+                self.do_call(next_pc, tgt)?,
 
             &AvrInsn::Rcall(ofs) => {
-                let tgt = AvrInsn::get_rel_jmp_target(*next_pc, ofs);
-                self.do_call(next_pc, tgt);
+                let tgt = self.device.wrap_pc(AvrInsn::get_rel_jmp_target(*next_pc, ofs));
+                self.do_call(next_pc, tgt)?;
+            },
+
+            &AvrInsn::Icall => {
+                let tgt = self.device.wrap_pc((self.get_reg16(Z_L.0) as u32) << 1);
+                self.do_call(next_pc, tgt)?;
             },
 
             &AvrInsn::Eicall => {
                 let tgt = self.io_mem.get_full_ind() << 1;
-                self.do_call(next_pc, tgt);
+                self.do_call(next_pc, tgt)?;
             },
 
-            &AvrInsn::Ret => *next_pc = self.pop_ret_addr(),
+            &AvrInsn::Ret => *next_pc = self.pop_ret_addr()?,
 
             &AvrInsn::Reti => {
-                self.io_mem.sreg.i = true;
-                *next_pc = self.pop_ret_addr();
+                match self.io_mem.pmic_mut() {
+                    Some(pmic) => pmic.reti(),
+                    None => self.io_mem.sreg.i = true,
+                }
+                *next_pc = self.pop_ret_addr()?;
+            },
+
+            &AvrInsn::Sleep => {
+                // without SLEEP.CTRL.SEN, it's a NOP
+                if let Some(mode) = self.sleep_mode() {
+                    if self.io_mem.sreg.i {
+                        self.sleeping = true;
+                        self.io_mem.sleep(mode);
+                    } else {
+                        self.stop(StopReason::SleepForever);
+                    }
+                }
             },
 
+            // there's no watchdog timer, so nothing to reset
+            &AvrInsn::Wdr => {},
+
+            &AvrInsn::Break => self.stop(StopReason::Break),
+
             &AvrInsn::Push(Reg(rr)) => {
                 let val = self.get_reg8(rr);
-                self.io_mem.push8(val);
+                let call_stack = call_stack!(self);
+                self.io_mem.push8(val, &call_stack, self.pc)?;
             }
 
             &AvrInsn::Pop(Reg(rd)) => {
-                let val = self.io_mem.pop8();
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.pop8(&call_stack, self.pc)?;
                 self.set_reg8(rd, val);
             }
 
@@ -459,6 +2929,54 @@ impl Emulator {
                         *next_pc, ofs.into());
                 },
 
+            &AvrInsn::Brhc(ofs) =>
+                if !self.io_mem.sreg.h {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brhs(ofs) =>
+                if self.io_mem.sreg.h {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brvc(ofs) =>
+                if !self.io_mem.sreg.v {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brvs(ofs) =>
+                if self.io_mem.sreg.v {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brid(ofs) =>
+                if !self.io_mem.sreg.i {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brie(ofs) =>
+                if self.io_mem.sreg.i {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brbc(bit, ofs) =>
+                if self.io_mem.sreg.as_u8() & (1 << bit) == 0 {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
+            &AvrInsn::Brbs(bit, ofs) =>
+                if self.io_mem.sreg.as_u8() & (1 << bit) != 0 {
+                    *next_pc = AvrInsn::get_rel_jmp_target(
+                        *next_pc, ofs.into());
+                },
+
             &AvrInsn::Sbrc(Reg(rr), bit) => {
                 let rr_val = self.get_reg8(rr);
                 self.skip_next_insn = (rr_val & (1 << bit)) == 0;
@@ -734,7 +3252,6 @@ impl Emulator {
                 sreg.s = sreg.n ^ sreg.v;
             },
 
-            // TODO: verify sreg
             &AvrInsn::Com(Reg(rd)) => {
                 let rd_val = self.get_reg8(rd);
                 let r_val = 0xff - rd_val;
@@ -749,15 +3266,15 @@ impl Emulator {
                 sreg.s = sreg.n ^ sreg.v;
             },
 
-            // TODO: verify sreg
             &AvrInsn::Neg(Reg(rd)) => {
                 let rd_val = self.get_reg8(rd);
-                let r_val = (-(rd_val as i8)) as u8;
+                let r_val = rd_val.wrapping_neg();
 
                 self.set_reg8(rd, r_val);
 
                 let sreg = &mut self.io_mem.sreg;
-                sreg.h = ((r_val & 0x40) != 0) && ((rd_val & 0x40) == 0);
+                // borrow from bit 3
+                sreg.h = ((r_val | rd_val) & 0x08) != 0;
                 sreg.v = r_val == 0x80;
                 sreg.n = (r_val & 0x80) != 0;
                 sreg.z = r_val == 0;
@@ -769,86 +3286,191 @@ impl Emulator {
                 let rd_val = self.get_reg8(rd);
                 let rr_val = self.get_reg8(rr);
                 let r_val = (rd_val as u16) * (rr_val as u16);
-                self.set_reg16(0, r_val);
+                self.set_mul_result(r_val, false);
+            },
 
-                let sreg = &mut self.io_mem.sreg;
-                sreg.c = (r_val & 0x8000) != 0;
-                sreg.z = r_val == 0;
+            &AvrInsn::Muls(Reg(rd), Reg(rr)) => {
+                let rd_val = self.get_reg8(rd) as i8;
+                let rr_val = self.get_reg8(rr) as i8;
+                let r_val = (rd_val as i16) * (rr_val as i16);
+                self.set_mul_result(r_val as u16, false);
+            },
+
+            &AvrInsn::Mulsu(Reg(rd), Reg(rr)) => {
+                let rd_val = self.get_reg8(rd) as i8;
+                let rr_val = self.get_reg8(rr);
+                let r_val = (rd_val as i16) * (rr_val as i16);
+                self.set_mul_result(r_val as u16, false);
+            },
+
+            &AvrInsn::Fmul(Reg(rd), Reg(rr)) => {
+                let rd_val = self.get_reg8(rd);
+                let rr_val = self.get_reg8(rr);
+                let r_val = (rd_val as u16) * (rr_val as u16);
+                self.set_mul_result(r_val, true);
+            },
+
+            &AvrInsn::Fmuls(Reg(rd), Reg(rr)) => {
+                let rd_val = self.get_reg8(rd) as i8;
+                let rr_val = self.get_reg8(rr) as i8;
+                let r_val = (rd_val as i16) * (rr_val as i16);
+                self.set_mul_result(r_val as u16, true);
+            },
+
+            &AvrInsn::Fmulsu(Reg(rd), Reg(rr)) => {
+                let rd_val = self.get_reg8(rd) as i8;
+                let rr_val = self.get_reg8(rr);
+                let r_val = (rd_val as i16) * (rr_val as i16);
+                self.set_mul_result(r_val as u16, true);
             },
 
             &AvrInsn::In(Reg(rd), port) => {
-                let call_stack = self.fmt_call_stack();
-                let val = self.io_mem.get8(port as u32, &call_stack, self.pc);
+                let addr = self.io_mem.port_addr(port as u32);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(addr, &call_stack, self.pc)?;
                 self.set_reg8(rd, val);
             },
 
             &AvrInsn::Out(port, Reg(rr)) => {
                 let val = self.get_reg8(rr);
-                let call_stack = self.fmt_call_stack();
-                self.io_mem.set8(port as u32, val, &call_stack, self.pc);
+                let addr = self.io_mem.port_addr(port as u32);
+                let call_stack = call_stack!(self);
+                self.io_mem.set8(addr, val, &call_stack, self.pc)?;
+            },
+
+            &AvrInsn::Sbi(port, bit) => {
+                let addr = self.io_mem.port_addr(port as u32);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(addr, &call_stack, self.pc)?;
+                self.io_mem.set8(
+                    addr, val | (1 << bit), &call_stack, self.pc)?;
+            },
+
+            &AvrInsn::Cbi(port, bit) => {
+                let addr = self.io_mem.port_addr(port as u32);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(addr, &call_stack, self.pc)?;
+                self.io_mem.set8(
+                    addr, val & !(1 << bit), &call_stack, self.pc)?;
+            },
+
+            &AvrInsn::Sbic(port, bit) => {
+                let addr = self.io_mem.port_addr(port as u32);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(addr, &call_stack, self.pc)?;
+                self.skip_next_insn = (val & (1 << bit)) == 0;
+            },
+
+            &AvrInsn::Sbis(port, bit) => {
+                let addr = self.io_mem.port_addr(port as u32);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(addr, &call_stack, self.pc)?;
+                self.skip_next_insn = (val & (1 << bit)) != 0;
             },
 
             &AvrInsn::LpmZ(Reg(rd), mema) => {
 
-                let addr = self.do_pre_mem_access(mema, false);
+                let addr = self.do_pre_mem_access(mema, false)?;
 
-                let call_stack = self.fmt_call_stack();
-                let val = self.prog_mem.get_prog_mem_byte(
-                    addr, &call_stack, self.pc);
+                let val = self.lpm_byte(addr);
                 self.set_reg8(rd, val);
 
-                self.do_post_mem_access(mema, false);
+                self.do_post_mem_access(mema, false)?;
             },
 
             &AvrInsn::ElpmZ(Reg(rd), mema) => {
-                let addr = self.do_pre_mem_access(mema, true);
+                let addr = self.do_pre_mem_access(mema, true)?;
 
-                let call_stack = self.fmt_call_stack();
-                let val = self.prog_mem.get_prog_mem_byte(
-                    addr, &call_stack, self.pc);
+                let val = self.lpm_byte(addr);
                 self.set_reg8(rd, val);
 
-                self.do_post_mem_access(mema, true);
+                self.do_post_mem_access(mema, true)?;
             },
 
             &AvrInsn::Ld(Reg(rd), mema) | &AvrInsn::Ldd(Reg(rd), mema) => {
-                let addr = self.do_pre_mem_access(mema, true);
+                let addr = self.do_pre_mem_access(mema, true)?;
 
-                let call_stack = self.fmt_call_stack();
-                let val = self.io_mem.get8(addr, &call_stack, self.pc);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(addr, &call_stack, self.pc)?;
                 self.set_reg8(rd, val);
 
-                self.do_post_mem_access(mema, true);
+                self.do_post_mem_access(mema, true)?;
             },
 
             &AvrInsn::St(mema, Reg(rr)) | &AvrInsn::Std(mema, Reg(rr)) => {
-                let addr = self.do_pre_mem_access(mema, true);
+                let addr = self.do_pre_mem_access(mema, true)?;
 
                 let val = self.get_reg8(rr);
-                let call_stack = self.fmt_call_stack();
-                self.io_mem.set8(addr, val, &call_stack, self.pc);
+                let call_stack = call_stack!(self);
+                self.io_mem.set8(addr, val, &call_stack, self.pc)?;
+
+                self.do_post_mem_access(mema, true)?;
+            },
 
-                self.do_post_mem_access(mema, true);
+            &AvrInsn::Des(k) => {
+                let mut data = 0u64;
+                let mut key = 0u64;
+                for i in (0..8).rev() {
+                    data = (data << 8) | (self.get_reg8(i) as u64);
+                    key = (key << 8) | (self.get_reg8(i + 8) as u64);
+                }
+
+                let data = des_round(data, key, k, self.io_mem.sreg.h);
+
+                for i in 0..8 {
+                    self.set_reg8(i, (data >> (8 * i)) as u8);
+                }
+            },
+
+            &AvrInsn::Spm | &AvrInsn::SpmZ => {
+                let z = self.io_mem.get_full_z();
+                let data = self.get_reg16(0);
+
+                if !self.io_mem.nvm.spm(&mut self.prog_mem, z, data) {
+                    self.io_mem.diag.warning(&format!(
+                        "TODO: SPM with NVM command {:#x}, z={:#x} @ {}; {:#x}",
+                        self.io_mem.nvm.cmd, z, call_stack!(self),
+                        self.pc));
+                }
+
+                if let &AvrInsn::SpmZ = insn {
+                    self.io_mem.set_full_z(z + 2);
+                }
             },
 
+            &AvrInsn::Xch(Reg(rd)) =>
+                self.do_atomic_rmw(rd, |_, rd_val| rd_val)?,
+
+            &AvrInsn::Las(Reg(rd)) =>
+                self.do_atomic_rmw(rd, |mem_val, rd_val| mem_val | rd_val)?,
+
+            &AvrInsn::Lac(Reg(rd)) =>
+                self.do_atomic_rmw(rd, |mem_val, rd_val| mem_val & !rd_val)?,
+
+            &AvrInsn::Lat(Reg(rd)) =>
+                self.do_atomic_rmw(rd, |mem_val, rd_val| mem_val ^ rd_val)?,
+
             &AvrInsn::Lds(Reg(rd), k) => {
-                let call_stack = self.fmt_call_stack();
-                let val = self.io_mem.get8(k as u32, &call_stack, self.pc);
+                let call_stack = call_stack!(self);
+                let val = self.io_mem.get8(k as u32, &call_stack, self.pc)?;
                 self.set_reg8(rd, val);
             },
 
             &AvrInsn::Sts(k, Reg(rr)) => {
                 let val = self.get_reg8(rr);
-                let call_stack = self.fmt_call_stack();
-                self.io_mem.set8(k as u32, val, &call_stack, self.pc);
+                let call_stack = call_stack!(self);
+                self.io_mem.set8(k as u32, val, &call_stack, self.pc)?;
             },
 
             _ => {
-                self.print_state();
-                panic!(
-                    "unimplemented instruction {:?} @ {:#x} after {} instructions",
-                    insn, self.pc, self.insn_count);
+                return Err(Error::UnimplementedInsn {
+                    insn: format!("{:?}", insn),
+                    pc: self.pc,
+                    insn_count: self.insn_count,
+                });
             }
         }
+
+        Ok(())
     }
 }
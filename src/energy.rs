@@ -0,0 +1,233 @@
+// Energy estimates from time spent in each power state
+//
+// Emulated time is split between the CPU's states, active or one of the
+// sleep modes, and each peripheral also gets the time it was switched on
+// and clocked. Given a table of currents for those, the report estimates
+// charge and average current, which is what power budgets are made of.
+//
+// The current table has one entry per line:
+//   <state or peripheral> <current>
+// e.g. "active 4.5mA", "power-save 1.2uA" or "USARTC0 40uA", with the
+// current in A, mA, uA or nA. States are "active" and the sleep modes:
+// "idle", "power-down", "power-save", "standby" and "extended-standby".
+// Two optional lines give the supply and the battery, for energy and
+// battery life:
+//   voltage <volts>V
+//   battery <capacity>mAh
+// Peripheral currents are on top of the CPU state's.
+
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use iomem::IOMemory;
+
+
+const NANOS_PER_SEC : f64 = 1e9;
+/// coulombs per mAh
+const COULOMBS_PER_MAH : f64 = 3.6;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CurrentTable {
+    /// (state or peripheral name, amps)
+    pub currents: Vec<(String, f64)>,
+    pub voltage: Option<f64>,
+    /// in mAh
+    pub battery: Option<f64>,
+}
+
+impl CurrentTable {
+    pub fn current(&self, name: &str) -> Option<f64> {
+        self.currents.iter().find(|c| c.0 == name).map(|c| c.1)
+    }
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad current table line {}", line_num + 1))
+}
+
+/// "4.5mA" in amps
+fn parse_current(s: &str) -> Option<f64> {
+    let units = [("nA", 1e-9), ("uA", 1e-6), ("µA", 1e-6), ("mA", 1e-3), ("A", 1.0)];
+    let &(unit, scale) = units.iter().find(|&&(unit, _)| s.ends_with(unit))?;
+    let val: f64 = s[..s.len() - unit.len()].parse().ok()?;
+    Some(val * scale)
+}
+
+fn parse_with_unit(s: &str, unit: &str) -> Option<f64> {
+    s.strip_suffix(unit).and_then(|num| num.parse().ok())
+}
+
+/// read a current table, see above
+pub fn load_current_table(path: &str) -> io::Result<CurrentTable> {
+    let r = BufReader::new(File::open(path)?);
+    let mut table = CurrentTable::default();
+
+    for (line_num, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 2 {
+            return Err(bad_line(line_num));
+        }
+
+        match parts[0] {
+            "voltage" => {
+                table.voltage = Some(parse_with_unit(parts[1], "V").ok_or_else(|| bad_line(line_num))?);
+            },
+            "battery" => {
+                table.battery = Some(parse_with_unit(parts[1], "mAh").ok_or_else(|| bad_line(line_num))?);
+            },
+            name => {
+                let current = parse_current(parts[1]).ok_or_else(|| bad_line(line_num))?;
+                table.currents.push((name.to_string(), current));
+            },
+        }
+    }
+
+    Ok(table)
+}
+
+fn add_time(times: &mut Vec<(String, u64)>, name: &str, nanos: u64) {
+    match times.iter_mut().find(|t| t.0 == name) {
+        Some(t) => t.1 += nanos,
+        None => times.push((name.to_string(), nanos)),
+    }
+}
+
+/// "4.5mA" rather than "0.0045A"
+fn fmt_si(val: f64, unit: &str) -> String {
+    let prefixes = [("", 1.0), ("m", 1e-3), ("u", 1e-6), ("n", 1e-9)];
+    let &(prefix, scale) = prefixes.iter()
+        .find(|&&(_, scale)| val.abs() >= scale)
+        .unwrap_or(&prefixes[prefixes.len() - 1]);
+    format!("{:.3}{}{}", val / scale, prefix, unit)
+}
+
+#[derive(Clone)]
+pub struct Energy {
+    /// (CPU state, nanoseconds), in the order first seen
+    states: Vec<(String, u64)>,
+    /// (peripheral, nanoseconds it was powered)
+    peripherals: Vec<(String, u64)>,
+
+    /// as of the start of the current step: the CPU state, and the indices
+    /// of the peripherals that were running
+    state: &'static str,
+    running: Vec<usize>,
+}
+
+impl Default for Energy {
+    fn default() -> Energy {
+        Energy::new()
+    }
+}
+
+impl Energy {
+    pub fn new() -> Energy {
+        Energy {
+            states: vec![],
+            peripherals: vec![],
+            state: "active",
+            running: vec![],
+        }
+    }
+
+    /// a step starts in CPU state `state`; note which peripherals in
+    /// `io_mem` are on and clocked
+    pub fn start(&mut self, state: &'static str, io_mem: &IOMemory) {
+        self.state = state;
+        self.running.clear();
+
+        let sleep_mode = io_mem.sleep_mode;
+        for (i, p) in io_mem.peripherals.iter().enumerate() {
+            let stopped = sleep_mode.is_some_and(|mode| p.stops_in_sleep(mode));
+            if p.powered() && !stopped {
+                self.running.push(i);
+            }
+        }
+    }
+
+    /// the step took `nanos`; charge it to the state and peripherals from
+    /// its start
+    pub fn finish(&mut self, nanos: u64, io_mem: &IOMemory) {
+        if nanos == 0 {
+            return;
+        }
+
+        add_time(&mut self.states, self.state, nanos);
+        for &i in &self.running {
+            if let Some(p) = io_mem.peripherals.get(i) {
+                add_time(&mut self.peripherals, p.name(), nanos);
+            }
+        }
+    }
+
+    /// time, duty cycle and, with a current table, charge per state and
+    /// peripheral
+    pub fn report(&self, table: Option<&CurrentTable>) -> String {
+        let mut out = String::new();
+        let total: u64 = self.states.iter().map(|s| s.1).sum();
+        let total_secs = total as f64 / NANOS_PER_SEC;
+        let mut charge = 0.0;
+        let mut missing = vec![];
+
+        let mut rows = |out: &mut String, times: &[(String, u64)]| {
+            for &(ref name, nanos) in times {
+                let secs = nanos as f64 / NANOS_PER_SEC;
+                let share = if total == 0 { 0.0 } else { 100.0 * nanos as f64 / total as f64 };
+                write!(out, "{:<18} {:>12.6}s {:>7.2}%", name, secs, share).unwrap();
+                match table.map(|t| t.current(name)) {
+                    Some(Some(current)) => {
+                        charge += current * secs;
+                        write!(out, " {:>12} {:>12}", fmt_si(current, "A"),
+                            fmt_si(current * secs / COULOMBS_PER_MAH * 1e-3, "Ah")).unwrap();
+                    },
+                    Some(None) => missing.push(name.clone()),
+                    None => {},
+                }
+                writeln!(out).unwrap();
+            }
+        };
+
+        writeln!(out, "{:<18} {:>13} {:>8}", "state", "time", "share").unwrap();
+        rows(&mut out, &self.states);
+        writeln!(out).unwrap();
+        writeln!(out, "{:<18} {:>13} {:>8}", "peripheral", "time on", "share").unwrap();
+        rows(&mut out, &self.peripherals);
+
+        let table = match table {
+            Some(table) => table,
+            None => return out,
+        };
+
+        writeln!(out).unwrap();
+        if !missing.is_empty() {
+            writeln!(out, "no current given for: {}", missing.join(", ")).unwrap();
+        }
+        let average = if total == 0 { 0.0 } else { charge / total_secs };
+        writeln!(out, "charge: {} over {:.6}s, average current {}",
+            fmt_si(charge / COULOMBS_PER_MAH * 1e-3, "Ah"), total_secs,
+            fmt_si(average, "A")).unwrap();
+        if let Some(voltage) = table.voltage {
+            writeln!(out, "energy: {} at {}V, average power {}",
+                fmt_si(charge * voltage, "J"), voltage,
+                fmt_si(average * voltage, "W")).unwrap();
+        }
+        if let Some(battery) = table.battery {
+            if average > 0.0 {
+                let hours = battery * 1e-3 / average;
+                writeln!(out, "battery life: {:.1} hours ({:.1} days) on {}mAh",
+                    hours, hours / 24.0, battery).unwrap();
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,100 @@
+use std::error;
+use std::fmt;
+use std::result;
+use taint::TaintSink;
+use isa::Isa;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// no valid instruction at pc
+    DecodeError { pc: u32 },
+
+    /// the instruction decoded fine, but the emulator doesn't support it
+    UnimplementedInsn { insn: String, pc: u32, insn_count: u64 },
+
+    /// the instruction isn't in the device's instruction set, e.g. MUL on
+    /// an ATtiny, so the firmware was probably built for another device
+    UnsupportedInsn { insn: String, pc: u32, device: &'static str, isa: Isa, needs: Isa },
+
+    /// data space access outside of emulated memory
+    BadIoAccess { addr: u32, pc: u32 },
+
+    /// a push below the start of SRAM, or a pop past its end. `sp` is
+    /// from before the push/pop.
+    StackFault { sp: u16, push: bool, pc: u32, call_stack: String },
+
+    /// SP entered the stack guard region
+    StackOverflow { sp: u16, pc: u32 },
+
+    /// host access outside of flash
+    BadFlashAccess { addr: u32 },
+
+    /// data space write to a write-protected region
+    WriteProtected { addr: u32, pc: u32 },
+
+    /// tainted data reached PC or an SPM address, with taint trapping on
+    Tainted { sink: TaintSink, pc: u32 },
+
+    /// a symbol the host asked for isn't in the symbol table
+    UnknownSymbol { name: String },
+
+    /// a host-initiated guest function call couldn't be made or didn't
+    /// return
+    BadCall { msg: String },
+
+    /// a script didn't compile, or failed while running
+    Script { msg: String },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DecodeError { pc } =>
+                write!(f, "couldn't decode instruction @ {:#x}", pc),
+
+            Error::UnimplementedInsn { ref insn, pc, insn_count } =>
+                write!(f,
+                    "unimplemented instruction {} @ {:#x} after {} instructions",
+                    insn, pc, insn_count),
+
+            Error::UnsupportedInsn { ref insn, pc, device, isa, needs } =>
+                write!(f,
+                    "{} @ {:#x} needs {}, but {} is {}; built for another device?",
+                    insn, pc, needs, device, isa),
+
+            Error::BadIoAccess { addr, pc } =>
+                write!(f, "bad data space access to {:#x} @ {:#x}", addr, pc),
+
+            Error::StackFault { sp, push, pc, ref call_stack } =>
+                write!(f, "stack {} out of SRAM, sp={:#06x} @ {}; {:#x}",
+                    if push { "overflow" } else { "underflow" }, sp, call_stack, pc),
+
+            Error::StackOverflow { sp, pc } =>
+                write!(f, "stack overflow into the guard region, sp={:#06x} @ {:#x}",
+                    sp, pc),
+
+            Error::BadFlashAccess { addr } =>
+                write!(f, "flash access to {:#x}, past the end of flash", addr),
+
+            Error::WriteProtected { addr, pc } =>
+                write!(f, "write to protected address {:#x} @ {:#x}", addr, pc),
+
+            Error::Tainted { sink, pc } =>
+                write!(f, "{} @ {:#x}", sink, pc),
+
+            Error::UnknownSymbol { ref name } =>
+                write!(f, "unknown symbol {:?}", name),
+
+            Error::BadCall { ref msg } =>
+                write!(f, "bad function call: {}", msg),
+
+            Error::Script { ref msg } =>
+                write!(f, "script error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {}
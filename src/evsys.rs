@@ -0,0 +1,188 @@
+// XMEGA event system
+//
+// Peripherals report the events they generate through
+// `Peripheral::poll_events`, as channel multiplexer values. Once per step,
+// IOMemory passes them to `Evsys::route`, and delivers the resulting channel
+// events to every peripheral with `Peripheral::event`.
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+
+
+pub const EVSYS_BASE : u32 = 0x0180;
+
+pub const NUM_CHANNELS : usize = 8;
+
+// register offsets
+const CH0MUX : u32 = 0x00;
+const CH7MUX : u32 = 0x07;
+const CH0CTRL : u32 = 0x08;
+const CH7CTRL : u32 = 0x0F;
+const STROBE : u32 = 0x10;
+const DATA : u32 = 0x11;
+
+// multiplexer values for the peripheral clock prescaler, clkPER/1 to
+// clkPER/32768
+const PRESCALER_1 : u8 = 0x80;
+const PRESCALER_32768 : u8 = 0x8F;
+
+// DMA trigger sources for event channels 0-2
+const DMA_TRIG_CH0 : u8 = 0x01;
+const DMA_TRIG_CH2 : u8 = 0x03;
+
+
+#[derive(Clone)]
+pub struct Evsys {
+    pub chmux: [u8; NUM_CHANNELS],
+    pub chctrl: [u8; NUM_CHANNELS],
+    pub data: u8,
+
+    /// channels strobed by software since the last routing
+    strobe: u8,
+    /// channels that fired in the last routing, and haven't been taken by
+    /// the DMA controller yet
+    fired: u8,
+    /// cycle count at the last routing, for prescaler events
+    last_tick: u64,
+}
+
+impl Default for Evsys {
+    fn default() -> Evsys {
+        Evsys::new()
+    }
+}
+
+impl Evsys {
+    pub fn new() -> Evsys {
+        Evsys {
+            chmux: [0; NUM_CHANNELS],
+            chctrl: [0; NUM_CHANNELS],
+            data: 0,
+
+            strobe: 0,
+            fired: 0,
+            last_tick: 0,
+        }
+    }
+
+    /// map events from `sources` (multiplexer values) to event channels.
+    /// returns one entry per channel event.
+    pub fn route(&mut self, sources: &[u8], now: u64) -> Vec<u8> {
+        let mut channels = vec![];
+        self.fired = 0;
+
+        for ch in 0..NUM_CHANNELS {
+            let mux = self.chmux[ch];
+            if mux == 0 {
+                continue;
+            }
+
+            let mut count = sources.iter().filter(|&&src| src == mux).count() as u64;
+
+            if (PRESCALER_1..=PRESCALER_32768).contains(&mux) {
+                // one event per prescaled clock edge
+                let shift = mux - PRESCALER_1;
+                count += (now >> shift).saturating_sub(self.last_tick >> shift);
+            }
+
+            if (self.strobe & (1 << ch)) != 0 {
+                count += 1;
+            }
+
+            if count > 0 {
+                self.fired |= 1 << ch;
+            }
+            for _ in 0..count {
+                channels.push(ch as u8);
+            }
+        }
+
+        self.strobe = 0;
+        self.last_tick = now;
+        channels
+    }
+}
+
+impl Peripheral for Evsys {
+    fn name(&self) -> &str {
+        "EVSYS"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (EVSYS_BASE, 0x12)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CH0MUX..=CH7MUX => self.chmux[(ofs - CH0MUX) as usize],
+            CH0CTRL..=CH7CTRL => self.chctrl[(ofs - CH0CTRL) as usize],
+            STROBE => 0,
+            DATA => self.data,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CH0MUX..=CH7MUX => self.chmux[(ofs - CH0MUX) as usize] = val,
+            CH0CTRL..=CH7CTRL => self.chctrl[(ofs - CH0CTRL) as usize] = val,
+            STROBE => self.strobe |= val,
+            DATA => self.data = val,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let last_tick = self.last_tick;
+        *self = Evsys::new();
+        self.last_tick = last_tick;
+    }
+
+    fn dma_request(&self, trigsrc: u8) -> bool {
+        (DMA_TRIG_CH0..=DMA_TRIG_CH2).contains(&trigsrc)
+            && (self.fired & (1 << (trigsrc - DMA_TRIG_CH0))) != 0
+    }
+
+    fn dma_ack(&mut self, trigsrc: u8) {
+        if (DMA_TRIG_CH0..=DMA_TRIG_CH2).contains(&trigsrc) {
+            self.fired &= !(1 << (trigsrc - DMA_TRIG_CH0));
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        state.extend_from_slice(&self.chmux);
+        state.extend_from_slice(&self.chctrl);
+        state.extend_from_slice(&[self.data, self.strobe, self.fired]);
+        state.write_u64::<LittleEndian>(self.last_tick).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() != 2 * NUM_CHANNELS + 3 + 8 {
+            return;
+        }
+
+        self.chmux.copy_from_slice(&state[..NUM_CHANNELS]);
+        self.chctrl.copy_from_slice(&state[NUM_CHANNELS..2 * NUM_CHANNELS]);
+
+        let mut r = &state[2 * NUM_CHANNELS..];
+        self.data = r.read_u8().unwrap();
+        self.strobe = r.read_u8().unwrap();
+        self.fired = r.read_u8().unwrap();
+        self.last_tick = r.read_u64::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
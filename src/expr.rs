@@ -0,0 +1,382 @@
+// Small expression language over the machine state, for breakpoint
+// conditions, e.g. "r24 == 0x42 && sp < 0x3f00" or "mem[0x2100] != 0"
+//
+// Values are 64-bit signed integers; comparisons and logical operators give
+// 0 or 1. Operands:
+//   123, 0x7b           numbers
+//   r0 - r31            registers
+//   x, y, z             pointer registers, including RAMPX/Y/Z
+//   sp, pc, sreg        pc is a byte address
+//   sreg.c ... sreg.i   single SREG flags
+//   cycles, insns       cycle and instruction counters
+//   mem[e], mem16[e]    data memory byte / little-endian word at e
+//   other names         a symbol's address, in data space for data symbols,
+//                       when parsed with a symbol table
+// Binary operators, loosest first: || && | ^ & (== !=) (< <= > >=)
+// (<< >>) (+ -) (* / %). Unary: ! - ~.
+//
+// Memory is read directly, so IO registers read as whatever was last stored
+// in data memory, without peripheral side effects.
+
+use emulator::Emulator;
+use symbols::{SymbolTable, DATA_OFFSET};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Var {
+    Reg(u8),
+    X,
+    Y,
+    Z,
+    Sp,
+    Pc,
+    Sreg,
+    /// SREG bit number
+    Flag(u8),
+    Cycles,
+    Insns,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnOp {
+    Not,
+    Neg,
+    BitNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Num(i64),
+    Var(Var),
+    /// byte or word read from data memory
+    Mem { addr: Box<Expr>, wide: bool },
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// longest first, so "<=" isn't read as "<"
+const OPERATORS : &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "!", "~",
+    "(", ")", "[", "]",
+];
+
+// binary operators by precedence level, loosest first
+const BINARY_OPS : &[&[(&str, BinOp)]] = &[
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[("<", BinOp::Lt), ("<=", BinOp::Le), (">", BinOp::Gt), (">=", BinOp::Ge)],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+// in SREG bit order
+const FLAG_NAMES : &str = "cznvshti";
+
+
+fn skip_whitespace(s: &str) -> &str {
+    s.find(|c: char| !c.is_whitespace()).map_or("", |i| &s[i..])
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = skip_whitespace(s);
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+
+        if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let val =
+                if let Some(hex) = word.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16)
+                } else {
+                    word.parse()
+                };
+            match val {
+                Ok(val) => tokens.push(Token::Num(val)),
+                Err(_) => return Err(format!("bad number {:?}", word)),
+            }
+            rest = &rest[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    rest = &rest[op.len()..];
+                },
+                None => return Err(format!("unexpected {:?}", c)),
+            }
+        }
+
+        rest = skip_whitespace(rest);
+    }
+
+    Ok(tokens)
+}
+
+fn parse_var(name: &str) -> Option<Var> {
+    let name = &name.to_lowercase()[..];
+    if name.starts_with("sreg.") && name.len() == 6 {
+        return FLAG_NAMES.find(&name[5..]).map(|bit| Var::Flag(bit as u8));
+    }
+
+    if let Some(num) = name.strip_prefix('r') {
+        return match num.parse::<u8>() {
+            Ok(r) if r < 32 => Some(Var::Reg(r)),
+            _ => None,
+        };
+    }
+
+    match name {
+        "x" => Some(Var::X),
+        "y" => Some(Var::Y),
+        "z" => Some(Var::Z),
+        "sp" => Some(Var::Sp),
+        "pc" => Some(Var::Pc),
+        "sreg" => Some(Var::Sreg),
+        "cycles" => Some(Var::Cycles),
+        "insns" => Some(Var::Insns),
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            Some(tok) => Err(format!("expected {:?}, got {:?}", op, tok)),
+            None => Err(format!("expected {:?}", op)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Op(o)) =>
+                    BINARY_OPS[level].iter().find(|&&(s, _)| s == o).map(|&(_, op)| op),
+                _ => None,
+            };
+            let op = match op {
+                Some(op) => op,
+                None => return Ok(lhs),
+            };
+
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(&Token::Op("!")) => Some(UnOp::Not),
+            Some(&Token::Op("-")) => Some(UnOp::Neg),
+            Some(&Token::Op("~")) => Some(UnOp::BitNot),
+            _ => None,
+        };
+
+        match op {
+            Some(op) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            },
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(val)) => Ok(Expr::Num(val)),
+
+            Some(Token::Op("(")) => {
+                let e = self.binary(0)?;
+                self.expect(")")?;
+                Ok(e)
+            },
+
+            Some(Token::Ident(ref name))
+                    if name.eq_ignore_ascii_case("mem") || name.eq_ignore_ascii_case("mem16") => {
+                self.expect("[")?;
+                let addr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem { addr: Box::new(addr), wide: name.eq_ignore_ascii_case("mem16") })
+            },
+
+            Some(Token::Ident(name)) => {
+                if let Some(var) = parse_var(&name) {
+                    return Ok(Expr::Var(var));
+                }
+                match self.symbols.and_then(|symbols| symbols.find(&name)) {
+                    Some(sym) if sym.addr >= DATA_OFFSET =>
+                        Ok(Expr::Num((sym.addr - DATA_OFFSET) as i64)),
+                    Some(sym) => Ok(Expr::Num(sym.addr as i64)),
+                    None => Err(format!("unknown name {:?}", name)),
+                }
+            },
+
+            Some(tok) => Err(format!("unexpected {:?}", tok)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn read_mem(emu: &Emulator, addr: i64) -> i64 {
+    if addr < 0 {
+        return 0;
+    }
+    emu.io_mem.data_mem.get(addr as usize).map_or(0, |&b| b as i64)
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        Expr::parse_inner(s, None)
+    }
+
+    /// with symbol names standing for their addresses
+    pub fn parse_with_symbols(s: &str, symbols: &SymbolTable) -> Result<Expr, String> {
+        Expr::parse_inner(s, Some(symbols))
+    }
+
+    fn parse_inner(s: &str, symbols: Option<&SymbolTable>) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0, symbols };
+        let e = parser.binary(0)?;
+
+        match parser.next() {
+            None => Ok(e),
+            Some(tok) => Err(format!("unexpected {:?}", tok)),
+        }
+    }
+
+    pub fn eval(&self, emu: &Emulator) -> i64 {
+        match *self {
+            Expr::Num(val) => val,
+
+            Expr::Var(var) => match var {
+                Var::Reg(r) => emu.get_reg8(r) as i64,
+                Var::X => emu.io_mem.get_full_x() as i64,
+                Var::Y => emu.io_mem.get_full_y() as i64,
+                Var::Z => emu.io_mem.get_full_z() as i64,
+                Var::Sp => emu.io_mem.get_sp() as i64,
+                Var::Pc => emu.pc as i64,
+                Var::Sreg => emu.io_mem.sreg.as_u8() as i64,
+                Var::Flag(bit) => ((emu.io_mem.sreg.as_u8() >> bit) & 1) as i64,
+                Var::Cycles => emu.cycle_count as i64,
+                Var::Insns => emu.insn_count as i64,
+            },
+
+            Expr::Mem { ref addr, wide } => {
+                let addr = addr.eval(emu);
+                if wide {
+                    read_mem(emu, addr) | (read_mem(emu, addr + 1) << 8)
+                } else {
+                    read_mem(emu, addr)
+                }
+            },
+
+            Expr::Unary(op, ref e) => {
+                let val = e.eval(emu);
+                match op {
+                    UnOp::Not => (val == 0) as i64,
+                    UnOp::Neg => val.wrapping_neg(),
+                    UnOp::BitNot => !val,
+                }
+            },
+
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let a = lhs.eval(emu);
+
+                // short-circuit
+                match op {
+                    BinOp::Or if a != 0 => return 1,
+                    BinOp::And if a == 0 => return 0,
+                    _ => {},
+                }
+
+                let b = rhs.eval(emu);
+                match op {
+                    BinOp::Or | BinOp::And => (b != 0) as i64,
+                    BinOp::BitOr => a | b,
+                    BinOp::BitXor => a ^ b,
+                    BinOp::BitAnd => a & b,
+                    BinOp::Eq => (a == b) as i64,
+                    BinOp::Ne => (a != b) as i64,
+                    BinOp::Lt => (a < b) as i64,
+                    BinOp::Le => (a <= b) as i64,
+                    BinOp::Gt => (a > b) as i64,
+                    BinOp::Ge => (a >= b) as i64,
+                    BinOp::Shl => a.wrapping_shl(b as u32),
+                    BinOp::Shr => a.wrapping_shr(b as u32),
+                    BinOp::Add => a.wrapping_add(b),
+                    BinOp::Sub => a.wrapping_sub(b),
+                    BinOp::Mul => a.wrapping_mul(b),
+                    // dividing by zero gives 0 rather than stopping everything
+                    BinOp::Div => a.checked_div(b).unwrap_or(0),
+                    BinOp::Rem => a.checked_rem(b).unwrap_or(0),
+                }
+            },
+        }
+    }
+}
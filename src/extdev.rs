@@ -0,0 +1,395 @@
+// Peripherals modelled by another process
+//
+// An external program, e.g. a Python script, can stand in for a peripheral
+// that yaavre doesn't have. The emulator either starts it and talks on its
+// stdin and stdout, or connects to it over TCP. The protocol is text, one
+// message per line, and every request gets exactly one reply line.
+//
+// The device speaks first, saying which register block in data space it
+// takes up and which pins it wants to hear about:
+//   hello <name> <base> <size> [<port>.<pin> ...]
+// Then the emulator sends, with <cycle> the current cycle count:
+//   read <cycle> <offset>              the CPU reads a register
+//   write <cycle> <offset> <value>     the CPU writes a register
+//   pin <cycle> <port>.<pin> <0|1|z>   a watched pin changed
+//   tick <cycle>                       the cycle the device asked for came
+//   reset                              the chip was reset
+// A reply is a list of words, so "ok" or an empty line does nothing:
+//   <value>                      for reads, the register's value
+//   irq=<vector>[:<level>]       raise an interrupt, at level 1 (the default)
+//                                to 3, until the CPU takes it
+//   drive=<port>.<pin>:<0|1|z>   drive a pin, or stop driving it
+//   wake=<cycle>                 send a tick at that cycle
+// Numbers may be decimal or hex with 0x.
+//
+// The device's state lives in the other process, so snapshots and clones of
+// the emulator all share it. If the connection breaks, the emulator warns and
+// carries on with reads returning 0.
+
+use std::any::Any;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+use gpio::PinState;
+use iomem::IOMemory;
+use peripheral::Peripheral;
+use wiring::PinDevice;
+
+
+fn parse_num(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// "PORTA.3" as ("PORTA", 3)
+fn parse_pin(s: &str) -> Option<(String, u8)> {
+    let mut parts = s.splitn(2, '.');
+    let port = parts.next()?;
+    let pin = parts.next()?.parse().ok().filter(|&pin| pin < 8)?;
+    Some((port.to_string(), pin))
+}
+
+fn parse_state(s: &str) -> Option<PinState> {
+    match s {
+        "0" => Some(PinState::Low),
+        "1" => Some(PinState::High),
+        "z" => Some(PinState::Floating),
+        _ => None,
+    }
+}
+
+fn state_char(state: PinState) -> char {
+    match state {
+        PinState::Low => '0',
+        PinState::High => '1',
+        PinState::Floating => 'z',
+    }
+}
+
+fn bad_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// the connection, and what the device asked for that hasn't happened yet
+struct Link {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+    /// the process, if we started it
+    child: Option<Child>,
+    /// why the connection broke, until it's been reported
+    error: Option<String>,
+    broken: bool,
+
+    now: u64,
+    /// (vector, level)
+    irqs: Vec<(u8, u8)>,
+    drives: Vec<(String, u8, PinState)>,
+    wake: Option<u64>,
+}
+
+impl Link {
+    /// send `request`, handle the reply's actions, and return the value in
+    /// it, if any
+    fn request(&mut self, request: &str) -> Option<u8> {
+        if self.broken {
+            return None;
+        }
+
+        match self.try_request(request) {
+            Ok(val) => val,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                self.broken = true;
+                None
+            },
+        }
+    }
+
+    fn try_request(&mut self, request: &str) -> io::Result<Option<u8>> {
+        writeln!(self.writer, "{}", request)?;
+        self.writer.flush()?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(bad_data("device closed the connection".to_string()));
+        }
+
+        let mut val = None;
+        for word in line.split_whitespace() {
+            let bad_word = || bad_data(format!("bad reply to {:?}: {:?}", request, word));
+
+            let mut parts = word.splitn(2, '=');
+            match (parts.next().unwrap(), parts.next()) {
+                ("ok", None) => {},
+                (num, None) => {
+                    val = Some(parse_num(num).filter(|&v| v < 0x100).ok_or_else(bad_word)? as u8);
+                },
+                ("irq", Some(arg)) => {
+                    let mut parts = arg.splitn(2, ':');
+                    let vector = parts.next().and_then(parse_num).filter(|&v| v < 0x100);
+                    let level = parts.next().map_or(Some(1), parse_num)
+                        .filter(|l| (1..=3).contains(l));
+                    match (vector, level) {
+                        (Some(vector), Some(level)) => self.irqs.push((vector as u8, level as u8)),
+                        _ => return Err(bad_word()),
+                    }
+                },
+                ("drive", Some(arg)) => {
+                    let mut parts = arg.splitn(2, ':');
+                    let pin = parts.next().and_then(parse_pin);
+                    let state = parts.next().and_then(parse_state);
+                    match (pin, state) {
+                        (Some((port, pin)), Some(state)) => self.drives.push((port, pin, state)),
+                        _ => return Err(bad_word()),
+                    }
+                },
+                ("wake", Some(arg)) => {
+                    self.wake = Some(parse_num(arg).ok_or_else(bad_word)?);
+                },
+                _ => return Err(bad_word()),
+            }
+        }
+
+        Ok(val)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// a device that said hello, ready to attach
+pub struct ExtDevice {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+    /// pins it watches
+    pub pins: Vec<(String, u8)>,
+    link: Arc<Mutex<Link>>,
+}
+
+impl ExtDevice {
+    fn open(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>,
+            child: Option<Child>) -> io::Result<ExtDevice> {
+
+        let mut link = Link {
+            reader: BufReader::new(reader),
+            writer,
+            child,
+            error: None,
+            broken: false,
+            now: 0,
+            irqs: vec![],
+            drives: vec![],
+            wake: None,
+        };
+
+        let mut line = String::new();
+        link.reader.read_line(&mut line)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let bad_hello = || bad_data(format!("bad hello from device: {:?}", line.trim()));
+        if words.len() < 4 || words[0] != "hello" {
+            return Err(bad_hello());
+        }
+
+        let base = parse_num(words[2]).ok_or_else(bad_hello)? as u32;
+        let size = parse_num(words[3]).filter(|&size| size > 0).ok_or_else(bad_hello)? as u32;
+        let pins = words[4..].iter()
+            .map(|s| parse_pin(s))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(bad_hello)?;
+
+        Ok(ExtDevice {
+            name: words[1].to_string(),
+            base,
+            size,
+            pins,
+            link: Arc::new(Mutex::new(link)),
+        })
+    }
+
+    /// start `command` with the shell, and talk to it on its stdin and
+    /// stdout
+    pub fn spawn(command: &str) -> io::Result<ExtDevice> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        ExtDevice::open(Box::new(stdout), Box::new(stdin), Some(child))
+    }
+
+    /// connect to a device listening at `addr`, e.g. "localhost:5555"
+    pub fn connect(addr: &str) -> io::Result<ExtDevice> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        ExtDevice::open(Box::new(reader), Box::new(stream), None)
+    }
+
+    /// add the register block, and the pin watcher, to `io_mem`
+    pub fn attach(self, io_mem: &mut IOMemory) {
+        let pins = ExtPins {
+            name: format!("{} pins", self.name),
+            device: self.name.clone(),
+            last: vec![None; self.pins.len()],
+            pins: self.pins,
+            link: self.link.clone(),
+        };
+        io_mem.attach_pin_device(Box::new(pins));
+        io_mem.add_peripheral(Box::new(ExtPeripheral {
+            name: self.name,
+            base: self.base,
+            size: self.size,
+            link: self.link,
+        }));
+    }
+}
+
+/// the device's register block
+#[derive(Clone)]
+pub struct ExtPeripheral {
+    name: String,
+    base: u32,
+    size: u32,
+    link: Arc<Mutex<Link>>,
+}
+
+impl ExtPeripheral {
+    fn link(&self) -> MutexGuard<'_, Link> {
+        self.link.lock().unwrap()
+    }
+}
+
+impl Peripheral for ExtPeripheral {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, self.size)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        let mut link = self.link();
+        let request = format!("read {} {:#x}", link.now, ofs);
+        link.request(&request).unwrap_or(0)
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        let mut link = self.link();
+        let request = format!("write {} {:#x} {:#04x}", link.now, ofs, val);
+        link.request(&request);
+    }
+
+    fn reset(&mut self) {
+        let mut link = self.link();
+        link.irqs.clear();
+        link.wake = None;
+        link.request("reset");
+    }
+
+    fn tick(&mut self, now: u64) {
+        let mut link = self.link();
+        link.now = now;
+        if link.wake.is_some_and(|wake| wake <= now) {
+            link.wake = None;
+            link.request(&format!("tick {}", now));
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.link().wake
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        self.link().irqs.iter().cloned().max_by_key(|&(_, level)| level)
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        self.link().irqs.retain(|&(v, _)| v != vector);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _state: &[u8]) {}
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// tells the device about pin changes, and drives the pins it asks for
+#[derive(Clone)]
+struct ExtPins {
+    name: String,
+    device: String,
+    pins: Vec<(String, u8)>,
+    last: Vec<Option<PinState>>,
+    link: Arc<Mutex<Link>>,
+}
+
+impl PinDevice for ExtPins {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn update(&mut self, io_mem: &mut IOMemory, now: u64) {
+        let mut link = self.link.lock().unwrap();
+
+        for (i, &(ref port, pin)) in self.pins.iter().enumerate() {
+            let state = io_mem.port(port).map(|p| p.pin_state(pin));
+            if state != self.last[i] {
+                self.last[i] = state;
+                if let Some(state) = state {
+                    link.request(&format!("pin {} {}.{} {}", now, port, pin, state_char(state)));
+                }
+            }
+        }
+
+        for (port, pin, state) in link.drives.drain(..) {
+            if let Some(port) = io_mem.port_mut(&port) {
+                port.drive_pin(pin, state);
+            }
+        }
+
+        if let Some(e) = link.error.take() {
+            io_mem.diag.warning(&format!("external device {}: {}", self.device, e));
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn PinDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
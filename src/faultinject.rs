@@ -0,0 +1,221 @@
+// Fault injection: bit flips in registers, SRAM or flash at a chosen time
+//
+// A single fault is set up with Emulator::inject_fault(), and happens just
+// before the first instruction at or after its cycle, or the next time
+// execution reaches its address. A Campaign runs a copy of the firmware for
+// each of a list of faults, and sorts the outcomes by comparing each run to
+// one without faults.
+
+use std::fmt;
+use emulator::{Emulator, StopReason};
+use stopcond::StopCondition;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultTarget {
+    Reg(u8),
+    Sreg,
+    /// a data space address
+    Data(u32),
+    /// a flash byte address
+    Flash(u32),
+}
+
+impl fmt::Display for FaultTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FaultTarget::Reg(r) => write!(f, "r{}", r),
+            FaultTarget::Sreg => write!(f, "SREG"),
+            FaultTarget::Data(addr) => write!(f, "data {:#x}", addr),
+            FaultTarget::Flash(addr) => write!(f, "flash {:#x}", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultTime {
+    /// the first instruction starting at or after this cycle
+    Cycle(u64),
+    /// the next time execution reaches this byte address
+    Pc(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultPoint {
+    pub at: FaultTime,
+    pub target: FaultTarget,
+    /// the bits to flip
+    pub mask: u8,
+}
+
+impl fmt::Display for FaultPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ^ {:#04x} @ ", self.target, self.mask)?;
+        match self.at {
+            FaultTime::Cycle(cycle) => write!(f, "cycle {}", cycle),
+            FaultTime::Pc(pc) => write!(f, "pc {:#x}", pc),
+        }
+    }
+}
+
+impl FaultPoint {
+    pub fn is_due(&self, emu: &Emulator) -> bool {
+        match self.at {
+            FaultTime::Cycle(cycle) => emu.cycle_count >= cycle,
+            FaultTime::Pc(pc) => emu.pc == pc,
+        }
+    }
+
+    /// flip the bits. flipping outside of data space or flash does nothing.
+    pub fn apply(&self, emu: &mut Emulator) {
+        match self.target {
+            FaultTarget::Reg(r) => {
+                let val = emu.get_reg8(r);
+                emu.set_reg8(r, val ^ self.mask);
+            },
+            FaultTarget::Sreg => {
+                let val = emu.io_mem.sreg.as_u8();
+                emu.io_mem.sreg.set_u8(val ^ self.mask);
+            },
+            FaultTarget::Data(addr) => {
+                if let Some(b) = emu.io_mem.data_mem.get_mut(addr as usize) {
+                    *b ^= self.mask;
+                }
+            },
+            FaultTarget::Flash(addr) => {
+                let word_addr = addr & !1;
+                if let Some(word) = emu.prog_mem.read_word(word_addr) {
+                    let shift = (addr & 1) * 8;
+                    let flipped = word ^ ((self.mask as u16) << shift);
+                    emu.prog_mem.write_word(word_addr, flipped);
+                }
+            },
+        }
+    }
+}
+
+/// every single-bit flip of each target at each time
+pub fn sweep(times: &[FaultTime], targets: &[FaultTarget]) -> Vec<FaultPoint> {
+    let mut points = vec![];
+    for &at in times {
+        for &target in targets {
+            for bit in 0..8 {
+                points.push(FaultPoint { at, target, mask: 1 << bit });
+            }
+        }
+    }
+    points
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Outcome {
+    /// ended the same way as without the fault
+    NoEffect,
+    /// reached the campaign's detection address
+    Detected,
+    /// ended differently, e.g. with other output or exit code
+    SilentCorruption,
+    /// stopped with an error
+    Crash,
+    /// still running at the cycle limit
+    Hang,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Outcome::NoEffect => "no effect",
+            Outcome::Detected => "detected",
+            Outcome::SilentCorruption => "silent corruption",
+            Outcome::Crash => "crash",
+            Outcome::Hang => "hang",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// how a run ended, for comparing to the run without faults
+#[derive(PartialEq)]
+struct Ending {
+    reason: StopReason,
+    uart_output: Vec<u8>,
+}
+
+pub struct Campaign {
+    /// runs still going at this cycle count hang
+    pub max_cycles: u64,
+    /// where the firmware goes when it notices a fault, e.g. its fault
+    /// handler
+    pub detect_pc: Option<u32>,
+}
+
+impl Campaign {
+    pub fn new(max_cycles: u64) -> Campaign {
+        Campaign {
+            max_cycles,
+            detect_pc: None,
+        }
+    }
+
+    /// run `emu` to the end; an Err is a description of the error
+    fn run(&self, emu: &mut Emulator) -> Result<Ending, String> {
+        let mut conds = vec![
+            StopCondition::CycleLimit(self.max_cycles.saturating_sub(emu.cycle_count)),
+        ];
+        if let Some(pc) = self.detect_pc {
+            conds.push(StopCondition::Pc(pc));
+        }
+
+        let res = emu.run_until_any(&conds);
+        match res.fault {
+            Some(e) => Err(e.to_string()),
+            None => Ok(Ending {
+                reason: res.reason,
+                uart_output: emu.io_mem.usart_output_log.clone(),
+            }),
+        }
+    }
+
+    /// inject each of `points` into its own copy of `base`, and see what
+    /// happens. fails if `base` doesn't end by itself without faults.
+    pub fn run_all(&self, base: &Emulator, points: &[FaultPoint])
+            -> Result<Vec<(FaultPoint, Outcome)>, String> {
+
+        let golden = self.run(&mut base.clone())
+            .map_err(|e| format!("without faults: {}", e))?;
+        match golden.reason {
+            StopReason::Condition(0) =>
+                return Err(format!("still running after {} cycles without faults",
+                                   self.max_cycles)),
+            StopReason::Condition(_) =>
+                return Err("reached the detection address without faults".to_string()),
+            _ => {},
+        }
+
+        Ok(points.iter().map(|&point| {
+            let mut emu = base.clone();
+            emu.inject_fault(point.at, point.target, point.mask);
+
+            let outcome = match self.run(&mut emu) {
+                Err(_) => Outcome::Crash,
+                Ok(Ending { reason: StopReason::Condition(0), .. }) => Outcome::Hang,
+                Ok(Ending { reason: StopReason::Condition(_), .. }) => Outcome::Detected,
+                Ok(ref ending) if *ending == golden => Outcome::NoEffect,
+                Ok(_) => Outcome::SilentCorruption,
+            };
+            (point, outcome)
+        }).collect())
+    }
+}
+
+/// how many runs had each outcome
+pub fn summary(results: &[(FaultPoint, Outcome)]) -> String {
+    let mut out = String::new();
+    for &outcome in &[Outcome::NoEffect, Outcome::Detected, Outcome::SilentCorruption,
+                      Outcome::Crash, Outcome::Hang] {
+        let n = results.iter().filter(|&&(_, o)| o == outcome).count();
+        out.push_str(&format!("{:>18}: {}\n", outcome.to_string(), n));
+    }
+    out
+}
@@ -0,0 +1,179 @@
+// Fuses, device ID and signature rows
+//
+// These can't be changed by the firmware, so they're configured by the host.
+// The config file has one setting per line, with values in hex:
+//   devid <3 bytes>
+//   revid <byte>
+//   fuse<n> <byte>
+//   serial <11 bytes: LOTNUM0-5, WAFNUM, COORDX0-1, COORDY0-1>
+//   sigrow <offset> <bytes>
+//   usersig <offset> <bytes>
+// Lines starting with '#' are ignored.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use hex;
+use progmem::FLASH_PAGE_SIZE;
+
+
+pub const NUM_FUSES : usize = 6;
+
+pub const PROD_SIG_ROW_SIZE : usize = 0x40;
+pub const USER_SIG_ROW_SIZE : usize = FLASH_PAGE_SIZE as usize;
+
+// production signature row offsets
+pub const LOTNUM0 : usize = 0x08;
+pub const WAFNUM : usize = 0x10;
+pub const COORDX0 : usize = 0x12;
+pub const COORDY1 : usize = 0x15;
+
+// FUSEBYTE2 bits
+const BOOTRST : u8 = 0x40;
+
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceConfig {
+    pub device_id: [u8; 3],
+    pub revision: u8,
+    pub fuses: [u8; NUM_FUSES],
+    pub prod_sig_row: Vec<u8>,
+    pub user_sig_row: Vec<u8>,
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad device config line {}", line_num + 1))
+}
+
+fn strip_hex_prefix(s: &str) -> &str {
+    s.strip_prefix("0x").unwrap_or(s)
+}
+
+fn parse_byte(s: &str) -> Option<u8> {
+    u8::from_str_radix(strip_hex_prefix(s), 16).ok()
+}
+
+impl Default for DeviceConfig {
+    fn default() -> DeviceConfig {
+        DeviceConfig::new()
+    }
+}
+
+impl DeviceConfig {
+    /// an atxmega128a4u with unprogrammed fuses and an erased signature row
+    pub fn new() -> DeviceConfig {
+        DeviceConfig {
+            device_id: [0x1E, 0x97, 0x46],
+            revision: 0,
+            fuses: [0xFF, 0x00, 0xFF, 0xFF, 0xFE, 0xFF],
+            prod_sig_row: vec![0xFF; PROD_SIG_ROW_SIZE],
+            user_sig_row: vec![0xFF; USER_SIG_ROW_SIZE],
+        }
+    }
+
+    /// whether the BOOTRST fuse moves the reset vector to the boot section
+    pub fn boot_reset(&self) -> bool {
+        (self.fuses[2] & BOOTRST) == 0
+    }
+
+    /// the serial number: lot number, wafer number and wafer coordinates
+    pub fn serial_number(&self) -> Vec<u8> {
+        let row = &self.prod_sig_row;
+        let mut serial = row[LOTNUM0..LOTNUM0 + 6].to_vec();
+        serial.push(row[WAFNUM]);
+        serial.extend_from_slice(&row[COORDX0..COORDY1 + 1]);
+        serial
+    }
+
+    pub fn set_serial_number(&mut self, serial: &[u8]) -> bool {
+        if serial.len() != 11 {
+            return false;
+        }
+
+        self.prod_sig_row[LOTNUM0..LOTNUM0 + 6].copy_from_slice(&serial[..6]);
+        self.prod_sig_row[WAFNUM] = serial[6];
+        self.prod_sig_row[COORDX0..COORDY1 + 1].copy_from_slice(&serial[7..]);
+        true
+    }
+
+    /// apply one config line (see above); returns false if it's malformed
+    pub fn apply_setting(&mut self, line: &str) -> bool {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            return true;
+        }
+
+        match parts[..] {
+            ["devid", val] => match hex::decode(val) {
+                Ok(ref id) if id.len() == 3 => {
+                    self.device_id.copy_from_slice(id);
+                    true
+                },
+                _ => false,
+            },
+            ["revid", val] => match parse_byte(val) {
+                Some(val) => {
+                    self.revision = val;
+                    true
+                },
+                None => false,
+            },
+            ["serial", val] => match hex::decode(val) {
+                Ok(serial) => self.set_serial_number(&serial),
+                Err(_) => false,
+            },
+            [name, val] if name.starts_with("fuse") => {
+                let index = name[4..].parse::<usize>().ok()
+                    .filter(|&i| i < NUM_FUSES);
+                match (index, parse_byte(val)) {
+                    (Some(i), Some(val)) => {
+                        self.fuses[i] = val;
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            [row, ofs, val] if row == "sigrow" || row == "usersig" => {
+                let ofs = usize::from_str_radix(strip_hex_prefix(ofs), 16);
+                let bytes = hex::decode(val);
+                let dest =
+                    if row == "sigrow" {
+                        &mut self.prod_sig_row
+                    } else {
+                        &mut self.user_sig_row
+                    };
+
+                match (ofs, bytes) {
+                    (Ok(ofs), Ok(bytes)) if ofs + bytes.len() <= dest.len() => {
+                        dest[ofs..ofs + bytes.len()].copy_from_slice(&bytes);
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<DeviceConfig> {
+        let r = BufReader::new(File::open(path)?);
+        let mut config = DeviceConfig::new();
+
+        for (line_num, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            if !config.apply_setting(line) {
+                return Err(bad_line(line_num));
+            }
+        }
+
+        Ok(config)
+    }
+}
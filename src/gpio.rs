@@ -0,0 +1,407 @@
+// XMEGA I/O ports, and the classic AVR's PINx/DDRx/PORTx triples
+
+use std::any::Any;
+use peripheral::Peripheral;
+
+
+// register offsets
+const DIR : u32 = 0x00;
+const DIRSET : u32 = 0x01;
+const DIRCLR : u32 = 0x02;
+const DIRTGL : u32 = 0x03;
+const OUT : u32 = 0x04;
+const OUTSET : u32 = 0x05;
+const OUTCLR : u32 = 0x06;
+const OUTTGL : u32 = 0x07;
+const IN : u32 = 0x08;
+const INTCTRL : u32 = 0x09;
+const INT0MASK : u32 = 0x0A;
+const INT1MASK : u32 = 0x0B;
+const INTFLAGS : u32 = 0x0C;
+const REMAP : u32 = 0x0E;
+const PIN0CTRL : u32 = 0x10;
+const PIN7CTRL : u32 = 0x17;
+
+// classic register offsets
+const CLASSIC_PIN : u32 = 0x00;
+const CLASSIC_DDR : u32 = 0x01;
+const CLASSIC_PORT : u32 = 0x02;
+
+// PINnCTRL input sense configuration
+const ISC_MASK : u8 = 0x07;
+const ISC_BOTHEDGES : u8 = 0;
+const ISC_RISING : u8 = 1;
+const ISC_FALLING : u8 = 2;
+const ISC_LEVEL : u8 = 3;
+
+// PINnCTRL output/pull configuration
+const OPC_MASK : u8 = 0x38;
+const OPC_PULLUP : u8 = 0x18;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinState {
+    Low,
+    High,
+    /// an input that nothing drives
+    Floating,
+}
+
+#[derive(Clone)]
+pub struct Port {
+    name: String,
+    base: u32,
+    /// vector of INT0; INT1 is the next one
+    int0_vector: u8,
+    /// EVSYS source of pin 0, if the port has pin events
+    ev_source: Option<u8>,
+    /// just PIN, DDR and PORT; OUT bits of inputs turn on the pull-ups
+    classic: bool,
+
+    pub dir: u8,
+    pub out: u8,
+    pub intctrl: u8,
+    pub int0mask: u8,
+    pub int1mask: u8,
+    pub intflags: u8,
+    pub remap: u8,
+    pub pinctrl: [u8; 8],
+
+    /// pins driven from outside, and their levels
+    ext_driven: u8,
+    ext_level: u8,
+
+    /// IN as of the last update, for edge detection
+    last_in: u8,
+
+    /// pin events not yet routed
+    events: Vec<u8>,
+}
+
+impl Port {
+    pub fn new(name: &str, base: u32, int0_vector: u8, ev_source: Option<u8>)
+            -> Port {
+
+        Port {
+            name: name.to_string(),
+            base,
+            int0_vector,
+            ev_source,
+            classic: false,
+
+            dir: 0,
+            out: 0,
+            intctrl: 0,
+            int0mask: 0,
+            int1mask: 0,
+            intflags: 0,
+            remap: 0,
+            pinctrl: [0; 8],
+
+            ext_driven: 0,
+            ext_level: 0,
+
+            last_in: 0,
+
+            events: vec![],
+        }
+    }
+
+    /// a classic AVR port, e.g. PORTB with PINB at `base`. pin change
+    /// interrupts aren't part of it.
+    pub fn classic(name: &str, base: u32) -> Port {
+        let mut port = Port::new(name, base, 0, None);
+        port.classic = true;
+        port
+    }
+
+    fn pull_up(&self, pin: usize) -> bool {
+        if self.classic {
+            (self.out & (1 << pin)) != 0
+        } else {
+            (self.pinctrl[pin] & OPC_MASK) == OPC_PULLUP
+        }
+    }
+
+    /// the value of the IN register
+    pub fn input_value(&self) -> u8 {
+        let mut val = 0;
+
+        for pin in 0..8 {
+            let bit = 1 << pin;
+            let high =
+                if (self.dir & bit) != 0 {
+                    (self.out & bit) != 0
+                } else if (self.ext_driven & bit) != 0 {
+                    (self.ext_level & bit) != 0
+                } else {
+                    self.pull_up(pin)
+                };
+
+            if high {
+                val |= bit;
+            }
+        }
+
+        val
+    }
+
+    pub fn pin_state(&self, pin: u8) -> PinState {
+        let bit = 1 << pin;
+
+        if (self.dir & bit) == 0 && (self.ext_driven & bit) == 0 {
+            PinState::Floating
+        } else if (self.input_value() & bit) != 0 {
+            PinState::High
+        } else {
+            PinState::Low
+        }
+    }
+
+    /// drive an input pin from outside. `PinState::Floating` releases it.
+    pub fn drive_pin(&mut self, pin: u8, state: PinState) {
+        let bit = 1 << pin;
+
+        match state {
+            PinState::Low => {
+                self.ext_driven |= bit;
+                self.ext_level &= !bit;
+            },
+            PinState::High => {
+                self.ext_driven |= bit;
+                self.ext_level |= bit;
+            },
+            PinState::Floating => self.ext_driven &= !bit,
+        }
+
+        self.update_input();
+    }
+
+    /// recompute IN and set interrupt flags for pins that changed
+    fn update_input(&mut self) {
+        let new_in = self.input_value();
+        let changed = self.last_in ^ new_in;
+
+        for pin in 0..8 {
+            let bit = 1 << pin;
+            let triggered = match self.pinctrl[pin] & ISC_MASK {
+                ISC_BOTHEDGES => (changed & bit) != 0,
+                ISC_RISING => (changed & bit) != 0 && (new_in & bit) != 0,
+                ISC_FALLING => (changed & bit) != 0 && (new_in & bit) == 0,
+                ISC_LEVEL => (new_in & bit) == 0,
+                _ => false,
+            };
+
+            if triggered {
+                if let Some(ev_source) = self.ev_source {
+                    self.events.push(ev_source + pin as u8);
+                }
+
+                if (self.int0mask & bit) != 0 {
+                    self.intflags |= 0x01;
+                }
+                if (self.int1mask & bit) != 0 {
+                    self.intflags |= 0x02;
+                }
+            }
+        }
+
+        self.last_in = new_in;
+    }
+}
+
+impl Peripheral for Port {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, if self.classic { 0x03 } else { 0x20 })
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        if self.classic {
+            return match ofs {
+                CLASSIC_PIN => self.input_value(),
+                CLASSIC_DDR => self.dir,
+                CLASSIC_PORT => self.out,
+                _ => 0,
+            };
+        }
+
+        match ofs {
+            DIR | DIRSET | DIRCLR | DIRTGL => self.dir,
+            OUT | OUTSET | OUTCLR | OUTTGL => self.out,
+            IN => self.input_value(),
+            INTCTRL => self.intctrl,
+            INT0MASK => self.int0mask,
+            INT1MASK => self.int1mask,
+            INTFLAGS => self.intflags,
+            REMAP => self.remap,
+            PIN0CTRL..=PIN7CTRL => self.pinctrl[(ofs - PIN0CTRL) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        if self.classic {
+            match ofs {
+                // writing ones to PIN toggles PORT
+                CLASSIC_PIN => self.out ^= val,
+                CLASSIC_DDR => self.dir = val,
+                CLASSIC_PORT => self.out = val,
+                _ => {},
+            }
+            self.update_input();
+            return;
+        }
+
+        match ofs {
+            DIR => self.dir = val,
+            DIRSET => self.dir |= val,
+            DIRCLR => self.dir &= !val,
+            DIRTGL => self.dir ^= val,
+            OUT => self.out = val,
+            OUTSET => self.out |= val,
+            OUTCLR => self.out &= !val,
+            OUTTGL => self.out ^= val,
+            INTCTRL => self.intctrl = val,
+            INT0MASK => self.int0mask = val,
+            INT1MASK => self.int1mask = val,
+            // write 1 to clear
+            INTFLAGS => self.intflags &= !val,
+            REMAP => self.remap = val,
+            PIN0CTRL..=PIN7CTRL =>
+                self.pinctrl[(ofs - PIN0CTRL) as usize] = val,
+            _ => {},
+        }
+
+        self.update_input();
+    }
+
+    fn reset(&mut self) {
+        let ext_driven = self.ext_driven;
+        let ext_level = self.ext_level;
+        let classic = self.classic;
+
+        *self = Port::new(&self.name, self.base, self.int0_vector, self.ev_source);
+        self.classic = classic;
+        self.ext_driven = ext_driven;
+        self.ext_level = ext_level;
+        self.last_in = self.input_value();
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let int0_level = self.intctrl & 0x3;
+        let int1_level = (self.intctrl >> 2) & 0x3;
+
+        let int0 =
+            if (self.intflags & 0x01) != 0 && int0_level != 0 {
+                Some((self.int0_vector, int0_level))
+            } else {
+                None
+            };
+        let int1 =
+            if (self.intflags & 0x02) != 0 && int1_level != 0 {
+                Some((self.int0_vector + 1, int1_level))
+            } else {
+                None
+            };
+
+        match (int0, int1) {
+            (Some(a), Some(b)) => Some(if b.1 > a.1 { b } else { a }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        if (self.intctrl & 0x3) != 0 {
+            out.push(self.int0_vector);
+        }
+        if (self.intctrl & 0xc) != 0 {
+            out.push(self.int0_vector + 1);
+        }
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        if vector == self.int0_vector {
+            self.intflags &= !0x01;
+        } else if vector == self.int0_vector + 1 {
+            self.intflags &= !0x02;
+        }
+    }
+
+    fn poll_events(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.events);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.dir, self.out, self.intctrl, self.int0mask, self.int1mask,
+            self.intflags, self.remap, self.ext_driven, self.ext_level,
+            self.last_in,
+        ];
+        state.extend_from_slice(&self.pinctrl);
+        state.extend_from_slice(&self.events);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 18 {
+            return;
+        }
+
+        self.dir = state[0];
+        self.out = state[1];
+        self.intctrl = state[2];
+        self.int0mask = state[3];
+        self.int1mask = state[4];
+        self.intflags = state[5];
+        self.remap = state[6];
+        self.ext_driven = state[7];
+        self.ext_level = state[8];
+        self.last_in = state[9];
+        self.pinctrl.copy_from_slice(&state[10..18]);
+        self.events = state[18..].to_vec();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the atxmega128a4u's ports
+pub fn default_ports() -> Vec<Port> {
+    vec![
+        Port::new("PORTA", 0x0600, 66, Some(0x50)),
+        Port::new("PORTB", 0x0620, 34, Some(0x58)),
+        Port::new("PORTC", 0x0640, 2, Some(0x60)),
+        Port::new("PORTD", 0x0660, 64, Some(0x68)),
+        Port::new("PORTE", 0x0680, 43, Some(0x70)),
+        Port::new("PORTR", 0x07E0, 4, None),
+    ]
+}
+
+/// the atmega328p's ports
+pub fn atmega328p_ports() -> Vec<Port> {
+    vec![
+        Port::classic("PORTB", 0x0023),
+        Port::classic("PORTC", 0x0026),
+        Port::classic("PORTD", 0x0029),
+    ]
+}
+
+/// the attiny85's port
+pub fn attiny85_ports() -> Vec<Port> {
+    vec![
+        Port::classic("PORTB", 0x0036),
+    ]
+}
@@ -0,0 +1,101 @@
+// Data space access counts: per register in IO space, and per page above it
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+
+/// the end of IO space, where per-address counting stops
+pub const IO_END : u32 = 0x1000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AccessCount {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl AccessCount {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+#[derive(Clone)]
+pub struct Heatmap {
+    pub page_size: u32,
+    io: Vec<AccessCount>,
+    /// page number -> counts
+    pages: HashMap<u32, AccessCount>,
+}
+
+impl Heatmap {
+    /// `page_size` must be a power of 2
+    pub fn new(page_size: u32) -> Heatmap {
+        Heatmap {
+            page_size,
+            io: vec![Default::default(); IO_END as usize],
+            pages: HashMap::new(),
+        }
+    }
+
+    fn count_mut(&mut self, addr: u32) -> &mut AccessCount {
+        if addr < IO_END {
+            &mut self.io[addr as usize]
+        } else {
+            let page = addr / self.page_size;
+            self.pages.entry(page).or_default()
+        }
+    }
+
+    pub fn read(&mut self, addr: u32) {
+        self.count_mut(addr).reads += 1;
+    }
+
+    pub fn write(&mut self, addr: u32) {
+        self.count_mut(addr).writes += 1;
+    }
+
+    /// (IO address, counts), busiest first
+    pub fn io_registers(&self) -> Vec<(u32, AccessCount)> {
+        let mut regs: Vec<_> = self.io
+            .iter()
+            .enumerate()
+            .filter(|&(_, c)| c.total() != 0)
+            .map(|(addr, &c)| (addr as u32, c))
+            .collect();
+        regs.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(&b.0)));
+        regs
+    }
+
+    /// (page start address, counts), busiest first
+    pub fn pages(&self) -> Vec<(u32, AccessCount)> {
+        let mut pages: Vec<_> = self.pages
+            .iter()
+            .map(|(&page, &c)| (page * self.page_size, c))
+            .collect();
+        pages.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(&b.0)));
+        pages
+    }
+
+    /// the `limit` busiest IO registers and memory pages
+    pub fn report(&self, limit: usize) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "{:<18} {:>12} {:>12}", "IO register", "reads", "writes")
+            .unwrap();
+        for (addr, c) in self.io_registers().into_iter().take(limit) {
+            writeln!(out, "{:<#18x} {:>12} {:>12}", addr, c.reads, c.writes)
+                .unwrap();
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "{:<18} {:>12} {:>12}", "memory page", "reads", "writes")
+            .unwrap();
+        for (start, c) in self.pages().into_iter().take(limit) {
+            let range = format!("{:#x}-{:#x}", start, start + self.page_size - 1);
+            writeln!(out, "{:<18} {:>12} {:>12}", range, c.reads, c.writes)
+                .unwrap();
+        }
+
+        out
+    }
+}
@@ -0,0 +1,80 @@
+// Hex+ASCII dumps of memory, like `hexdump -C`, and memory saved to files
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+const BYTES_PER_LINE : usize = 16;
+
+/// `data`, which starts at address `base`, 16 bytes per line with the
+/// address in front and printable characters at the end
+pub fn hexdump(base: u32, data: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+        write!(out, "{:08x} ", base as usize + i * BYTES_PER_LINE).unwrap();
+        for j in 0..BYTES_PER_LINE {
+            // an extra space between the two halves
+            if j == BYTES_PER_LINE / 2 {
+                out.push(' ');
+            }
+            match line.get(j) {
+                Some(b) => write!(out, " {:02x}", b).unwrap(),
+                None => out.push_str("   "),
+            }
+        }
+
+        let text: String = line.iter()
+            .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+            .collect();
+        writeln!(out, "  |{}|", text).unwrap();
+    }
+
+    out
+}
+
+/// `data`, which starts at address `base`, as Intel HEX, with extended
+/// linear address records past the first 64 KiB
+pub fn intel_hex(base: u32, data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut upper = 0;
+
+    let mut ofs = 0;
+    while ofs < data.len() {
+        let addr = base + ofs as u32;
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            record(&mut out, 0, 4, &[(upper >> 8) as u8, upper as u8]);
+        }
+
+        // records can't cross into the next 64 KiB
+        let len = BYTES_PER_LINE.min(data.len() - ofs).min(0x10000 - (addr & 0xffff) as usize);
+        record(&mut out, addr as u16, 0, &data[ofs..ofs + len]);
+        ofs += len;
+    }
+
+    record(&mut out, 0, 1, &[]);
+    out
+}
+
+fn record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut sum = data.len() as u8;
+    sum = sum.wrapping_add((addr >> 8) as u8).wrapping_add(addr as u8).wrapping_add(kind);
+    write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, kind).unwrap();
+    for &b in data {
+        write!(out, "{:02X}", b).unwrap();
+        sum = sum.wrapping_add(b);
+    }
+    writeln!(out, "{:02X}", sum.wrapping_neg()).unwrap();
+}
+
+/// write `data` to `path`: as Intel HEX if it ends with .hex or .ihex,
+/// otherwise as is
+pub fn save(path: &Path, base: u32, data: &[u8]) -> io::Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("hex") | Some("ihex") => fs::write(path, intel_hex(base, data)),
+        _ => fs::write(path, data),
+    }
+}
@@ -0,0 +1,101 @@
+// The last few executed instructions, for crash reports
+//
+// A state dump shows where the firmware crashed, but rarely how it got
+// there. With history enabled, the emulator keeps the last N instructions,
+// with the registers they changed, and Emulator::crash_report() lists them
+// before the state at the fault.
+
+use std::collections::VecDeque;
+use std::collections::vec_deque::Iter;
+use std::fmt::Write;
+use disa::AvrInsn;
+use symbols::SymbolTable;
+use trace::fmt_sreg;
+
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    /// number of instructions executed before this one
+    pub insn_count: u64,
+    /// byte address
+    pub pc: u32,
+    pub insn: AvrInsn,
+    pub skipped: bool,
+    /// after the instruction
+    pub regs: [u8; 32],
+    pub sp: u16,
+    pub sreg: u8,
+}
+
+#[derive(Clone)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    size: usize,
+}
+
+impl History {
+    pub fn new(size: usize) -> History {
+        History {
+            entries: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.size == 0 {
+            return;
+        }
+        if self.entries.len() == self.size {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// oldest first
+    pub fn entries(&self) -> Iter<'_, HistoryEntry> {
+        self.entries.iter()
+    }
+
+    /// one line per instruction, with the registers, SP and SREG it
+    /// changed. the oldest one has nothing to compare to, so it shows no
+    /// changes.
+    pub fn fmt(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        let mut prev: Option<&HistoryEntry> = None;
+
+        for e in &self.entries {
+            let location = match symbols.lookup(e.pc) {
+                Some(_) => format!("<{}>", symbols.fmt_addr(e.pc)),
+                None => String::new(),
+            };
+            write!(out, "{:>10} {:#07x} {:<24} {:?}", e.insn_count, e.pc, location, e.insn)
+                .unwrap();
+            if e.skipped {
+                write!(out, " (skipped)").unwrap();
+            }
+
+            if let Some(p) = prev {
+                for r in 0..32 {
+                    if e.regs[r] != p.regs[r] {
+                        write!(out, " r{}={:02x}", r, e.regs[r]).unwrap();
+                    }
+                }
+                if e.sp != p.sp {
+                    write!(out, " sp={:#06x}", e.sp).unwrap();
+                }
+                if e.sreg != p.sreg {
+                    write!(out, " sreg={}", fmt_sreg(e.sreg)).unwrap();
+                }
+            }
+            writeln!(out).unwrap();
+
+            prev = Some(e);
+        }
+
+        out
+    }
+}
@@ -0,0 +1,372 @@
+// Host calls ("semihosting"): a register block in unused IO space through
+// which firmware can ask the emulator for host services
+//
+// Firmware fills in the argument registers and then writes a command to CMD.
+// Calls need the whole data space, so like DMA transfers they are carried out
+// by IOMemory, before the next instruction. After that, STATUS holds 0 or an
+// error code, and RESULT (32 bits, little-endian) the call's result.
+//
+// Commands; pointers are data space addresses:
+//   WRITE_STDERR  ARG0 = buffer, ARG1 = length, passed on to the diagnostics
+//                 sink. RESULT = bytes written
+//   READ_FILE     ARG0 = NUL-terminated path, ARG1 = buffer, ARG2 = buffer
+//                 size. RESULT = bytes read. Paths are relative to the
+//                 directory the host allowed; without one, this fails.
+//   TIME          RESULT = seconds since the Unix epoch
+//   EXIT          stop the emulator; the low byte of ARG0 is the exit code
+//   DATETIME      ARG0 = 8-byte buffer for the UTC date and time: year (16
+//                 bits), month (1-12), day (1-31), hour, minute, second, and
+//                 day of the week (0 is Sunday). RESULT = what TIME returns.
+//
+// The time is the host's, or a simulated clock that starts at a given date
+// and runs with emulated time, so runs are repeatable.
+
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use iomem::IOMemory;
+
+
+/// in the reserved part of the IO space
+pub const HOSTCALL_BASE : u32 = 0x0FC0;
+
+// register offsets
+const CMD : u32 = 0x00;
+const STATUS : u32 = 0x01;
+const ARG0 : u32 = 0x02;
+const ARG2_END : u32 = 0x07;
+const RESULT : u32 = 0x08;
+const RESULT_END : u32 = 0x0B;
+
+// commands
+pub const CMD_WRITE_STDERR : u8 = 0x01;
+pub const CMD_READ_FILE : u8 = 0x02;
+pub const CMD_TIME : u8 = 0x03;
+pub const CMD_EXIT : u8 = 0x04;
+pub const CMD_DATETIME : u8 = 0x05;
+
+// STATUS values
+pub const STATUS_OK : u8 = 0x00;
+pub const STATUS_BAD_CMD : u8 = 0x01;
+pub const STATUS_IO_ERROR : u8 = 0x02;
+pub const STATUS_DENIED : u8 = 0x03;
+pub const STATUS_BAD_ADDR : u8 = 0x04;
+
+/// longest READ_FILE path
+const MAX_PATH_LEN : u16 = 256;
+
+const SECS_PER_DAY : u64 = 24 * 60 * 60;
+
+
+/// where TIME and DATETIME get the time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostClock {
+    Host,
+    /// starts at this many seconds since the Unix epoch at power-on, and
+    /// runs with emulated time
+    Simulated(u64),
+}
+
+/// (year, month, day) of day `days` since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    // shift the epoch to 0000-03-01, so leap days end the year
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// days since 1970-01-01 of a date
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// seconds since the Unix epoch of a UTC date and time like
+/// "2024-02-29T13:45:00", or just a date
+pub fn parse_datetime(s: &str) -> Option<u64> {
+    let mut parts = s.splitn(2, 'T');
+    let date: Vec<&str> = parts.next()?.split('-').collect();
+    let time: Vec<&str> = parts.next().unwrap_or("0:0:0").split(':').collect();
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+
+    let year = date[0].parse().ok().filter(|&y| y >= 1970)?;
+    let month = date[1].parse().ok().filter(|&m| (1..=12).contains(&m))?;
+    let day: u8 = date[2].parse().ok().filter(|&d| d >= 1)?;
+    let hour: u64 = time[0].parse().ok().filter(|&h| h < 24)?;
+    let minute: u64 = time[1].parse().ok().filter(|&m| m < 60)?;
+    let second: u64 = time[2].parse().ok().filter(|&s| s < 60)?;
+
+    // reject e.g. February 30th
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    Some(days as u64 * SECS_PER_DAY + hour * 3600 + minute * 60 + second)
+}
+
+/// DATETIME's buffer for `secs` since the Unix epoch
+fn datetime_bytes(secs: u64) -> [u8; 8] {
+    let days = (secs / SECS_PER_DAY) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let time = secs % SECS_PER_DAY;
+    // 1970-01-01 was a Thursday
+    let weekday = ((days + 4) % 7) as u8;
+    [year as u8, (year >> 8) as u8, month, day,
+     (time / 3600) as u8, (time / 60 % 60) as u8, (time % 60) as u8, weekday]
+}
+
+
+#[derive(Clone)]
+pub struct HostCalls {
+    base: u32,
+    /// READ_FILE may only read files under here
+    pub files_root: Option<PathBuf>,
+    pub clock: HostClock,
+
+    pub args: [u16; 3],
+    pub status: u8,
+    pub result: u32,
+    /// command written by the firmware, not yet carried out
+    pending: Option<u8>,
+    /// exit code from EXIT, for the emulator to pick up
+    exit_code: Option<u8>,
+}
+
+fn io_status(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => STATUS_DENIED,
+        _ => STATUS_IO_ERROR,
+    }
+}
+
+impl HostCalls {
+    pub fn new(base: u32) -> HostCalls {
+        HostCalls {
+            base,
+            files_root: None,
+            clock: HostClock::Host,
+
+            args: [0; 3],
+            status: STATUS_OK,
+            result: 0,
+            pending: None,
+            exit_code: None,
+        }
+    }
+
+    /// the exit code, if the firmware asked to exit
+    pub fn take_exit(&mut self) -> Option<u8> {
+        self.exit_code.take()
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// carry out the pending command, if any
+    pub fn run(&mut self, io: &mut IOMemory) {
+        let cmd = match self.pending.take() {
+            Some(cmd) => cmd,
+            None => return,
+        };
+
+        let res = match cmd {
+            CMD_WRITE_STDERR => self.write_stderr(io),
+            CMD_READ_FILE => self.read_file(io),
+            CMD_TIME => Ok(self.now(io) as u32),
+            CMD_DATETIME => self.datetime(io),
+            CMD_EXIT => {
+                self.exit_code = Some(self.args[0] as u8);
+                Ok(0)
+            },
+            _ => Err(STATUS_BAD_CMD),
+        };
+
+        match res {
+            Ok(result) => {
+                self.status = STATUS_OK;
+                self.result = result;
+            },
+            Err(status) => {
+                self.status = status;
+                self.result = 0;
+            },
+        }
+    }
+
+    /// seconds since the Unix epoch
+    fn now(&self, io: &IOMemory) -> u64 {
+        match self.clock {
+            HostClock::Host => SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            HostClock::Simulated(start) => {
+                let elapsed = io.clock().map_or(Duration::from_secs(0), |clk| clk.elapsed());
+                start + elapsed.as_secs()
+            },
+        }
+    }
+
+    fn datetime(&mut self, io: &mut IOMemory) -> Result<u32, u8> {
+        let now = self.now(io);
+        for (i, &b) in datetime_bytes(now).iter().enumerate() {
+            io.set8(self.args[0] as u32 + i as u32, b, &"<host call>", 0)
+                .map_err(|_| STATUS_BAD_ADDR)?;
+        }
+        Ok(now as u32)
+    }
+
+    fn read_mem(io: &mut IOMemory, addr: u16, len: u16)
+            -> Result<Vec<u8>, u8> {
+        (0..len)
+            .map(|i| io.get8(addr as u32 + i as u32, &"<host call>", 0)
+                 .map_err(|_| STATUS_BAD_ADDR))
+            .collect()
+    }
+
+    fn write_stderr(&mut self, io: &mut IOMemory) -> Result<u32, u8> {
+        let data = HostCalls::read_mem(io, self.args[0], self.args[1])?;
+
+        io.diag.guest_stderr(&data);
+        Ok(data.len() as u32)
+    }
+
+    /// `path` under `files_root`, as long as it doesn't try to leave it
+    fn host_path(&self, path: &str) -> Result<PathBuf, u8> {
+        let root = match self.files_root {
+            Some(ref root) => root,
+            None => return Err(STATUS_DENIED),
+        };
+
+        let path = Path::new(path);
+        let inside = path.components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !inside {
+            return Err(STATUS_DENIED);
+        }
+
+        Ok(root.join(path))
+    }
+
+    fn read_file(&mut self, io: &mut IOMemory) -> Result<u32, u8> {
+        let mut name = vec![];
+        for i in 0..MAX_PATH_LEN {
+            match HostCalls::read_mem(io, self.args[0].wrapping_add(i), 1)?[0] {
+                0 => break,
+                b => name.push(b),
+            }
+        }
+        let name = String::from_utf8(name).map_err(|_| STATUS_IO_ERROR)?;
+        let path = self.host_path(&name)?;
+
+        let mut data = vec![];
+        File::open(path)
+            .and_then(|f| f.take(self.args[2] as u64).read_to_end(&mut data))
+            .map_err(|e| io_status(&e))?;
+
+        for (i, &b) in data.iter().enumerate() {
+            io.set8(self.args[1] as u32 + i as u32, b, &"<host call>", 0)
+                .map_err(|_| STATUS_BAD_ADDR)?;
+        }
+        Ok(data.len() as u32)
+    }
+}
+
+impl Peripheral for HostCalls {
+    fn name(&self) -> &str {
+        "HOST"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x10)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CMD => self.pending.unwrap_or(0),
+            STATUS => self.status,
+            ARG0..=ARG2_END => {
+                let arg = self.args[((ofs - ARG0) / 2) as usize];
+                (arg >> (8 * ((ofs - ARG0) % 2))) as u8
+            },
+            RESULT..=RESULT_END => (self.result >> (8 * (ofs - RESULT))) as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CMD => self.pending = Some(val),
+            ARG0..=ARG2_END => {
+                let shift = 8 * ((ofs - ARG0) % 2);
+                let arg = &mut self.args[((ofs - ARG0) / 2) as usize];
+                *arg = (*arg & !(0xff << shift)) | ((val as u16) << shift);
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.args = [0; 3];
+        self.status = STATUS_OK;
+        self.result = 0;
+        self.pending = None;
+        self.exit_code = None;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        for &arg in self.args.iter() {
+            state.write_u16::<LittleEndian>(arg).unwrap();
+        }
+        state.push(self.status);
+        state.write_u32::<LittleEndian>(self.result).unwrap();
+        state.push(self.pending.unwrap_or(0));
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        let mut r = state;
+        for arg in self.args.iter_mut() {
+            *arg = r.read_u16::<LittleEndian>().unwrap_or(0);
+        }
+        self.status = r.read_u8().unwrap_or(STATUS_OK);
+        self.result = r.read_u32::<LittleEndian>().unwrap_or(0);
+        self.pending = match r.read_u8().unwrap_or(0) {
+            0 => None,
+            cmd => Some(cmd),
+        };
+        self.exit_code = None;
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
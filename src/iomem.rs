@@ -1,11 +1,50 @@
 use disa::{X_L, Y_L, Z_L};
 use registers::RegisterFile;
 use sreg::SReg;
+use error::{Error, Result};
+use diag::{NullSink, SharedSink};
+use nvm::{NvmController, NVM_BASE, NVM_SIZE};
+use peripheral::Peripheral;
+use wiring::PinDevice;
+use gpio::{atmega328p_ports, attiny85_ports, default_ports, PinState, Port};
+use timer::{default_timers, TimerCounter};
+use megatimer::{atmega328p_timers, attiny85_timers};
+use adc::Adc;
+use spi::{default_spis, Spi};
+use twi::{default_twis, Twi};
+use usart::{atmega328p_usarts, default_usarts, Usart};
+use hostcall::HostCalls;
+use usb::Usb;
+use aes::Aes;
+use crc::Crc;
+use rtc::Rtc;
+use dma::Dma;
+use evsys::Evsys;
+use pmic::Pmic;
+use rst::ResetController;
+use sleep::{SleepController, SleepMode, SLEEP_BASE};
+use clk::Clock;
+use heatmap::{Heatmap, IO_END};
+use protect::WriteProtect;
+use device::{Device, ATXMEGA128A4U};
+use isa::Isa;
+use sched::Scheduler;
+use meminit::MemInit;
+use std::any::Any;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+#[cfg(unix)]
+use pty::Pty;
 
 
 // TODO: chip-specific?
 
-// iox128a4u.h
+// iox128a4u.h. the CPU registers are IO addresses, the same on every AVR;
+// see port_addr() for where they are in data space.
+pub const CCP : u32 = 0x0034;
 pub const RAMPD : u32 = 0x0038;
 pub const RAMPX : u32 = 0x0039;
 pub const RAMPY : u32 = 0x003A;
@@ -15,75 +54,422 @@ pub const SPL : u32 = 0x003D;
 pub const SPH : u32 = 0x003E;
 pub const SREG : u32 = 0x003F;
 
-pub const OSC : u32 = 0x50;
+pub const MCU : u32 = 0x90;
 
 pub const USART_C0 : u32 = 0x08A0;
 
 
+/// the atxmega128a4u's built-in peripherals
+pub fn default_peripherals() -> Vec<Box<dyn Peripheral>> {
+    let mut peripherals: Vec<Box<dyn Peripheral>> = vec![
+        // in the gap in CLK's range, so it has to come first
+        Box::new(SleepController::new(SLEEP_BASE)),
+        Box::new(Clock::new()),
+        Box::new(Aes::new(0x00C0, 31)),
+        Box::new(Crc::new(0x00D0)),
+        Box::new(Dma::new()),
+        Box::new(Evsys::new()),
+        Box::new(Pmic::new()),
+        Box::new(ResetController::new()),
+        Box::new(Rtc::new(0x0400, 10)),
+    ];
+
+    for port in default_ports() {
+        peripherals.push(Box::new(port));
+    }
+
+    for timer in default_timers() {
+        peripherals.push(Box::new(timer));
+    }
+
+    peripherals.push(Box::new(Adc::new("ADCA", 0x0200, 71, 0x10, 0x20)));
+
+    for spi in default_spis() {
+        peripherals.push(Box::new(spi));
+    }
+
+    for twi in default_twis() {
+        peripherals.push(Box::new(twi));
+    }
+
+    for usart in default_usarts() {
+        peripherals.push(Box::new(usart));
+    }
+
+    peripherals.push(Box::new(Usb::new(0x04C0, 125)));
+
+    peripherals
+}
+
+/// the atmega328p's: the Arduino Uno's ports, timers and serial port
+pub fn atmega328p_peripherals() -> Vec<Box<dyn Peripheral>> {
+    let mut peripherals: Vec<Box<dyn Peripheral>> = vec![
+        // SMCR
+        Box::new(SleepController::new(0x0053)),
+    ];
+
+    for port in atmega328p_ports() {
+        peripherals.push(Box::new(port));
+    }
+
+    for timer in atmega328p_timers() {
+        peripherals.push(Box::new(timer));
+    }
+
+    for usart in atmega328p_usarts() {
+        peripherals.push(Box::new(usart));
+    }
+
+    peripherals
+}
+
+/// the attiny85's: its port and Timer0. there's no USART to talk to the
+/// host through.
+pub fn attiny85_peripherals() -> Vec<Box<dyn Peripheral>> {
+    let mut peripherals: Vec<Box<dyn Peripheral>> = vec![
+        Box::new(SleepController::mcucr(0x0055)),
+    ];
+
+    for port in attiny85_ports() {
+        peripherals.push(Box::new(port));
+    }
+
+    for timer in attiny85_timers() {
+        peripherals.push(Box::new(timer));
+    }
+
+    peripherals
+}
+
+
+/// a pointer register, with a RAMP register for its top byte on devices
+/// with more than 64 KiB of data space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PtrReg {
+    X,
+    Y,
+    Z,
+}
+
+impl PtrReg {
+    /// the pointer register whose low byte is register `reg`
+    pub fn from_reg(reg: u8) -> Option<PtrReg> {
+        match reg {
+            26 => Some(PtrReg::X),
+            28 => Some(PtrReg::Y),
+            30 => Some(PtrReg::Z),
+            _ => None,
+        }
+    }
+
+    /// the number of its low byte's register
+    pub fn low(self) -> u8 {
+        match self {
+            PtrReg::X => X_L.0,
+            PtrReg::Y => Y_L.0,
+            PtrReg::Z => Z_L.0,
+        }
+    }
+
+    fn ramp(self) -> u32 {
+        match self {
+            PtrReg::X => RAMPX,
+            PtrReg::Y => RAMPY,
+            PtrReg::Z => RAMPZ,
+        }
+    }
+}
+
+
+/// an access to IO space, see IOMemory::io_accesses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoAccess {
+    pub addr: u32,
+    pub val: u8,
+    pub write: bool,
+}
+
 pub struct IOMemory {
     pub regs: RegisterFile,
     pub sreg: SReg,
 
     pub data_mem: Vec<u8>,
+    /// see Device::isa
+    pub isa: Isa,
+    /// see Device::io_offset
+    pub io_offset: u32,
+    /// the stack has to stay in [sram_start, data_mem.len())
+    pub sram_start: u32,
+    /// see Device::ramp_regs
+    pub ramp_regs: &'static [u32],
+    /// see Device::host_usart
+    pub host_usart: Option<&'static str>,
+    /// what SRAM and the registers hold after a power-on reset
+    pub mem_init: MemInit,
 
-    pub usart_input: Vec<u8>,
+    pub usart_input: VecDeque<u8>,
     pub usart_output_log: Vec<u8>,
+    #[cfg(unix)]
+    pub uart_pty: Option<Pty>,
+    /// whether to take USART input from the outside world (i.e. the pty)
+    pub uart_live_input: bool,
+    /// bytes taken from the outside world, for the emulator to record
+    pub uart_rx_received: Vec<u8>,
+    /// don't check the pty for input again before this cycle
+    uart_next_poll: u64,
+
+    pub nvm: NvmController,
+
+    /// data space accesses, including the stack and DMA transfers
+    pub data_reads: u64,
+    pub data_writes: u64,
+    /// per-address access counts, when enabled
+    pub heatmap: Option<Heatmap>,
+    /// IO space accesses since this was last emptied, when enabled
+    pub io_accesses: Option<Vec<IoAccess>>,
+    /// regions firmware isn't allowed to write to, e.g. mapped flash
+    pub write_protect: WriteProtect,
+    /// writing here means "exit with the value written as exit code"
+    pub exit_addr: Option<u32>,
+    pub exit_request: Option<u8>,
+
+    pub peripherals: Vec<Box<dyn Peripheral>>,
+    /// devices wired to the GPIO pins
+    pub pin_devices: Vec<Box<dyn PinDevice>>,
+    /// peripherals' next events, by index in `peripherals`
+    pub schedule: Scheduler,
+    /// the cycle peripherals were last ticked at
+    last_tick: u64,
+    /// the peripherals to tick this time, kept to save allocating
+    due: Vec<usize>,
+    /// interrupt vectors raised from the host side
+    pub injected_interrupts: Vec<u8>,
+    /// the mode the CPU is sleeping in, if it is. peripherals whose clock
+    /// stops in it aren't ticked.
+    pub sleep_mode: Option<SleepMode>,
+
+    pub diag: SharedSink,
+}
+
+// the pty stays with the original
+impl Clone for IOMemory {
+    fn clone(&self) -> IOMemory {
+        IOMemory {
+            regs: self.regs.clone(),
+            sreg: self.sreg.clone(),
+            data_mem: self.data_mem.clone(),
+            isa: self.isa,
+            io_offset: self.io_offset,
+            sram_start: self.sram_start,
+            ramp_regs: self.ramp_regs,
+            host_usart: self.host_usart,
+            mem_init: self.mem_init,
+
+            usart_input: self.usart_input.clone(),
+            usart_output_log: self.usart_output_log.clone(),
+            #[cfg(unix)]
+            uart_pty: None,
+            uart_live_input: self.uart_live_input,
+            uart_rx_received: self.uart_rx_received.clone(),
+            uart_next_poll: self.uart_next_poll,
+
+
+            nvm: self.nvm.clone(),
+
+            data_reads: self.data_reads,
+            data_writes: self.data_writes,
+            heatmap: self.heatmap.clone(),
+            io_accesses: self.io_accesses.clone(),
+            write_protect: self.write_protect.clone(),
+            exit_addr: self.exit_addr,
+            exit_request: self.exit_request,
+
+            peripherals: self.peripherals.clone(),
+            pin_devices: self.pin_devices.clone(),
+            schedule: self.schedule.clone(),
+            last_tick: self.last_tick,
+            due: vec![],
+            injected_interrupts: self.injected_interrupts.clone(),
+            sleep_mode: self.sleep_mode,
+
+            diag: self.diag.clone(),
+        }
+    }
+}
 
-    pub rtc_cnt : u16,
+impl Default for IOMemory {
+    fn default() -> IOMemory {
+        IOMemory::new()
+    }
 }
 
 impl IOMemory {
     pub fn new() -> IOMemory {
+        IOMemory::for_device(&ATXMEGA128A4U)
+    }
+
+    /// data space sized for `device`
+    pub fn for_device(device: &Device) -> IOMemory {
         IOMemory {
             regs: RegisterFile::new(),
             sreg: SReg::new(),
-            data_mem: vec![0; 1 << 22],
+            data_mem: vec![0; device.data_size()],
+            isa: device.isa,
+            io_offset: device.io_offset,
+            sram_start: device.sram_start,
+            ramp_regs: device.ramp_regs,
+            host_usart: device.host_usart,
+            mem_init: MemInit::Fill(0),
 
-            usart_input: vec![],
+            usart_input: VecDeque::new(),
             usart_output_log: vec![],
+            #[cfg(unix)]
+            uart_pty: None,
+            uart_live_input: true,
+            uart_rx_received: vec![],
+            uart_next_poll: 0,
+
+
+            nvm: NvmController::for_page_size(device.flash_page_size),
+
+            data_reads: 0,
+            data_writes: 0,
+            heatmap: None,
+            io_accesses: None,
+            write_protect: WriteProtect::new(),
+            exit_addr: None,
+            exit_request: None,
+
+            peripherals: (device.peripherals)(),
+            pin_devices: vec![],
+            schedule: Scheduler::new(),
+            last_tick: 0,
+            due: vec![],
+            injected_interrupts: vec![],
+            sleep_mode: None,
 
-            rtc_cnt: 0,
+            diag: Arc::new(NullSink),
         }
     }
 
+    /// return IO registers and peripherals to their reset values. a power-on
+    /// reset also clears the registers, SRAM and USART queues. the pty and
+    /// anything the host attached to peripherals stay.
+    pub fn reset(&mut self, power_on: bool) {
+        self.sreg = SReg::new();
+
+        if power_on {
+            // IO registers and EEPROM are zeroed, the rest is up to mem_init
+            let sram_start = self.sram_start as usize;
+            for b in self.data_mem[..sram_start].iter_mut() {
+                *b = 0;
+            }
+
+            let mut pattern = self.mem_init.bytes();
+            self.regs = RegisterFile::new();
+            for (b, val) in self.regs.r.iter_mut().zip(&mut pattern) {
+                *b = val;
+            }
+            for (b, val) in self.data_mem[sram_start..].iter_mut().zip(&mut pattern) {
+                *b = val;
+            }
+
+            self.usart_input.clear();
+            self.usart_output_log.clear();
+            self.uart_rx_received.clear();
+        } else {
+            // IO space only
+            let io_end = self.io_end() as usize;
+            for b in self.data_mem[..io_end].iter_mut() {
+                *b = 0;
+            }
+        }
+
+        self.uart_next_poll = 0;
+        self.exit_request = None;
+        self.nvm.reset();
+        self.injected_interrupts.clear();
+        self.schedule.clear();
+        if power_on {
+            self.last_tick = 0;
+        }
+        self.sleep_mode = None;
+
+        for p in self.peripherals.iter_mut() {
+            p.reset();
+        }
+    }
+
+    // these are only used for the CPU registers in the IO space, but a
+    // restored state could have a truncated data_mem
     fn _get8(&self, addr: u32) -> u8 {
-        self.data_mem[addr as usize]
+        self.data_mem.get(addr as usize).cloned().unwrap_or(0)
     }
 
     fn _set8(&mut self, addr: u32, val: u8) {
-        self.data_mem[addr as usize] = val;
+        if let Some(p) = self.data_mem.get_mut(addr as usize) {
+            *p = val;
+        }
+    }
+
+    /// the data space address of IO address `port`, e.g. for IN/OUT
+    pub fn port_addr(&self, port: u32) -> u32 {
+        port + self.io_offset
+    }
+
+    /// SPL, SPH, or an extended addressing register the device has
+    fn is_cpu_reg(&self, addr: u32) -> bool {
+        addr == self.port_addr(SPL) || addr == self.port_addr(SPH)
+            || self.ramp_regs.iter().any(|&port| addr == self.port_addr(port))
+    }
+
+    // extended addressing registers the device doesn't have read as 0 and
+    // ignore writes
+    fn get_ramp(&self, port: u32) -> u8 {
+        if self.ramp_regs.contains(&port) {
+            self._get8(self.port_addr(port))
+        } else {
+            0
+        }
+    }
+
+    fn set_ramp(&mut self, port: u32, val: u8) {
+        if self.ramp_regs.contains(&port) {
+            let addr = self.port_addr(port);
+            self._set8(addr, val);
+        }
     }
 
     pub fn get_rampd(&self) -> u8 {
-        self._get8(RAMPD)
+        self.get_ramp(RAMPD)
     }
 
     pub fn get_rampx(&self) -> u8 {
-        self._get8(RAMPX)
+        self.get_ramp(RAMPX)
     }
 
     pub fn get_rampy(&self) -> u8 {
-        self._get8(RAMPY)
+        self.get_ramp(RAMPY)
     }
 
     pub fn get_rampz(&self) -> u8 {
-        self._get8(RAMPZ)
+        self.get_ramp(RAMPZ)
     }
 
     pub fn get_eind(&self) -> u8 {
-        self._get8(EIND)
+        self.get_ramp(EIND)
     }
 
     pub fn set_rampx(&mut self, val: u8) {
-        self._set8(RAMPX, val);
+        self.set_ramp(RAMPX, val);
     }
 
     pub fn set_rampy(&mut self, val: u8) {
-        self._set8(RAMPY, val);
+        self.set_ramp(RAMPY, val);
     }
 
     pub fn set_rampz(&mut self, val: u8) {
-        self._set8(RAMPZ, val);
+        self.set_ramp(RAMPZ, val);
     }
 
     pub fn get_full_x(&self) -> u32 {
@@ -106,12 +492,11 @@ impl IOMemory {
             | (self.regs.get16(Z_L.0) as u32)
     }
 
-    pub fn get_full_reg(&self, reg: u8) -> u32 {
+    pub fn get_full_reg(&self, reg: PtrReg) -> u32 {
         match reg {
-            26 => self.get_full_x(),
-            28 => self.get_full_y(),
-            30 => self.get_full_z(),
-            _ => panic!("bad register {}", reg)
+            PtrReg::X => self.get_full_x(),
+            PtrReg::Y => self.get_full_y(),
+            PtrReg::Z => self.get_full_z(),
         }
     }
 
@@ -130,79 +515,677 @@ impl IOMemory {
         self.set_rampz(((val >> 16) & 0xff) as u8);
     }
 
-    pub fn set_full_reg(&mut self, reg: u8, val: u32) {
+    /// addresses through X, Y or Z are 24 bits on devices with the
+    /// register's RAMP register, and 16 bits without
+    pub fn full_reg_mask(&self, reg: PtrReg) -> u32 {
+        if self.ramp_regs.contains(&reg.ramp()) { 0xffffff } else { 0xffff }
+    }
+
+    pub fn set_full_reg(&mut self, reg: PtrReg, val: u32) {
         match reg {
-            26 => self.set_full_x(val),
-            28 => self.set_full_y(val),
-            30 => self.set_full_z(val),
-            _ => panic!("bad register {}", reg)
+            PtrReg::X => self.set_full_x(val),
+            PtrReg::Y => self.set_full_y(val),
+            PtrReg::Z => self.set_full_z(val),
         }
     }
 
-    pub fn get8(&mut self, addr: u32, call_stack: &str, pc: u32) -> u8 {
-        match addr {
-            // oscillator status = ready
-            0x0051 => 0xff,
-
-            // rtc
-            0x0401 => 0,
-            0x0408 => {
-                self.rtc_cnt += 1000;
-                (self.rtc_cnt & 0xff) as u8
+    pub fn add_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+        self.peripherals.push(peripheral);
+        self.schedule.touch_all();
+    }
+
+    /// the peripheral with a register at `addr`, and the register's offset
+    fn find_peripheral(&self, addr: u32) -> Option<(usize, u32)> {
+        self.peripherals.iter()
+            .enumerate()
+            .filter_map(|(i, p)| p.reg_offset(addr).map(|ofs| (i, ofs)))
+            .next()
+    }
+
+    pub fn peripheral<T: Any>(&self, name: &str) -> Option<&T> {
+        self.peripherals
+            .iter()
+            .find(|p| p.name() == name)
+            .and_then(|p| p.as_any().downcast_ref())
+    }
+
+    pub fn peripheral_mut<T: Any>(&mut self, name: &str) -> Option<&mut T> {
+        let i = self.peripherals.iter().position(|p| p.name() == name)?;
+        // whatever the caller does may move its next event
+        self.schedule.touch(i);
+        self.peripherals[i].as_any_mut().downcast_mut()
+    }
+
+    pub fn attach_pin_device(&mut self, device: Box<dyn PinDevice>) {
+        self.pin_devices.push(device);
+    }
+
+    pub fn pin_device<T: Any>(&self, name: &str) -> Option<&T> {
+        self.pin_devices
+            .iter()
+            .find(|d| d.name() == name)
+            .and_then(|d| d.as_any().downcast_ref())
+    }
+
+    pub fn pin_device_mut<T: Any>(&mut self, name: &str) -> Option<&mut T> {
+        self.pin_devices
+            .iter_mut()
+            .find(|d| d.name() == name)
+            .and_then(|d| d.as_any_mut().downcast_mut())
+    }
+
+    // devices look at and drive pins through the ports, so they're taken
+    // out while they run
+    fn update_pin_devices(&mut self, now: u64) {
+        if self.pin_devices.is_empty() {
+            return;
+        }
+
+        let mut devices = mem::take(&mut self.pin_devices);
+        for d in devices.iter_mut() {
+            d.update(self, now);
+        }
+        self.pin_devices = devices;
+    }
+
+    pub fn port(&self, name: &str) -> Option<&Port> {
+        self.peripheral(name)
+    }
+
+    pub fn port_mut(&mut self, name: &str) -> Option<&mut Port> {
+        self.peripheral_mut(name)
+    }
+
+    pub fn timer(&self, name: &str) -> Option<&TimerCounter> {
+        self.peripheral(name)
+    }
+
+    pub fn timer_mut(&mut self, name: &str) -> Option<&mut TimerCounter> {
+        self.peripheral_mut(name)
+    }
+
+    pub fn adc(&self, name: &str) -> Option<&Adc> {
+        self.peripheral(name)
+    }
+
+    pub fn adc_mut(&mut self, name: &str) -> Option<&mut Adc> {
+        self.peripheral_mut(name)
+    }
+
+    pub fn spi(&self, name: &str) -> Option<&Spi> {
+        self.peripheral(name)
+    }
+
+    pub fn spi_mut(&mut self, name: &str) -> Option<&mut Spi> {
+        self.peripheral_mut(name)
+    }
+
+    pub fn twi(&self, name: &str) -> Option<&Twi> {
+        self.peripheral(name)
+    }
+
+    pub fn twi_mut(&mut self, name: &str) -> Option<&mut Twi> {
+        self.peripheral_mut(name)
+    }
+
+    pub fn usart(&self, name: &str) -> Option<&Usart> {
+        self.peripheral(name)
+    }
+
+    pub fn usart_mut(&mut self, name: &str) -> Option<&mut Usart> {
+        self.peripheral_mut(name)
+    }
+
+    /// the USART the host talks to, if the device has one
+    pub fn host_usart(&self) -> Option<&Usart> {
+        self.usart(self.host_usart?)
+    }
+
+    pub fn host_usart_mut(&mut self) -> Option<&mut Usart> {
+        let name = self.host_usart?;
+        self.usart_mut(name)
+    }
+
+    /// select or deselect SPI slaves according to their chip select pins
+    fn update_spi_chip_selects(&mut self) {
+        let mut updates = vec![];
+
+        for (i, p) in self.peripherals.iter().enumerate() {
+            if let Some(spi) = p.as_any().downcast_ref::<Spi>() {
+                for (j, slave) in spi.slaves.iter().enumerate() {
+                    if let Some((ref port, pin)) = slave.chip_select {
+                        let selected = self.port(port)
+                            .map(|p| p.pin_state(pin) == PinState::Low)
+                            .unwrap_or(false);
+                        if selected != slave.selected {
+                            updates.push((i, j, selected));
+                        }
+                    }
+                }
+            }
+        }
+
+        for (i, j, selected) in updates {
+            if let Some(spi) = self.peripherals[i].as_any_mut().downcast_mut::<Spi>() {
+                spi.set_selected(j, selected);
+                self.schedule.touch(i);
+            }
+        }
+    }
+
+    /// tick the peripherals with something due at cycle `now` (see
+    /// sched.rs), and let them interact
+    pub fn tick_peripherals(&mut self, now: u64) {
+        self.last_tick = now;
+        self.update_spi_chip_selects();
+        self.update_pin_devices(now);
+        self.feed_usart(now);
+        self.update_rtc_clock(now);
+
+        let mut due = mem::take(&mut self.due);
+        self.schedule.take_due(now, self.peripherals.len(), &mut due);
+        for &i in &due {
+            if !self.stopped(i) {
+                self.peripherals[i].tick(now);
+            }
+        }
+
+        self.collect_usart_output();
+        self.route_events(now);
+        self.run_dma();
+        self.run_host_calls();
+        self.run_usb(now);
+
+        for &i in &due {
+            self.reschedule(i);
+        }
+        self.due = due;
+    }
+
+    // whether peripheral `i`'s clock is stopped in the current sleep mode
+    fn stopped(&self, i: usize) -> bool {
+        self.sleep_mode.is_some_and(|mode| self.peripherals[i].stops_in_sleep(mode))
+    }
+
+    fn reschedule(&mut self, i: usize) {
+        let next = if self.stopped(i) { None } else { self.peripherals[i].next_event() };
+        self.schedule.schedule(i, next);
+    }
+
+    /// bring peripheral `i` up to date before the CPU accesses it. it was
+    /// last ticked at its last event, which may have been a while ago.
+    fn catch_up(&mut self, i: usize) {
+        if !self.stopped(i) {
+            self.peripherals[i].tick(self.last_tick);
+        }
+        self.schedule.touch(i);
+    }
+
+    /// the peripherals' states were replaced, e.g. from a snapshot taken at
+    /// cycle `now`; tick and reschedule them all
+    pub fn peripherals_restored(&mut self, now: u64) {
+        self.last_tick = now;
+        self.schedule.clear();
+    }
+
+    /// the CPU went to sleep in `mode`; peripherals whose clock stops in it
+    /// aren't ticked until it wakes up
+    pub fn sleep(&mut self, mode: SleepMode) {
+        self.sleep_mode = Some(mode);
+        self.schedule.touch_all();
+    }
+
+    /// the CPU woke up at cycle `now`; restart the peripherals whose clock
+    /// stopped
+    pub fn wake(&mut self, now: u64) {
+        if let Some(mode) = self.sleep_mode.take() {
+            for (i, p) in self.peripherals.iter_mut().enumerate() {
+                if p.stops_in_sleep(mode) {
+                    p.resume(now);
+                    self.schedule.touch(i);
+                }
+            }
+        }
+    }
+
+    /// the cycle of the next thing peripherals or devices on the pins do
+    /// by themselves, including taking in USART input
+    pub fn next_event_cycle(&mut self) -> Option<u64> {
+        // touched peripherals' events may have moved since they were last
+        // scheduled
+        for i in self.schedule.touched(self.peripherals.len()) {
+            self.reschedule(i);
+        }
+
+        let pin_devices = self.pin_devices.iter().filter_map(|d| d.next_event()).min();
+        self.schedule.earliest().into_iter()
+            .chain(self.uart_poll_due())
+            .chain(pin_devices)
+            .min()
+    }
+
+    // when to check the pty for USART input next, if it's worth checking
+    fn uart_poll_due(&self) -> Option<u64> {
+        #[cfg(unix)]
+        {
+            let ready = self.host_usart().is_some_and(|u| u.ready_to_receive());
+            if ready && self.uart_live_input && self.uart_pty.is_some() {
+                return Some(self.uart_next_poll);
+            }
+        }
+
+        None
+    }
+
+    /// deliver events generated by peripherals through the event system
+    fn route_events(&mut self, now: u64) {
+        let mut sources = vec![];
+        for p in self.peripherals.iter_mut() {
+            p.poll_events(&mut sources);
+        }
+
+        let channels = {
+            let evsys = self.peripherals
+                .iter_mut()
+                .filter_map(|p| p.as_any_mut().downcast_mut::<Evsys>())
+                .next();
+            match evsys {
+                Some(evsys) => evsys.route(&sources, now),
+                None => return,
+            }
+        };
+
+        for channel in channels {
+            for p in self.peripherals.iter_mut() {
+                p.event(channel);
+            }
+            self.schedule.touch_all();
+        }
+    }
+
+    fn run_dma(&mut self) {
+        let i = self.peripherals.iter().position(|p| p.as_any().is::<Dma>());
+        let i = match i {
+            Some(i) => i,
+            None => return,
+        };
+
+        let active = self.peripherals[i].as_any()
+            .downcast_ref::<Dma>()
+            .is_some_and(|dma| dma.active());
+        if !active {
+            return;
+        }
+
+        // take the controller out while it accesses the data space
+        let mut p = self.peripherals.remove(i);
+        p.as_any_mut().downcast_mut::<Dma>().unwrap().run(self);
+        self.peripherals.insert(i, p);
+        // the peripherals it touched were at other indices meanwhile
+        self.schedule.touch_all();
+    }
+
+    fn run_host_calls(&mut self) {
+        let i = self.peripherals.iter().position(|p| p.as_any().is::<HostCalls>());
+        let i = match i {
+            Some(i) => i,
+            None => return,
+        };
+
+        let pending = self.peripherals[i].as_any()
+            .downcast_ref::<HostCalls>()
+            .is_some_and(|host| host.pending());
+        if !pending {
+            return;
+        }
+
+        // like DMA, calls access the data space
+        let mut p = self.peripherals.remove(i);
+        p.as_any_mut().downcast_mut::<HostCalls>().unwrap().run(self);
+        self.peripherals.insert(i, p);
+        self.schedule.touch_all();
+    }
+
+    // the RTC runs on its own clock, so it needs to know the time
+    fn update_rtc_clock(&mut self, now: u64) {
+        let (nanos, cpu_freq, rtc_freq) = match self.clock() {
+            Some(clk) => (clk.nanos_at(now), clk.cpu_freq(), clk.rtc_freq()),
+            None => return,
+        };
+        if let Some(rtc) = self.rtc_mut() {
+            rtc.set_clock(nanos, cpu_freq, rtc_freq);
+        }
+    }
+
+    fn run_usb(&mut self, now: u64) {
+        let i = self.peripherals.iter().position(|p| p.as_any().is::<Usb>());
+        let i = match i {
+            Some(i) => i,
+            None => return,
+        };
+
+        let due = self.peripherals[i].as_any()
+            .downcast_ref::<Usb>()
+            .is_some_and(|usb| usb.due(now));
+        if !due {
+            return;
+        }
+
+        // the endpoint table and buffers are in SRAM
+        let mut p = self.peripherals.remove(i);
+        p.as_any_mut().downcast_mut::<Usb>().unwrap().run(self, now);
+        self.peripherals.insert(i, p);
+        self.schedule.touch_all();
+    }
+
+    /// whether anything requests a DMA transfer for trigger source `trigsrc`
+    pub fn dma_request(&self, trigsrc: u8) -> bool {
+        self.peripherals.iter().any(|p| p.dma_request(trigsrc))
+    }
+
+    pub fn dma_ack(&mut self, trigsrc: u8) {
+        for p in self.peripherals.iter_mut() {
+            p.dma_ack(trigsrc);
+        }
+        self.schedule.touch_all();
+    }
+
+    pub fn host_calls(&self) -> Option<&HostCalls> {
+        self.peripheral("HOST")
+    }
+
+    pub fn host_calls_mut(&mut self) -> Option<&mut HostCalls> {
+        self.peripheral_mut("HOST")
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.peripheral("RTC")
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.peripheral_mut("RTC")
+    }
+
+    pub fn usb(&self) -> Option<&Usb> {
+        self.peripheral("USB")
+    }
+
+    pub fn usb_mut(&mut self) -> Option<&mut Usb> {
+        self.peripheral_mut("USB")
+    }
+
+    pub fn pmic(&self) -> Option<&Pmic> {
+        self.peripheral("PMIC")
+    }
+
+    pub fn pmic_mut(&mut self) -> Option<&mut Pmic> {
+        self.peripheral_mut("PMIC")
+    }
+
+    pub fn clock(&self) -> Option<&Clock> {
+        self.peripheral("CLK")
+    }
+
+    pub fn clock_mut(&mut self) -> Option<&mut Clock> {
+        self.peripheral_mut("CLK")
+    }
+
+    pub fn sleep_controller(&self) -> Option<&SleepController> {
+        self.peripheral("SLEEP")
+    }
+
+    pub fn reset_controller(&self) -> Option<&ResetController> {
+        self.peripheral("RST")
+    }
+
+    pub fn reset_controller_mut(&mut self) -> Option<&mut ResetController> {
+        self.peripheral_mut("RST")
+    }
+
+    /// the interrupt to take now as (vector, level), ignoring SREG.I.
+    /// injected interrupts are high level.
+    ///
+    /// if there's a PMIC, it decides. otherwise, it's the highest level, then
+    /// the lowest vector.
+    pub fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let injected = self.injected_interrupts.iter().map(|&v| (v, 3));
+        let candidates = self.peripherals
+            .iter()
+            .filter_map(|p| p.pending_interrupt())
+            .chain(injected);
+
+        match self.pmic() {
+            Some(pmic) => pmic.select(candidates),
+            None => candidates.max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0))),
+        }
+    }
+
+    /// the vectors of the interrupts the firmware has enabled
+    pub fn enabled_interrupts(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for p in &self.peripherals {
+            p.enabled_interrupts(&mut out);
+        }
+        out
+    }
+
+    pub fn interrupt_taken(&mut self, vector: u8) {
+        if let Some(i) = self.injected_interrupts.iter().position(|&v| v == vector) {
+            self.injected_interrupts.remove(i);
+        }
+
+        for p in self.peripherals.iter_mut() {
+            p.interrupt_taken(vector);
+        }
+        self.schedule.touch_all();
+    }
+
+    /// start the USART receiving the next input byte, once it's done with
+    /// the last one
+    fn feed_usart(&mut self, now: u64) {
+        let frame_cycles = match self.host_usart() {
+            Some(usart) if usart.ready_to_receive() => usart.frame_cycles(),
+            _ => return,
+        };
+
+        // a byte can't arrive faster than a frame anyway
+        if self.usart_input.is_empty() && now >= self.uart_next_poll {
+            self.poll_uart_pty();
+            self.uart_next_poll = now + frame_cycles;
+        }
+
+        if let Some(val) = self.usart_input.pop_front() {
+            self.host_usart_mut().unwrap().receive(val);
+        }
+    }
+
+    /// send bytes the USART finished transmitting to the pty, or the sink
+    fn collect_usart_output(&mut self) {
+        let transmitted = match self.host_usart_mut() {
+            Some(usart) => usart.take_transmitted(),
+            None => return,
+        };
+
+        for val in transmitted {
+            self.usart_output_log.push(val);
+
+            #[cfg(unix)]
+            {
+                if let Some(ref mut pty) = self.uart_pty {
+                    pty.write_byte(val);
+                    continue;
+                }
+            }
+
+            self.diag.uart_output(val);
+        }
+    }
+
+    /// move a byte from the pty (if any) into the USART input queue
+    fn poll_uart_pty(&mut self) {
+        #[cfg(unix)]
+        {
+            if self.uart_live_input && self.usart_input.is_empty() {
+                if let Some(ref mut pty) = self.uart_pty {
+                    if let Some(val) = pty.try_read_byte() {
+                        self.usart_input.push_back(val);
+                        self.uart_rx_received.push(val);
+                    }
+                }
+            }
+        }
+    }
+
+    fn data_get8(&self, addr: u32, pc: u32) -> Result<u8> {
+        match self.data_mem.get(addr as usize) {
+            Some(&val) => Ok(val),
+            None => Err(Error::BadIoAccess { addr, pc }),
+        }
+    }
+
+    fn data_set8(&mut self, addr: u32, val: u8, pc: u32) -> Result<()> {
+        match self.data_mem.get_mut(addr as usize) {
+            Some(p) => {
+                *p = val;
+                Ok(())
             },
-            0x0409 => (self.rtc_cnt >> 8) as u8,
+            None => Err(Error::BadIoAccess { addr, pc }),
+        }
+    }
 
-            0x08a0 => self.usart_input.remove(0),
-            0x08a1 => 0x20 | (if self.usart_input.is_empty() { 0 } else { 0x80 }),
+    pub fn get8(&mut self, addr: u32, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<u8> {
 
+        self.data_reads += 1;
+        if let Some(ref mut heatmap) = self.heatmap {
+            heatmap.read(addr);
+        }
+
+        let val = match addr {
             // simple IO regs
-            0x38...0x3e => self._get8(addr),
+            _ if self.is_cpu_reg(addr) => self._get8(addr),
 
-            SREG => self.sreg.as_u8(),
+            _ if addr == self.port_addr(SREG) => self.sreg.as_u8(),
 
             // data memory
-            0x2000...0x1000000 => self._get8(addr),
+            _ if addr >= self.sram_start => self.data_get8(addr, pc)?,
 
-            _ => {
-                println!("TODO: io read from {:#x} @ {}; {:#x}",
-                    addr, call_stack, pc);
-                0
+            // the register file, on classic AVRs
+            _ if addr < self.io_offset => self.regs.r[addr as usize],
+
+            _ if (NVM_BASE..NVM_BASE + NVM_SIZE).contains(&addr) =>
+                self.nvm.read(addr - NVM_BASE),
+
+            // MCU.DEVID0-2, MCU.REVID
+            0x0090..=0x0092 if self.isa == Isa::Xmega =>
+                self.nvm.config.device_id[(addr - MCU) as usize],
+            0x0093 if self.isa == Isa::Xmega => self.nvm.config.revision,
+
+            _ => match self.find_peripheral(addr) {
+                Some((i, ofs)) => {
+                    self.catch_up(i);
+                    self.peripherals[i].read(ofs)
+                },
+                None => {
+                    self.diag.warning(&format!(
+                        "TODO: io read from {:#x} @ {}; {:#x}",
+                        addr, call_stack, pc));
+                    0
+                },
+            },
+        };
+
+        self.log_io_access(addr, val, false);
+        Ok(val)
+    }
+
+    /// the end of IO space: where SRAM or mapped EEPROM start
+    fn io_end(&self) -> u32 {
+        cmp::min(IO_END, self.sram_start)
+    }
+
+    fn log_io_access(&mut self, addr: u32, val: u8, write: bool) {
+        if addr >= self.io_offset && addr < self.io_end() {
+            if let Some(ref mut accesses) = self.io_accesses {
+                accesses.push(IoAccess { addr, val, write });
             }
         }
     }
 
-    pub fn set8(&mut self, addr: u32, val: u8, call_stack: &str, pc: u32) {
-        match addr {
-            0x08a0 => {
-                self.usart_output_log.push(val);
-                if val.is_ascii_whitespace() || val.is_ascii_graphic() {
-                    print!("{}", val as char);
-                }
+    pub fn set8(&mut self, addr: u32, val: u8, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<()> {
+
+        self.data_writes += 1;
+        if let Some(ref mut heatmap) = self.heatmap {
+            heatmap.write(addr);
+        }
+        self.log_io_access(addr, val, true);
+
+        let region = self.write_protect.find(addr).map(|r| r.name.clone());
+        if let Some(region) = region {
+            self.write_protect.violations += 1;
+            if self.write_protect.trap {
+                return Err(Error::WriteProtected { addr, pc });
             }
 
+            self.diag.warning(&format!(
+                "WARNING: dropping write to {} at {:#x} = {:#x} @ {}; {:#x}",
+                region, addr, val, call_stack, pc));
+            return Ok(());
+        }
+
+        if Some(addr) == self.exit_addr {
+            self.exit_request = Some(val);
+            return Ok(());
+        }
+
+        match addr {
+            // protected register unlocking; nothing is actually protected.
+            // classic AVRs have MCUSR there.
+            _ if self.isa == Isa::Xmega && addr == self.port_addr(CCP) => {},
+
             // simple IO regs
-            0x38...0x3e => self._set8(addr, val),
+            _ if self.is_cpu_reg(addr) => self._set8(addr, val),
 
-            SREG => self.sreg.set_u8(val),
+            _ if addr == self.port_addr(SREG) => self.sreg.set_u8(val),
 
             // data memory
-            0x2000...0x1000000 => self._set8(addr, val),
+            _ if addr >= self.sram_start => self.data_set8(addr, val, pc)?,
+
+            // the register file, on classic AVRs
+            _ if addr < self.io_offset => self.regs.r[addr as usize] = val,
+
+            _ if (NVM_BASE..NVM_BASE + NVM_SIZE).contains(&addr) =>
+                self.nvm.write(addr - NVM_BASE, val),
 
             _ => {
-                println!("TODO: io write to {:#x} = {:#x} @ {}; {:#x}",
-                    addr, val, call_stack, pc);
+                if let Some((i, ofs)) = self.find_peripheral(addr) {
+                    self.catch_up(i);
+                    self.peripherals[i].write(ofs, val);
+                    return Ok(());
+                }
+
+                self.diag.warning(&format!(
+                    "TODO: io write to {:#x} = {:#x} @ {}; {:#x}",
+                    addr, val, call_stack, pc));
             }
         }
+
+        Ok(())
     }
 
-    pub fn get16(&mut self, addr: u32, call_stack: &str, pc: u32) -> u16 {
-        ((self.get8(addr + 1, call_stack, pc) as u16) << 8)
-          | (self.get8(addr, call_stack, pc) as u16)
+    pub fn get16(&mut self, addr: u32, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<u16> {
+
+        Ok(((self.get8(addr + 1, call_stack, pc)? as u16) << 8)
+          | (self.get8(addr, call_stack, pc)? as u16))
     }
 
-    pub fn set16(&mut self, addr: u32, val: u16, call_stack: &str, pc: u32) {
-        self.set8(addr, (val & 0xff) as u8, call_stack, pc);
-        self.set8(addr + 1, ((val >> 8) & 0xff) as u8, call_stack, pc);
+    pub fn set16(&mut self, addr: u32, val: u16, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<()> {
+
+        self.set8(addr, (val & 0xff) as u8, call_stack, pc)?;
+        self.set8(addr + 1, ((val >> 8) & 0xff) as u8, call_stack, pc)
     }
 
     fn _get16(&self, addr: u32) -> u16 {
@@ -215,50 +1198,83 @@ impl IOMemory {
     }
 
     pub fn get_sp(&self) -> u16 {
-        self._get16(SPL)
+        self._get16(self.port_addr(SPL))
     }
 
     pub fn set_sp(&mut self, val: u16) {
-        self._set16(SPL, val)
+        let addr = self.port_addr(SPL);
+        self._set16(addr, val)
     }
 
-    pub fn push8(&mut self, val: u8) {
+    fn in_sram(&self, addr: u32) -> bool {
+        addr >= self.sram_start && (addr as usize) < self.data_mem.len()
+    }
+
+    // pushes and pops that would leave SRAM fault without touching SP, so
+    // the state shows where it happened. an SP that wrapped through 0 is
+    // caught by the next push.
+    pub fn push8(&mut self, val: u8, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<()> {
+
+        self.data_writes += 1;
         let old_sp = self.get_sp();
+        if !self.in_sram(old_sp as u32) {
+            return Err(Error::StackFault {
+                sp: old_sp, push: true, pc, call_stack: call_stack.to_string(),
+            });
+        }
+        if let Some(ref mut heatmap) = self.heatmap {
+            heatmap.write(old_sp as u32);
+        }
         self._set8(old_sp as u32, val);
-
-        self.set_sp(old_sp - 1);
+        self.set_sp(old_sp.wrapping_sub(1));
+        Ok(())
     }
 
-    pub fn pop8(&mut self) -> u8 {
+    pub fn pop8(&mut self, call_stack: &dyn fmt::Display, pc: u32) -> Result<u8> {
+        self.data_reads += 1;
         let old_sp = self.get_sp();
-        self.set_sp(old_sp + 1);
+        let sp = old_sp as u32 + 1;
+        if !self.in_sram(sp) {
+            return Err(Error::StackFault {
+                sp: old_sp, push: false, pc, call_stack: call_stack.to_string(),
+            });
+        }
+        self.set_sp(sp as u16);
 
-        self._get8(self.get_sp() as u32)
+        if let Some(ref mut heatmap) = self.heatmap {
+            heatmap.read(sp);
+        }
+        Ok(self._get8(sp))
     }
 
-    pub fn push16(&mut self, val: u16) {
-        self.push8(((val >> 0) & 0xff) as u8);
-        self.push8(((val >> 8) & 0xff) as u8);
+    pub fn push16(&mut self, val: u16, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<()> {
+
+        self.push8((val & 0xff) as u8, call_stack, pc)?;
+        self.push8(((val >> 8) & 0xff) as u8, call_stack, pc)
     }
 
-    pub fn pop16(&mut self) -> u16 {
+    pub fn pop16(&mut self, call_stack: &dyn fmt::Display, pc: u32) -> Result<u16> {
         let mut val;
-        val = (self.pop8() as u16) << 8;
-        val |= self.pop8() as u16;
-        val
+        val = (self.pop8(call_stack, pc)? as u16) << 8;
+        val |= self.pop8(call_stack, pc)? as u16;
+        Ok(val)
     }
 
-    pub fn push24(&mut self, val: u32) {
-        self.push8(((val >> 0) & 0xff) as u8);
-        self.push8(((val >> 8) & 0xff) as u8);
-        self.push8(((val >> 16) & 0xff) as u8);
+    pub fn push24(&mut self, val: u32, call_stack: &dyn fmt::Display, pc: u32)
+            -> Result<()> {
+
+        self.push8((val & 0xff) as u8, call_stack, pc)?;
+        self.push8(((val >> 8) & 0xff) as u8, call_stack, pc)?;
+        self.push8(((val >> 16) & 0xff) as u8, call_stack, pc)
     }
 
-    pub fn pop24(&mut self) -> u32 {
+    pub fn pop24(&mut self, call_stack: &dyn fmt::Display, pc: u32) -> Result<u32> {
         let mut val;
-        val = (self.pop8() as u32) << 16;
-        val |= (self.pop8() as u32) << 8;
-        val |= self.pop8() as u32;
-        val
+        val = (self.pop8(call_stack, pc)? as u32) << 16;
+        val |= (self.pop8(call_stack, pc)? as u32) << 8;
+        val |= self.pop8(call_stack, pc)? as u32;
+        Ok(val)
     }
 }
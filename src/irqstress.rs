@@ -0,0 +1,95 @@
+// Interrupt timing stress: firing enabled interrupts at random cycles
+//
+// Critical section bugs, like reading a multi-byte variable that an
+// interrupt handler writes without disabling interrupts first, only show up
+// when an interrupt lands on exactly the wrong instruction. In stress mode,
+// the emulator raises interrupts the firmware has enabled at pseudo-random
+// cycles, so the handlers run at many more points than they normally would.
+//
+// The schedule is seeded, so a failing run can be repeated with the same
+// seed. The interrupts fired are also kept as an input log, which replays
+// the exact schedule even if the firmware or the options change.
+
+use meminit::splitmix64;
+use replay::{InputEvent, InputLog};
+
+
+#[derive(Clone, Debug)]
+pub struct IrqStress {
+    seed: u64,
+    state: u64,
+    /// average cycles between interrupts
+    mean_gap: u64,
+    /// cycle of the next interrupt
+    next: u64,
+    /// (cycle, vector) of the interrupts fired so far
+    pub fired: Vec<(u64, u8)>,
+}
+
+impl IrqStress {
+    pub fn new(seed: u64, mean_gap: u64) -> IrqStress {
+        let mut stress = IrqStress {
+            seed,
+            state: seed,
+            mean_gap: mean_gap.max(1),
+            next: 0,
+            fired: vec![],
+        };
+        stress.next = stress.gap();
+        stress
+    }
+
+    /// "SEED" or "SEED:GAP", where GAP is the average number of cycles
+    /// between interrupts (default 1000)
+    pub fn parse(spec: &str) -> Option<IrqStress> {
+        let mut parts = spec.splitn(2, ':');
+        let seed = parts.next()?.parse().ok()?;
+        let mean_gap = match parts.next() {
+            Some(gap) => gap.parse().ok().filter(|&gap| gap > 0)?,
+            None => 1000,
+        };
+        Some(IrqStress::new(seed, mean_gap))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// the cycle at which to fire the next interrupt
+    pub fn next_cycle(&self) -> u64 {
+        self.next
+    }
+
+    // uniform in [1, 2 * mean_gap], so gaps average out to mean_gap
+    fn gap(&mut self) -> u64 {
+        1 + splitmix64(&mut self.state) % (2 * self.mean_gap)
+    }
+
+    /// pick one of the `enabled` interrupt vectors to fire at `now`, and
+    /// schedule the next one. returns None if nothing is enabled.
+    pub fn fire(&mut self, now: u64, enabled: &[u8]) -> Option<u8> {
+        self.next = now + self.gap();
+        if enabled.is_empty() {
+            return None;
+        }
+
+        let i = splitmix64(&mut self.state) % enabled.len() as u64;
+        let vector = enabled[i as usize];
+        self.fired.push((now, vector));
+        Some(vector)
+    }
+
+    /// the interrupts fired so far, for Emulator::start_replay()
+    pub fn schedule(&self) -> InputLog {
+        let mut log = InputLog::new();
+        for &(cycle, vector) in &self.fired {
+            log.push(cycle, InputEvent::Interrupt(vector));
+        }
+        log
+    }
+
+    pub fn report(&self) -> String {
+        format!("interrupt stress seed {}: fired {} interrupts\n",
+                self.seed, self.fired.len())
+    }
+}
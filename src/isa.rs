@@ -0,0 +1,81 @@
+// Instruction set levels, as avr-gcc's -mmcu families name them
+//
+// Each level has everything the ones before it have, so an instruction's
+// level is the first one that has it. Firmware built for a bigger core can
+// contain instructions the emulated device doesn't have, which real hardware
+// would execute as something else; the emulator stops on them instead.
+
+use std::fmt;
+use disa::AvrInsn;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Isa {
+    /// classic cores up to 8 KiB, e.g. the ATtiny2313
+    Avr2,
+    /// avr2 with MOVW, LPM Rd,Z and SPM, e.g. the ATtiny85
+    Avr25,
+    /// with the MUL family, up to 8 KiB, e.g. the ATmega8
+    Avr4,
+    /// with JMP and CALL, up to 64 KiB, e.g. the ATmega328P
+    Avr5,
+    /// with ELPM, up to 128 KiB, e.g. the ATmega128
+    Avr51,
+    /// with EIJMP and EICALL, e.g. the ATmega2560
+    Avr6,
+    /// with DES, SPM Z+ and the read-modify-write instructions
+    Xmega,
+}
+
+impl Isa {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Isa::Avr2 => "avr2",
+            Isa::Avr25 => "avr25",
+            Isa::Avr4 => "avr4",
+            Isa::Avr5 => "avr5",
+            Isa::Avr51 => "avr51",
+            Isa::Avr6 => "avr6",
+            Isa::Xmega => "xmega",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Isa> {
+        ISAS.iter().find(|isa| isa.name() == name).cloned()
+    }
+
+    pub fn has(&self, insn: &AvrInsn) -> bool {
+        min_isa(insn) <= *self
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub const ISAS : &[Isa] = &[
+    Isa::Avr2, Isa::Avr25, Isa::Avr4, Isa::Avr5, Isa::Avr51, Isa::Avr6, Isa::Xmega,
+];
+
+/// the first level with `insn`
+pub fn min_isa(insn: &AvrInsn) -> Isa {
+    match *insn {
+        AvrInsn::Movw(..) | AvrInsn::LpmZ(..) | AvrInsn::Spm => Isa::Avr25,
+
+        AvrInsn::Mul(..) | AvrInsn::Muls(..) | AvrInsn::Mulsu(..)
+        | AvrInsn::Fmul(..) | AvrInsn::Fmuls(..) | AvrInsn::Fmulsu(..) => Isa::Avr4,
+
+        AvrInsn::Jmp(_) | AvrInsn::Call(_) => Isa::Avr5,
+
+        AvrInsn::Elpm | AvrInsn::ElpmZ(..) => Isa::Avr51,
+
+        AvrInsn::Eijmp | AvrInsn::Eicall => Isa::Avr6,
+
+        AvrInsn::Des(_) | AvrInsn::SpmZ
+        | AvrInsn::Xch(_) | AvrInsn::Las(_) | AvrInsn::Lac(_) | AvrInsn::Lat(_) => Isa::Xmega,
+
+        _ => Isa::Avr2,
+    }
+}
@@ -0,0 +1,31 @@
+// Just enough JSON writing for state dumps and traces
+
+use std::fmt::Write;
+
+
+/// `s` as a quoted JSON string
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// a JSON array of numbers
+pub fn array<T: ToString>(items: &[T]) -> String {
+    let items: Vec<String> = items.iter().map(|x| x.to_string()).collect();
+    format!("[{}]", items.join(","))
+}
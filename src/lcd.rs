@@ -0,0 +1,400 @@
+// A virtual HD44780 character LCD, wired to a GPIO port in 4-bit mode
+//
+// RS, E and D4-D7 are on one port, with D4-D7 on consecutive pins, and R/W
+// tied to ground, so the firmware can only write; it has to wait out the
+// controller's busy times instead of polling the busy flag, which most
+// firmware does anyway. The controller takes D4-D7 on E's falling edge.
+//
+// After power-on the controller is in 8-bit mode, where each E pulse is a
+// whole instruction, with D0-D3 reading as 0. That's what makes the usual
+// 0x3, 0x3, 0x3, 0x2 sequence switch to 4-bit mode whatever state the
+// controller was in.
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use gpio::PinState;
+use iomem::IOMemory;
+use wiring::PinDevice;
+
+
+/// DDRAM is 2 lines of 40 characters, at 0x00 and 0x40
+const LINE_LEN : u8 = 40;
+const LINE2 : u8 = 0x40;
+const CGRAM_SIZE : usize = 64;
+
+/// cycles without writes after which a changed display counts as updated,
+/// so that frames aren't logged character by character
+const QUIET_CYCLES : u64 = 50_000;
+
+// instructions, by their highest set bit
+const CLEAR : u8 = 0x01;
+const HOME : u8 = 0x02;
+const ENTRY_MODE : u8 = 0x04;
+const DISPLAY_CTRL : u8 = 0x08;
+const SHIFT : u8 = 0x10;
+const FUNCTION_SET : u8 = 0x20;
+const SET_CGRAM_ADDR : u8 = 0x40;
+const SET_DDRAM_ADDR : u8 = 0x80;
+
+// ENTRY_MODE bits
+const INCREMENT : u8 = 0x02;
+const SHIFT_DISPLAY : u8 = 0x01;
+// DISPLAY_CTRL bits
+const DISPLAY_ON : u8 = 0x04;
+// SHIFT bits
+const SHIFT_SCREEN : u8 = 0x08;
+const SHIFT_RIGHT : u8 = 0x04;
+// FUNCTION_SET bits
+const EIGHT_BIT : u8 = 0x10;
+
+
+/// which pins of `port` the LCD is on
+#[derive(Clone, Debug, PartialEq)]
+pub struct LcdPins {
+    pub port: String,
+    pub rs: u8,
+    pub e: u8,
+    /// D4; D5-D7 are the next 3 pins
+    pub d4: u8,
+}
+
+impl LcdPins {
+    /// "PORT:RS,E,D4", e.g. "PORTD:0,1,4" for RS on PD0, E on PD1 and
+    /// D4-D7 on PD4-PD7
+    pub fn parse(spec: &str) -> Option<LcdPins> {
+        let mut parts = spec.splitn(2, ':');
+        let port = parts.next()?;
+        let pins: Vec<u8> = parts.next()?
+            .split(',')
+            .map(|s| s.parse().ok().filter(|&pin| pin < 8))
+            .collect::<Option<_>>()?;
+
+        match pins[..] {
+            [rs, e, d4] if d4 <= 4 => Some(LcdPins { port: port.to_string(), rs, e, d4 }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hd44780 {
+    name: String,
+    pins: LcdPins,
+    pub cols: u8,
+    pub rows: u8,
+
+    ddram: [u8; 0x80],
+    cgram: [u8; CGRAM_SIZE],
+    /// address counter
+    addr: u8,
+    /// whether `addr` is in CGRAM rather than DDRAM
+    in_cgram: bool,
+    entry_mode: u8,
+    display_ctrl: u8,
+    eight_bit: bool,
+    /// how far the display is shifted left
+    shift: u8,
+
+    /// high nibble received, waiting for the low one
+    high_nibble: Option<u8>,
+    last_e: bool,
+
+    /// the cycle of the last write, and whether the display changed since
+    /// the last frame
+    last_write: u64,
+    dirty: bool,
+    /// (cycle, rows) whenever the display changed, see QUIET_CYCLES
+    pub frames: Vec<(u64, Vec<String>)>,
+}
+
+impl Hd44780 {
+    /// a `cols` x `rows` display, e.g. 16x2 or 20x4
+    pub fn new(name: &str, pins: LcdPins, cols: u8, rows: u8) -> Hd44780 {
+        Hd44780 {
+            name: name.to_string(),
+            pins,
+            cols,
+            rows,
+
+            ddram: [b' '; 0x80],
+            cgram: [0; CGRAM_SIZE],
+            addr: 0,
+            in_cgram: false,
+            entry_mode: INCREMENT,
+            display_ctrl: 0,
+            eight_bit: true,
+            shift: 0,
+
+            high_nibble: None,
+            last_e: false,
+
+            last_write: 0,
+            dirty: false,
+            frames: vec![],
+        }
+    }
+
+    /// the text on the display, a string per row. characters outside of
+    /// printable ASCII, including custom ones, show as '?'.
+    pub fn lines(&self) -> Vec<String> {
+        (0..self.rows)
+            .map(|row| {
+                // rows 2 and 3 of 4-line displays continue rows 0 and 1
+                let line = (row % 2) * LINE2;
+                let start = (row / 2) * self.cols;
+                (0..self.cols)
+                    .map(|col| {
+                        if (self.display_ctrl & DISPLAY_ON) == 0 {
+                            return ' ';
+                        }
+                        let pos = (start + col + self.shift) % LINE_LEN;
+                        let c = self.ddram[(line + pos) as usize];
+                        if (0x20..0x7f).contains(&c) { c as char } else { '?' }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // `lines` with a border around them
+    fn boxed(&self, lines: &[String]) -> String {
+        let border = format!("+{}+\n", "-".repeat(self.cols as usize));
+        let mut out = border.clone();
+        for line in lines {
+            out.push_str(&format!("|{}|\n", line));
+        }
+        out.push_str(&border);
+        out
+    }
+
+    /// what the display shows now, with a border
+    pub fn fmt_display(&self) -> String {
+        self.boxed(&self.lines())
+    }
+
+    /// the frames logged so far, each with a border and the cycle it
+    /// appeared at, and the display as it is now if it changed since
+    pub fn fmt_frames(&self) -> String {
+        let mut out = String::new();
+        for &(cycle, ref lines) in &self.frames {
+            out.push_str(&format!("cycle {}\n", cycle));
+            out.push_str(&self.boxed(lines));
+        }
+
+        let lines = self.lines();
+        if self.dirty && self.frames.last().is_none_or(|(_, last)| *last != lines) {
+            out.push_str(&format!("cycle {}\n", self.last_write));
+            out.push_str(&self.boxed(&lines));
+        }
+        out
+    }
+
+    // the next address after `addr`, wrapping within and between lines
+    fn step_addr(&self, addr: u8, increment: bool) -> u8 {
+        if self.in_cgram {
+            let next = if increment { addr.wrapping_add(1) } else { addr.wrapping_sub(1) };
+            return next % CGRAM_SIZE as u8;
+        }
+
+        let line = addr & LINE2;
+        let pos = addr & !LINE2;
+        match (increment, pos) {
+            (true, _) if pos + 1 >= LINE_LEN => line ^ LINE2,
+            (true, _) => addr + 1,
+            (false, 0) => (line ^ LINE2) + LINE_LEN - 1,
+            (false, _) => addr - 1,
+        }
+    }
+
+    fn shift_display(&mut self, right: bool) {
+        self.shift = if right {
+            (self.shift + LINE_LEN - 1) % LINE_LEN
+        } else {
+            (self.shift + 1) % LINE_LEN
+        };
+        self.dirty = true;
+    }
+
+    fn command(&mut self, cmd: u8) {
+        if cmd >= SET_DDRAM_ADDR {
+            self.addr = cmd & 0x7f;
+            self.in_cgram = false;
+        } else if cmd >= SET_CGRAM_ADDR {
+            self.addr = cmd & 0x3f;
+            self.in_cgram = true;
+        } else if cmd >= FUNCTION_SET {
+            self.eight_bit = (cmd & EIGHT_BIT) != 0;
+        } else if cmd >= SHIFT {
+            let right = (cmd & SHIFT_RIGHT) != 0;
+            if (cmd & SHIFT_SCREEN) != 0 {
+                self.shift_display(right);
+            } else {
+                self.addr = self.step_addr(self.addr, right);
+            }
+        } else if cmd >= DISPLAY_CTRL {
+            self.display_ctrl = cmd;
+            self.dirty = true;
+        } else if cmd >= ENTRY_MODE {
+            self.entry_mode = cmd;
+        } else if cmd >= HOME {
+            self.addr = 0;
+            self.in_cgram = false;
+            self.shift = 0;
+            self.dirty = true;
+        } else if cmd == CLEAR {
+            self.ddram = [b' '; 0x80];
+            self.addr = 0;
+            self.in_cgram = false;
+            self.shift = 0;
+            self.entry_mode |= INCREMENT;
+            self.dirty = true;
+        }
+    }
+
+    fn data(&mut self, val: u8) {
+        let increment = (self.entry_mode & INCREMENT) != 0;
+        if self.in_cgram {
+            self.cgram[self.addr as usize % CGRAM_SIZE] = val;
+        } else {
+            self.ddram[self.addr as usize] = val;
+            self.dirty = true;
+            if (self.entry_mode & SHIFT_DISPLAY) != 0 {
+                self.shift_display(!increment);
+            }
+        }
+        self.addr = self.step_addr(self.addr, increment);
+    }
+
+    // E went low with `nibble` on D4-D7
+    fn strobe(&mut self, rs: bool, nibble: u8, now: u64) {
+        let val = if self.eight_bit {
+            // D0-D3 aren't connected
+            Some(nibble << 4)
+        } else {
+            match self.high_nibble.take() {
+                Some(high) => Some((high << 4) | nibble),
+                None => {
+                    self.high_nibble = Some(nibble);
+                    None
+                },
+            }
+        };
+
+        if let Some(val) = val {
+            if rs {
+                self.data(val);
+            } else {
+                self.command(val);
+            }
+        }
+        self.last_write = now;
+    }
+}
+
+fn is_high(state: PinState) -> bool {
+    state == PinState::High
+}
+
+impl PinDevice for Hd44780 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn update(&mut self, io_mem: &mut IOMemory, now: u64) {
+        let (e, rs, nibble) = match io_mem.port(&self.pins.port) {
+            Some(port) => {
+                let nibble = (0..4)
+                    .filter(|&i| is_high(port.pin_state(self.pins.d4 + i)))
+                    .fold(0, |acc, i| acc | (1 << i));
+                (is_high(port.pin_state(self.pins.e)),
+                 is_high(port.pin_state(self.pins.rs)),
+                 nibble)
+            },
+            None => return,
+        };
+
+        if self.last_e && !e {
+            self.strobe(rs, nibble, now);
+        }
+        self.last_e = e;
+
+        if self.dirty && now >= self.last_write + QUIET_CYCLES {
+            self.dirty = false;
+            let lines = self.lines();
+            if self.frames.last().is_none_or(|(_, last)| *last != lines) {
+                self.frames.push((now, lines));
+            }
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.ddram.to_vec();
+        state.extend_from_slice(&self.cgram);
+        state.extend_from_slice(&[
+            self.addr,
+            self.in_cgram as u8,
+            self.entry_mode,
+            self.display_ctrl,
+            self.eight_bit as u8,
+            self.shift,
+            self.high_nibble.unwrap_or(0xff),
+            self.last_e as u8,
+            self.dirty as u8,
+        ]);
+        state.write_u64::<LittleEndian>(self.last_write).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        let regs = 0x80 + CGRAM_SIZE;
+        if state.len() < regs + 9 + 8 {
+            return;
+        }
+
+        self.ddram.copy_from_slice(&state[..0x80]);
+        self.cgram.copy_from_slice(&state[0x80..regs]);
+        let r = &state[regs..];
+        self.addr = r[0];
+        self.in_cgram = r[1] != 0;
+        self.entry_mode = r[2];
+        self.display_ctrl = r[3];
+        self.eight_bit = r[4] != 0;
+        self.shift = r[5];
+        self.high_nibble = if r[6] == 0xff { None } else { Some(r[6]) };
+        self.last_e = r[7] != 0;
+        self.dirty = r[8] != 0;
+        self.last_write = (&r[9..]).read_u64::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn PinDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the LCDs attached to `io_mem`
+pub fn attached(io_mem: &IOMemory) -> Vec<&Hd44780> {
+    io_mem.pin_devices
+        .iter()
+        .filter_map(|d| d.as_any().downcast_ref())
+        .collect()
+}
+
+/// "COLSxROWS", e.g. "16x2"
+pub fn parse_size(spec: &str) -> Option<(u8, u8)> {
+    let mut parts = spec.splitn(2, 'x');
+    let cols = parts.next()?.parse().ok().filter(|&cols| cols > 0 && cols <= LINE_LEN)?;
+    let rows = parts.next()?.parse().ok().filter(|&rows| rows > 0 && rows <= 4)?;
+    // 4 rows take 2 from each DDRAM line
+    if rows > 2 && cols > LINE_LEN / 2 {
+        return None;
+    }
+    Some((cols, rows))
+}
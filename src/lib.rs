@@ -1,17 +1,107 @@
-#![feature(slice_patterns)]
-
 extern crate hex;
 extern crate byteorder;
 extern crate disa;
+extern crate addr2line;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "scripting")]
+extern crate rhai;
+#[cfg(feature = "tui")]
+extern crate ratatui;
+#[cfg(feature = "tui")]
+extern crate crossterm;
 
+#[cfg(all(unix, feature = "signals"))]
 extern crate signal_notify;
+#[cfg(unix)]
+extern crate libc;
 
 
+pub mod error;
+pub mod diag;
 pub mod registers;
 pub mod emulator;
+pub mod cycles;
+pub mod des;
+pub mod snapshot;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod replay;
+pub mod timetravel;
+pub mod debugger;
+pub mod remote;
+pub mod expr;
+pub mod breakpoint;
+pub mod symbols;
+pub mod lines;
+pub mod elf;
+pub mod disasm;
+pub mod trace;
+pub mod vcd;
+pub mod json;
+pub mod profile;
+pub mod callgraph;
+pub mod callstack;
+pub mod coverage;
+pub mod stats;
+pub mod energy;
+pub mod heatmap;
+pub mod stack;
+pub mod history;
+pub mod hexdump;
+pub mod taint;
+pub mod faultinject;
+pub mod irqstress;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod protect;
 pub mod sreg;
 pub mod progmem;
+pub mod device;
+pub mod isa;
+pub mod regmap;
 pub mod iomem;
+pub mod nvm;
+pub mod fuses;
+pub mod peripheral;
+pub mod wiring;
+pub mod lcd;
+pub mod board;
+pub mod stimulus;
+pub mod gpio;
+pub mod timer;
+pub mod megatimer;
+pub mod adc;
+pub mod spi;
+pub mod twi;
+pub mod usart;
+pub mod usb;
+pub mod aes;
+pub mod crc;
+pub mod hostcall;
+pub mod extdev;
+pub mod call;
+pub mod warp;
+pub mod sched;
+pub mod meminit;
+pub mod stopcond;
+pub mod statehash;
+pub mod lockstep;
+pub mod cosim;
+pub mod dma;
+pub mod evsys;
+pub mod pmic;
+pub mod rst;
+pub mod sleep;
+pub mod clk;
+pub mod rtc;
+#[cfg(unix)]
+pub mod pty;
+#[cfg(feature = "tui")]
+pub mod tui;
 
 
-pub use emulator::Emulator;
+pub use emulator::{Emulator, RunResult, StopReason, UnimplementedPolicy};
+pub use error::{Error, Result};
+pub use remote::EmulatorHandle;
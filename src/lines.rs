@@ -0,0 +1,62 @@
+// Source line information, for showing file:line next to code addresses
+//
+// Built once from an ELF file's DWARF line programs, so lookups are a binary
+// search and the table can be cloned and sent between threads.
+
+use std::path::Path;
+
+
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    /// (start, end, file index, line), sorted by address
+    rows: Vec<(u32, u32, usize, u32)>,
+    files: Vec<String>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable { rows: vec![], files: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// add the line for byte addresses [start, end). rows must be added in
+    /// address order.
+    pub fn add(&mut self, start: u32, end: u32, file: &str, line: u32) {
+        let file_index =
+            match self.files.iter().position(|f| f == file) {
+                Some(i) => i,
+                None => {
+                    self.files.push(file.to_string());
+                    self.files.len() - 1
+                },
+            };
+        self.rows.push((start, end, file_index, line));
+    }
+
+    /// the source file and line of byte address `addr`
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = match self.rows.binary_search_by_key(&addr, |r| r.0) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+
+        let (_, end, file, line) = self.rows[i];
+        if addr >= end {
+            return None;
+        }
+        Some((&self.files[file], line))
+    }
+
+    /// e.g. "main.c:123", without the directory
+    pub fn fmt_line(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(file, line)| {
+            let name = Path::new(file).file_name()
+                .map_or(file.to_string(), |n| n.to_string_lossy().into_owned());
+            format!("{}:{}", name, line)
+        })
+    }
+}
@@ -0,0 +1,201 @@
+// Lockstep comparison of two executions, one instruction at a time
+//
+// Each side says what the CPU state was after every instruction: an
+// emulator by running it, a trace by replaying it. The first instruction
+// after which PC, the registers, SREG or SP differ is reported with both
+// states, e.g. to check new instructions' flags against another emulator, or
+// one yaavre version against a JSON trace (see trace.rs) from another.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use emulator::Emulator;
+use trace::fmt_sreg;
+
+
+/// the CPU state after an instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuState {
+    /// number of instructions executed before this one
+    pub insn_count: u64,
+    /// the instruction's address
+    pub pc: u32,
+    pub regs: [u8; 32],
+    pub sreg: u8,
+    pub sp: u16,
+}
+
+impl CpuState {
+    /// what differs from `other`, e.g. ["r24", "SREG"]
+    pub fn differences(&self, other: &CpuState) -> Vec<String> {
+        let mut diffs = vec![];
+        if self.pc != other.pc {
+            diffs.push("PC".to_string());
+        }
+        for r in 0..32 {
+            if self.regs[r] != other.regs[r] {
+                diffs.push(format!("r{}", r));
+            }
+        }
+        if self.sreg != other.sreg {
+            diffs.push("SREG".to_string());
+        }
+        if self.sp != other.sp {
+            diffs.push("SP".to_string());
+        }
+        diffs
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "#{} pc={:#x} sp={:#06x} sreg={}", self.insn_count, self.pc,
+                 self.sp, fmt_sreg(self.sreg))?;
+        for (i, regs) in self.regs.chunks(8).enumerate() {
+            let vals: Vec<String> = regs.iter().map(|r| format!("{:02x}", r)).collect();
+            writeln!(f, "r{:<2}: {}", i * 8, vals.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// a source of CPU states, one per instruction
+pub trait StateSource {
+    /// the state after the next instruction, or None once there are no
+    /// more
+    fn next_state(&mut self) -> Result<Option<CpuState>, String>;
+}
+
+/// runs an emulator until it stops
+pub struct EmulatorSource<'a> {
+    pub emu: &'a mut Emulator,
+}
+
+impl<'a> StateSource for EmulatorSource<'a> {
+    fn next_state(&mut self) -> Result<Option<CpuState>, String> {
+        // interrupt entries and idle cycles aren't instructions
+        loop {
+            let (pc, insn_count) = (self.emu.pc, self.emu.insn_count);
+            let res = self.emu.step();
+            if let Some(e) = res.fault {
+                return Err(e.to_string());
+            }
+
+            if self.emu.insn_count != insn_count {
+                return Ok(Some(CpuState {
+                    insn_count,
+                    pc,
+                    regs: self.emu.io_mem.regs.r,
+                    sreg: self.emu.io_mem.sreg.as_u8(),
+                    sp: self.emu.io_mem.get_sp(),
+                }));
+            }
+            if self.emu.halted {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// replays a JSON trace of every instruction. the trace only has the
+/// registers each instruction changed, so it starts from given values.
+pub struct TraceSource {
+    lines: Lines<BufReader<File>>,
+    line_num: usize,
+    regs: [u8; 32],
+}
+
+/// the number after `"key":` in a JSON trace line
+fn json_num(line: &str, key: &str) -> Option<u64> {
+    let start = line.find(&format!("\"{}\":", key))? + key.len() + 3;
+    let len = line[start..].find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(line.len() - start);
+    line[start..start + len].parse().ok()
+}
+
+/// the "regs" object in a JSON trace line, as (register, value) pairs
+fn json_regs(line: &str) -> Option<Vec<(usize, u8)>> {
+    let start = line.find("\"regs\":{")? + 8;
+    let end = start + line[start..].find('}')?;
+    line[start..end]
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, ':');
+            let r = parts.next()?.trim_matches('"').parse().ok()?;
+            let val = parts.next()?.parse().ok()?;
+            if r < 32 { Some((r, val)) } else { None }
+        })
+        .collect()
+}
+
+impl TraceSource {
+    pub fn open(path: &str, regs: [u8; 32]) -> ::std::io::Result<TraceSource> {
+        Ok(TraceSource {
+            lines: BufReader::new(File::open(path)?).lines(),
+            line_num: 0,
+            regs,
+        })
+    }
+}
+
+impl StateSource for TraceSource {
+    fn next_state(&mut self) -> Result<Option<CpuState>, String> {
+        let line = match self.lines.next() {
+            Some(line) => line.map_err(|e| e.to_string())?,
+            None => return Ok(None),
+        };
+        self.line_num += 1;
+
+        let line_num = self.line_num;
+        let bad_line = || format!("bad trace line {}", line_num);
+        for (r, val) in json_regs(&line).ok_or_else(bad_line)? {
+            self.regs[r] = val;
+        }
+
+        Ok(Some(CpuState {
+            insn_count: json_num(&line, "n").ok_or_else(bad_line)?,
+            pc: json_num(&line, "pc").ok_or_else(bad_line)? as u32,
+            regs: self.regs,
+            sreg: json_num(&line, "sreg").ok_or_else(bad_line)? as u8,
+            sp: json_num(&line, "sp").ok_or_else(bad_line)? as u16,
+        }))
+    }
+}
+
+
+pub enum LockstepResult {
+    /// both sides ran out together, or `limit` instructions matched
+    Matched { insns: u64 },
+    /// one side ran out first; true if it was the first side
+    Ended { insns: u64, first_ended: bool },
+    /// the states after instruction number `insns` differ
+    Diverged { insns: u64, a: CpuState, b: CpuState },
+}
+
+/// compare `a` and `b` for up to `limit` instructions
+pub fn run_lockstep(a: &mut dyn StateSource, b: &mut dyn StateSource,
+                    limit: Option<u64>) -> Result<LockstepResult, String> {
+    let mut insns = 0;
+    loop {
+        if Some(insns) == limit {
+            return Ok(LockstepResult::Matched { insns });
+        }
+
+        match (a.next_state()?, b.next_state()?) {
+            (None, None) => return Ok(LockstepResult::Matched { insns }),
+            (None, Some(_)) =>
+                return Ok(LockstepResult::Ended { insns, first_ended: true }),
+            (Some(_), None) =>
+                return Ok(LockstepResult::Ended { insns, first_ended: false }),
+            // instruction counts aren't compared, as they may start
+            // differently, e.g. for a trace of part of a run
+            (Some(sa), Some(sb)) => if !sa.differences(&sb).is_empty() {
+                return Ok(LockstepResult::Diverged { insns, a: sa, b: sb });
+            },
+        }
+
+        insns += 1;
+    }
+}
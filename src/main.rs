@@ -1,16 +1,1809 @@
 extern crate clap;
 extern crate yaavre;
 extern crate hex;
+#[cfg(not(all(unix, feature = "signals")))]
+extern crate ctrlc;
 
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use std::sync::Arc;
+use yaavre::diag::{QuietSink, SharedSink};
+use yaavre::replay::InputLog;
+use yaavre::debugger::{parse_breakpoint, Debugger};
+use yaavre::symbols::{SymbolTable, DATA_OFFSET};
+use yaavre::trace::{JsonTracer, TextTracer, TraceFilter, Tracer};
+use yaavre::fuses::DeviceConfig;
+use yaavre::device::{Device, ATXMEGA128A4U};
+use yaavre::isa::Isa;
+use std::io;
+use std::cmp;
+use std::fs::File;
+use std::net::TcpStream;
+use std::time::Duration;
+use std::path::PathBuf;
+use yaavre::hostcall::{parse_datetime, HostClock, HOSTCALL_BASE};
+use yaavre::meminit::MemInit;
+use yaavre::irqstress::IrqStress;
+use yaavre::board::{Binding, Board, BOARD_NAME};
+use yaavre::stimulus::PinStimulus;
+use yaavre::cosim::Cosim;
+use yaavre::extdev::ExtDevice;
+use yaavre::usb::CdcPort;
+use yaavre::lcd::{parse_size as parse_lcd_size, Hd44780, LcdPins};
+use yaavre::stopcond::StopCondition;
+use yaavre::statehash::{first_divergence, HashLog};
+use yaavre::lockstep::{run_lockstep, EmulatorSource, LockstepResult, StateSource,
+                       TraceSource};
+use yaavre::faultinject::{summary as fault_summary, sweep, Campaign, FaultTarget,
+                          FaultTime, Outcome};
+#[cfg(feature = "scripting")]
+use yaavre::script::ScriptEvent;
+use yaavre::{StopReason, UnimplementedPolicy};
 
+mod sinks;
+use sinks::{StdoutSink, UartWriterSink};
+
+
+#[cfg(unix)]
+fn attach_uart_pty(emu: &mut yaavre::Emulator) {
+    let pty = yaavre::pty::Pty::open().unwrap();
+    println!("USART connected to {}", pty.slave_path());
+    emu.io_mem.uart_pty = Some(pty);
+}
+
+#[cfg(not(unix))]
+fn attach_uart_pty(_emu: &mut yaavre::Emulator) {
+    eprintln!("--uart-pty is only supported on Unix");
+    std::process::exit(1);
+}
+
+/// connect the USB CDC-ACM port to "pty" or "tcp:HOST:PORT"
+fn attach_usb_cdc(emu: &mut yaavre::Emulator, dest: &str) {
+    let port = if let Some(addr) = dest.strip_prefix("tcp:") {
+        TcpStream::connect(addr)
+            .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
+            .map(CdcPort::Tcp)
+    } else if dest == "pty" {
+        open_cdc_pty()
+    } else {
+        eprintln!("bad --usb-cdc {:?}", dest);
+        std::process::exit(1);
+    };
+
+    match port {
+        Ok(port) => emu.io_mem.usb_mut().unwrap().set_cdc_port(port),
+        Err(e) => {
+            eprintln!("can't open --usb-cdc {:?}: {}", dest, e);
+            std::process::exit(1);
+        },
+    }
+}
+
+#[cfg(unix)]
+fn open_cdc_pty() -> io::Result<CdcPort> {
+    let pty = yaavre::pty::Pty::open()?;
+    println!("USB CDC port connected to {}", pty.slave_path());
+    Ok(CdcPort::Pty(pty))
+}
+
+#[cfg(not(unix))]
+fn open_cdc_pty() -> io::Result<CdcPort> {
+    eprintln!("--usb-cdc pty is only supported on Unix");
+    std::process::exit(1);
+}
+
+/// without signal support, pause on Ctrl-C through the stop flag
+#[cfg(not(all(unix, feature = "signals")))]
+fn handle_ctrl_c(emu: &yaavre::Emulator) {
+    let stop = emu.stop_handle();
+    ctrlc::set_handler(move || stop.store(true, std::sync::atomic::Ordering::SeqCst))
+        .unwrap();
+}
+
+#[cfg(all(unix, feature = "signals"))]
+fn handle_ctrl_c(_emu: &yaavre::Emulator) {}
+
+/// where --uart-out says USART output should go
+fn uart_out_sink(dest: &str) -> io::Result<SharedSink> {
+    let out: Option<Box<dyn io::Write + Send>> = if dest == "none" {
+        None
+    } else if let Some(addr) = dest.strip_prefix("tcp:") {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Some(Box::new(stream))
+    } else {
+        Some(Box::new(File::create(dest)?))
+    };
+
+    Ok(Arc::new(UartWriterSink::new(out)))
+}
+
+fn run_debugger(emu: &mut yaavre::Emulator, tui: bool) {
+    if tui {
+        return run_tui(emu);
+    }
+
+    let stdin = io::stdin();
+    Debugger::new(emu).repl(stdin.lock(), io::stdout()).unwrap();
+}
+
+#[cfg(feature = "tui")]
+fn run_tui(emu: &mut yaavre::Emulator) {
+    yaavre::tui::Tui::new(emu).run().unwrap();
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_tui(_emu: &mut yaavre::Emulator) {
+    eprintln!("--tui needs yaavre built with the tui feature");
+    std::process::exit(1);
+}
+
+/// "every:N", "io:START-END" (hex) or "break:ID"
+#[cfg(feature = "scripting")]
+fn parse_script_event(spec: &str) -> Option<ScriptEvent> {
+    let parts: Vec<_> = spec.splitn(2, ':').collect();
+    match parts[..] {
+        ["every", n] => n.parse().ok().map(ScriptEvent::Every),
+        ["break", id] => id.parse().ok().map(ScriptEvent::Breakpoint),
+        ["io", range] => {
+            let bounds: Vec<_> = range.splitn(2, '-')
+                .map(|s| u32::from_str_radix(s, 16))
+                .collect();
+            match bounds[..] {
+                [Ok(start), Ok(end)] => Some(ScriptEvent::IoAccess(start, end)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn add_scripts<'a, I: Iterator<Item = &'a str>>(emu: &mut yaavre::Emulator, specs: I) {
+    for spec in specs {
+        let parts: Vec<_> = spec.splitn(2, '=').collect();
+        let (event, path) = match &parts[..] {
+            &[event, path] => match parse_script_event(event) {
+                Some(event) => (event, path),
+                None => {
+                    eprintln!("bad --script event {:?}", event);
+                    std::process::exit(1);
+                },
+            },
+            _ => {
+                eprintln!("bad --script {:?}", spec);
+                std::process::exit(1);
+            },
+        };
+
+        let added = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| emu.add_script(event, &source).map_err(|e| e.to_string()));
+        if let Err(e) = added {
+            eprintln!("can't load script {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn add_scripts<'a, I: Iterator<Item = &'a str>>(_emu: &mut yaavre::Emulator, _specs: I) {
+    eprintln!("--script needs yaavre built with the scripting feature");
+    std::process::exit(1);
+}
+
+const LCD_NAME : &str = "LCD";
+
+/// attach an LCD as --lcd says
+fn attach_lcd(emu: &mut yaavre::Emulator, spec: &str) {
+    // the size is after the second colon
+    let (pins, size) = match spec.match_indices(':').nth(1) {
+        Some((i, _)) => (&spec[..i], parse_lcd_size(&spec[i + 1..])),
+        None => (spec, Some((16, 2))),
+    };
+
+    let pins = LcdPins::parse(pins).filter(|pins| emu.io_mem.port(&pins.port).is_some());
+    match (pins, size) {
+        (Some(pins), Some((cols, rows))) => emu.io_mem.attach_pin_device(
+            Box::new(Hd44780::new(LCD_NAME, pins, cols, rows))),
+        _ => {
+            eprintln!("bad --lcd {:?}", spec);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// attach a board with the --led and --button LEDs and buttons, if any
+fn attach_board(emu: &mut yaavre::Emulator, matches: &ArgMatches) {
+    let leds = matches.values_of("led").into_iter().flatten();
+    let buttons = matches.values_of("button").into_iter().flatten();
+    let mut board = Board::new();
+    let mut any = false;
+
+    for (spec, is_button) in leds.map(|s| (s, false)).chain(buttons.map(|s| (s, true))) {
+        // LEDs are active high and buttons active low unless they say otherwise
+        let binding = Binding::parse(spec, is_button)
+            .filter(|b| emu.io_mem.port(&b.port).is_some());
+        match binding {
+            Some(binding) if is_button => board.add_button(binding),
+            Some(binding) => board.add_led(binding),
+            None => {
+                eprintln!("bad --{} {:?}", if is_button { "button" } else { "led" }, spec);
+                std::process::exit(1);
+            },
+        }
+        any = true;
+    }
+
+    if let Some(path) = matches.value_of("button-script") {
+        board.load_script(path).unwrap();
+    }
+    if any {
+        emu.io_mem.attach_pin_device(Box::new(board));
+    }
+}
+
+/// "START-END" in hex, or a data symbol marking the end of the region
+fn parse_stack_guard(symbols: &SymbolTable, sram_start: u32, spec: &str) -> Option<(u16, u16)> {
+    let parts: Vec<_> = spec.splitn(2, '-')
+        .map(|s| u16::from_str_radix(s, 16))
+        .collect();
+    if let &[Ok(start), Ok(end)] = &parts[..] {
+        return Some((start, end));
+    }
+
+    symbols.find(spec)
+        .filter(|sym| sym.addr >= DATA_OFFSET)
+        .map(|sym| (sram_start as u16, (sym.addr - DATA_OFFSET) as u16))
+}
+
+/// --until, --max-insns etc.
+fn stop_conditions(matches: &ArgMatches, symbols: &SymbolTable) -> Vec<StopCondition> {
+    let mut conds = vec![];
+
+    if let Some(specs) = matches.values_of("until") {
+        for spec in specs {
+            let addr = u32::from_str_radix(spec, 16);
+            conds.push(match addr {
+                Ok(addr) if symbols.find(spec).is_none() => StopCondition::Pc(addr),
+                _ => StopCondition::Symbol(spec.to_string()),
+            });
+        }
+    }
+
+    let parse_count = |name| matches.value_of(name).map(|s: &str|
+        s.parse().unwrap_or_else(|_| {
+            eprintln!("bad --{} {:?}", name, s);
+            std::process::exit(1);
+        }));
+    if let Some(n) = parse_count("max-insns") {
+        conds.push(StopCondition::InsnLimit(n));
+    }
+    if let Some(n) = parse_count("max-cycles") {
+        conds.push(StopCondition::CycleLimit(n));
+    }
+
+    if matches.is_present("stop-on-uart") {
+        conds.push(StopCondition::UartOutput);
+    }
+
+    conds
+}
+
+/// "r24=1f"
+fn parse_reg_setting(setting: &str) -> Option<(u8, u8)> {
+    let parts: Vec<_> = setting.splitn(2, '=').collect();
+    match &parts[..] {
+        &[reg, val] if reg.starts_with('r') => {
+            let r = reg[1..].parse().ok().filter(|&r: &u8| r < 32)?;
+            let val = u8::from_str_radix(val, 16).ok()?;
+            Some((r, val))
+        },
+        _ => None,
+    }
+}
+
+/// the chip picked with --device (and --isa, for a run), by default the
+/// atxmega128a4u
+fn device_arg(matches: &ArgMatches) -> Device {
+    let device = matches.value_of("device").map_or(&ATXMEGA128A4U, |name| {
+        Device::by_name(name).unwrap_or_else(|| {
+            eprintln!("unknown device {:?}", name);
+            std::process::exit(1);
+        })
+    });
+
+    let mut device = device.clone();
+    if let Some(name) = matches.value_of("isa") {
+        device.isa = Isa::by_name(name).unwrap_or_else(|| {
+            eprintln!("bad --isa {:?}", name);
+            std::process::exit(1);
+        });
+    }
+    device
+}
+
+fn lockstep_emulator(path: &str, device: &Device) -> yaavre::Emulator {
+    let mut emu = yaavre::Emulator::for_device(device);
+    emu.load(path).unwrap_or_else(|e| {
+        eprintln!("can't load {:?}: {}", path, e);
+        std::process::exit(2);
+    });
+    emu.reset();
+    emu
+}
+
+/// the lockstep subcommand. the exit status is 1 if the runs differ.
+fn lockstep(matches: &ArgMatches) {
+    let device = device_arg(matches);
+    let mut emu = lockstep_emulator(matches.value_of("FILE").unwrap(), &device);
+    let limit = matches.value_of("max-insns").map(|s| s.parse().unwrap_or_else(|_| {
+        eprintln!("bad --max-insns {:?}", s);
+        std::process::exit(2);
+    }));
+
+    let mut other_emu;
+    let mut other;
+    let mut trace;
+    let (reference, name): (&mut dyn StateSource, _) = match matches.value_of("trace") {
+        Some(path) => {
+            trace = TraceSource::open(path, emu.io_mem.regs.r).unwrap_or_else(|e| {
+                eprintln!("can't read {:?}: {}", path, e);
+                std::process::exit(2);
+            });
+            (&mut trace, path)
+        },
+        None => {
+            let path = matches.value_of("against").unwrap();
+            other_emu = lockstep_emulator(path, &device);
+            other = EmulatorSource { emu: &mut other_emu };
+            (&mut other, path)
+        },
+    };
+
+    let result = run_lockstep(&mut EmulatorSource { emu: &mut emu }, reference, limit);
+    match result {
+        Ok(LockstepResult::Matched { insns }) =>
+            println!("{} instructions matched", insns),
+        Ok(LockstepResult::Ended { insns, first_ended }) => {
+            let ended = if first_ended { matches.value_of("FILE").unwrap() } else { name };
+            println!("{} instructions matched, then {} ended", insns, ended);
+        },
+        Ok(LockstepResult::Diverged { insns, a, b }) => {
+            println!("difference after {} matching instructions: {}",
+                     insns, a.differences(&b).join(", "));
+            print!("{}:\n{}{}:\n{}", matches.value_of("FILE").unwrap(), a, name, b);
+            std::process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        },
+    }
+}
+
+fn load_hash_log(path: &str) -> Vec<(u64, u64)> {
+    HashLog::load(path).unwrap_or_else(|e| {
+        eprintln!("can't read {:?}: {}", path, e);
+        std::process::exit(2);
+    })
+}
+
+/// the compare-hashes subcommand. the exit status is 1 if the logs differ.
+fn compare_hashes(matches: &ArgMatches) {
+    let a = load_hash_log(matches.value_of("A").unwrap());
+    let b = load_hash_log(matches.value_of("B").unwrap());
+
+    match first_divergence(&a, &b) {
+        Some((last_match, insns)) => {
+            println!("first difference between instructions {} and {}", last_match, insns);
+            std::process::exit(1);
+        },
+        None => println!("no differences"),
+    }
+}
+
+/// "r24", "sreg", "data:ADDR" or "flash:ADDR", with ADDR in hex
+fn parse_fault_target(spec: &str) -> Option<FaultTarget> {
+    if spec == "sreg" {
+        return Some(FaultTarget::Sreg);
+    }
+    if let Some(reg) = spec.strip_prefix('r') {
+        return reg.parse().ok().filter(|&r: &u8| r < 32).map(FaultTarget::Reg);
+    }
+
+    let parts: Vec<_> = spec.splitn(2, ':').collect();
+    match parts[..] {
+        ["data", addr] => u32::from_str_radix(addr, 16).ok().map(FaultTarget::Data),
+        ["flash", addr] => u32::from_str_radix(addr, 16).ok().map(FaultTarget::Flash),
+        _ => None,
+    }
+}
+
+/// the fault-campaign subcommand
+fn fault_campaign(matches: &ArgMatches) {
+    let mut emu = yaavre::Emulator::for_device(&device_arg(matches));
+    let path = matches.value_of("FILE").unwrap();
+    emu.load(path).unwrap_or_else(|e| {
+        eprintln!("can't load {:?}: {}", path, e);
+        std::process::exit(2);
+    });
+    emu.reset();
+
+    let parse_num = |name| matches.value_of(name).map(|s: &str|
+        s.parse().unwrap_or_else(|_| {
+            eprintln!("bad --{} {:?}", name, s);
+            std::process::exit(2);
+        }));
+    let max_cycles = parse_num("max-cycles").unwrap();
+    let step = parse_num("step").unwrap_or(100).max(1);
+    let start = parse_num("from").unwrap_or(0);
+    let end = parse_num("to").unwrap_or(max_cycles).min(max_cycles);
+    let times: Vec<_> = (start..end).step_by(step as usize)
+        .map(FaultTime::Cycle)
+        .collect();
+
+    let targets: Vec<_> = match matches.values_of("target") {
+        Some(specs) => specs.map(|spec| parse_fault_target(spec).unwrap_or_else(|| {
+            eprintln!("bad --target {:?}", spec);
+            std::process::exit(2);
+        })).collect(),
+        None => (0..32).map(FaultTarget::Reg).collect(),
+    };
+
+    let mut campaign = Campaign::new(max_cycles);
+    if let Some(spec) = matches.value_of("detect") {
+        campaign.detect_pc = Some(emu.resolve_code_addr(spec).unwrap_or_else(|| {
+            eprintln!("bad --detect {:?}", spec);
+            std::process::exit(2);
+        }));
+    }
+
+    let results = campaign.run_all(&emu, &sweep(&times, &targets)).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    });
+    for &(point, outcome) in &results {
+        if outcome != Outcome::NoEffect {
+            println!("{}: {}", point, outcome);
+        }
+    }
+    print!("{}", fault_summary(&results));
+}
+
+fn disasm(matches: &ArgMatches) {
+    let mut emu = yaavre::Emulator::for_device(&device_arg(matches));
+    let path = matches.value_of("FILE").unwrap();
+    emu.load(path).unwrap_or_else(|e| {
+        eprintln!("can't load {:?}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    if let Some(path) = matches.value_of("symbols") {
+        emu.symbols = SymbolTable::load_nm(path).unwrap();
+    }
+
+    let parse_addr = |name| matches.value_of(name).map(|s|
+        u32::from_str_radix(s, 16).unwrap_or_else(|_| {
+            eprintln!("bad --{} {:?}", name, s);
+            std::process::exit(1);
+        }));
+
+    let prog_mem = &emu.prog_mem;
+    let insns =
+        if let Some(name) = matches.value_of("symbol") {
+            match emu.symbols.range(name) {
+                Some((start, end)) => prog_mem.get_insns_at(start, end),
+                None => {
+                    eprintln!("unknown function {:?}", name);
+                    std::process::exit(1);
+                },
+            }
+        } else {
+            let start = parse_addr("start").unwrap_or(0);
+            match parse_addr("end") {
+                Some(end) => prog_mem.get_insns_at_incl(start, end),
+                None => prog_mem.get_insns_at(start, prog_mem.len()),
+            }
+        };
+
+    print!("{}", yaavre::disasm::listing(prog_mem, &emu.symbols, &emu.lines, insns));
+}
+
+/// where `actual` starts differing from `expected`, if it does
+fn first_difference(actual: &[u8], expected: &[u8]) -> Option<usize> {
+    if actual == expected {
+        return None;
+    }
+
+    Some(actual.iter().zip(expected)
+         .position(|(a, e)| a != e)
+         .unwrap_or(cmp::min(actual.len(), expected.len())))
+}
+
+/// run firmware as a test and exit with 0 if it passed
+fn run_test(matches: &ArgMatches) {
+    let mut emu = yaavre::Emulator::for_device(&device_arg(matches));
+    // warnings still get printed
+    emu.set_diagnostics_sink(Arc::new(UartWriterSink::new(None)));
+    let path = matches.value_of("FILE").unwrap();
+    emu.load(path).unwrap_or_else(|e| {
+        eprintln!("can't load {:?}: {}", path, e);
+        std::process::exit(2);
+    });
+    emu.reset();
+
+    match matches.value_of("exit-at") {
+        Some(spec) => match emu.resolve_code_addr(spec) {
+            Some(addr) => emu.exit_pc = Some(addr),
+            None => {
+                eprintln!("bad --exit-at {:?}", spec);
+                std::process::exit(2);
+            },
+        },
+        None => { emu.exit_at_exit_symbol(); },
+    }
+
+    if matches.is_present("host-calls") {
+        emu.enable_host_calls(HOSTCALL_BASE, None);
+    }
+
+    let timeout = matches.value_of("timeout-cycles")
+        .map_or(u64::MAX, |s| s.parse().unwrap_or_else(|_| {
+            eprintln!("bad --timeout-cycles {:?}", s);
+            std::process::exit(2);
+        }));
+
+    let failure = match emu.run_until_cycle(timeout) {
+        Ok(StopReason::Exit(_)) | Ok(StopReason::Halted) => match emu.exit_code() {
+            Some(0) => None,
+            Some(code) => Some(format!("exit code {}", code)),
+            None => Some("halted".to_string()),
+        },
+        Ok(StopReason::CycleLimit) =>
+            Some(format!("timed out after {} cycles", timeout)),
+        Ok(reason) => Some(format!("stopped: {:?}", reason)),
+        Err(e) => Some(format!("error: {}", e)),
+    };
+
+    let output = emu.take_uart_output();
+    let failure = failure.or_else(|| {
+        let path = matches.value_of("expect-output")?;
+        let expected = std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("can't read {:?}: {}", path, e);
+            std::process::exit(2);
+        });
+        first_difference(&output, &expected).map(|i|
+            format!("output differs from {} at byte {}", path, i))
+    });
+
+    if let Some(path) = matches.value_of("save-output") {
+        std::fs::write(path, &output).unwrap();
+    }
+
+    match failure {
+        None => println!("PASS ({} cycles)", emu.cycle_count),
+        Some(msg) => {
+            println!("FAIL: {} @ {}", msg, emu.fmt_location(emu.pc));
+            std::process::exit(1);
+        },
+    }
+}
+
+/// run two firmware files with their USARTs connected
+fn cosim(matches: &ArgMatches) {
+    let device = device_arg(matches);
+    let load = |name| {
+        let mut emu = yaavre::Emulator::for_device(&device);
+        // warnings still get printed
+        emu.set_diagnostics_sink(Arc::new(UartWriterSink::new(None)));
+        let path = matches.value_of(name).unwrap();
+        emu.load(path).unwrap_or_else(|e| {
+            eprintln!("can't load {:?}: {}", path, e);
+            std::process::exit(1);
+        });
+        emu.reset();
+        emu
+    };
+    let mut cosim = Cosim::new(load("A"), load("B"));
+
+    let stop = match matches.value_of("timeout-us") {
+        Some(s) => {
+            let us = s.parse().unwrap_or_else(|_| {
+                eprintln!("bad --timeout-us {:?}", s);
+                std::process::exit(1);
+            });
+            cosim.run_until(Duration::from_micros(us))
+        },
+        None => Some(cosim.run()),
+    };
+
+    match stop {
+        Some(stop) => {
+            let emu = if stop.instance == 0 { &cosim.a } else { &cosim.b };
+            let name = if stop.instance == 0 { "A" } else { "B" };
+            match stop.result.fault {
+                Some(e) => println!("{} error: {} @ {}", name, e, emu.fmt_location(emu.pc)),
+                None => println!("{} stopped: {:?} @ {}", name, stop.result.reason,
+                                 emu.fmt_location(emu.pc)),
+            }
+        },
+        None => println!("timed out"),
+    }
+
+    println!("A sent: {:?}", String::from_utf8_lossy(&cosim.a.take_uart_output()));
+    println!("B sent: {:?}", String::from_utf8_lossy(&cosim.b.take_uart_output()));
+}
 
 fn main() {
     let matches = App::new("yaavre")
                     .arg(Arg::with_name("BIN").index(1))
+                    .arg(Arg::with_name("device")
+                            .long("device")
+                            .value_name("NAME")
+                            .possible_values(&["atxmega128a4u", "atmega328p", "attiny85"])
+                            .global(true)
+                            .help("the chip to emulate (default \
+                                   atxmega128a4u)"))
+                    .arg(Arg::with_name("isa")
+                            .long("isa")
+                            .value_name("LEVEL")
+                            .possible_values(&["avr2", "avr25", "avr4", "avr5",
+                                               "avr51", "avr6", "xmega"])
+                            .help("stop on instructions LEVEL doesn't have, \
+                                   instead of the device's own level"))
+                    .arg(Arg::with_name("quiet")
+                            .short("q")
+                            .long("quiet")
+                            .conflicts_with("verbose")
+                            .help("print only USART output and errors"))
+                    .arg(Arg::with_name("verbose")
+                            .short("v")
+                            .long("verbose")
+                            .multiple(true)
+                            .help("also print a run summary; twice, trace \
+                                   instructions to stdout"))
+                    .arg(Arg::with_name("dump-file")
+                            .long("dump-file")
+                            .value_name("FILE")
+                            .help("where SIGUSR2 dumps the state and data \
+                                   memory (default yaavre-dump.txt)"))
+                    .arg(Arg::with_name("uart-pty")
+                            .long("uart-pty")
+                            .help("connect the USART to a new pseudo-terminal"))
+                    .arg(Arg::with_name("uart-out")
+                            .long("uart-out")
+                            .value_name("FILE|tcp:HOST:PORT|none")
+                            .conflicts_with("uart-pty")
+                            .help("write USART output as is to a file or a \
+                                   TCP connection, or drop it, instead of \
+                                   printing it"))
+                    .arg(Arg::with_name("host-calls")
+                            .long("host-calls")
+                            .help("let the firmware call host services \
+                                   through registers at 0xfc0"))
+                    .arg(Arg::with_name("host-files")
+                            .long("host-files")
+                            .value_name("DIR")
+                            .requires("host-calls")
+                            .help("let host calls read files under DIR"))
+                    .arg(Arg::with_name("host-time")
+                            .long("host-time")
+                            .value_name("host|YYYY-MM-DD[THH:MM:SS]")
+                            .requires("host-calls")
+                            .help("what host calls say the UTC time is: the \
+                                   host's time (the default), or a clock \
+                                   starting at the given date and running \
+                                   with emulated time"))
+                    .arg(Arg::with_name("ext-device")
+                            .long("ext-device")
+                            .value_name("COMMAND|tcp:HOST:PORT")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("let a program model a peripheral, talking \
+                                   on its stdin and stdout, or over TCP; see \
+                                   src/extdev.rs for the protocol"))
+                    .arg(Arg::with_name("usb-cdc")
+                            .long("usb-cdc")
+                            .value_name("pty|tcp:HOST:PORT")
+                            .help("enumerate the firmware's USB device and \
+                                   connect its CDC-ACM serial port to a new \
+                                   pseudo-terminal or a TCP connection"))
+                    .arg(Arg::with_name("exit-addr")
+                            .long("exit-addr")
+                            .value_name("ADDR")
+                            .help("exit when the firmware writes to data \
+                                   address ADDR (hex), with the value \
+                                   written as exit code"))
+                    .arg(Arg::with_name("exit-at")
+                            .long("exit-at")
+                            .value_name("ADDR|SYMBOL")
+                            .help("exit when execution reaches ADDR (hex) or \
+                                   SYMBOL (e.g. _exit), with r24 as exit \
+                                   code"))
+                    .arg(Arg::with_name("mem-init")
+                            .long("mem-init")
+                            .value_name("BYTE|random:SEED")
+                            .help("fill SRAM and the registers with BYTE \
+                                   (hex) or seeded random bytes at power-on, \
+                                   instead of zeros"))
+                    .arg(Arg::with_name("entry")
+                            .long("entry")
+                            .value_name("ADDR|SYMBOL")
+                            .help("start executing at ADDR (hex) or SYMBOL \
+                                   instead of the reset vector"))
+                    .arg(Arg::with_name("sp")
+                            .long("sp")
+                            .value_name("ADDR")
+                            .help("initial stack pointer (hex)"))
+                    .arg(Arg::with_name("set-reg")
+                            .long("set-reg")
+                            .value_name("rN=VAL")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("set a register (VAL in hex) before \
+                                   running"))
+                    .arg(Arg::with_name("poke")
+                            .long("poke")
+                            .value_name("ADDR=BYTES")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("write BYTES (hex) to data space at ADDR \
+                                   (hex) before running"))
+                    .arg(Arg::with_name("until")
+                            .long("until")
+                            .value_name("ADDR|SYMBOL")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("stop when execution reaches ADDR (hex) or \
+                                   SYMBOL"))
+                    .arg(Arg::with_name("max-insns")
+                            .long("max-insns")
+                            .value_name("N")
+                            .help("stop after N instructions"))
+                    .arg(Arg::with_name("max-cycles")
+                            .long("max-cycles")
+                            .value_name("N")
+                            .help("stop after N cycles"))
+                    .arg(Arg::with_name("stop-on-uart")
+                            .long("stop-on-uart")
+                            .help("stop when the USART first transmits"))
+                    .arg(Arg::with_name("warp")
+                            .long("warp")
+                            .help("skip over delay loops and idle polling \
+                                   loops instead of running them"))
+                    .arg(Arg::with_name("rtc-wall-clock")
+                            .long("rtc-wall-clock")
+                            .help("run the RTC on the host's clock instead \
+                                   of emulated time"))
+                    .arg(Arg::with_name("record")
+                            .long("record")
+                            .value_name("FILE")
+                            .conflicts_with("replay")
+                            .help("record external inputs to FILE"))
+                    .arg(Arg::with_name("replay")
+                            .long("replay")
+                            .value_name("FILE")
+                            .help("replay external inputs recorded in FILE"))
+                    .arg(Arg::with_name("irq-stress")
+                            .long("irq-stress")
+                            .value_name("SEED[:GAP]")
+                            .conflicts_with("replay")
+                            .help("fire enabled interrupts at random cycles, \
+                                   GAP cycles apart on average (default \
+                                   1000), to find critical section bugs"))
+                    .arg(Arg::with_name("irq-stress-log")
+                            .long("irq-stress-log")
+                            .value_name("FILE")
+                            .requires("irq-stress")
+                            .help("save the interrupts --irq-stress fired \
+                                   to FILE, for --replay"))
+                    .arg(Arg::with_name("adc-stimulus")
+                            .long("adc-stimulus")
+                            .value_name("FILE")
+                            .help("feed ADC input values from a CSV file of \
+                                   cycle,pin,value lines"))
+                    .arg(Arg::with_name("pin-stimulus")
+                            .long("pin-stimulus")
+                            .value_name("FILE")
+                            .help("drive input pins from a CSV file of \
+                                   cycle,PORT.PIN,0|1|z lines"))
+                    .arg(Arg::with_name("device-config")
+                            .long("device-config")
+                            .value_name("FILE")
+                            .help("load the device ID, fuses and signature \
+                                   rows from FILE"))
+                    .arg(Arg::with_name("serial")
+                            .long("serial")
+                            .value_name("HEX")
+                            .help("set the 11 byte serial number in the \
+                                   production signature row"))
+                    .arg(Arg::with_name("fuse")
+                            .long("fuse")
+                            .value_name("N=VAL")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("set fuse byte N to VAL (hex)"))
+                    .arg(Arg::with_name("lock-bits")
+                            .long("lock-bits")
+                            .value_name("VAL")
+                            .help("set the lock bits (hex)"))
+                    .arg(Arg::with_name("xosc-freq")
+                            .long("xosc-freq")
+                            .value_name("HZ")
+                            .help("frequency of the external crystal, or 0 \
+                                   for none (default 16000000)"))
+                    .arg(Arg::with_name("break")
+                            .long("break")
+                            .short("b")
+                            .value_name("ADDR [if COND]")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("stop in the debugger at ADDR, e.g. \
+                                   --break '0x1234 if r24 == 0x42'"))
+                    .arg(Arg::with_name("symbols")
+                            .long("symbols")
+                            .value_name("FILE")
+                            .help("load symbols from avr-nm output, \
+                                   instead of from the ELF file"))
+                    .arg(Arg::with_name("trace")
+                            .long("trace")
+                            .value_name("FILE")
+                            .help("write an instruction trace to FILE"))
+                    .arg(Arg::with_name("trace-format")
+                            .long("trace-format")
+                            .value_name("FORMAT")
+                            .possible_values(&["text", "json"])
+                            .requires("trace")
+                            .help("text (default), or JSON lines"))
+                    .arg(Arg::with_name("trace-regs")
+                            .long("trace-regs")
+                            .requires("trace")
+                            .help("include changed registers and flags in \
+                                   the trace"))
+                    .arg(Arg::with_name("trace-range")
+                            .long("trace-range")
+                            .value_name("START-END")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .requires("trace")
+                            .help("only trace pc in [START, END) (hex)"))
+                    .arg(Arg::with_name("trace-fn")
+                            .long("trace-fn")
+                            .value_name("SYMBOL")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .requires("trace")
+                            .help("only trace inside function SYMBOL"))
+                    .arg(Arg::with_name("io-trace")
+                            .long("io-trace")
+                            .value_name("FILE")
+                            .help("write every IO register access to FILE, \
+                                   with register names and bit fields"))
+                    .arg(Arg::with_name("vcd")
+                            .long("vcd")
+                            .value_name("FILE")
+                            .help("write changes of the --vcd-signal signals \
+                                   (default SREG.I and SP) to FILE, for \
+                                   GTKWave"))
+                    .arg(Arg::with_name("vcd-signal")
+                            .long("vcd-signal")
+                            .value_name("SIGNAL")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .requires("vcd")
+                            .help("record SIGNAL in the --vcd file: a port \
+                                   (PORTA), a pin (PORTA.3), sreg.i, sp, or \
+                                   an IO register (TCC0.CNTL)"))
+                    .arg(Arg::with_name("lcd")
+                            .long("lcd")
+                            .value_name("PORT:RS,E,D4[:COLSxROWS]")
+                            .help("attach an HD44780 character LCD in 4-bit \
+                                   mode, e.g. PORTD:0,1,4:16x2 for RS on \
+                                   PD0, E on PD1 and D4-D7 on PD4-PD7 \
+                                   (default size 16x2)"))
+                    .arg(Arg::with_name("lcd-log")
+                            .long("lcd-log")
+                            .value_name("FILE")
+                            .requires("lcd")
+                            .help("write what the LCD showed, every time it \
+                                   changed, to FILE at exit"))
+                    .arg(Arg::with_name("led")
+                            .long("led")
+                            .value_name("NAME=PORT.PIN[:low]")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("put an LED on a pin, lit while the pin is \
+                                   high, or low with :low"))
+                    .arg(Arg::with_name("button")
+                            .long("button")
+                            .value_name("NAME=PORT.PIN[:high]")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("put a push button on a pin, pulling it \
+                                   low while pressed, or high with :high"))
+                    .arg(Arg::with_name("button-script")
+                            .long("button-script")
+                            .value_name("FILE")
+                            .requires("button")
+                            .help("press and release buttons as FILE says, \
+                                   one \"cycle,button,press|release\" per \
+                                   line"))
+                    .arg(Arg::with_name("led-log")
+                            .long("led-log")
+                            .value_name("FILE")
+                            .requires("led")
+                            .help("write every time an LED turned on or off \
+                                   to FILE at exit"))
+                    .arg(Arg::with_name("profile")
+                            .long("profile")
+                            .help("print cycles spent per function at exit"))
+                    .arg(Arg::with_name("profile-folded")
+                            .long("profile-folded")
+                            .value_name("FILE")
+                            .help("write folded stacks for flamegraph.pl to \
+                                   FILE at exit"))
+                    .arg(Arg::with_name("call-graph")
+                            .long("call-graph")
+                            .value_name("FILE")
+                            .help("write a Graphviz call graph, with call \
+                                   counts, to FILE at exit"))
+                    .arg(Arg::with_name("call-trace")
+                            .long("call-trace")
+                            .value_name("FILE")
+                            .help("write function entries and exits over \
+                                   time as Chrome trace JSON to FILE at exit"))
+                    .arg(Arg::with_name("coverage")
+                            .long("coverage")
+                            .value_name("FILE")
+                            .help("print a coverage summary at exit, and \
+                                   write executed addresses to FILE (for \
+                                   addr2line)"))
+                    .arg(Arg::with_name("lcov")
+                            .long("lcov")
+                            .value_name("FILE")
+                            .requires("coverage")
+                            .help("write coverage as an lcov tracefile to \
+                                   FILE at exit (needs an ELF file with \
+                                   debug info)"))
+                    .arg(Arg::with_name("stats")
+                            .long("stats")
+                            .help("print instruction counts, speed, memory \
+                                   traffic and stack depth when the program \
+                                   stops"))
+                    .arg(Arg::with_name("energy")
+                            .long("energy")
+                            .value_name("CURRENT_TABLE")
+                            .min_values(0)
+                            .max_values(1)
+                            .help("track time spent active and in each \
+                                   sleep mode, and each peripheral's time \
+                                   on, and print them at exit; with a \
+                                   table of currents, estimate consumption"))
+                    .arg(Arg::with_name("heatmap")
+                            .long("heatmap")
+                            .value_name("PAGE_SIZE")
+                            .min_values(0)
+                            .max_values(1)
+                            .help("count data accesses per IO register and \
+                                   per memory page (default 256 bytes), and \
+                                   print the busiest at exit"))
+                    .arg(Arg::with_name("history")
+                            .long("history")
+                            .value_name("N")
+                            .help("list the last N instructions when the \
+                                   program crashes (default 32, 0 for none)"))
+                    .arg(Arg::with_name("unimplemented")
+                            .long("unimplemented")
+                            .value_name("POLICY")
+                            .possible_values(&["fault", "halt", "skip"])
+                            .help("on instructions that don't decode or \
+                                   aren't implemented: fault (default), \
+                                   halt with a report, or skip them"))
+                    .arg(Arg::with_name("stack-report")
+                            .long("stack-report")
+                            .help("print stack usage per function at exit"))
+                    .arg(Arg::with_name("stack-guard")
+                            .long("stack-guard")
+                            .value_name("START-END|SYMBOL")
+                            .help("stop if SP enters [START, END) (hex), or \
+                                   goes below data symbol SYMBOL, e.g. \
+                                   __heap_start"))
+                    .arg(Arg::with_name("taint")
+                            .long("taint")
+                            .help("track data read from the USARTs, and \
+                                   print where it reached PC or an SPM \
+                                   address at exit"))
+                    .arg(Arg::with_name("script")
+                            .long("script")
+                            .value_name("EVENT=FILE")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("run Rhai script FILE on EVENT: every:N \
+                                   (instructions), io:START-END (hex) or \
+                                   break:ID (needs the scripting \
+                                   feature)"))
+                    .arg(Arg::with_name("trap-taint")
+                            .long("trap-taint")
+                            .help("like --taint, but stop the first time"))
+                    .arg(Arg::with_name("flash-map")
+                            .long("flash-map")
+                            .value_name("ADDR")
+                            .help("mirror flash read-only in data space at \
+                                   ADDR (hex)"))
+                    .arg(Arg::with_name("protect")
+                            .long("protect")
+                            .value_name("START-END")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("drop writes to data space [START, END) \
+                                   (hex) with a warning"))
+                    .arg(Arg::with_name("trap-protected")
+                            .long("trap-protected")
+                            .help("stop on writes to protected regions \
+                                   instead of dropping them"))
+                    .arg(Arg::with_name("state-json")
+                            .long("state-json")
+                            .value_name("FILE")
+                            .help("write the final state to FILE as JSON"))
+                    .arg(Arg::with_name("debug")
+                            .long("debug")
+                            .short("d")
+                            .help("start in the interactive debugger"))
+                    .arg(Arg::with_name("tui")
+                            .long("tui")
+                            .help("use the full-screen debugger whenever \
+                                   the debugger starts, with code, \
+                                   registers, stack, watches and USART \
+                                   output in panes (needs the tui \
+                                   feature)"))
+                    .arg(Arg::with_name("checkpoint-interval")
+                            .long("checkpoint-interval")
+                            .value_name("N")
+                            .help("checkpoint every N instructions for \
+                                   stepping back in the debugger"))
+                    .arg(Arg::with_name("hash-interval")
+                            .long("hash-interval")
+                            .value_name("N")
+                            .help("hash the state every N instructions for \
+                                   --hash-log and --check-hashes (default \
+                                   10000)"))
+                    .arg(Arg::with_name("hash-log")
+                            .long("hash-log")
+                            .value_name("FILE")
+                            .help("write the state hashes to FILE"))
+                    .arg(Arg::with_name("check-hashes")
+                            .long("check-hashes")
+                            .value_name("FILE")
+                            .help("stop where the state hashes first differ \
+                                   from a --hash-log FILE"))
+                    .subcommand(SubCommand::with_name("lockstep")
+                            .about("run firmware instruction by instruction \
+                                    next to a JSON trace or another \
+                                    firmware file, stopping where PC, the \
+                                    registers, SREG or SP first differ")
+                            .arg(Arg::with_name("FILE")
+                                    .required(true)
+                                    .index(1))
+                            .arg(Arg::with_name("trace")
+                                    .long("trace")
+                                    .value_name("TRACE")
+                                    .required_unless("against")
+                                    .help("a --trace-format json trace of \
+                                           every instruction"))
+                            .arg(Arg::with_name("against")
+                                    .long("against")
+                                    .value_name("FILE")
+                                    .conflicts_with("trace")
+                                    .help("firmware to run in a second \
+                                           emulator"))
+                            .arg(Arg::with_name("max-insns")
+                                    .long("max-insns")
+                                    .value_name("N")
+                                    .help("stop after N matching \
+                                           instructions")))
+                    .subcommand(SubCommand::with_name("fault-campaign")
+                            .about("flip each bit of the targets every STEP \
+                                    cycles, one fault per run, and count \
+                                    how the runs end compared to a run \
+                                    without faults")
+                            .arg(Arg::with_name("FILE")
+                                    .required(true)
+                                    .index(1))
+                            .arg(Arg::with_name("max-cycles")
+                                    .long("max-cycles")
+                                    .value_name("N")
+                                    .required(true)
+                                    .help("runs still going after N cycles \
+                                           hang; the run without faults \
+                                           must end before"))
+                            .arg(Arg::with_name("from")
+                                    .long("from")
+                                    .value_name("CYCLE")
+                                    .help("first injection cycle (default \
+                                           0)"))
+                            .arg(Arg::with_name("to")
+                                    .long("to")
+                                    .value_name("CYCLE")
+                                    .help("stop injecting at CYCLE \
+                                           (default --max-cycles)"))
+                            .arg(Arg::with_name("step")
+                                    .long("step")
+                                    .value_name("N")
+                                    .help("cycles between injections \
+                                           (default 100)"))
+                            .arg(Arg::with_name("target")
+                                    .long("target")
+                                    .value_name("TARGET")
+                                    .multiple(true)
+                                    .number_of_values(1)
+                                    .help("rN, sreg, data:ADDR or \
+                                           flash:ADDR (hex); default all \
+                                           registers"))
+                            .arg(Arg::with_name("detect")
+                                    .long("detect")
+                                    .value_name("ADDR|SYMBOL")
+                                    .help("the firmware's fault handler; \
+                                           runs reaching it count as \
+                                           detected")))
+                    .subcommand(SubCommand::with_name("compare-hashes")
+                            .about("find where two --hash-log files first \
+                                    differ")
+                            .arg(Arg::with_name("A")
+                                    .required(true)
+                                    .index(1))
+                            .arg(Arg::with_name("B")
+                                    .required(true)
+                                    .index(2)))
+                    .subcommand(SubCommand::with_name("disasm")
+                            .about("list instructions, like objdump -d")
+                            .arg(Arg::with_name("FILE")
+                                    .required(true)
+                                    .index(1))
+                            .arg(Arg::with_name("start")
+                                    .long("start")
+                                    .value_name("ADDR")
+                                    .help("first byte address (hex)"))
+                            .arg(Arg::with_name("end")
+                                    .long("end")
+                                    .value_name("ADDR")
+                                    .help("address of the last instruction \
+                                           (hex)"))
+                            .arg(Arg::with_name("symbol")
+                                    .long("symbol")
+                                    .value_name("FUNCTION")
+                                    .conflicts_with_all(&["start", "end"])
+                                    .help("only list FUNCTION"))
+                            .arg(Arg::with_name("symbols")
+                                    .long("symbols")
+                                    .value_name("FILE")
+                                    .help("load symbols from avr-nm output")))
+                    .subcommand(SubCommand::with_name("test")
+                            .about("run firmware as a test; the exit status \
+                                    is 0 if it passed, 1 if it failed")
+                            .arg(Arg::with_name("FILE")
+                                    .required(true)
+                                    .index(1))
+                            .arg(Arg::with_name("timeout-cycles")
+                                    .long("timeout-cycles")
+                                    .value_name("N")
+                                    .help("fail if the firmware is still \
+                                           running after N cycles"))
+                            .arg(Arg::with_name("expect-output")
+                                    .long("expect-output")
+                                    .value_name("FILE")
+                                    .help("fail unless the USART output is \
+                                           exactly FILE's contents"))
+                            .arg(Arg::with_name("save-output")
+                                    .long("save-output")
+                                    .value_name("FILE")
+                                    .help("write the USART output to FILE, \
+                                           e.g. to make a new golden file"))
+                            .arg(Arg::with_name("exit-at")
+                                    .long("exit-at")
+                                    .value_name("ADDR|SYMBOL")
+                                    .help("finish when execution reaches \
+                                           ADDR or SYMBOL, with r24 as exit \
+                                           code (default _exit or exit, if \
+                                           the symbols have one)"))
+                            .arg(Arg::with_name("host-calls")
+                                    .long("host-calls")
+                                    .help("let the firmware exit through a \
+                                           host call")))
+                    .subcommand(SubCommand::with_name("cosim")
+                            .about("run two firmware files side by side, \
+                                    each one's USART connected to the \
+                                    other's, and print what each sent")
+                            .arg(Arg::with_name("A")
+                                    .required(true)
+                                    .index(1))
+                            .arg(Arg::with_name("B")
+                                    .required(true)
+                                    .index(2))
+                            .arg(Arg::with_name("timeout-us")
+                                    .long("timeout-us")
+                                    .value_name("N")
+                                    .help("stop after N microseconds of \
+                                           emulated time")))
                     .get_matches();
 
-    let mut emu = yaavre::Emulator::new();
-    emu.load_bin(matches.value_of("BIN").unwrap()).unwrap();
-    emu.run();
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        disasm(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("test") {
+        run_test(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("lockstep") {
+        lockstep(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("fault-campaign") {
+        fault_campaign(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cosim") {
+        cosim(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("compare-hashes") {
+        compare_hashes(matches);
+        return;
+    }
+
+    let device = device_arg(&matches);
+    let mut emu = yaavre::Emulator::for_device(&device);
+    let sink = match matches.value_of("uart-out") {
+        Some(dest) => uart_out_sink(dest).unwrap_or_else(|e| {
+            eprintln!("can't open --uart-out {:?}: {}", dest, e);
+            std::process::exit(1);
+        }),
+        None => Arc::new(StdoutSink),
+    };
+    // 0 with -q, 1 by default, 2 with -v, 3 with -vv
+    let verbosity =
+        if matches.is_present("quiet") { 0 } else { 1 + matches.occurrences_of("verbose") };
+    if verbosity == 0 {
+        emu.set_diagnostics_sink(Arc::new(QuietSink(sink)));
+    } else {
+        emu.set_diagnostics_sink(sink);
+    }
+    emu.load(matches.value_of("BIN").unwrap()).unwrap();
+
+    if let Some(path) = matches.value_of("dump-file") {
+        emu.dump_path = PathBuf::from(path);
+    }
+    handle_ctrl_c(&emu);
+
+    if let Some(path) = matches.value_of("device-config") {
+        emu.io_mem.nvm.config = DeviceConfig::load(path).unwrap();
+    }
+
+    if let Some(serial) = matches.value_of("serial") {
+        let serial = hex::decode(serial).unwrap();
+        if !emu.io_mem.nvm.config.set_serial_number(&serial) {
+            eprintln!("--serial needs 11 bytes");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(fuses) = matches.values_of("fuse") {
+        for fuse in fuses {
+            let setting = format!("fuse{}", fuse.replacen("=", " ", 1));
+            if !emu.io_mem.nvm.config.apply_setting(&setting) {
+                eprintln!("bad --fuse value: {}", fuse);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(val) = matches.value_of("lock-bits") {
+        emu.io_mem.nvm.lock_bits = u8::from_str_radix(val, 16).unwrap();
+    }
+
+    if let Some(freq) = matches.value_of("xosc-freq") {
+        let freq: u32 = freq.parse().unwrap();
+        let clock = emu.io_mem.clock_mut().unwrap_or_else(|| {
+            eprintln!("--xosc-freq is not available on {}", device.name);
+            std::process::exit(1);
+        });
+        clock.xosc_freq = if freq == 0 { None } else { Some(freq) };
+    }
+
+    if let Some(spec) = matches.value_of("mem-init") {
+        emu.io_mem.mem_init = MemInit::parse(spec).unwrap_or_else(|| {
+            eprintln!("bad --mem-init {:?}", spec);
+            std::process::exit(1);
+        });
+    }
+
+    // the fuses decide where execution starts
+    emu.reset();
+
+    if let Some(addr) = matches.value_of("flash-map") {
+        emu.map_flash(u32::from_str_radix(addr, 16).unwrap());
+    }
+
+    if let Some(ranges) = matches.values_of("protect") {
+        for range in ranges {
+            let parts: Vec<_> = range.splitn(2, '-')
+                .map(|s| u32::from_str_radix(s, 16))
+                .collect();
+            match &parts[..] {
+                &[Ok(start), Ok(end)] =>
+                    emu.io_mem.write_protect.add(start, end, "protected memory"),
+                _ => {
+                    eprintln!("bad --protect {:?}", range);
+                    std::process::exit(1);
+                },
+            }
+        }
+    }
+
+    emu.io_mem.write_protect.trap = matches.is_present("trap-protected");
+
+    if matches.is_present("uart-pty") {
+        attach_uart_pty(&mut emu);
+    }
+
+    if let Some(addr) = matches.value_of("exit-addr") {
+        emu.io_mem.exit_addr = Some(u32::from_str_radix(addr, 16).unwrap());
+    }
+
+    if matches.is_present("warp") {
+        emu.enable_warp();
+    }
+
+    if matches.is_present("rtc-wall-clock") {
+        let rtc = emu.io_mem.rtc_mut().unwrap_or_else(|| {
+            eprintln!("--rtc-wall-clock is not available on {}", device.name);
+            std::process::exit(1);
+        });
+        rtc.sync_to_wall_clock();
+    }
+
+    if matches.is_present("host-calls") {
+        let files_root = matches.value_of("host-files").map(PathBuf::from);
+        emu.enable_host_calls(HOSTCALL_BASE, files_root);
+
+        let clock = match matches.value_of("host-time") {
+            None | Some("host") => HostClock::Host,
+            Some(s) => match parse_datetime(s) {
+                Some(start) => HostClock::Simulated(start),
+                None => {
+                    eprintln!("bad --host-time {:?}", s);
+                    std::process::exit(1);
+                },
+            },
+        };
+        emu.io_mem.host_calls_mut().unwrap().clock = clock;
+    }
+
+    for spec in matches.values_of("ext-device").into_iter().flatten() {
+        let device = if let Some(addr) = spec.strip_prefix("tcp:") {
+            ExtDevice::connect(addr)
+        } else {
+            ExtDevice::spawn(spec)
+        };
+        match device {
+            Ok(device) => device.attach(&mut emu.io_mem),
+            Err(e) => {
+                eprintln!("can't start --ext-device {:?}: {}", spec, e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    if let Some(dest) = matches.value_of("usb-cdc") {
+        attach_usb_cdc(&mut emu, dest);
+    }
+
+    if let Some(path) = matches.value_of("adc-stimulus") {
+        let stimulus = yaavre::adc::load_stimulus(path).unwrap();
+        let adc = emu.io_mem.adc_mut("ADCA").unwrap_or_else(|| {
+            eprintln!("--adc-stimulus is not available on {}", device.name);
+            std::process::exit(1);
+        });
+        adc.set_stimulus(stimulus);
+    }
+
+    if let Some(path) = matches.value_of("symbols") {
+        emu.symbols = SymbolTable::load_nm(path).unwrap();
+    }
+
+    if let Some(spec) = matches.value_of("exit-at") {
+        match emu.resolve_code_addr(spec) {
+            Some(addr) => emu.exit_pc = Some(addr),
+            None => {
+                eprintln!("bad --exit-at {:?}", spec);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    if let Some(spec) = matches.value_of("entry") {
+        if let Err(e) = emu.set_entry(spec) {
+            eprintln!("bad --entry: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(sp) = matches.value_of("sp") {
+        emu.io_mem.set_sp(u16::from_str_radix(sp, 16).unwrap_or_else(|_| {
+            eprintln!("bad --sp {:?}", sp);
+            std::process::exit(1);
+        }));
+    }
+
+    if let Some(settings) = matches.values_of("set-reg") {
+        for setting in settings {
+            match parse_reg_setting(setting) {
+                Some((r, val)) => emu.set_reg8(r, val),
+                None => {
+                    eprintln!("bad --set-reg {:?}", setting);
+                    std::process::exit(1);
+                },
+            }
+        }
+    }
+
+    if let Some(pokes) = matches.values_of("poke") {
+        for poke in pokes {
+            let parts: Vec<_> = poke.splitn(2, '=').collect();
+            let parsed = match &parts[..] {
+                &[addr, bytes] =>
+                    u32::from_str_radix(addr, 16).ok()
+                        .and_then(|addr| hex::decode(bytes).ok().map(|b| (addr, b))),
+                _ => None,
+            };
+            let res = match parsed {
+                Some((addr, bytes)) =>
+                    emu.poke(addr, &bytes).map_err(|e| e.to_string()),
+                None => Err("expected ADDR=BYTES".to_string()),
+            };
+            if let Err(e) = res {
+                eprintln!("bad --poke {:?}: {}", poke, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = matches.value_of("trace") {
+        let mut filter = TraceFilter::all();
+
+        if let Some(ranges) = matches.values_of("trace-range") {
+            for range in ranges {
+                let parts: Vec<_> = range.splitn(2, '-')
+                    .map(|s| u32::from_str_radix(s, 16))
+                    .collect();
+                match &parts[..] {
+                    &[Ok(start), Ok(end)] => filter.add_range(start, end),
+                    _ => {
+                        eprintln!("bad --trace-range {:?}", range);
+                        std::process::exit(1);
+                    },
+                }
+            }
+        }
+
+        if let Some(names) = matches.values_of("trace-fn") {
+            for name in names {
+                if !filter.add_symbol(&emu.symbols, name) {
+                    eprintln!("unknown function {:?}", name);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        let tracer: Box<dyn Tracer> =
+            if matches.value_of("trace-format") == Some("json") {
+                Box::new(JsonTracer::create(path).unwrap())
+            } else {
+                let show_changes = matches.is_present("trace-regs");
+                Box::new(TextTracer::create(path, show_changes).unwrap())
+            };
+        emu.set_tracer(tracer, filter);
+    } else if verbosity >= 3 {
+        let tracer = TextTracer::new(io::stdout(), false);
+        emu.set_tracer(Box::new(tracer), TraceFilter::all());
+    }
+
+    if let Some(path) = matches.value_of("io-trace") {
+        let out = File::create(path).unwrap_or_else(|e| {
+            eprintln!("can't open --io-trace {:?}: {}", path, e);
+            std::process::exit(1);
+        });
+        emu.set_io_trace(Box::new(io::BufWriter::new(out)));
+    }
+
+    if let Some(spec) = matches.value_of("lcd") {
+        attach_lcd(&mut emu, spec);
+    }
+    attach_board(&mut emu, &matches);
+
+    if let Some(path) = matches.value_of("pin-stimulus") {
+        let stimulus = PinStimulus::new(yaavre::stimulus::load_stimulus(path).unwrap());
+        if let Some(port) = stimulus.missing_ports(&emu.io_mem).first() {
+            eprintln!("bad --pin-stimulus: no port {:?}", port);
+            std::process::exit(1);
+        }
+        emu.io_mem.attach_pin_device(Box::new(stimulus));
+    }
+
+    if let Some(path) = matches.value_of("vcd") {
+        let out = File::create(path).unwrap_or_else(|e| {
+            eprintln!("can't open --vcd {:?}: {}", path, e);
+            std::process::exit(1);
+        });
+        let signals: Vec<&str> = match matches.values_of("vcd-signal") {
+            Some(values) => values.collect(),
+            None => vec!["sreg.i", "sp"],
+        };
+        if let Err(e) = emu.start_vcd(Box::new(io::BufWriter::new(out)), &signals) {
+            eprintln!("bad --vcd-signal: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if matches.is_present("profile") || matches.is_present("profile-folded") {
+        emu.enable_profiling();
+    }
+
+    if matches.is_present("call-graph") || matches.is_present("call-trace") {
+        emu.enable_call_graph();
+    }
+
+    if matches.is_present("coverage") {
+        emu.enable_coverage();
+    }
+
+    if matches.is_present("stats") {
+        emu.enable_stats();
+    }
+
+    if matches.is_present("stack-report") || matches.is_present("stack-guard") {
+        let guard = matches.value_of("stack-guard").map(|spec| {
+            match parse_stack_guard(&emu.symbols, emu.device.sram_start, spec) {
+                Some(guard) => guard,
+                None => {
+                    eprintln!("bad --stack-guard {:?}", spec);
+                    std::process::exit(1);
+                },
+            }
+        });
+        emu.enable_stack_monitor(guard);
+    }
+
+    if matches.is_present("taint") || matches.is_present("trap-taint") {
+        emu.enable_taint(matches.is_present("trap-taint"));
+    }
+
+    if let Some(specs) = matches.values_of("script") {
+        add_scripts(&mut emu, specs);
+    }
+
+    let history = matches.value_of("history").map_or(32, |s| s.parse().unwrap_or_else(|_| {
+        eprintln!("bad --history {:?}", s);
+        std::process::exit(1);
+    }));
+    if history > 0 {
+        emu.enable_history(history);
+    }
+
+    emu.unimplemented_policy = match matches.value_of("unimplemented") {
+        Some("halt") => UnimplementedPolicy::Halt,
+        Some("skip") => UnimplementedPolicy::Skip,
+        _ => UnimplementedPolicy::Fault,
+    };
+
+    let current_table = matches.value_of("energy")
+        .map(|path| yaavre::energy::load_current_table(path).unwrap());
+    if matches.is_present("energy") {
+        emu.enable_energy();
+    }
+
+    if matches.is_present("heatmap") {
+        let page_size: u32 = matches.value_of("heatmap")
+            .map_or(256, |s| s.parse().unwrap());
+        if !page_size.is_power_of_two() {
+            eprintln!("--heatmap page size must be a power of 2");
+            std::process::exit(1);
+        }
+        emu.enable_heatmap(page_size);
+    }
+
+    if let Some(specs) = matches.values_of("break") {
+        for spec in specs {
+            let added = parse_breakpoint(spec)
+                .ok_or("bad address".to_string())
+                .and_then(|(addr, cond)| emu.add_breakpoint(addr, cond));
+            if let Err(e) = added {
+                eprintln!("bad --break {:?}: {}", spec, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("record") {
+        emu.start_recording();
+    }
+
+    if let Some(path) = matches.value_of("replay") {
+        emu.start_replay(InputLog::load(path).unwrap());
+    }
+
+    if let Some(spec) = matches.value_of("irq-stress") {
+        emu.irq_stress = Some(IrqStress::parse(spec).unwrap_or_else(|| {
+            eprintln!("bad --irq-stress {:?}", spec);
+            std::process::exit(1);
+        }));
+    }
+
+    let debug = matches.is_present("debug");
+    let tui = matches.is_present("tui");
+    if debug || matches.is_present("checkpoint-interval") {
+        let interval = matches.value_of("checkpoint-interval")
+            .map(|s| s.parse().unwrap())
+            .unwrap_or(10000);
+        emu.enable_time_travel(interval, 32);
+    }
+
+    if matches.is_present("hash-log") || matches.is_present("check-hashes") {
+        let interval = matches.value_of("hash-interval")
+            .map(|s| s.parse().unwrap())
+            .unwrap_or(10000);
+        emu.enable_state_hashing(interval);
+        if let Some(path) = matches.value_of("check-hashes") {
+            let reference = load_hash_log(path);
+            emu.state_hashes.as_mut().unwrap().set_reference(&reference);
+        }
+    }
+
+    let conds = stop_conditions(&matches, &emu.symbols);
+
+    let result =
+        if debug {
+            run_debugger(&mut emu, tui);
+            Ok(())
+        } else {
+            let res = if conds.is_empty() { emu.run() } else { emu.run_until_any(&conds) };
+
+            if verbosity >= 1 {
+                print!("{}", emu.fmt_state());
+            }
+            if verbosity >= 2 {
+                println!("{:?} after {} instructions, {} cycles ({:?})",
+                         res.reason, res.insns, res.cycles, emu.emulated_time());
+            }
+            if let Some(report) = emu.stats_report() {
+                print!("{}", report);
+            }
+
+            match res.reason {
+                StopReason::Break => {
+                    println!("BREAK @ {:#x}", emu.pc);
+                    run_debugger(&mut emu, tui);
+                    Ok(())
+                },
+                StopReason::Breakpoint(id) => {
+                    println!("breakpoint {} @ {:#x}", id, emu.pc);
+                    run_debugger(&mut emu, tui);
+                    Ok(())
+                },
+                StopReason::Condition(i) => {
+                    println!("stopped: {}", conds[i]);
+                    Ok(())
+                },
+                StopReason::Diverged(insns) => {
+                    let last_match =
+                        emu.state_hashes.as_ref().unwrap().last_match_before(insns);
+                    println!("state diverged from {} between instructions {} and {}",
+                             matches.value_of("check-hashes").unwrap(), last_match, insns);
+                    Ok(())
+                },
+                StopReason::Unimplemented => {
+                    println!("unimplemented instruction @ {:#x}", emu.pc);
+                    run_debugger(&mut emu, tui);
+                    Ok(())
+                },
+                StopReason::Interrupted => {
+                    println!("interrupted @ {:#x}", emu.pc);
+                    run_debugger(&mut emu, tui);
+                    Ok(())
+                },
+                _ => match res.fault {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                },
+            }
+        };
+
+    emu.clear_tracer();
+    emu.clear_io_trace();
+    emu.stop_vcd();
+
+    if let Some(path) = matches.value_of("lcd-log") {
+        let lcd: &Hd44780 = emu.io_mem.pin_device(LCD_NAME).unwrap();
+        std::fs::write(path, lcd.fmt_frames()).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("led-log") {
+        let board: &Board = emu.io_mem.pin_device(BOARD_NAME).unwrap();
+        std::fs::write(path, board.fmt_led_events()).unwrap();
+    }
+
+    if matches.is_present("profile") {
+        print!("{}", emu.profile_report().unwrap());
+    }
+
+    if let Some(path) = matches.value_of("profile-folded") {
+        let folded = emu.profiler.as_ref().unwrap().folded(&emu.symbols);
+        std::fs::write(path, folded).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("call-graph") {
+        std::fs::write(path, emu.call_graph_dot().unwrap()).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("call-trace") {
+        std::fs::write(path, emu.call_trace_json().unwrap()).unwrap();
+    }
+
+    if matches.is_present("stack-report") {
+        print!("{}", emu.stack_report().unwrap());
+    }
+
+    if matches.is_present("heatmap") {
+        print!("{}", emu.heatmap_report(20).unwrap());
+    }
+
+    if let Some(report) = emu.energy_report(current_table.as_ref()) {
+        print!("{}", report);
+    }
+
+    if let Some(report) = emu.taint_report() {
+        print!("{}", report);
+    }
+
+    if let Some(path) = matches.value_of("coverage") {
+        print!("{}", emu.coverage_report().unwrap());
+        std::fs::write(path, emu.coverage.as_ref().unwrap().addresses()).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("lcov") {
+        match emu.coverage_lcov() {
+            Some(lcov) => std::fs::write(path, lcov).unwrap(),
+            None => eprintln!("no line info for --lcov"),
+        }
+    }
+
+    if let Some(path) = matches.value_of("hash-log") {
+        emu.state_hashes.as_ref().unwrap().save(path).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("state-json") {
+        std::fs::write(path, emu.state_json()).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("record") {
+        emu.stop_recording().unwrap().save(path).unwrap();
+    }
+
+    if let Some(path) = matches.value_of("irq-stress-log") {
+        emu.irq_stress.as_ref().unwrap().schedule().save(path).unwrap();
+    }
+
+    if let Err(e) = result {
+        eprint!("{}", emu.crash_report(&e));
+        std::process::exit(1);
+    }
+
+    if let StopReason::Exit(code) = emu.stop_reason {
+        std::process::exit(code as i32);
+    }
 }
@@ -0,0 +1,555 @@
+// Classic AVR timer/counters, like the ATmega328P's Timer0, 1 and 2
+//
+// Counting follows the waveform generation mode: normal, CTC, fast PWM and
+// phase correct PWM (phase and frequency correct counts the same way), with
+// TOP from the mode, OCRnA or ICRn. The output compare pins aren't driven,
+// OCRn writes take effect immediately even in the PWM modes, and Timer2's
+// asynchronous mode and input capture aren't modelled.
+//
+// The registers aren't in one block: TIFRn and TIMSKn are apart from the
+// rest, and which address holds what differs between chips, so each timer
+// has a table of them. Where timers share TIFR and TIMSK, as on the
+// ATtiny85, only one of them can have them.
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// registers, as offsets for read() and write()
+pub const TCCRA : u32 = 0x00;
+pub const TCCRB : u32 = 0x01;
+pub const TCCRC : u32 = 0x02;
+pub const TCNT : u32 = 0x03;
+pub const TCNT_H : u32 = 0x04;
+pub const ICR : u32 = 0x05;
+pub const ICR_H : u32 = 0x06;
+pub const OCRA : u32 = 0x07;
+pub const OCRA_H : u32 = 0x08;
+pub const OCRB : u32 = 0x09;
+pub const OCRB_H : u32 = 0x0A;
+pub const TIFR : u32 = 0x0B;
+pub const TIMSK : u32 = 0x0C;
+
+// flags, as kept internally
+const TOV : u8 = 0x01;
+const OCFA : u8 = 0x02;
+const OCFB : u8 = 0x04;
+
+// TCCRB bits
+const CS_MASK : u8 = 0x07;
+
+/// clock dividers for CS values 0-7. 0 is stopped, or clocked from a pin.
+pub const PRESCALERS : [u64; 8] = [0, 1, 8, 64, 256, 1024, 0, 0];
+/// Timer2's, which has more steps
+pub const ASYNC_PRESCALERS : [u64; 8] = [0, 1, 8, 32, 64, 128, 256, 1024];
+
+
+/// how a timer sits on a particular chip
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimerLayout {
+    /// (base, size) of TCCRnA and the registers after it
+    pub block: (u32, u32),
+    /// (data space address, register), for every register it has
+    pub regs: &'static [(u32, u32)],
+    /// the TOV, OCFA and OCFB bits in TIFR, and their enables in TIMSK
+    pub bits: [u8; 3],
+    /// OVF, COMPA and COMPB vectors
+    pub vectors: [u8; 3],
+    /// 16 bits, with ICRn and 4 WGM bits
+    pub wide: bool,
+    pub prescalers: [u64; 8],
+}
+
+pub const ATMEGA328P_TIMER0 : TimerLayout = TimerLayout {
+    block: (0x44, 0x05),
+    regs: &[
+        (0x44, TCCRA), (0x45, TCCRB), (0x46, TCNT), (0x47, OCRA), (0x48, OCRB),
+        (0x35, TIFR), (0x6E, TIMSK),
+    ],
+    bits: [0x01, 0x02, 0x04],
+    vectors: [16, 14, 15],
+    wide: false,
+    prescalers: PRESCALERS,
+};
+
+pub const ATMEGA328P_TIMER1 : TimerLayout = TimerLayout {
+    block: (0x80, 0x0C),
+    regs: &[
+        (0x80, TCCRA), (0x81, TCCRB), (0x82, TCCRC),
+        (0x84, TCNT), (0x85, TCNT_H), (0x86, ICR), (0x87, ICR_H),
+        (0x88, OCRA), (0x89, OCRA_H), (0x8A, OCRB), (0x8B, OCRB_H),
+        (0x36, TIFR), (0x6F, TIMSK),
+    ],
+    bits: [0x01, 0x02, 0x04],
+    vectors: [13, 11, 12],
+    wide: true,
+    prescalers: PRESCALERS,
+};
+
+pub const ATMEGA328P_TIMER2 : TimerLayout = TimerLayout {
+    block: (0xB0, 0x05),
+    regs: &[
+        (0xB0, TCCRA), (0xB1, TCCRB), (0xB2, TCNT), (0xB3, OCRA), (0xB4, OCRB),
+        (0x37, TIFR), (0x70, TIMSK),
+    ],
+    bits: [0x01, 0x02, 0x04],
+    vectors: [9, 7, 8],
+    wide: false,
+    prescalers: ASYNC_PRESCALERS,
+};
+
+/// Timer0; Timer1 is a different design with a PLL clock, and isn't here
+pub const ATTINY85_TIMER0 : TimerLayout = TimerLayout {
+    block: (0x48, 0x03),
+    regs: &[
+        (0x48, OCRB), (0x49, OCRA), (0x4A, TCCRA), (0x52, TCNT), (0x53, TCCRB),
+        (0x58, TIFR), (0x59, TIMSK),
+    ],
+    bits: [0x02, 0x10, 0x08],
+    vectors: [5, 10, 11],
+    wide: false,
+    prescalers: PRESCALERS,
+};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Normal,
+    Ctc,
+    FastPwm,
+    PhaseCorrect,
+}
+
+#[derive(Clone)]
+pub struct MegaTimer {
+    name: String,
+    layout: TimerLayout,
+
+    pub tccra: u8,
+    pub tccrb: u8,
+    pub tcnt: u16,
+    pub icr: u16,
+    pub ocra: u16,
+    pub ocrb: u16,
+    /// TOV, OCFA and OCFB in the internal layout
+    pub flags: u8,
+    pub mask: u8,
+    /// high byte latch for 16-bit accesses
+    pub temp: u8,
+    /// phase correct modes count back down from TOP
+    counting_down: bool,
+
+    /// cycle count at the last tick
+    last_tick: u64,
+    /// cycles not yet counted because of the prescaler
+    prescaler_acc: u64,
+}
+
+impl MegaTimer {
+    pub fn new(name: &str, layout: TimerLayout) -> MegaTimer {
+        MegaTimer {
+            name: name.to_string(),
+            layout,
+
+            tccra: 0,
+            tccrb: 0,
+            tcnt: 0,
+            icr: 0,
+            ocra: 0,
+            ocrb: 0,
+            flags: 0,
+            mask: 0,
+            temp: 0,
+            counting_down: false,
+
+            last_tick: 0,
+            prescaler_acc: 0,
+        }
+    }
+
+    /// clock divider selected by TCCRnB, or None if the timer is stopped or
+    /// clocked from a pin
+    pub fn prescaler(&self) -> Option<u64> {
+        match self.layout.prescalers[(self.tccrb & CS_MASK) as usize] {
+            0 => None,
+            div => Some(div),
+        }
+    }
+
+    fn wgm(&self) -> u8 {
+        let high = if self.layout.wide { (self.tccrb >> 3) & 0x3 } else { (self.tccrb >> 3) & 0x1 };
+        (high << 2) | (self.tccra & 0x3)
+    }
+
+    fn max(&self) -> u16 {
+        if self.layout.wide { 0xffff } else { 0xff }
+    }
+
+    /// (mode, TOP) for the WGM bits
+    fn mode(&self) -> (Mode, u16) {
+        let max = self.max();
+        if !self.layout.wide {
+            return match self.wgm() {
+                1 => (Mode::PhaseCorrect, max),
+                2 => (Mode::Ctc, self.ocra),
+                3 => (Mode::FastPwm, max),
+                5 => (Mode::PhaseCorrect, self.ocra),
+                7 => (Mode::FastPwm, self.ocra),
+                _ => (Mode::Normal, max),
+            };
+        }
+
+        match self.wgm() {
+            1 => (Mode::PhaseCorrect, 0xff),
+            2 => (Mode::PhaseCorrect, 0x1ff),
+            3 => (Mode::PhaseCorrect, 0x3ff),
+            4 => (Mode::Ctc, self.ocra),
+            5 => (Mode::FastPwm, 0xff),
+            6 => (Mode::FastPwm, 0x1ff),
+            7 => (Mode::FastPwm, 0x3ff),
+            8 | 10 => (Mode::PhaseCorrect, self.icr),
+            9 | 11 => (Mode::PhaseCorrect, self.ocra),
+            12 => (Mode::Ctc, self.icr),
+            14 => (Mode::FastPwm, self.icr),
+            15 => (Mode::FastPwm, self.ocra),
+            _ => (Mode::Normal, max),
+        }
+    }
+
+    fn counting_down(&self) -> bool {
+        self.counting_down && self.mode().0 == Mode::PhaseCorrect
+    }
+
+    /// timer clocks until the next one that wraps, turns around or matches
+    /// a compare register
+    fn counts_to_event(&self) -> u64 {
+        let (mode, top) = self.mode();
+        let (cnt, top) = (self.tcnt as u64, top as u64);
+        let ocrs = [self.ocra as u64, self.ocrb as u64];
+
+        if mode == Mode::PhaseCorrect {
+            if self.counting_down() {
+                let to_bottom = if cnt == 0 { 1 } else { cnt };
+                ocrs.iter()
+                    .filter(|&&ocr| ocr < cnt)
+                    .map(|&ocr| cnt - ocr)
+                    .fold(to_bottom, |a, b| a.min(b))
+            } else {
+                let to_top = if cnt >= top { 1 } else { top - cnt };
+                ocrs.iter()
+                    .filter(|&&ocr| ocr > cnt && ocr <= top)
+                    .map(|&ocr| ocr - cnt)
+                    .fold(to_top, |a, b| a.min(b))
+            }
+        } else {
+            let wrap = if cnt <= top { top } else { self.max() as u64 };
+            ocrs.iter()
+                .filter(|&&ocr| ocr > cnt && ocr <= wrap)
+                .map(|&ocr| ocr - cnt)
+                .fold(wrap - cnt + 1, |a, b| a.min(b))
+        }
+    }
+
+    /// one timer clock
+    fn count(&mut self) {
+        let (mode, top) = self.mode();
+        let max = self.max();
+
+        if mode == Mode::PhaseCorrect {
+            if self.counting_down {
+                if self.tcnt == 0 {
+                    self.counting_down = false;
+                    self.tcnt = if top > 0 { 1 } else { 0 };
+                } else {
+                    self.tcnt -= 1;
+                    if self.tcnt == 0 {
+                        self.flags |= TOV;
+                    }
+                }
+            } else if self.tcnt >= top {
+                self.counting_down = true;
+                self.tcnt = top.saturating_sub(1);
+            } else {
+                self.tcnt += 1;
+            }
+        } else if self.tcnt == top || self.tcnt == max {
+            self.counting_down = false;
+            // CTC clears at TOP without overflowing
+            if mode != Mode::Ctc || self.tcnt == max {
+                self.flags |= TOV;
+            }
+            self.tcnt = 0;
+        } else {
+            self.tcnt += 1;
+        }
+
+        if self.tcnt == self.ocra {
+            self.flags |= OCFA;
+        }
+        if self.tcnt == self.ocrb {
+            self.flags |= OCFB;
+        }
+    }
+
+    /// count `counts` timer clocks, setting the overflow and compare match
+    /// flags
+    pub fn advance(&mut self, mut counts: u64) {
+        while counts > 0 {
+            let step = counts.min(self.counts_to_event());
+
+            // nothing happens on the way to the event
+            let quiet = (step - 1) as u16;
+            if self.counting_down() {
+                self.tcnt -= quiet;
+            } else {
+                self.tcnt += quiet;
+            }
+            self.count();
+
+            counts -= step;
+        }
+    }
+
+    /// the TIFR/TIMSK value for internal flags `flags`
+    fn flags_to_reg(&self, flags: u8) -> u8 {
+        [TOV, OCFA, OCFB].iter()
+            .zip(&self.layout.bits)
+            .filter(|&(&flag, _)| (flags & flag) != 0)
+            .fold(0, |val, (_, &bit)| val | bit)
+    }
+
+    /// the internal flags for TIFR/TIMSK value `val`
+    fn reg_to_flags(&self, val: u8) -> u8 {
+        [TOV, OCFA, OCFB].iter()
+            .zip(&self.layout.bits)
+            .filter(|&(_, &bit)| (val & bit) != 0)
+            .fold(0, |flags, (&flag, _)| flags | flag)
+    }
+
+    /// (flag, vector) for each interrupt source
+    fn interrupt_sources(&self) -> [(u8, u8); 3] {
+        let v = self.layout.vectors;
+        [(TOV, v[0]), (OCFA, v[1]), (OCFB, v[2])]
+    }
+
+    // the high byte goes through TEMP: read after the low byte, and
+    // written before it
+    fn read16(&mut self, reg: u32, val: u16) -> u8 {
+        if self.layout.wide && (reg == TCNT_H || reg == ICR_H || reg == OCRA_H || reg == OCRB_H) {
+            self.temp
+        } else {
+            self.temp = (val >> 8) as u8;
+            val as u8
+        }
+    }
+
+    /// the new value, for a write to the low byte
+    fn write16(&mut self, reg: u32, val: u8) -> Option<u16> {
+        if !self.layout.wide {
+            Some(val as u16)
+        } else if reg == TCNT_H || reg == ICR_H || reg == OCRA_H || reg == OCRB_H {
+            self.temp = val;
+            None
+        } else {
+            Some(((self.temp as u16) << 8) | (val as u16))
+        }
+    }
+}
+
+impl Peripheral for MegaTimer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        self.layout.block
+    }
+
+    fn reg_offset(&self, addr: u32) -> Option<u32> {
+        self.layout.regs.iter()
+            .find(|r| r.0 == addr)
+            .map(|r| r.1)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            TCCRA => self.tccra,
+            TCCRB => self.tccrb,
+            TCNT | TCNT_H => {
+                let tcnt = self.tcnt;
+                self.read16(ofs, tcnt)
+            },
+            ICR | ICR_H => {
+                let icr = self.icr;
+                self.read16(ofs, icr)
+            },
+            OCRA | OCRA_H => {
+                let ocra = self.ocra;
+                self.read16(ofs, ocra)
+            },
+            OCRB | OCRB_H => {
+                let ocrb = self.ocrb;
+                self.read16(ofs, ocrb)
+            },
+            TIFR => self.flags_to_reg(self.flags),
+            TIMSK => self.flags_to_reg(self.mask),
+            // TCCRnC only has force strobes
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            TCCRA => self.tccra = val,
+            // FOCnA/B are strobes
+            TCCRB => self.tccrb = if self.layout.wide { val } else { val & 0x3f },
+            TCNT | TCNT_H => {
+                if let Some(tcnt) = self.write16(ofs, val) {
+                    self.tcnt = tcnt;
+                }
+            },
+            ICR | ICR_H => {
+                if let Some(icr) = self.write16(ofs, val) {
+                    self.icr = icr;
+                }
+            },
+            OCRA | OCRA_H => {
+                if let Some(ocra) = self.write16(ofs, val) {
+                    self.ocra = ocra;
+                }
+            },
+            OCRB | OCRB_H => {
+                if let Some(ocrb) = self.write16(ofs, val) {
+                    self.ocrb = ocrb;
+                }
+            },
+            // write 1 to clear
+            TIFR => self.flags &= !self.reg_to_flags(val),
+            TIMSK => self.mask = self.reg_to_flags(val),
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let last_tick = self.last_tick;
+        *self = MegaTimer::new(&self.name, self.layout);
+        self.last_tick = last_tick;
+    }
+
+    fn tick(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.last_tick);
+        self.last_tick = now;
+
+        if let Some(div) = self.prescaler() {
+            let total = self.prescaler_acc + elapsed;
+            self.prescaler_acc = total % div;
+            self.advance(total / div);
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        self.last_tick = now;
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let div = self.prescaler()?;
+        Some(self.last_tick + self.counts_to_event() * div - self.prescaler_acc)
+    }
+
+    // classic AVRs have no interrupt levels; everything is low level
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        self.interrupt_sources()
+            .iter()
+            .filter(|&&(flag, _)| (self.flags & self.mask & flag) != 0)
+            .map(|&(_, vector)| (vector, 1))
+            .min()
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        out.extend(self.interrupt_sources()
+            .iter()
+            .filter(|&&(flag, _)| (self.mask & flag) != 0)
+            .map(|&(_, vector)| vector));
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        for &(flag, v) in self.interrupt_sources().iter() {
+            if v == vector {
+                self.flags &= !flag;
+            }
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.prescaler().is_some()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.tccra, self.tccrb, self.flags, self.mask, self.temp,
+            self.counting_down as u8,
+        ];
+        for &w in &[self.tcnt, self.icr, self.ocra, self.ocrb] {
+            state.write_u16::<LittleEndian>(w).unwrap();
+        }
+        state.write_u64::<LittleEndian>(self.last_tick).unwrap();
+        state.write_u64::<LittleEndian>(self.prescaler_acc).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        // 6 bytes, 4 words, 2 u64s
+        if state.len() < 6 + 8 + 16 {
+            return;
+        }
+
+        self.tccra = state[0];
+        self.tccrb = state[1];
+        self.flags = state[2];
+        self.mask = state[3];
+        self.temp = state[4];
+        self.counting_down = state[5] != 0;
+
+        let mut r = &state[6..];
+        self.tcnt = r.read_u16::<LittleEndian>().unwrap();
+        self.icr = r.read_u16::<LittleEndian>().unwrap();
+        self.ocra = r.read_u16::<LittleEndian>().unwrap();
+        self.ocrb = r.read_u16::<LittleEndian>().unwrap();
+        self.last_tick = r.read_u64::<LittleEndian>().unwrap();
+        self.prescaler_acc = r.read_u64::<LittleEndian>().unwrap();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the atmega328p's timers
+pub fn atmega328p_timers() -> Vec<MegaTimer> {
+    vec![
+        MegaTimer::new("TC0", ATMEGA328P_TIMER0),
+        MegaTimer::new("TC1", ATMEGA328P_TIMER1),
+        MegaTimer::new("TC2", ATMEGA328P_TIMER2),
+    ]
+}
+
+/// the attiny85's timer
+pub fn attiny85_timers() -> Vec<MegaTimer> {
+    vec![
+        MegaTimer::new("TC0", ATTINY85_TIMER0),
+    ]
+}
@@ -0,0 +1,77 @@
+// Power-on contents of SRAM and the registers
+//
+// Real SRAM comes up holding garbage, so zero-filled memory can hide reads
+// of uninitialized variables. Filling it with 0xff or pseudo-random bytes
+// instead makes those bugs show up. The random pattern is seeded so runs can
+// be repeated.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemInit {
+    Fill(u8),
+    Random(u64),
+}
+
+/// splitmix64: the next pseudo-random number after `state`. good enough for
+/// garbage, and the same everywhere.
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+pub struct PatternBytes {
+    init: MemInit,
+    state: u64,
+    buf: u64,
+    left: u32,
+}
+
+impl Iterator for PatternBytes {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        match self.init {
+            MemInit::Fill(val) => Some(val),
+            MemInit::Random(_) => {
+                if self.left == 0 {
+                    self.buf = splitmix64(&mut self.state);
+                    self.left = 8;
+                }
+
+                let val = self.buf as u8;
+                self.buf >>= 8;
+                self.left -= 1;
+                Some(val)
+            },
+        }
+    }
+}
+
+impl MemInit {
+    /// "00", "ff" etc. (hex), or "random:SEED" (decimal)
+    pub fn parse(spec: &str) -> Option<MemInit> {
+        if let Some(seed) = spec.strip_prefix("random:") {
+            seed.parse().ok().map(MemInit::Random)
+        } else {
+            u8::from_str_radix(spec, 16).ok().map(MemInit::Fill)
+        }
+    }
+
+    /// the pattern, from the start
+    pub fn bytes(self) -> PatternBytes {
+        let seed = match self {
+            MemInit::Random(seed) => seed,
+            MemInit::Fill(_) => 0,
+        };
+
+        PatternBytes {
+            init: self,
+            state: seed,
+            buf: 0,
+            left: 0,
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// XMEGA NVM controller, for flash self-programming with SPM, and reading
+// fuses, lock bits and signature rows
+
+use progmem::{ProgramMemory, FLASH_PAGE_SIZE, FLASH_SIZE};
+use fuses::DeviceConfig;
+
+
+pub const NVM_BASE : u32 = 0x01C0;
+pub const NVM_SIZE : u32 = 0x10;
+
+// register offsets
+const ADDR0 : u32 = 0x0;
+const ADDR1 : u32 = 0x1;
+const ADDR2 : u32 = 0x2;
+const DATA0 : u32 = 0x4;
+const DATA1 : u32 = 0x5;
+const DATA2 : u32 = 0x6;
+const LOCK_BITS : u32 = 0x7;
+const CMD : u32 = 0xA;
+const CTRLA : u32 = 0xB;
+const CTRLB : u32 = 0xC;
+const INTCTRL : u32 = 0xD;
+const STATUS : u32 = 0xF;
+
+const CTRLA_CMDEX : u8 = 0x01;
+const STATUS_FLOAD : u8 = 0x01;
+
+// commands
+pub const CMD_NOP : u8 = 0x00;
+pub const CMD_READ_USER_SIG_ROW : u8 = 0x01;
+pub const CMD_READ_CALIB_ROW : u8 = 0x02;
+pub const CMD_READ_FUSES : u8 = 0x07;
+pub const CMD_WRITE_LOCK_BITS : u8 = 0x08;
+pub const CMD_ERASE_APP : u8 = 0x20;
+pub const CMD_ERASE_APP_PAGE : u8 = 0x22;
+pub const CMD_LOAD_FLASH_BUFFER : u8 = 0x23;
+pub const CMD_WRITE_APP_PAGE : u8 = 0x24;
+pub const CMD_ERASE_WRITE_APP_PAGE : u8 = 0x25;
+pub const CMD_ERASE_FLASH_BUFFER : u8 = 0x26;
+pub const CMD_ERASE_BOOT_PAGE : u8 = 0x2A;
+pub const CMD_ERASE_FLASH_PAGE : u8 = 0x2B;
+pub const CMD_WRITE_BOOT_PAGE : u8 = 0x2C;
+pub const CMD_ERASE_WRITE_BOOT_PAGE : u8 = 0x2D;
+pub const CMD_WRITE_FLASH_PAGE : u8 = 0x2E;
+pub const CMD_ERASE_WRITE_FLASH_PAGE : u8 = 0x2F;
+pub const CMD_APP_CRC : u8 = 0x38;
+pub const CMD_BOOT_CRC : u8 = 0x39;
+pub const CMD_FLASH_RANGE_CRC : u8 = 0x3A;
+
+// atxmega128a4u
+const APP_SECTION_SIZE : u32 = 0x20000;
+
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NvmController {
+    pub addr: u32,
+    pub data: [u8; 3],
+    pub cmd: u8,
+    pub ctrlb: u8,
+    pub intctrl: u8,
+
+    /// a flash page. 0xffff where nothing was loaded.
+    pub page_buffer: Vec<u16>,
+    pub buffer_loaded: bool,
+
+    /// 0xff is unlocked. firmware can only clear bits.
+    pub lock_bits: u8,
+    pub config: DeviceConfig,
+
+    /// (first, last) flash byte of a CRC command, for the emulator to run
+    /// through the CRC module
+    pub crc_request: Option<(u32, u32)>,
+}
+
+impl Default for NvmController {
+    fn default() -> NvmController {
+        NvmController::new()
+    }
+}
+
+impl NvmController {
+    pub fn new() -> NvmController {
+        NvmController::for_page_size(FLASH_PAGE_SIZE)
+    }
+
+    /// for flash with `page_size` byte pages
+    pub fn for_page_size(page_size: u32) -> NvmController {
+        NvmController {
+            addr: 0,
+            data: [0; 3],
+            cmd: CMD_NOP,
+            ctrlb: 0,
+            intctrl: 0,
+
+            page_buffer: vec![0xffff; (page_size / 2) as usize],
+            buffer_loaded: false,
+
+            lock_bits: 0xff,
+            config: DeviceConfig::new(),
+
+            crc_request: None,
+        }
+    }
+
+    /// reset the registers, keeping the non-volatile lock bits and config
+    pub fn reset(&mut self) {
+        let lock_bits = self.lock_bits;
+        let config = self.config.clone();
+
+        *self = NvmController::for_page_size(self.page_size());
+        self.lock_bits = lock_bits;
+        self.config = config;
+    }
+
+    /// LPM reads from a signature row instead of flash while the matching
+    /// command is set
+    pub fn read_lpm(&self, addr: u32) -> Option<u8> {
+        let row = match self.cmd {
+            CMD_READ_USER_SIG_ROW => &self.config.user_sig_row,
+            CMD_READ_CALIB_ROW => &self.config.prod_sig_row,
+            _ => return None,
+        };
+
+        Some(row.get(addr as usize).cloned().unwrap_or(0xff))
+    }
+
+    fn execute(&mut self) {
+        match self.cmd {
+            CMD_ERASE_FLASH_BUFFER => self.erase_buffer(),
+            CMD_READ_FUSES => {
+                self.data[0] = self.config.fuses.get(self.addr as usize)
+                    .cloned()
+                    .unwrap_or(0xff);
+            },
+            CMD_WRITE_LOCK_BITS => self.lock_bits &= self.data[0],
+            CMD_APP_CRC => self.crc_request = Some((0, APP_SECTION_SIZE - 1)),
+            CMD_BOOT_CRC => self.crc_request = Some((APP_SECTION_SIZE, FLASH_SIZE - 1)),
+            // the end address is in DATA
+            CMD_FLASH_RANGE_CRC => {
+                let end = self.data[0] as u32 | (self.data[1] as u32) << 8
+                    | (self.data[2] as u32) << 16;
+                self.crc_request = Some((self.addr, end));
+            },
+            _ => {},
+        }
+    }
+
+    pub fn read(&self, ofs: u32) -> u8 {
+        match ofs {
+            ADDR0 => self.addr as u8,
+            ADDR1 => (self.addr >> 8) as u8,
+            ADDR2 => (self.addr >> 16) as u8,
+            DATA0 => self.data[0],
+            DATA1 => self.data[1],
+            DATA2 => self.data[2],
+            LOCK_BITS => self.lock_bits,
+            CMD => self.cmd,
+            CTRLB => self.ctrlb,
+            INTCTRL => self.intctrl,
+            // everything completes instantly, so never busy
+            STATUS if self.buffer_loaded => STATUS_FLOAD,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            ADDR0 => self.addr = (self.addr & !0xff) | (val as u32),
+            ADDR1 => self.addr = (self.addr & !0xff00) | ((val as u32) << 8),
+            ADDR2 => self.addr = (self.addr & !0xff0000) | ((val as u32) << 16),
+            DATA0 => self.data[0] = val,
+            DATA1 => self.data[1] = val,
+            DATA2 => self.data[2] = val,
+            CMD => self.cmd = val,
+            CTRLA if (val & CTRLA_CMDEX) != 0 => self.execute(),
+            CTRLB => self.ctrlb = val,
+            INTCTRL => self.intctrl = val,
+            _ => {},
+        }
+    }
+
+    fn page_size(&self) -> u32 {
+        (self.page_buffer.len() * 2) as u32
+    }
+
+    fn erase_buffer(&mut self) {
+        for word in self.page_buffer.iter_mut() {
+            *word = 0xffff;
+        }
+
+        self.buffer_loaded = false;
+    }
+
+    fn write_page(&mut self, flash: &mut ProgramMemory, addr: u32) -> bool {
+        let page_start = addr & !(self.page_size() - 1);
+        let buffer = self.page_buffer.clone();
+        self.erase_buffer();
+
+        buffer.iter().enumerate().all(|(i, &word)| {
+            let word_addr = page_start + 2 * (i as u32);
+            // programming can only clear bits
+            let old = flash.read_word(word_addr).unwrap_or(0xffff);
+            flash.write_word(word_addr, old & word)
+        })
+    }
+
+    /// executes SPM with the current command, Z=`z` and R1:R0=`data`.
+    /// returns false if the command isn't supported.
+    pub fn spm(&mut self, flash: &mut ProgramMemory, z: u32, data: u16)
+            -> bool {
+
+        match self.cmd {
+            CMD_LOAD_FLASH_BUFFER => {
+                let index = ((z % self.page_size()) / 2) as usize;
+                self.page_buffer[index] = data;
+                self.buffer_loaded = true;
+                true
+            },
+
+            CMD_ERASE_APP =>
+                (0..APP_SECTION_SIZE)
+                    .step_by(flash.page_size() as usize)
+                    .all(|addr| flash.erase_page(addr)),
+
+            CMD_ERASE_APP_PAGE | CMD_ERASE_BOOT_PAGE | CMD_ERASE_FLASH_PAGE =>
+                z < flash.size() && flash.erase_page(z),
+
+            CMD_WRITE_APP_PAGE | CMD_WRITE_BOOT_PAGE | CMD_WRITE_FLASH_PAGE =>
+                z < flash.size() && self.write_page(flash, z),
+
+            CMD_ERASE_WRITE_APP_PAGE | CMD_ERASE_WRITE_BOOT_PAGE
+            | CMD_ERASE_WRITE_FLASH_PAGE =>
+                z < flash.size()
+                    && flash.erase_page(z)
+                    && self.write_page(flash, z),
+
+            _ => false,
+        }
+    }
+}
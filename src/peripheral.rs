@@ -0,0 +1,108 @@
+// Interface for memory-mapped peripherals
+
+use std::any::Any;
+use sleep::SleepMode;
+
+
+pub trait Peripheral: Send {
+    /// e.g. "PORTA"
+    fn name(&self) -> &str;
+
+    /// (base address, size) of the register block in data space
+    fn addr_range(&self) -> (u32, u32);
+
+    /// the offset to pass to read() and write() for data space address
+    /// `addr`, if it's one of the peripheral's registers. that's anywhere in
+    /// addr_range() unless the peripheral has registers elsewhere too, like
+    /// a classic AVR timer's interrupt flags.
+    fn reg_offset(&self, addr: u32) -> Option<u32> {
+        let (base, size) = self.addr_range();
+        if addr >= base && addr < base + size {
+            Some(addr - base)
+        } else {
+            None
+        }
+    }
+
+    /// `ofs` is relative to the base address
+    fn read(&mut self, ofs: u32) -> u8;
+
+    fn write(&mut self, ofs: u32, val: u8);
+
+    /// go back to the power-on register values. anything the host set up,
+    /// like external pin levels or attached devices, stays.
+    fn reset(&mut self);
+
+    /// called before every instruction with the current cycle count
+    fn tick(&mut self, _now: u64) {}
+
+    /// the cycle at which the peripheral's state next changes by itself,
+    /// e.g. a timer overflowing, if it's counting towards anything. ticking
+    /// at that cycle is enough to see the change.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// whether the peripheral's clock stops in sleep mode `mode`, so that it
+    /// doesn't tick until the CPU wakes up
+    fn stops_in_sleep(&self, _mode: SleepMode) -> bool {
+        false
+    }
+
+    /// whether the peripheral is switched on, for energy estimates
+    fn powered(&self) -> bool {
+        false
+    }
+
+    /// the CPU woke up at cycle `now` after the peripheral's clock was
+    /// stopped; carry on as if no time had passed
+    fn resume(&mut self, _now: u64) {}
+
+    /// the most urgent interrupt this peripheral wants, as (vector number,
+    /// level), where level is 1 (low) to 3 (high)
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        None
+    }
+
+    /// add the vectors of the interrupts the firmware has enabled, flagged
+    /// or not, to `out`
+    fn enabled_interrupts(&self, _out: &mut Vec<u8>) {}
+
+    /// the CPU jumped to `vector`; clear flags that hardware clears
+    /// automatically
+    fn interrupt_taken(&mut self, _vector: u8) {}
+
+    /// whether the peripheral is requesting a DMA transfer for DMA trigger
+    /// source `trigsrc`
+    fn dma_request(&self, _trigsrc: u8) -> bool {
+        false
+    }
+
+    /// the DMA controller started a transfer for `trigsrc`; clear the
+    /// request
+    fn dma_ack(&mut self, _trigsrc: u8) {}
+
+    /// move events generated since the last call to `out`, as EVSYS channel
+    /// multiplexer values (e.g. 0xC0 for TCC0 overflow)
+    fn poll_events(&mut self, _out: &mut Vec<u8>) {}
+
+    /// event channel `channel` fired
+    fn event(&mut self, _channel: u8) {}
+
+    /// internal state, for snapshots
+    fn save_state(&self) -> Vec<u8>;
+
+    fn load_state(&mut self, state: &[u8]);
+
+    fn box_clone(&self) -> Box<dyn Peripheral>;
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl Clone for Box<dyn Peripheral> {
+    fn clone(&self) -> Box<dyn Peripheral> {
+        self.box_clone()
+    }
+}
@@ -0,0 +1,156 @@
+// XMEGA programmable multilevel interrupt controller
+//
+// Unlike classic AVRs, taking an interrupt doesn't clear SREG.I. Instead, the
+// PMIC remembers which levels are executing, and only lets a higher level
+// interrupt preempt them. RETI ends the highest executing level.
+
+use std::any::Any;
+use peripheral::Peripheral;
+
+
+pub const PMIC_BASE : u32 = 0x00A0;
+
+/// where the vectors are when CTRL.IVSEL is set (start of the boot section)
+pub const BOOT_VECTORS_BASE : u32 = 0x20000;
+
+// register offsets
+const STATUS : u32 = 0x00;
+const INTPRI : u32 = 0x01;
+const CTRL : u32 = 0x02;
+
+// CTRL bits
+const RREN : u8 = 0x80;
+const IVSEL : u8 = 0x40;
+const LVLEN_MASK : u8 = 0x07;
+
+
+#[derive(Clone)]
+pub struct Pmic {
+    /// executing levels, one bit per level
+    pub status: u8,
+    /// the last low level vector taken, for round-robin scheduling
+    pub intpri: u8,
+    pub ctrl: u8,
+}
+
+impl Default for Pmic {
+    fn default() -> Pmic {
+        Pmic::new()
+    }
+}
+
+impl Pmic {
+    pub fn new() -> Pmic {
+        Pmic {
+            status: 0,
+            intpri: 0,
+            ctrl: 0,
+        }
+    }
+
+    /// the highest executing level, or 0
+    pub fn current_level(&self) -> u8 {
+        8 - (self.status & LVLEN_MASK).leading_zeros() as u8
+    }
+
+    /// whether an interrupt of `level` would be taken now
+    pub fn accepts(&self, level: u8) -> bool {
+        level != 0
+            && (self.ctrl & (1 << (level - 1))) != 0
+            && level > self.current_level()
+    }
+
+    /// choose the interrupt to take out of `candidates` (vector, level)
+    /// pairs: highest level first, then lowest vector. with round-robin
+    /// enabled, low level vectors after INTPRI come first.
+    pub fn select<I>(&self, candidates: I) -> Option<(u8, u8)>
+            where I: Iterator<Item=(u8, u8)> {
+
+        let round_robin = (self.ctrl & RREN) != 0;
+        let intpri = self.intpri;
+
+        candidates
+            .filter(|&(_, level)| self.accepts(level))
+            .min_by_key(|&(vector, level)| {
+                let wrapped = round_robin && level == 1 && vector <= intpri;
+                (3 - level, wrapped, vector)
+            })
+    }
+
+    pub fn vectors_base(&self) -> u32 {
+        if (self.ctrl & IVSEL) != 0 { BOOT_VECTORS_BASE } else { 0 }
+    }
+
+    pub fn enter(&mut self, vector: u8, level: u8) {
+        self.status |= 1 << (level - 1);
+        if level == 1 {
+            self.intpri = vector;
+        }
+    }
+
+    /// RETI ends the highest executing level
+    pub fn reti(&mut self) {
+        let level = self.current_level();
+        if level != 0 {
+            self.status &= !(1 << (level - 1));
+        }
+    }
+}
+
+impl Peripheral for Pmic {
+    fn name(&self) -> &str {
+        "PMIC"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (PMIC_BASE, 0x03)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            STATUS => self.status,
+            INTPRI => self.intpri,
+            CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            // STATUS is read-only
+            INTPRI => self.intpri = val,
+            CTRL => self.ctrl = val,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Pmic::new();
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.status, self.intpri, self.ctrl]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() != 3 {
+            return;
+        }
+
+        self.status = state[0];
+        self.intpri = state[1];
+        self.ctrl = state[2];
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,166 @@
+// Execution profiler
+//
+// Attributes instructions and cycles to the call stack they ran in, using
+// the call targets in `Emulator::call_stack`. Interrupt handlers count as
+// calls from whatever they interrupted. Cycles spent asleep count too, but
+// not as instructions.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use symbols::SymbolTable;
+use callstack::Frame;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cost {
+    pub insns: u64,
+    pub cycles: u64,
+}
+
+impl Cost {
+    fn add(&mut self, other: Cost) {
+        self.insns += other.insns;
+        self.cycles += other.cycles;
+    }
+}
+
+/// name of the outermost frame, i.e. everything outside of any call
+const TOP_FRAME : &str = "[top]";
+
+#[derive(Clone, Default)]
+pub struct Profiler {
+    /// call targets, outermost first, and the cost spent with exactly that
+    /// stack
+    stacks: Vec<(Vec<u32>, Cost)>,
+    index: HashMap<Vec<u32>, usize>,
+    /// index of the stack being charged, which usually stays the same
+    current: Option<usize>,
+}
+
+fn same_stack(key: &[u32], call_stack: &[Frame]) -> bool {
+    key.len() == call_stack.len()
+        && key.iter().zip(call_stack).all(|(&a, f)| a == f.to)
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Default::default()
+    }
+
+    /// charge costs to `call_stack`, in Emulator::call_stack form, from now
+    /// on
+    pub fn set_stack(&mut self, call_stack: &[Frame]) {
+        let i = match self.current {
+            Some(i) if same_stack(&self.stacks[i].0, call_stack) => i,
+            _ => {
+                let key: Vec<u32> = call_stack.iter().map(|f| f.to).collect();
+                let existing = self.index.get(&key).cloned();
+                match existing {
+                    Some(i) => i,
+                    None => {
+                        let i = self.stacks.len();
+                        self.stacks.push((key.clone(), Cost::default()));
+                        self.index.insert(key, i);
+                        i
+                    },
+                }
+            },
+        };
+
+        self.current = Some(i);
+    }
+
+    pub fn charge(&mut self, cost: Cost) {
+        if let Some(i) = self.current {
+            self.stacks[i].1.add(cost);
+        }
+    }
+
+    pub fn total(&self) -> Cost {
+        let mut total = Cost::default();
+        for &(_, cost) in self.stacks.iter() {
+            total.add(cost);
+        }
+        total
+    }
+
+    /// (function address, self cost, total cost including callees). None is
+    /// the top frame.
+    pub fn functions(&self) -> Vec<(Option<u32>, Cost, Cost)> {
+        let mut funcs: HashMap<Option<u32>, (Cost, Cost)> = HashMap::new();
+
+        for &(ref stack, cost) in self.stacks.iter() {
+            funcs.entry(stack.last().cloned()).or_default()
+                .0.add(cost);
+
+            // count recursive functions once per stack
+            let mut seen = vec![None];
+            seen.extend(stack.iter().map(|&addr| Some(addr)));
+            seen.sort();
+            seen.dedup();
+            for func in seen {
+                funcs.entry(func).or_default().1.add(cost);
+            }
+        }
+
+        let mut funcs: Vec<_> = funcs.into_iter()
+            .map(|(func, (self_cost, total))| (func, self_cost, total))
+            .collect();
+        funcs.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        funcs
+    }
+
+    fn name(symbols: &SymbolTable, func: Option<u32>) -> String {
+        match func {
+            Some(addr) => symbols.fmt_addr(addr),
+            None => TOP_FRAME.to_string(),
+        }
+    }
+
+    /// a table of functions, by self cycles
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let total = self.total();
+        let percent = |cycles: u64| {
+            if total.cycles == 0 {
+                0.0
+            } else {
+                100.0 * (cycles as f64) / (total.cycles as f64)
+            }
+        };
+
+        let mut out = String::new();
+        writeln!(out, "{:<32} {:>14} {:>6} {:>14} {:>6} {:>12}",
+            "function", "self cycles", "%", "total cycles", "%", "self insns")
+            .unwrap();
+
+        for (func, self_cost, total_cost) in self.functions() {
+            writeln!(out, "{:<32} {:>14} {:>6.2} {:>14} {:>6.2} {:>12}",
+                Profiler::name(symbols, func),
+                self_cost.cycles, percent(self_cost.cycles),
+                total_cost.cycles, percent(total_cost.cycles),
+                self_cost.insns).unwrap();
+        }
+
+        writeln!(out, "total: {} cycles, {} instructions",
+            total.cycles, total.insns).unwrap();
+        out
+    }
+
+    /// folded stacks for flamegraph.pl: "[top];main;foo <cycles>" per line
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self.stacks
+            .iter()
+            .filter(|&&(_, cost)| cost.cycles != 0)
+            .map(|&(ref stack, cost)| {
+                let mut names = vec![TOP_FRAME.to_string()];
+                names.extend(stack.iter().map(|&addr| symbols.fmt_addr(addr)));
+                format!("{} {}", names.join(";"), cost.cycles)
+            })
+            .collect();
+        lines.sort();
+
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+}
@@ -1,61 +1,169 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt};
 use std::io::{Cursor, Result};
+use std::cmp;
+use std::fmt;
+use std::sync::Arc;
 use disa::{AvrInsn, AvrDisassembler};
+use diag::{NullSink, SharedSink};
+use device::Device;
 
 
+// atxmega128a4u: 128K application + 8K boot section
+pub const FLASH_SIZE : u32 = 0x22000;
+pub const FLASH_PAGE_SIZE : u32 = 0x200;
+
+
+#[derive(Clone)]
 pub struct ProgramMemory {
     words: Vec<u16>,
+
+    /// incremented whenever flash contents change, so that anything caching
+    /// decoded instructions knows to throw them away
+    version: u64,
+
+    /// of the device's flash, in bytes. the image can be shorter.
+    size: u32,
+    page_size: u32,
+
+    pub diag: SharedSink,
+}
+
+impl Default for ProgramMemory {
+    fn default() -> ProgramMemory {
+        ProgramMemory::new()
+    }
 }
 
 impl ProgramMemory {
     pub fn new() -> ProgramMemory {
-        ProgramMemory { words: vec!() }
+        ProgramMemory {
+            words: vec!(),
+            version: 0,
+            size: FLASH_SIZE,
+            page_size: FLASH_PAGE_SIZE,
+            diag: Arc::new(NullSink),
+        }
+    }
+
+    /// flash the size of `device`'s
+    pub fn for_device(device: &Device) -> ProgramMemory {
+        ProgramMemory {
+            size: device.flash_size,
+            page_size: device.flash_page_size,
+            ..ProgramMemory::new()
+        }
     }
 
     pub fn set_bytes(&mut self, bytes: &[u8]) -> Result<()> {
         self.words = vec![0; bytes.len() / 2];
+        self.version += 1;
 
         let mut rdr = Cursor::new(bytes);
         rdr.read_u16_into::<LittleEndian>(&mut self.words)
     }
 
-    pub fn get_prog_mem_byte(&self, addr: u32, call_stack: &str, pc: u32)
-            -> u8 {
+    pub fn words(&self) -> &[u16] {
+        &self.words
+    }
 
-        let pmem_index = (addr / 2) as usize;
+    pub fn set_words(&mut self, words: Vec<u16>) {
+        self.words = words;
+        self.version += 1;
+    }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// size of the loaded image in bytes
+    pub fn len(&self) -> u32 {
+        (self.words.len() * 2) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// size of the device's flash in bytes
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// the word at byte address `addr`, if it's in the image
+    pub fn read_word(&self, addr: u32) -> Option<u16> {
+        self.words.get((addr / 2) as usize).cloned()
+    }
+
+    /// the byte at byte address `addr`, if it's in the image
+    pub fn read_byte(&self, addr: u32) -> Option<u8> {
+        self.read_word(addr).map(|word| (word >> ((addr & 1) * 8)) as u8)
+    }
+
+    /// writes a word at byte address `addr`, growing the image if needed.
+    /// returns false if `addr` is outside of flash.
+    pub fn write_word(&mut self, addr: u32, val: u16) -> bool {
+        if addr >= self.size {
+            return false;
+        }
+
+        let pmem_index = (addr / 2) as usize;
         if pmem_index >= self.words.len() {
-            println!(
-                "WARNING: replacing pmem read from {:#x} @ {}; {:#x} with 0",
-                addr, call_stack, pc);
-            return 0;
+            self.words.resize(pmem_index + 1, 0xffff);
         }
 
-        let word = self.words[pmem_index];
+        self.words[pmem_index] = val;
+        self.version += 1;
+        true
+    }
+
+    /// erases the page containing byte address `addr`
+    pub fn erase_page(&mut self, addr: u32) -> bool {
+        let page_start = addr & !(self.page_size - 1);
+        (page_start..page_start + self.page_size)
+            .step_by(2)
+            .all(|a| self.write_word(a, 0xffff))
+    }
 
-        let mut bytes: [u8; 2] = [0; 2];
-        (&mut bytes[..]).write_u16::<LittleEndian>(word).unwrap();
+    /// the byte at `addr` for LPM. past the image, flash is erased.
+    pub fn get_prog_mem_byte(&self, addr: u32, call_stack: &dyn fmt::Display, pc: u32)
+            -> u8 {
 
-        bytes[(addr & 1) as usize]
+        match self.read_byte(addr) {
+            Some(val) => val,
+            None => {
+                self.diag.warning(&format!(
+                    "WARNING: replacing pmem read from {:#x} @ {}; {:#x} with 0xff",
+                    addr, call_stack, pc));
+                0xff
+            },
+        }
     }
 
     pub fn get_insn_at(&self, addr: u32) -> Option<AvrInsn> {
         let pmem_index = (addr / 2) as usize;
+        if pmem_index >= self.words.len() {
+            return None;
+        }
+
         let decode_input = &self.words[pmem_index..];
         AvrInsn::decode(decode_input).map(|(_, insn)| insn)
     }
 
-    pub fn get_insns_at(&self, start: u32, end: u32) -> AvrDisassembler {
-        let start_index = (start / 2) as usize;
-        let end_index = (end / 2) as usize;
+    pub fn get_insns_at(&self, start: u32, end: u32) -> AvrDisassembler<'_> {
+        let end_index = cmp::min((end / 2) as usize, self.words.len());
+        let start_index = cmp::min((start / 2) as usize, end_index);
         let disasm_input = &self.words[start_index..end_index];
         AvrDisassembler::new(start, disasm_input)
     }
 
     /// like get_insns_at, but with an inclusive [start, end] range
-    pub fn get_insns_at_incl(&self, start: u32, end: u32) -> AvrDisassembler {
-        let last_insn = self.get_insn_at(end).unwrap();
+    pub fn get_insns_at_incl(&self, start: u32, end: u32) -> AvrDisassembler<'_> {
+        let last_size = self.get_insn_at(end).map_or(2, |insn| insn.byte_size());
 
-        self.get_insns_at(start, end + (last_insn.byte_size() as u32))
+        self.get_insns_at(start, end + (last_size as u32))
     }
 }
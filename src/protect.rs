@@ -0,0 +1,52 @@
+// Write protection for regions of data space, to catch wild pointers
+//
+// Firmware can't write to flash through the data space; only SPM (through
+// the NVM controller) can change it. Writes to a protected region are
+// dropped and reported, or stop the emulator if `trap` is set.
+
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub start: u32,
+    /// exclusive
+    pub end: u32,
+    pub name: String,
+}
+
+#[derive(Clone)]
+pub struct WriteProtect {
+    pub regions: Vec<Region>,
+    /// return an error instead of only warning
+    pub trap: bool,
+    /// writes that were dropped
+    pub violations: u64,
+}
+
+impl Default for WriteProtect {
+    fn default() -> WriteProtect {
+        WriteProtect::new()
+    }
+}
+
+impl WriteProtect {
+    pub fn new() -> WriteProtect {
+        WriteProtect {
+            regions: vec![],
+            trap: false,
+            violations: 0,
+        }
+    }
+
+    pub fn add(&mut self, start: u32, end: u32, name: &str) {
+        self.regions.push(Region { start, end, name: name.to_string() });
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.regions.retain(|r| r.name != name);
+    }
+
+    /// the protected region containing `addr`, if any
+    pub fn find(&self, addr: u32) -> Option<&Region> {
+        self.regions.iter().find(|r| addr >= r.start && addr < r.end)
+    }
+}
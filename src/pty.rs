@@ -0,0 +1,80 @@
+// Pseudo-terminal backend for the emulated USART
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use libc;
+
+
+pub struct Pty {
+    master: File,
+    slave_path: String,
+}
+
+impl Pty {
+    /// allocates a new pseudo-terminal in raw mode, with a non-blocking
+    /// master side
+    pub fn open() -> io::Result<Pty> {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // from here on, dropping master closes fd
+            let master = File::from_raw_fd(fd);
+
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let slave_path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            // no echo, no CR/LF translation etc.
+            let mut tio: libc::termios = ::std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut tio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut tio);
+            if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0
+                || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Pty { master, slave_path })
+        }
+    }
+
+    /// path of the slave side, e.g. /dev/pts/5
+    pub fn slave_path(&self) -> &str {
+        &self.slave_path
+    }
+
+    /// returns the next byte written by the host, if there is one
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        let mut buf = [0; 1];
+        // EAGAIN means no data, EIO means no process has the slave open
+        match self.master.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    pub fn write_byte(&mut self, val: u8) {
+        // drop the byte if nobody is reading and the buffer is full, like a
+        // real serial line would
+        let _ = self.master.write(&[val]);
+    }
+}
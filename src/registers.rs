@@ -1,7 +1,15 @@
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RegisterFile {
     pub r: [u8; 32],
 }
 
+impl Default for RegisterFile {
+    fn default() -> RegisterFile {
+        RegisterFile::new()
+    }
+}
+
 impl RegisterFile {
     pub fn new() -> RegisterFile {
         RegisterFile {
@@ -0,0 +1,641 @@
+// IO register maps: the names and bit fields of a device's IO registers
+//
+// Used to describe IO accesses as e.g. "USARTC0.CTRLB = 0x18 (RXEN|TXEN)"
+// rather than "write 0x18 to 0x8a4". A single-bit field is shown by name
+// when it's set; a wider field is always shown with its value.
+
+use iomem::IoAccess;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub mask: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegDef {
+    /// from the start of the block
+    pub ofs: u32,
+    pub name: &'static str,
+    pub fields: &'static [Field],
+}
+
+/// identical groups of registers inside a block, e.g. ADC and DMA channels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channels {
+    /// offset of the first channel from the start of the block
+    pub ofs: u32,
+    pub count: u32,
+    pub size: u32,
+    pub regs: &'static [RegDef],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoBlock {
+    pub name: &'static str,
+    pub base: u32,
+    pub regs: &'static [RegDef],
+    pub channels: Option<Channels>,
+}
+
+
+macro_rules! fields {
+    ($($name:ident = $mask:expr),*) => {
+        &[$(Field { name: stringify!($name), mask: $mask }),*]
+    }
+}
+
+macro_rules! regs {
+    ($($ofs:expr => $name:expr $(, $fields:expr)*;)*) => {
+        &[$(RegDef { ofs: $ofs, name: $name, fields: regs!(@fields $($fields)*) }),*]
+    };
+    (@fields) => { &[] };
+    (@fields $fields:expr) => { $fields };
+}
+
+const INTLVL : &[Field] = fields!(INTLVL = 0x03);
+
+const CPU_REGS : &[RegDef] = regs! {
+    0x04 => "CCP";
+    0x08 => "RAMPD";
+    0x09 => "RAMPX";
+    0x0A => "RAMPY";
+    0x0B => "RAMPZ";
+    0x0C => "EIND";
+    0x0D => "SPL";
+    0x0E => "SPH";
+    0x0F => "SREG", fields!(I = 0x80, T = 0x40, H = 0x20, S = 0x10,
+                            V = 0x08, N = 0x04, Z = 0x02, C = 0x01);
+};
+
+const CLK_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(SCLKSEL = 0x07);
+    0x01 => "PSCTRL", fields!(PSADIV = 0x7C, PSBCDIV = 0x03);
+    0x02 => "LOCK", fields!(LOCK = 0x01);
+    0x03 => "RTCCTRL", fields!(RTCSRC = 0x0E, RTCEN = 0x01);
+    0x04 => "USBCTRL";
+};
+
+const SLEEP_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(SMODE = 0x0E, SEN = 0x01);
+};
+
+const OSC_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(PLLEN = 0x10, XOSCEN = 0x08, RC32KEN = 0x04,
+                            RC32MEN = 0x02, RC2MEN = 0x01);
+    0x01 => "STATUS", fields!(PLLRDY = 0x10, XOSCRDY = 0x08, RC32KRDY = 0x04,
+                              RC32MRDY = 0x02, RC2MRDY = 0x01);
+    0x02 => "XOSCCTRL";
+    0x03 => "XOSCFAIL";
+    0x04 => "RC32KCAL";
+    0x05 => "PLLCTRL", fields!(PLLSRC = 0xC0, PLLDIV = 0x20, PLLFAC = 0x1F);
+    0x06 => "DFLLCTRL";
+};
+
+const AES_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(START = 0x80, AUTO = 0x40, RESET = 0x20,
+                            DECRYPT = 0x10, XOR = 0x04);
+    0x01 => "STATUS", fields!(ERROR = 0x80, SRIF = 0x01);
+    0x02 => "STATE";
+    0x03 => "KEY";
+    0x04 => "INTCTRL", INTLVL;
+};
+
+const CRC_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(RESET = 0xC0, CRC32 = 0x20, SOURCE = 0x0F);
+    0x01 => "STATUS", fields!(ZERO = 0x02, BUSY = 0x01);
+    0x03 => "DATAIN";
+    0x04 => "CHECKSUM0";
+    0x05 => "CHECKSUM1";
+    0x06 => "CHECKSUM2";
+    0x07 => "CHECKSUM3";
+};
+
+const RTC_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(PRESCALER = 0x07);
+    0x01 => "STATUS", fields!(SYNCBUSY = 0x01);
+    0x02 => "INTCTRL", fields!(COMPINTLVL = 0x0C, OVFINTLVL = 0x03);
+    0x03 => "INTFLAGS", fields!(COMPIF = 0x02, OVFIF = 0x01);
+    0x04 => "TEMP";
+    0x08 => "CNTL";
+    0x09 => "CNTH";
+    0x0A => "PERL";
+    0x0B => "PERH";
+    0x0C => "COMPL";
+    0x0D => "COMPH";
+};
+
+const RST_REGS : &[RegDef] = regs! {
+    0x00 => "STATUS", fields!(SDRF = 0x40, SRF = 0x20, PDIRF = 0x10, WDRF = 0x08,
+                              BORF = 0x04, EXTRF = 0x02, PORF = 0x01);
+    0x01 => "CTRL", fields!(SWRST = 0x01);
+};
+
+const PMIC_REGS : &[RegDef] = regs! {
+    0x00 => "STATUS", fields!(NMIEX = 0x80, HILVLEX = 0x04, MEDLVLEX = 0x02,
+                              LOLVLEX = 0x01);
+    0x01 => "INTPRI";
+    0x02 => "CTRL", fields!(RREN = 0x80, IVSEL = 0x40, HILVLEN = 0x04,
+                            MEDLVLEN = 0x02, LOLVLEN = 0x01);
+};
+
+const MCU_REGS : &[RegDef] = regs! {
+    0x00 => "DEVID0";
+    0x01 => "DEVID1";
+    0x02 => "DEVID2";
+    0x03 => "REVID";
+};
+
+const DMA_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(ENABLE = 0x80, RESET = 0x40, DBUFMODE = 0x0C,
+                            PRIMODE = 0x03);
+    0x03 => "INTFLAGS", fields!(CH3ERRIF = 0x80, CH2ERRIF = 0x40, CH1ERRIF = 0x20,
+                                CH0ERRIF = 0x10, CH3TRNIF = 0x08, CH2TRNIF = 0x04,
+                                CH1TRNIF = 0x02, CH0TRNIF = 0x01);
+    0x04 => "STATUS", fields!(CH3BUSY = 0x80, CH2BUSY = 0x40, CH1BUSY = 0x20,
+                              CH0BUSY = 0x10, CH3PEND = 0x08, CH2PEND = 0x04,
+                              CH1PEND = 0x02, CH0PEND = 0x01);
+    0x06 => "TEMPL";
+    0x07 => "TEMPH";
+};
+
+const DMA_CH_REGS : &[RegDef] = regs! {
+    0x00 => "CTRLA", fields!(ENABLE = 0x80, RESET = 0x40, REPEAT = 0x20,
+                             TRFREQ = 0x10, SINGLE = 0x04, BURSTLEN = 0x03);
+    0x01 => "CTRLB", fields!(CHBUSY = 0x80, CHPEND = 0x40, ERRIF = 0x20,
+                             TRNIF = 0x10, ERRINTLVL = 0x0C, TRNINTLVL = 0x03);
+    0x02 => "ADDRCTRL", fields!(SRCRELOAD = 0xC0, SRCDIR = 0x30,
+                                DESTRELOAD = 0x0C, DESTDIR = 0x03);
+    0x03 => "TRIGSRC";
+    0x04 => "TRFCNTL";
+    0x05 => "TRFCNTH";
+    0x06 => "REPCNT";
+    0x08 => "SRCADDR0";
+    0x09 => "SRCADDR1";
+    0x0A => "SRCADDR2";
+    0x0C => "DESTADDR0";
+    0x0D => "DESTADDR1";
+    0x0E => "DESTADDR2";
+};
+
+const EVSYS_REGS : &[RegDef] = regs! {
+    0x00 => "CH0MUX";
+    0x01 => "CH1MUX";
+    0x02 => "CH2MUX";
+    0x03 => "CH3MUX";
+    0x04 => "CH4MUX";
+    0x05 => "CH5MUX";
+    0x06 => "CH6MUX";
+    0x07 => "CH7MUX";
+    0x08 => "CH0CTRL";
+    0x09 => "CH1CTRL";
+    0x0A => "CH2CTRL";
+    0x0B => "CH3CTRL";
+    0x0C => "CH4CTRL";
+    0x0D => "CH5CTRL";
+    0x0E => "CH6CTRL";
+    0x0F => "CH7CTRL";
+    0x10 => "STROBE";
+    0x11 => "DATA";
+};
+
+const NVM_REGS : &[RegDef] = regs! {
+    0x00 => "ADDR0";
+    0x01 => "ADDR1";
+    0x02 => "ADDR2";
+    0x04 => "DATA0";
+    0x05 => "DATA1";
+    0x06 => "DATA2";
+    0x07 => "LOCKBITS";
+    0x0A => "CMD";
+    0x0B => "CTRLA", fields!(CMDEX = 0x01);
+    0x0C => "CTRLB";
+    0x0D => "INTCTRL", fields!(SPMLVL = 0x0C, EELVL = 0x03);
+    0x0F => "STATUS", fields!(NVMBUSY = 0x80, FBUSY = 0x40, EELOAD = 0x02,
+                              FLOAD = 0x01);
+};
+
+const ADC_REGS : &[RegDef] = regs! {
+    0x00 => "CTRLA", fields!(DMASEL = 0xC0, CH3START = 0x20, CH2START = 0x10,
+                             CH1START = 0x08, CH0START = 0x04, FLUSH = 0x02,
+                             ENABLE = 0x01);
+    0x01 => "CTRLB", fields!(IMPMODE = 0x80, CURRLIMIT = 0x60, CONMODE = 0x10,
+                             FREERUN = 0x08, RESOLUTION = 0x06);
+    0x02 => "REFCTRL", fields!(REFSEL = 0x70, BANDGAP = 0x02, TEMPREF = 0x01);
+    0x03 => "EVCTRL", fields!(SWEEP = 0xC0, EVSEL = 0x38, EVACT = 0x07);
+    0x04 => "PRESCALER", fields!(PRESCALER = 0x07);
+    0x06 => "INTFLAGS", fields!(CH3IF = 0x08, CH2IF = 0x04, CH1IF = 0x02,
+                                CH0IF = 0x01);
+    0x07 => "TEMP";
+    0x10 => "CH0RESL";
+    0x11 => "CH0RESH";
+    0x12 => "CH1RESL";
+    0x13 => "CH1RESH";
+    0x14 => "CH2RESL";
+    0x15 => "CH2RESH";
+    0x16 => "CH3RESL";
+    0x17 => "CH3RESH";
+    0x18 => "CMPL";
+    0x19 => "CMPH";
+};
+
+const ADC_CH_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(START = 0x80, GAIN = 0x1C, INPUTMODE = 0x03);
+    0x01 => "MUXCTRL", fields!(MUXPOS = 0x78, MUXNEG = 0x07);
+    0x02 => "INTCTRL", fields!(INTMODE = 0x0C, INTLVL = 0x03);
+    0x03 => "INTFLAGS", fields!(IF = 0x01);
+    0x04 => "RESL";
+    0x05 => "RESH";
+    0x06 => "SCAN";
+};
+
+const TWI_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(SDAHOLD = 0x06, EDIEN = 0x01);
+    0x01 => "MASTER.CTRLA", fields!(INTLVL = 0xC0, RIEN = 0x20, WIEN = 0x10,
+                                    ENABLE = 0x08);
+    0x02 => "MASTER.CTRLB", fields!(TIMEOUT = 0x0C, QCEN = 0x02, SMEN = 0x01);
+    0x03 => "MASTER.CTRLC", fields!(ACKACT = 0x04, CMD = 0x03);
+    0x04 => "MASTER.STATUS", fields!(RIF = 0x80, WIF = 0x40, CLKHOLD = 0x20,
+                                     RXACK = 0x10, ARBLOST = 0x08, BUSERR = 0x04,
+                                     BUSSTATE = 0x03);
+    0x05 => "MASTER.BAUD";
+    0x06 => "MASTER.ADDR";
+    0x07 => "MASTER.DATA";
+    0x08 => "SLAVE.CTRLA";
+    0x09 => "SLAVE.CTRLB";
+    0x0A => "SLAVE.STATUS";
+    0x0B => "SLAVE.ADDR";
+    0x0C => "SLAVE.DATA";
+    0x0D => "SLAVE.ADDRMASK";
+};
+
+const PINCTRL : &[Field] = fields!(INVEN = 0x40, OPC = 0x38, ISC = 0x07);
+
+const PORT_REGS : &[RegDef] = regs! {
+    0x00 => "DIR";
+    0x01 => "DIRSET";
+    0x02 => "DIRCLR";
+    0x03 => "DIRTGL";
+    0x04 => "OUT";
+    0x05 => "OUTSET";
+    0x06 => "OUTCLR";
+    0x07 => "OUTTGL";
+    0x08 => "IN";
+    0x09 => "INTCTRL", fields!(INT1LVL = 0x0C, INT0LVL = 0x03);
+    0x0A => "INT0MASK";
+    0x0B => "INT1MASK";
+    0x0C => "INTFLAGS", fields!(INT1IF = 0x02, INT0IF = 0x01);
+    0x0E => "REMAP";
+    0x10 => "PIN0CTRL", PINCTRL;
+    0x11 => "PIN1CTRL", PINCTRL;
+    0x12 => "PIN2CTRL", PINCTRL;
+    0x13 => "PIN3CTRL", PINCTRL;
+    0x14 => "PIN4CTRL", PINCTRL;
+    0x15 => "PIN5CTRL", PINCTRL;
+    0x16 => "PIN6CTRL", PINCTRL;
+    0x17 => "PIN7CTRL", PINCTRL;
+};
+
+const TC_CTRLF : &[Field] = fields!(CMD = 0x0C, LUPD = 0x02, DIR = 0x01);
+const TC_CTRLG : &[Field] = fields!(CCDBV = 0x10, CCCBV = 0x08, CCBBV = 0x04,
+                                    CCABV = 0x02, PERBV = 0x01);
+
+const TC_REGS : &[RegDef] = regs! {
+    0x00 => "CTRLA", fields!(CLKSEL = 0x0F);
+    0x01 => "CTRLB", fields!(CCDEN = 0x80, CCCEN = 0x40, CCBEN = 0x20,
+                             CCAEN = 0x10, WGMODE = 0x07);
+    0x02 => "CTRLC";
+    0x03 => "CTRLD", fields!(EVACT = 0xE0, EVDLY = 0x10, EVSEL = 0x0F);
+    0x04 => "CTRLE", fields!(BYTEM = 0x03);
+    0x06 => "INTCTRLA", fields!(ERRINTLVL = 0x0C, OVFINTLVL = 0x03);
+    0x07 => "INTCTRLB", fields!(CCDINTLVL = 0xC0, CCCINTLVL = 0x30,
+                                CCBINTLVL = 0x0C, CCAINTLVL = 0x03);
+    0x08 => "CTRLFCLR", TC_CTRLF;
+    0x09 => "CTRLFSET", TC_CTRLF;
+    0x0A => "CTRLGCLR", TC_CTRLG;
+    0x0B => "CTRLGSET", TC_CTRLG;
+    0x0C => "INTFLAGS", fields!(CCDIF = 0x80, CCCIF = 0x40, CCBIF = 0x20,
+                                CCAIF = 0x10, ERRIF = 0x02, OVFIF = 0x01);
+    0x0F => "TEMP";
+    0x20 => "CNTL";
+    0x21 => "CNTH";
+    0x26 => "PERL";
+    0x27 => "PERH";
+    0x28 => "CCAL";
+    0x29 => "CCAH";
+    0x2A => "CCBL";
+    0x2B => "CCBH";
+    0x2C => "CCCL";
+    0x2D => "CCCH";
+    0x2E => "CCDL";
+    0x2F => "CCDH";
+    0x36 => "PERBUFL";
+    0x37 => "PERBUFH";
+    0x38 => "CCABUFL";
+    0x39 => "CCABUFH";
+    0x3A => "CCBBUFL";
+    0x3B => "CCBBUFH";
+    0x3C => "CCCBUFL";
+    0x3D => "CCCBUFH";
+    0x3E => "CCDBUFL";
+    0x3F => "CCDBUFH";
+};
+
+const SPI_REGS : &[RegDef] = regs! {
+    0x00 => "CTRL", fields!(CLK2X = 0x80, ENABLE = 0x40, DORD = 0x20,
+                            MASTER = 0x10, MODE = 0x0C, PRESCALER = 0x03);
+    0x01 => "INTCTRL", INTLVL;
+    0x02 => "STATUS", fields!(IF = 0x80, WRCOL = 0x40);
+    0x03 => "DATA";
+};
+
+const USART_REGS : &[RegDef] = regs! {
+    0x00 => "DATA";
+    0x01 => "STATUS", fields!(RXCIF = 0x80, TXCIF = 0x40, DREIF = 0x20,
+                              FERR = 0x10, BUFOVF = 0x08, PERR = 0x04,
+                              RXB8 = 0x01);
+    0x03 => "CTRLA", fields!(RXCINTLVL = 0x30, TXCINTLVL = 0x0C,
+                             DREINTLVL = 0x03);
+    0x04 => "CTRLB", fields!(RXEN = 0x10, TXEN = 0x08, CLK2X = 0x04,
+                             MPCM = 0x02, TXB8 = 0x01);
+    0x05 => "CTRLC", fields!(CMODE = 0xC0, PMODE = 0x30, SBMODE = 0x08,
+                             CHSIZE = 0x07);
+    0x06 => "BAUDCTRLA";
+    0x07 => "BAUDCTRLB", fields!(BSCALE = 0xF0, BSEL = 0x0F);
+};
+
+const USB_INTFLAGSA : &[Field] = fields!(SOFIF = 0x80, SUSPENDIF = 0x40, RESUMEIF = 0x20,
+                                         RSTIF = 0x10, CRCIF = 0x08, UNFIF = 0x04,
+                                         OVFIF = 0x02, STALLIF = 0x01);
+const USB_INTFLAGSB : &[Field] = fields!(TRNIF = 0x02, SETUPIF = 0x01);
+
+const USB_REGS : &[RegDef] = regs! {
+    0x00 => "CTRLA", fields!(ENABLE = 0x80, SPEED = 0x40, FIFOEN = 0x20,
+                             STFRNUM = 0x10, MAXEP = 0x0F);
+    0x01 => "CTRLB", fields!(PULLRST = 0x10, RWAKEUP = 0x04, GNACK = 0x02,
+                             ATTACH = 0x01);
+    0x02 => "STATUS", fields!(URESUME = 0x08, RESUME = 0x04, SUSPEND = 0x02,
+                              BUSRST = 0x01);
+    0x03 => "ADDR";
+    0x04 => "FIFOWP";
+    0x05 => "FIFORP";
+    0x06 => "EPPTRL";
+    0x07 => "EPPTRH";
+    0x08 => "INTCTRLA", fields!(SOFIE = 0x80, BUSEVIE = 0x40, BUSERRIE = 0x20,
+                                STALLIE = 0x10, INTLVL = 0x03);
+    0x09 => "INTCTRLB", fields!(TRNIE = 0x02, SETUPIE = 0x01);
+    0x0A => "INTFLAGSACLR", USB_INTFLAGSA;
+    0x0B => "INTFLAGSASET", USB_INTFLAGSA;
+    0x0C => "INTFLAGSBCLR", USB_INTFLAGSB;
+    0x0D => "INTFLAGSBSET", USB_INTFLAGSB;
+    0x3A => "CAL0";
+    0x3B => "CAL1";
+};
+
+const fn block(name: &'static str, base: u32, regs: &'static [RegDef]) -> IoBlock {
+    IoBlock { name, base, regs, channels: None }
+}
+
+/// the peripherals emulated for the ATxmega128A4U
+pub const ATXMEGA128A4U_IO : &[IoBlock] = &[
+    block("CPU", 0x0030, CPU_REGS),
+    block("CLK", 0x0040, CLK_REGS),
+    block("SLEEP", 0x0048, SLEEP_REGS),
+    block("OSC", 0x0050, OSC_REGS),
+    block("RST", 0x0078, RST_REGS),
+    block("MCU", 0x0090, MCU_REGS),
+    block("PMIC", 0x00A0, PMIC_REGS),
+    block("AES", 0x00C0, AES_REGS),
+    block("CRC", 0x00D0, CRC_REGS),
+    IoBlock {
+        name: "DMA",
+        base: 0x0100,
+        regs: DMA_REGS,
+        channels: Some(Channels { ofs: 0x10, count: 4, size: 0x10, regs: DMA_CH_REGS }),
+    },
+    block("EVSYS", 0x0180, EVSYS_REGS),
+    block("NVM", 0x01C0, NVM_REGS),
+    IoBlock {
+        name: "ADCA",
+        base: 0x0200,
+        regs: ADC_REGS,
+        channels: Some(Channels { ofs: 0x20, count: 4, size: 0x08, regs: ADC_CH_REGS }),
+    },
+    block("RTC", 0x0400, RTC_REGS),
+    block("TWIC", 0x0480, TWI_REGS),
+    block("TWIE", 0x04A0, TWI_REGS),
+    block("USB", 0x04C0, USB_REGS),
+    block("PORTA", 0x0600, PORT_REGS),
+    block("PORTB", 0x0620, PORT_REGS),
+    block("PORTC", 0x0640, PORT_REGS),
+    block("PORTD", 0x0660, PORT_REGS),
+    block("PORTE", 0x0680, PORT_REGS),
+    block("PORTR", 0x07E0, PORT_REGS),
+    block("TCC0", 0x0800, TC_REGS),
+    block("TCC1", 0x0840, TC_REGS),
+    block("TCD0", 0x0900, TC_REGS),
+    block("TCD1", 0x0940, TC_REGS),
+    block("TCE0", 0x0A00, TC_REGS),
+    block("USARTC0", 0x08A0, USART_REGS),
+    block("SPIC", 0x08C0, SPI_REGS),
+    block("SPID", 0x09C0, SPI_REGS),
+];
+
+
+const CLASSIC_CPU_REGS : &[RegDef] = regs! {
+    0x0D => "SPL";
+    0x0E => "SPH";
+    0x0F => "SREG", fields!(I = 0x80, T = 0x40, H = 0x20, S = 0x10,
+                            V = 0x08, N = 0x04, Z = 0x02, C = 0x01);
+};
+
+const SMCR_REGS : &[RegDef] = regs! {
+    0x00 => "SMCR", fields!(SM = 0x0E, SE = 0x01);
+};
+
+const CLASSIC_PORT_REGS : &[RegDef] = regs! {
+    0x00 => "PIN";
+    0x01 => "DDR";
+    0x02 => "PORT";
+};
+
+const CLASSIC_TC_TCCRA : &[Field] = fields!(COMA = 0xC0, COMB = 0x30, WGM = 0x03);
+const CLASSIC_TC_TIFR : &[Field] = fields!(OCFB = 0x04, OCFA = 0x02, TOV = 0x01);
+const CLASSIC_TC_TIMSK : &[Field] = fields!(OCIEB = 0x04, OCIEA = 0x02, TOIE = 0x01);
+
+// the blocks start at TIFRn, the lowest address
+const ATMEGA328P_TC0_REGS : &[RegDef] = regs! {
+    0x00 => "TIFR", CLASSIC_TC_TIFR;
+    0x0F => "TCCRA", CLASSIC_TC_TCCRA;
+    0x10 => "TCCRB", fields!(FOCA = 0x80, FOCB = 0x40, WGM2 = 0x08, CS = 0x07);
+    0x11 => "TCNT";
+    0x12 => "OCRA";
+    0x13 => "OCRB";
+    0x39 => "TIMSK", CLASSIC_TC_TIMSK;
+};
+
+const ATMEGA328P_TC1_REGS : &[RegDef] = regs! {
+    0x00 => "TIFR", fields!(ICF = 0x20, OCFB = 0x04, OCFA = 0x02, TOV = 0x01);
+    0x39 => "TIMSK", fields!(ICIE = 0x20, OCIEB = 0x04, OCIEA = 0x02, TOIE = 0x01);
+    0x4A => "TCCRA", CLASSIC_TC_TCCRA;
+    0x4B => "TCCRB", fields!(ICNC = 0x80, ICES = 0x40, WGM = 0x18, CS = 0x07);
+    0x4C => "TCCRC", fields!(FOCA = 0x80, FOCB = 0x40);
+    0x4E => "TCNTL";
+    0x4F => "TCNTH";
+    0x50 => "ICRL";
+    0x51 => "ICRH";
+    0x52 => "OCRAL";
+    0x53 => "OCRAH";
+    0x54 => "OCRBL";
+    0x55 => "OCRBH";
+};
+
+const ATMEGA328P_TC2_REGS : &[RegDef] = regs! {
+    0x00 => "TIFR", CLASSIC_TC_TIFR;
+    0x39 => "TIMSK", CLASSIC_TC_TIMSK;
+    0x79 => "TCCRA", CLASSIC_TC_TCCRA;
+    0x7A => "TCCRB", fields!(FOCA = 0x80, FOCB = 0x40, WGM2 = 0x08, CS = 0x07);
+    0x7B => "TCNT";
+    0x7C => "OCRA";
+    0x7D => "OCRB";
+};
+
+const CLASSIC_USART_REGS : &[RegDef] = regs! {
+    0x00 => "UCSRA", fields!(RXC = 0x80, TXC = 0x40, UDRE = 0x20, FE = 0x10,
+                             DOR = 0x08, UPE = 0x04, U2X = 0x02, MPCM = 0x01);
+    0x01 => "UCSRB", fields!(RXCIE = 0x80, TXCIE = 0x40, UDRIE = 0x20, RXEN = 0x10,
+                             TXEN = 0x08, UCSZ2 = 0x04, RXB8 = 0x02, TXB8 = 0x01);
+    0x02 => "UCSRC", fields!(UMSEL = 0xC0, UPM = 0x30, USBS = 0x08, UCSZ = 0x06,
+                             UCPOL = 0x01);
+    0x04 => "UBRRL";
+    0x05 => "UBRRH";
+    0x06 => "UDR";
+};
+
+/// the peripherals emulated for the ATmega328P
+pub const ATMEGA328P_IO : &[IoBlock] = &[
+    block("PORTB", 0x0023, CLASSIC_PORT_REGS),
+    block("PORTC", 0x0026, CLASSIC_PORT_REGS),
+    block("PORTD", 0x0029, CLASSIC_PORT_REGS),
+    block("TC0", 0x0035, ATMEGA328P_TC0_REGS),
+    block("TC1", 0x0036, ATMEGA328P_TC1_REGS),
+    block("TC2", 0x0037, ATMEGA328P_TC2_REGS),
+    block("CPU", 0x0050, CLASSIC_CPU_REGS),
+    block("SLEEP", 0x0053, SMCR_REGS),
+    block("USART0", 0x00C0, CLASSIC_USART_REGS),
+];
+
+const MCUCR_REGS : &[RegDef] = regs! {
+    0x00 => "MCUCR", fields!(BODS = 0x80, PUD = 0x40, SE = 0x20, SM = 0x18,
+                             BODSE = 0x04, ISC0 = 0x03);
+};
+
+const ATTINY85_TC0_REGS : &[RegDef] = regs! {
+    0x00 => "OCRB";
+    0x01 => "OCRA";
+    0x02 => "TCCRA", CLASSIC_TC_TCCRA;
+    0x0A => "TCNT";
+    0x0B => "TCCRB", fields!(FOCA = 0x80, FOCB = 0x40, WGM2 = 0x08, CS = 0x07);
+    0x10 => "TIFR", fields!(OCF1A = 0x40, OCF1B = 0x20, OCFA = 0x10, OCFB = 0x08,
+                            TOV1 = 0x04, TOV = 0x02);
+    0x11 => "TIMSK", fields!(OCIE1A = 0x40, OCIE1B = 0x20, OCIEA = 0x10, OCIEB = 0x08,
+                             TOIE1 = 0x04, TOIE = 0x02);
+};
+
+/// the peripherals emulated for the ATtiny85
+pub const ATTINY85_IO : &[IoBlock] = &[
+    block("PORTB", 0x0036, CLASSIC_PORT_REGS),
+    block("TC0", 0x0048, ATTINY85_TC0_REGS),
+    block("CPU", 0x0050, CLASSIC_CPU_REGS),
+    block("SLEEP", 0x0055, MCUCR_REGS),
+];
+
+
+/// the register at `addr`, e.g. ("ADCA.CH1.MUXCTRL", its fields)
+pub fn lookup(blocks: &[IoBlock], addr: u32) -> Option<(String, &'static [Field])> {
+    for b in blocks {
+        if addr < b.base {
+            continue;
+        }
+        let ofs = addr - b.base;
+
+        if let Some(r) = b.regs.iter().find(|r| r.ofs == ofs) {
+            return Some((format!("{}.{}", b.name, r.name), r.fields));
+        }
+
+        if let Some(ref ch) = b.channels {
+            if ofs >= ch.ofs && ofs < ch.ofs + ch.count * ch.size {
+                let (n, ch_ofs) = ((ofs - ch.ofs) / ch.size, (ofs - ch.ofs) % ch.size);
+                if let Some(r) = ch.regs.iter().find(|r| r.ofs == ch_ofs) {
+                    return Some((format!("{}.CH{}.{}", b.name, n, r.name), r.fields));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// the address of the register called `name`, e.g. "USARTC0.STATUS" or
+/// "ADCA.CH1.MUXCTRL"
+pub fn find(blocks: &[IoBlock], name: &str) -> Option<u32> {
+    let parts: Vec<&str> = name.split('.').collect();
+    let block = blocks.iter().find(|b| b.name == parts[0])?;
+
+    match parts[1..] {
+        [reg] => block.regs.iter()
+            .find(|r| r.name == reg)
+            .map(|r| block.base + r.ofs),
+
+        [ch_name, reg] => {
+            let ch = block.channels?;
+            if !ch_name.starts_with("CH") {
+                return None;
+            }
+            let n: u32 = ch_name[2..].parse().ok().filter(|&n| n < ch.count)?;
+            ch.regs.iter()
+                .find(|r| r.name == reg)
+                .map(|r| block.base + ch.ofs + n * ch.size + r.ofs)
+        },
+
+        _ => None,
+    }
+}
+
+/// `val` split into `fields`, e.g. "RXEN|TXEN|CHSIZE=3"
+pub fn decode_fields(fields: &[Field], val: u8) -> String {
+    let parts: Vec<String> = fields.iter()
+        .filter_map(|f| {
+            if f.mask.count_ones() == 1 {
+                if (val & f.mask) != 0 { Some(f.name.to_string()) } else { None }
+            } else {
+                Some(format!("{}={}", f.name, (val & f.mask) >> f.mask.trailing_zeros()))
+            }
+        })
+        .collect();
+    parts.join("|")
+}
+
+/// e.g. "USARTC0.CTRLB = 0x18 (RXEN|TXEN)", or "0x08af = 0x18" for an
+/// address that isn't in the map
+pub fn describe(blocks: &[IoBlock], addr: u32, val: u8) -> String {
+    match lookup(blocks, addr) {
+        Some((name, fields)) => {
+            let decoded = decode_fields(fields, val);
+            if decoded.is_empty() {
+                format!("{} = {:#04x}", name, val)
+            } else {
+                format!("{} = {:#04x} ({})", name, val, decoded)
+            }
+        },
+        None => format!("{:#06x} = {:#04x}", addr, val),
+    }
+}
+
+/// one line for an IO trace:
+///   <insn count> <pc> <R|W> <register> = <value> (<fields>)
+pub fn fmt_access(blocks: &[IoBlock], insn_count: u64, pc: u32, access: &IoAccess) -> String {
+    format!("{:>10} {:#07x} {} {}", insn_count, pc,
+            if access.write { 'W' } else { 'R' },
+            describe(blocks, access.addr, access.val))
+}
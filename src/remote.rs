@@ -0,0 +1,93 @@
+// Controlling an emulator running on another thread
+//
+// The emulator thread calls Emulator::serve(), which runs the firmware until
+// it's paused or stops, then waits for commands until it's resumed. Handles
+// can be cloned and used from any thread. Commands are carried out between
+// instructions, so they can be sent while the firmware runs as well.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use emulator::Emulator;
+
+
+pub type RemoteFn = Box<dyn FnOnce(&mut Emulator) + Send>;
+
+pub enum Command {
+    /// call this on the emulator thread
+    Run(RemoteFn),
+    /// continue running, if paused
+    Resume,
+    /// stop, and return from serve()
+    Quit,
+}
+
+
+#[derive(Clone)]
+pub struct EmulatorHandle {
+    commands: mpsc::Sender<Command>,
+    stop: Arc<AtomicBool>,
+    uart_input: mpsc::Sender<Vec<u8>>,
+}
+
+impl EmulatorHandle {
+    pub(crate) fn new(commands: mpsc::Sender<Command>, stop: Arc<AtomicBool>,
+                      uart_input: mpsc::Sender<Vec<u8>>) -> EmulatorHandle {
+        EmulatorHandle { commands, stop, uart_input }
+    }
+
+    /// stop before the next instruction, with StopReason::Interrupted
+    pub fn pause(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    // the rest return false or None if the emulator thread is gone
+
+    pub fn resume(&self) -> bool {
+        self.commands.send(Command::Resume).is_ok()
+    }
+
+    pub fn quit(&self) -> bool {
+        self.commands.send(Command::Quit).is_ok()
+    }
+
+    /// call `f` on the emulator thread, and wait for its result
+    pub fn with<F, R>(&self, f: F) -> Option<R>
+            where F: FnOnce(&mut Emulator) -> R + Send + 'static,
+                  R: Send + 'static {
+
+        let (tx, rx) = mpsc::channel();
+        let cmd = Command::Run(Box::new(move |emu| {
+            let _ = tx.send(f(emu));
+        }));
+        self.commands.send(cmd).ok()?;
+        rx.recv().ok()
+    }
+
+    pub fn pc(&self) -> Option<u32> {
+        self.with(|emu| emu.pc)
+    }
+
+    pub fn regs(&self) -> Option<[u8; 32]> {
+        self.with(|emu| {
+            let mut regs = [0; 32];
+            for (r, val) in regs.iter_mut().enumerate() {
+                *val = emu.get_reg8(r as u8);
+            }
+            regs
+        })
+    }
+
+    pub fn add_breakpoint(&self, addr: u32, condition: Option<String>)
+            -> Option<Result<usize, String>> {
+        self.with(move |emu| {
+            emu.add_breakpoint(addr, condition.as_ref().map(|s| &s[..]))
+        })
+    }
+
+    /// queue bytes for the firmware to receive on the USART, without
+    /// waiting for the emulator thread
+    pub fn send_uart(&self, data: &[u8]) -> bool {
+        self.uart_input.send(data.to_vec()).is_ok()
+    }
+}
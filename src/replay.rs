@@ -0,0 +1,104 @@
+// Recording and replaying of external inputs, for reproducible runs
+//
+// The log is a text file with one event per line:
+//   <cycle> uart <byte in hex>
+//   <cycle> irq <vector>
+//   <cycle> dump
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::str::FromStr;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    /// a byte arrived on the USART
+    UartByte(u8),
+    /// an interrupt raised by the host
+    Interrupt(u8),
+    /// a state dump was requested with SIGUSR1
+    StateDump,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct InputLog {
+    /// (cycle, event), in cycle order
+    pub events: Vec<(u64, InputEvent)>,
+}
+
+#[derive(Clone)]
+pub enum InputMode {
+    /// take inputs from the outside world and don't record them
+    Live,
+    /// take inputs from the outside world, and log them
+    Record(InputLog),
+    /// ignore the outside world, and take inputs from the log instead.
+    /// `next` is the index of the next event to feed. if `then_record` is
+    /// set, switch to recording (appending to the same log) once all events
+    /// have been fed; this is used when re-executing after stepping back.
+    Replay { log: InputLog, next: usize, then_record: bool },
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad input log line {}", line_num + 1))
+}
+
+impl InputLog {
+    pub fn new() -> InputLog {
+        InputLog { events: vec![] }
+    }
+
+    pub fn push(&mut self, cycle: u64, event: InputEvent) {
+        self.events.push((cycle, event));
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+
+        for &(cycle, ref event) in &self.events {
+            match *event {
+                InputEvent::UartByte(val) =>
+                    writeln!(w, "{} uart {:02x}", cycle, val)?,
+                InputEvent::Interrupt(vector) =>
+                    writeln!(w, "{} irq {}", cycle, vector)?,
+                InputEvent::StateDump =>
+                    writeln!(w, "{} dump", cycle)?,
+            }
+        }
+
+        w.flush()
+    }
+
+    pub fn load(path: &str) -> io::Result<InputLog> {
+        let r = BufReader::new(File::open(path)?);
+        let mut log = InputLog::new();
+
+        for (line_num, line) in r.lines().enumerate() {
+            let line = line?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.is_empty() {
+                continue;
+            }
+
+            let cycle = u64::from_str(parts[0])
+                .map_err(|_| bad_line(line_num))?;
+
+            let event = match parts[1..] {
+                ["uart", val] => InputEvent::UartByte(
+                    u8::from_str_radix(val, 16)
+                        .map_err(|_| bad_line(line_num))?),
+                ["irq", vector] => InputEvent::Interrupt(
+                    u8::from_str(vector).map_err(|_| bad_line(line_num))?),
+                ["dump"] => InputEvent::StateDump,
+                _ => return Err(bad_line(line_num)),
+            };
+
+            log.push(cycle, event);
+        }
+
+        Ok(log)
+    }
+}
@@ -0,0 +1,134 @@
+// XMEGA reset controller
+
+use std::any::Any;
+use peripheral::Peripheral;
+
+
+pub const RST_BASE : u32 = 0x0078;
+
+// register offsets
+const STATUS : u32 = 0x00;
+const CTRL : u32 = 0x01;
+
+// CTRL bits
+const SWRST : u8 = 0x01;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetCause {
+    PowerOn,
+    External,
+    BrownOut,
+    Watchdog,
+    /// from the programming and debug interface
+    Pdi,
+    /// RST.CTRL.SWRST was written
+    Software,
+}
+
+impl ResetCause {
+    /// the flag in RST.STATUS
+    pub fn status_flag(&self) -> u8 {
+        match *self {
+            ResetCause::PowerOn => 0x01,
+            ResetCause::External => 0x02,
+            ResetCause::BrownOut => 0x04,
+            ResetCause::Watchdog => 0x08,
+            ResetCause::Pdi => 0x10,
+            ResetCause::Software => 0x20,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ResetController {
+    /// reset causes since the flags were last cleared. survives resets.
+    pub status: u8,
+    software_reset_requested: bool,
+}
+
+impl Default for ResetController {
+    fn default() -> ResetController {
+        ResetController::new()
+    }
+}
+
+impl ResetController {
+    pub fn new() -> ResetController {
+        ResetController {
+            status: ResetCause::PowerOn.status_flag(),
+            software_reset_requested: false,
+        }
+    }
+
+    /// note a reset; a power-on reset clears the other flags
+    pub fn record(&mut self, cause: ResetCause) {
+        if cause == ResetCause::PowerOn {
+            self.status = 0;
+        }
+        self.status |= cause.status_flag();
+    }
+
+    /// whether the firmware asked for a software reset since the last call
+    pub fn take_reset_request(&mut self) -> bool {
+        let requested = self.software_reset_requested;
+        self.software_reset_requested = false;
+        requested
+    }
+}
+
+impl Peripheral for ResetController {
+    fn name(&self) -> &str {
+        "RST"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (RST_BASE, 0x02)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            // write 1 to clear
+            STATUS => self.status &= !val,
+            CTRL if (val & SWRST) != 0 =>
+                self.software_reset_requested = true,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.software_reset_requested = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.status, self.software_reset_requested as u8]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() != 2 {
+            return;
+        }
+
+        self.status = state[0];
+        self.software_reset_requested = state[1] != 0;
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
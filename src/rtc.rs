@@ -0,0 +1,401 @@
+// XMEGA 16-bit real-time counter
+//
+// The RTC counts its own clock, selected in CLK.RTCCTRL, so it follows
+// emulated time, whatever the CPU clock does. Optionally it follows the
+// host's wall clock instead, for firmware that shows the time; that isn't
+// deterministic, and a sleeping CPU then waits for the host.
+//
+// Writes take effect at once, so STATUS.SYNCBUSY always reads 0.
+
+use std::any::Any;
+use std::time::Instant;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const CTRL : u32 = 0x00;
+const STATUS : u32 = 0x01;
+const INTCTRL : u32 = 0x02;
+const INTFLAGS : u32 = 0x03;
+const TEMP : u32 = 0x04;
+const CNT : u32 = 0x08;
+const CNT_H : u32 = 0x09;
+const PER : u32 = 0x0A;
+const PER_H : u32 = 0x0B;
+const COMP : u32 = 0x0C;
+const COMP_H : u32 = 0x0D;
+
+// INTFLAGS bits
+const OVFIF : u8 = 0x01;
+const COMPIF : u8 = 0x02;
+
+// EVSYS sources
+const EV_OVF : u8 = 0x08;
+const EV_COMP : u8 = 0x09;
+
+const NANOS_PER_SEC : u64 = 1_000_000_000;
+
+
+#[derive(Clone)]
+pub struct Rtc {
+    base: u32,
+    /// vector of OVF; COMP is the next one
+    ovf_vector: u8,
+
+    pub ctrl: u8,
+    pub intctrl: u8,
+    pub intflags: u8,
+    /// high byte latch for 16-bit accesses
+    pub temp: u8,
+    pub cnt: u16,
+    pub per: u16,
+    pub comp: u16,
+
+    /// the RTC clock from CLK.RTCCTRL, or None if it's off
+    clock_freq: Option<u32>,
+    cpu_freq: u32,
+    /// emulated time, as of the last set_clock()
+    nanos: u64,
+    /// time and cycle count at the last tick
+    last_nanos: u64,
+    last_tick: u64,
+    /// RTC clocks not yet counted because of the prescaler
+    prescaler_acc: u64,
+    /// the wall clock when it was synced, and the RTC's time then
+    wall_clock: Option<(Instant, u64)>,
+
+    /// events not yet routed
+    events: Vec<u8>,
+}
+
+impl Rtc {
+    pub fn new(base: u32, ovf_vector: u8) -> Rtc {
+        Rtc {
+            base,
+            ovf_vector,
+
+            ctrl: 0,
+            intctrl: 0,
+            intflags: 0,
+            temp: 0,
+            cnt: 0,
+            per: 0xffff,
+            comp: 0,
+
+            clock_freq: None,
+            cpu_freq: 2_000_000,
+            nanos: 0,
+            last_nanos: 0,
+            last_tick: 0,
+            prescaler_acc: 0,
+            wall_clock: None,
+
+            events: vec![],
+        }
+    }
+
+    /// the clocks as of the coming tick: emulated time in nanoseconds, the
+    /// CPU frequency and the RTC clock's
+    pub fn set_clock(&mut self, nanos: u64, cpu_freq: u32, clock_freq: Option<u32>) {
+        self.nanos = nanos;
+        self.cpu_freq = cpu_freq;
+        self.clock_freq = clock_freq;
+    }
+
+    /// follow the host's wall clock from now on
+    pub fn sync_to_wall_clock(&mut self) {
+        self.wall_clock = Some((Instant::now(), self.last_nanos));
+    }
+
+    /// time as the RTC sees it
+    fn time_nanos(&self) -> u64 {
+        match self.wall_clock {
+            Some((start, base)) => {
+                let elapsed = start.elapsed();
+                base + elapsed.as_secs() * NANOS_PER_SEC + elapsed.subsec_nanos() as u64
+            },
+            None => self.nanos,
+        }
+    }
+
+    pub fn prescaler(&self) -> Option<u64> {
+        match self.ctrl & 0x07 {
+            1 => Some(1),
+            2 => Some(2),
+            3 => Some(8),
+            4 => Some(16),
+            5 => Some(64),
+            6 => Some(256),
+            7 => Some(1024),
+            _ => None,
+        }
+    }
+
+    /// RTC clock edges up to time `nanos`
+    fn edges_at(freq: u32, nanos: u64) -> u64 {
+        (nanos as u128 * freq as u128 / NANOS_PER_SEC as u128) as u64
+    }
+
+    /// count `counts` prescaled clocks, setting OVF and COMP flags
+    fn advance(&mut self, mut counts: u64) {
+        while counts > 0 {
+            let cnt = self.cnt as u64;
+            let top = if cnt <= self.per as u64 { self.per as u64 } else { 0xffff };
+            let to_ovf = top - cnt + 1;
+            let step = counts.min(to_ovf);
+            let wrapped = step == to_ovf;
+
+            // values the counter passes through, excluding the current one
+            let last = if wrapped { top } else { cnt + step };
+            let comp = self.comp as u64;
+            if (comp > cnt && comp <= last) || (wrapped && comp == 0) {
+                self.intflags |= COMPIF;
+                self.events.push(EV_COMP);
+            }
+
+            if wrapped {
+                self.intflags |= OVFIF;
+                self.events.push(EV_OVF);
+                self.cnt = 0;
+            } else {
+                self.cnt = last as u16;
+            }
+
+            // after a long sleep, whole periods only set the same flags
+            // again, so count one of them and then the rest
+            counts -= step;
+            let period = self.per as u64 + 1;
+            if wrapped && counts >= 2 * period {
+                counts = period + counts % period;
+            }
+        }
+    }
+
+    fn read16(&mut self, ofs: u32, val: u16) -> u8 {
+        if (ofs & 1) == 0 {
+            self.temp = (val >> 8) as u8;
+            val as u8
+        } else {
+            self.temp
+        }
+    }
+
+    /// returns the new value once the high byte is written
+    fn write16(&mut self, ofs: u32, val: u8) -> Option<u16> {
+        if (ofs & 1) == 0 {
+            self.temp = val;
+            None
+        } else {
+            Some(((val as u16) << 8) | (self.temp as u16))
+        }
+    }
+
+    /// (INTFLAGS bit, vector, level) for each interrupt source
+    fn interrupt_sources(&self) -> [(u8, u8, u8); 2] {
+        [
+            (OVFIF, self.ovf_vector, self.intctrl & 0x3),
+            (COMPIF, self.ovf_vector + 1, (self.intctrl >> 2) & 0x3),
+        ]
+    }
+}
+
+impl Peripheral for Rtc {
+    fn name(&self) -> &str {
+        "RTC"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x10)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            STATUS => 0,
+            INTCTRL => self.intctrl,
+            INTFLAGS => self.intflags,
+            TEMP => self.temp,
+            CNT..=CNT_H => {
+                let cnt = self.cnt;
+                self.read16(ofs, cnt)
+            },
+            PER..=PER_H => {
+                let per = self.per;
+                self.read16(ofs, per)
+            },
+            COMP..=COMP_H => {
+                let comp = self.comp;
+                self.read16(ofs, comp)
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL => self.ctrl = val & 0x07,
+            INTCTRL => self.intctrl = val & 0x0f,
+            // write 1 to clear
+            INTFLAGS => self.intflags &= !val,
+            TEMP => self.temp = val,
+            CNT..=CNT_H => {
+                if let Some(cnt) = self.write16(ofs, val) {
+                    self.cnt = cnt;
+                }
+            },
+            PER..=PER_H => {
+                if let Some(per) = self.write16(ofs, val) {
+                    self.per = per;
+                }
+            },
+            COMP..=COMP_H => {
+                if let Some(comp) = self.write16(ofs, val) {
+                    self.comp = comp;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let rtc = Rtc {
+            clock_freq: self.clock_freq,
+            cpu_freq: self.cpu_freq,
+            nanos: self.nanos,
+            last_nanos: self.last_nanos,
+            last_tick: self.last_tick,
+            wall_clock: self.wall_clock,
+            ..Rtc::new(self.base, self.ovf_vector)
+        };
+        *self = rtc;
+    }
+
+    fn tick(&mut self, now: u64) {
+        let nanos = self.time_nanos();
+        let last_nanos = self.last_nanos;
+        self.last_nanos = nanos;
+        self.last_tick = now;
+
+        if let (Some(freq), Some(div)) = (self.clock_freq, self.prescaler()) {
+            let edges = Rtc::edges_at(freq, nanos)
+                .saturating_sub(Rtc::edges_at(freq, last_nanos));
+            let total = self.prescaler_acc + edges;
+            self.prescaler_acc = total % div;
+            self.advance(total / div);
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.rtc_runs()
+    }
+
+    fn resume(&mut self, now: u64) {
+        self.last_nanos = self.time_nanos();
+        self.last_tick = now;
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // cycles don't say when the wall clock gets there
+        if self.wall_clock.is_some() {
+            return None;
+        }
+        let freq = self.clock_freq? as u64;
+        let div = self.prescaler()?;
+
+        // the next overflow or compare match, in prescaled clocks
+        let cnt = self.cnt as u64;
+        let top = if cnt <= self.per as u64 { self.per as u64 } else { 0xffff };
+        let comp = self.comp as u64;
+        let mut counts = top - cnt + 1;
+        if comp > cnt && comp <= top {
+            counts = counts.min(comp - cnt);
+        }
+
+        // when the RTC clock edge that makes it happen comes, rounded up to
+        // a CPU cycle
+        let edge = Rtc::edges_at(freq as u32, self.last_nanos) + counts * div - self.prescaler_acc;
+        let edge_nanos = (edge as u128 * NANOS_PER_SEC as u128).div_ceil(freq as u128);
+        let wait = edge_nanos.saturating_sub(self.last_nanos as u128);
+        let cycles = (wait * self.cpu_freq as u128).div_ceil(NANOS_PER_SEC as u128);
+        Some(self.last_tick + cycles as u64)
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        // highest level, then lowest vector
+        self.interrupt_sources()
+            .iter()
+            .filter(|&&(flag, _, level)| (self.intflags & flag) != 0 && level != 0)
+            .map(|&(_, vector, level)| (vector, level))
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        out.extend(self.interrupt_sources()
+            .iter()
+            .filter(|&&(_, _, level)| level != 0)
+            .map(|&(_, vector, _)| vector));
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        for &(flag, v, _) in self.interrupt_sources().iter() {
+            if v == vector {
+                self.intflags &= !flag;
+            }
+        }
+    }
+
+    fn poll_events(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.events);
+    }
+
+    fn powered(&self) -> bool {
+        self.clock_freq.is_some() && self.prescaler().is_some()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.ctrl, self.intctrl, self.intflags, self.temp];
+        for &w in [self.cnt, self.per, self.comp].iter() {
+            state.write_u16::<LittleEndian>(w).unwrap();
+        }
+        state.write_u64::<LittleEndian>(self.last_nanos).unwrap();
+        state.write_u64::<LittleEndian>(self.last_tick).unwrap();
+        state.write_u64::<LittleEndian>(self.prescaler_acc).unwrap();
+        state.extend_from_slice(&self.events);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        // 4 bytes, 3 words, 3 u64s, then events
+        if state.len() < 4 + 6 + 24 {
+            return;
+        }
+
+        self.ctrl = state[0];
+        self.intctrl = state[1];
+        self.intflags = state[2];
+        self.temp = state[3];
+
+        let mut r = &state[4..];
+        self.cnt = r.read_u16::<LittleEndian>().unwrap();
+        self.per = r.read_u16::<LittleEndian>().unwrap();
+        self.comp = r.read_u16::<LittleEndian>().unwrap();
+        self.last_nanos = r.read_u64::<LittleEndian>().unwrap();
+        self.last_tick = r.read_u64::<LittleEndian>().unwrap();
+        self.prescaler_acc = r.read_u64::<LittleEndian>().unwrap();
+        self.events = r.to_vec();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
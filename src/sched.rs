@@ -0,0 +1,140 @@
+// Cycle-keyed queue of upcoming peripheral events
+//
+// Peripherals say when their state next changes on their own (see
+// Peripheral::next_event), e.g. a timer overflowing or a USART frame ending.
+// Until then ticking them does nothing, so only the peripherals with an event
+// due are ticked, plus the ones something else changed since (a register
+// access, the host, another peripheral), whose next event may have moved.
+// The queue also keeps the earliest event at hand, so the CPU can skip
+// straight to it when it has nothing else to do, e.g. while sleeping.
+//
+// Rescheduling a source leaves its old entry in the heap; stale entries are
+// dropped when they reach the top, or all at once when they pile up.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+
+#[derive(Clone)]
+pub struct Scheduler {
+    /// (cycle, source)
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    /// each source's current event, indexed by source
+    scheduled: Vec<Option<u64>>,
+    /// sources changed since they were last ticked
+    touched: Vec<usize>,
+    /// every source counts as touched, e.g. after a reset
+    all_touched: bool,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            heap: BinaryHeap::new(),
+            scheduled: vec![],
+            touched: vec![],
+            all_touched: true,
+        }
+    }
+
+    /// replace `source`'s event with one at cycle `at`, or none
+    pub fn schedule(&mut self, source: usize, at: Option<u64>) {
+        if source >= self.scheduled.len() {
+            self.scheduled.resize(source + 1, None);
+        }
+
+        if self.scheduled[source] != at {
+            self.scheduled[source] = at;
+            if let Some(cycle) = at {
+                self.heap.push(Reverse((cycle, source)));
+                if self.heap.len() > 2 * self.scheduled.len() {
+                    self.compact();
+                }
+            }
+        }
+    }
+
+    // drop the stale entries
+    fn compact(&mut self) {
+        self.heap = self.scheduled.iter()
+            .enumerate()
+            .filter_map(|(source, &at)| at.map(|cycle| Reverse((cycle, source))))
+            .collect();
+    }
+
+    /// `source` changed, so it has to be ticked and rescheduled even if its
+    /// event isn't due
+    pub fn touch(&mut self, source: usize) {
+        if !self.all_touched && !self.touched.contains(&source) {
+            self.touched.push(source);
+        }
+    }
+
+    pub fn touch_all(&mut self) {
+        self.all_touched = true;
+        self.touched.clear();
+    }
+
+    /// the touched sources, out of `count`
+    pub fn touched(&self, count: usize) -> Vec<usize> {
+        if self.all_touched {
+            (0..count).collect()
+        } else {
+            self.touched.iter().cloned().filter(|&source| source < count).collect()
+        }
+    }
+
+    /// fill `out` with the sources to tick at cycle `now`, in order: the
+    /// ones with an event due and the touched ones, out of `count`. they're
+    /// expected to be rescheduled after that.
+    pub fn take_due(&mut self, now: u64, count: usize, out: &mut Vec<usize>) {
+        out.clear();
+        if self.all_touched {
+            self.all_touched = false;
+            out.extend(0..count);
+        }
+        out.append(&mut self.touched);
+
+        while let Some(&Reverse((cycle, source))) = self.heap.peek() {
+            if cycle > now {
+                break;
+            }
+
+            self.heap.pop();
+            if self.scheduled[source] == Some(cycle) {
+                self.scheduled[source] = None;
+                out.push(source);
+            }
+        }
+
+        // sources can go away, e.g. when host calls are enabled again
+        out.retain(|&source| source < count);
+        out.sort_unstable();
+        out.dedup();
+    }
+
+    /// the cycle of the earliest event
+    pub fn earliest(&mut self) -> Option<u64> {
+        while let Some(&Reverse((cycle, source))) = self.heap.peek() {
+            if self.scheduled[source] == Some(cycle) {
+                return Some(cycle);
+            }
+            self.heap.pop();
+        }
+
+        None
+    }
+
+    /// forget all events, and tick everything next time
+    pub fn clear(&mut self) {
+        self.heap.clear();
+        self.scheduled.clear();
+        self.touch_all();
+    }
+}
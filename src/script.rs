@@ -0,0 +1,286 @@
+// Rhai scripts attached to emulator events, behind the "scripting" feature
+//
+// A script runs when its event happens: a breakpoint is hit, an instruction
+// accesses a range of IO space, or every N instructions. It sees the
+// machine as `emu`:
+//
+//     emu.reg(24), emu.set_reg(24, 0)
+//     emu.read(0x2000), emu.write(0x2000, 1), emu.read16(addr)
+//     emu.pc, emu.sreg (both writable), emu.sp, emu.insns, emu.cycles
+//     emu.stop()
+//
+// IO access scripts also get `addr`, `value` and `write` for each access,
+// after the instruction that made it; breakpoint scripts get `id`, and
+// execution continues past the breakpoint if the script returns false.
+// print() goes to the diagnostics sink.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST, INT};
+use iomem::{IoAccess, SPL};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptEvent {
+    /// the breakpoint with this id was hit
+    Breakpoint(usize),
+    /// an instruction accessed IO space in [start, end)
+    IoAccess(u32, u32),
+    /// every N instructions
+    Every(u64),
+}
+
+/// the machine state a script works on, moved out of the emulator while
+/// scripts run
+pub struct MachineState {
+    pub regs: [u8; 32],
+    pub sreg: u8,
+    pub pc: u32,
+    pub data_mem: Vec<u8>,
+    pub insn_count: u64,
+    pub cycle_count: u64,
+    /// see Device::io_offset
+    pub io_offset: u32,
+    /// a script called emu.stop()
+    pub stop: bool,
+}
+
+/// `emu` in scripts
+#[derive(Clone)]
+pub struct Machine(Arc<Mutex<MachineState>>);
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl Machine {
+    pub fn new(state: MachineState) -> Machine {
+        Machine(Arc::new(Mutex::new(state)))
+    }
+
+    /// take the state back out, leaving an empty one
+    pub fn take_state(&self) -> MachineState {
+        let mut state = self.0.lock().unwrap();
+        let empty = MachineState {
+            regs: state.regs,
+            sreg: state.sreg,
+            pc: state.pc,
+            data_mem: vec![],
+            insn_count: state.insn_count,
+            cycle_count: state.cycle_count,
+            io_offset: state.io_offset,
+            stop: false,
+        };
+        mem::replace(&mut *state, empty)
+    }
+
+    fn reg(&mut self, r: INT) -> ScriptResult<INT> {
+        let state = self.0.lock().unwrap();
+        match state.regs.get(r as usize) {
+            Some(&val) if r >= 0 => Ok(val as INT),
+            _ => Err(format!("bad register {}", r).into()),
+        }
+    }
+
+    fn set_reg(&mut self, r: INT, val: INT) -> ScriptResult<()> {
+        let mut state = self.0.lock().unwrap();
+        match state.regs.get_mut(r as usize) {
+            Some(p) if r >= 0 => {
+                *p = val as u8;
+                Ok(())
+            },
+            _ => Err(format!("bad register {}", r).into()),
+        }
+    }
+
+    fn read(&mut self, addr: INT) -> ScriptResult<INT> {
+        let state = self.0.lock().unwrap();
+        match state.data_mem.get(addr as usize) {
+            Some(&val) if addr >= 0 => Ok(val as INT),
+            _ => Err(format!("bad address {:#x}", addr).into()),
+        }
+    }
+
+    fn read16(&mut self, addr: INT) -> ScriptResult<INT> {
+        Ok(self.read(addr)? | (self.read(addr + 1)? << 8))
+    }
+
+    fn write(&mut self, addr: INT, val: INT) -> ScriptResult<()> {
+        let mut state = self.0.lock().unwrap();
+        match state.data_mem.get_mut(addr as usize) {
+            Some(p) if addr >= 0 => {
+                *p = val as u8;
+                Ok(())
+            },
+            _ => Err(format!("bad address {:#x}", addr).into()),
+        }
+    }
+
+    fn pc(&mut self) -> INT {
+        self.0.lock().unwrap().pc as INT
+    }
+
+    fn set_pc(&mut self, pc: INT) {
+        self.0.lock().unwrap().pc = pc as u32;
+    }
+
+    fn sreg(&mut self) -> INT {
+        self.0.lock().unwrap().sreg as INT
+    }
+
+    fn set_sreg(&mut self, sreg: INT) {
+        self.0.lock().unwrap().sreg = sreg as u8;
+    }
+
+    fn sp(&mut self) -> ScriptResult<INT> {
+        let addr = self.0.lock().unwrap().io_offset + SPL;
+        self.read16(addr as INT)
+    }
+
+    fn insns(&mut self) -> INT {
+        self.0.lock().unwrap().insn_count as INT
+    }
+
+    fn cycles(&mut self) -> INT {
+        self.0.lock().unwrap().cycle_count as INT
+    }
+
+    fn stop(&mut self) {
+        self.0.lock().unwrap().stop = true;
+    }
+}
+
+
+#[derive(Clone)]
+struct Hook {
+    event: ScriptEvent,
+    ast: Arc<AST>,
+    /// for Every, the instruction count of the next run
+    next: u64,
+}
+
+/// a script to run, with the variables to give it
+pub struct ScriptCall {
+    ast: Arc<AST>,
+    vars: Vec<(&'static str, Dynamic)>,
+}
+
+#[derive(Clone)]
+pub struct Scripts {
+    engine: Arc<Engine>,
+    hooks: Vec<Hook>,
+    /// print() output not yet passed on
+    printed: Arc<Mutex<Vec<String>>>,
+}
+
+impl Default for Scripts {
+    fn default() -> Scripts {
+        Scripts::new()
+    }
+}
+
+impl Scripts {
+    pub fn new() -> Scripts {
+        let printed = Arc::new(Mutex::new(vec![]));
+
+        let mut engine = Engine::new();
+        engine.register_type_with_name::<Machine>("Machine")
+            .register_fn("reg", Machine::reg)
+            .register_fn("set_reg", Machine::set_reg)
+            .register_fn("read", Machine::read)
+            .register_fn("read16", Machine::read16)
+            .register_fn("write", Machine::write)
+            .register_get_set("pc", Machine::pc, Machine::set_pc)
+            .register_get_set("sreg", Machine::sreg, Machine::set_sreg)
+            .register_get("sp", Machine::sp)
+            .register_get("insns", Machine::insns)
+            .register_get("cycles", Machine::cycles)
+            .register_fn("stop", Machine::stop);
+
+        let out = printed.clone();
+        engine.on_print(move |s| out.lock().unwrap().push(s.to_string()));
+
+        Scripts {
+            engine: Arc::new(engine),
+            hooks: vec![],
+            printed,
+        }
+    }
+
+    /// compile `source` and run it on `event` from now on
+    pub fn add(&mut self, event: ScriptEvent, source: &str) -> Result<(), String> {
+        let ast = self.engine.compile(source).map_err(|e| e.to_string())?;
+        let next = match event {
+            ScriptEvent::Every(n) => n.max(1),
+            _ => 0,
+        };
+        self.hooks.push(Hook { event, ast: Arc::new(ast), next });
+        Ok(())
+    }
+
+    pub fn wants_io_accesses(&self) -> bool {
+        self.hooks.iter().any(|h| matches!(h.event, ScriptEvent::IoAccess(..)))
+    }
+
+    /// the scripts to run after an instruction, which made `accesses`
+    pub fn after_insn(&mut self, insn_count: u64, accesses: &[IoAccess]) -> Vec<ScriptCall> {
+        let mut calls = vec![];
+        for hook in self.hooks.iter_mut() {
+            match hook.event {
+                ScriptEvent::Every(n) if insn_count >= hook.next => {
+                    // warp mode can skip over multiples of n
+                    hook.next = (insn_count / n.max(1) + 1) * n.max(1);
+                    calls.push(ScriptCall { ast: hook.ast.clone(), vars: vec![] });
+                },
+
+                ScriptEvent::IoAccess(start, end) => {
+                    for a in accesses.iter().filter(|a| a.addr >= start && a.addr < end) {
+                        calls.push(ScriptCall {
+                            ast: hook.ast.clone(),
+                            vars: vec![
+                                ("addr", Dynamic::from(a.addr as INT)),
+                                ("value", Dynamic::from(a.val as INT)),
+                                ("write", Dynamic::from(a.write)),
+                            ],
+                        });
+                    }
+                },
+
+                _ => {},
+            }
+        }
+        calls
+    }
+
+    pub fn on_breakpoint(&self, id: usize) -> Vec<ScriptCall> {
+        self.hooks.iter()
+            .filter(|h| h.event == ScriptEvent::Breakpoint(id))
+            .map(|h| ScriptCall {
+                ast: h.ast.clone(),
+                vars: vec![("id", Dynamic::from(id as INT))],
+            })
+            .collect()
+    }
+
+    /// run `calls` on `machine`. returns false if any script returned
+    /// false.
+    pub fn run(&self, calls: &[ScriptCall], machine: &Machine) -> Result<bool, String> {
+        let mut keep_going = true;
+        for call in calls {
+            let mut scope = Scope::new();
+            scope.push("emu", machine.clone());
+            for &(name, ref val) in &call.vars {
+                scope.push_dynamic(name, val.clone());
+            }
+
+            let res: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &call.ast)
+                .map_err(|e| e.to_string())?;
+            if res.as_bool() == Ok(false) {
+                keep_going = false;
+            }
+        }
+        Ok(keep_going)
+    }
+
+    pub fn take_output(&self) -> Vec<String> {
+        mem::take(&mut *self.printed.lock().unwrap())
+    }
+}
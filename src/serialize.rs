@@ -0,0 +1,129 @@
+// Serde support for the emulator state, behind the "serde" feature
+//
+// An Emulator serializes as its device's name and a Snapshot, so the same
+// things are kept as with Emulator::snapshot(): the machine state, but not
+// symbols, breakpoints, tracers etc. An IOMemory keeps the Snapshot's IO
+// parts. Peripherals are saved through Peripheral::save_state.
+
+use std::collections::VecDeque;
+use std::result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
+use device::{Device, ATXMEGA128A4U};
+use emulator::Emulator;
+use iomem::IOMemory;
+use nvm::NvmController;
+use registers::RegisterFile;
+use snapshot::Snapshot;
+use sreg::SReg;
+
+
+#[derive(Serialize)]
+struct EmulatorStateRef<'a> {
+    device: &'a str,
+    snapshot: Snapshot,
+}
+
+#[derive(Deserialize)]
+struct EmulatorState {
+    device: String,
+    snapshot: Snapshot,
+}
+
+impl Serialize for Emulator {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> result::Result<S::Ok, S::Error> {
+
+        let state = EmulatorStateRef {
+            device: self.device.name,
+            snapshot: self.snapshot(),
+        };
+        state.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Emulator {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> result::Result<Emulator, D::Error> {
+
+        let state = EmulatorState::deserialize(deserializer)?;
+        let device = Device::by_name(&state.device).ok_or_else(||
+            D::Error::custom(format!("unknown device {:?}", state.device)))?;
+
+        let mut emu = Emulator::for_device(device);
+        emu.restore(&state.snapshot);
+        // restore() keeps the device config, but here there's none to keep
+        emu.io_mem.nvm.config = state.snapshot.nvm.config.clone();
+        Ok(emu)
+    }
+}
+
+
+#[derive(Serialize)]
+struct IoStateRef<'a> {
+    regs: &'a RegisterFile,
+    sreg: &'a SReg,
+    data_mem: &'a [u8],
+    usart_input: &'a VecDeque<u8>,
+    usart_output_log: &'a [u8],
+    nvm: &'a NvmController,
+    peripheral_state: Vec<(String, Vec<u8>)>,
+    injected_interrupts: &'a [u8],
+}
+
+#[derive(Deserialize)]
+struct IoState {
+    regs: RegisterFile,
+    sreg: SReg,
+    data_mem: Vec<u8>,
+    usart_input: VecDeque<u8>,
+    usart_output_log: Vec<u8>,
+    nvm: NvmController,
+    peripheral_state: Vec<(String, Vec<u8>)>,
+    injected_interrupts: Vec<u8>,
+}
+
+impl Serialize for IOMemory {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> result::Result<S::Ok, S::Error> {
+
+        let state = IoStateRef {
+            regs: &self.regs,
+            sreg: &self.sreg,
+            data_mem: &self.data_mem,
+            usart_input: &self.usart_input,
+            usart_output_log: &self.usart_output_log,
+            nvm: &self.nvm,
+            peripheral_state: self.peripherals
+                .iter()
+                .map(|p| (p.name().to_string(), p.save_state()))
+                .collect(),
+            injected_interrupts: &self.injected_interrupts,
+        };
+        state.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IOMemory {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> result::Result<IOMemory, D::Error> {
+
+        let state = IoState::deserialize(deserializer)?;
+
+        // the data space comes from the state, so the device doesn't matter
+        let mut io = IOMemory::for_device(&ATXMEGA128A4U);
+        io.regs = state.regs;
+        io.sreg = state.sreg;
+        io.data_mem = state.data_mem;
+        io.usart_input = state.usart_input;
+        io.usart_output_log = state.usart_output_log;
+        io.nvm = state.nvm;
+        for (name, p_state) in state.peripheral_state {
+            if let Some(p) = io.peripherals.iter_mut().find(|p| p.name() == name) {
+                p.load_state(&p_state);
+            }
+        }
+        io.injected_interrupts = state.injected_interrupts;
+        Ok(io)
+    }
+}
@@ -0,0 +1,82 @@
+// The CLI's diagnostics sinks. They print, so they live in the binary rather
+// than in the library's diag module.
+
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use yaavre::diag::DiagnosticsSink;
+
+
+fn write_stderr(data: &[u8]) {
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    // there's nowhere to report a failure to
+    let _ = stderr.write_all(data).and_then(|_| stderr.flush());
+}
+
+/// prints warnings, state dumps and printable USART output to stdout; the
+/// default
+pub struct StdoutSink;
+
+impl DiagnosticsSink for StdoutSink {
+    fn warning(&self, msg: &str) {
+        println!("{}", msg);
+    }
+
+    fn uart_output(&self, val: u8) {
+        if val.is_ascii_whitespace() || val.is_ascii_graphic() {
+            print!("{}", val as char);
+        }
+    }
+
+    fn state_dump(&self, state: &str) {
+        print!("{}", state);
+    }
+
+    fn guest_stderr(&self, data: &[u8]) {
+        write_stderr(data);
+    }
+}
+
+
+/// prints warnings and state dumps to stdout like StdoutSink, but writes USART output as is
+/// to `out` (e.g. a file or a socket), or drops it if there's none
+pub struct UartWriterSink {
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl UartWriterSink {
+    pub fn new(out: Option<Box<dyn Write + Send>>) -> UartWriterSink {
+        UartWriterSink {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl DiagnosticsSink for UartWriterSink {
+    fn warning(&self, msg: &str) {
+        println!("{}", msg);
+    }
+
+    fn uart_output(&self, val: u8) {
+        let mut out = self.out.lock().unwrap();
+        // unbuffered, so nothing's lost if the process exits
+        let failed = match *out {
+            Some(ref mut w) => w.write_all(&[val]).err(),
+            None => None,
+        };
+
+        if let Some(e) = failed {
+            eprintln!("USART output failed, dropping the rest: {}", e);
+            *out = None;
+        }
+    }
+
+    fn state_dump(&self, state: &str) {
+        print!("{}", state);
+    }
+
+    fn guest_stderr(&self, data: &[u8]) {
+        write_stderr(data);
+    }
+}
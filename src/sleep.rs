@@ -0,0 +1,160 @@
+// XMEGA sleep controller, which classic AVRs have as SMCR or in MCUCR
+//
+// SLEEP only sleeps when SLEEP.CTRL.SEN is set, and CTRL.SMODE says how
+// deeply. SMCR has the same bits, and the same modes plus ADC noise
+// reduction, which stops the CPU like idle. Small ATtinys keep SE and SM in
+// MCUCR, in other places and with fewer modes but the same numbering. In idle, only the CPU stops. The deeper modes also stop the
+// peripheral clock, so timers, USARTs and the like freeze and can't wake
+// the CPU; what's left are pin changes, the RTC in the modes that keep it
+// running, and USB.
+
+use std::any::Any;
+use peripheral::Peripheral;
+
+
+pub const SLEEP_BASE : u32 = 0x0048;
+
+// register offsets
+const CTRL : u32 = 0x00;
+
+// CTRL bits
+const SEN : u8 = 0x01;
+const SMODE_MASK : u8 = 0x0E;
+
+// ATtiny MCUCR bits
+const MCUCR_SE : u8 = 0x20;
+const MCUCR_SM_MASK : u8 = 0x18;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepMode {
+    Idle,
+    PowerDown,
+    PowerSave,
+    Standby,
+    ExtendedStandby,
+}
+
+impl SleepMode {
+    /// the mode for a CTRL.SMODE value. the reserved values, and classic
+    /// AVRs' ADC noise reduction, act like idle.
+    pub fn from_smode(smode: u8) -> SleepMode {
+        match smode {
+            2 => SleepMode::PowerDown,
+            3 => SleepMode::PowerSave,
+            6 => SleepMode::Standby,
+            7 => SleepMode::ExtendedStandby,
+            _ => SleepMode::Idle,
+        }
+    }
+
+    /// whether the peripheral clock runs, i.e. whether this is idle
+    pub fn peripheral_clock(&self) -> bool {
+        *self == SleepMode::Idle
+    }
+
+    /// whether the RTC keeps counting
+    pub fn rtc_runs(&self) -> bool {
+        !matches!(*self, SleepMode::PowerDown | SleepMode::Standby)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SleepMode::Idle => "idle",
+            SleepMode::PowerDown => "power-down",
+            SleepMode::PowerSave => "power-save",
+            SleepMode::Standby => "standby",
+            SleepMode::ExtendedStandby => "extended-standby",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SleepController {
+    base: u32,
+    /// where SEN and SMODE are in the register
+    sen: u8,
+    smode_mask: u8,
+    /// MCUCR's other bits are plain storage
+    mcucr: bool,
+    pub ctrl: u8,
+}
+
+impl SleepController {
+    pub fn new(base: u32) -> SleepController {
+        SleepController { base, sen: SEN, smode_mask: SMODE_MASK, mcucr: false, ctrl: 0 }
+    }
+
+    /// a small ATtiny's, in MCUCR at `base`
+    pub fn mcucr(base: u32) -> SleepController {
+        SleepController {
+            base,
+            sen: MCUCR_SE,
+            smode_mask: MCUCR_SM_MASK,
+            mcucr: true,
+            ctrl: 0,
+        }
+    }
+
+    /// the mode SLEEP would enter, or None if SEN is clear and it does
+    /// nothing
+    pub fn mode(&self) -> Option<SleepMode> {
+        if (self.ctrl & self.sen) != 0 {
+            let smode = (self.ctrl & self.smode_mask) >> self.smode_mask.trailing_zeros();
+            Some(SleepMode::from_smode(smode))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for SleepController {
+    fn name(&self) -> &str {
+        "SLEEP"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x01)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL if self.mcucr => self.ctrl = val,
+            CTRL => self.ctrl = val & (SMODE_MASK | SEN),
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ctrl = 0;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.ctrl]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if let Some(&ctrl) = state.first() {
+            self.ctrl = ctrl;
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
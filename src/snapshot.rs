@@ -0,0 +1,225 @@
+// Saved emulator state, see Emulator::snapshot() and Emulator::restore()
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use nvm::NvmController;
+use callstack::{Frame, FrameKind};
+
+
+const MAGIC: &[u8; 8] = b"YAAVSNAP";
+const VERSION: u32 = 8;
+
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    pub pc: u32,
+    pub call_stack: Vec<Frame>,
+    pub skip_next_insn: bool,
+    pub sleeping: bool,
+    pub insn_count: u64,
+    pub cycle_count: u64,
+
+    pub regs: [u8; 32],
+    pub sreg: u8,
+    pub data_mem: Vec<u8>,
+    pub flash: Vec<u16>,
+
+    pub usart_input: Vec<u8>,
+    pub usart_output_log: Vec<u8>,
+    pub nvm: NvmController,
+    /// (name, state) for each peripheral
+    pub peripheral_state: Vec<(String, Vec<u8>)>,
+    pub injected_interrupts: Vec<u8>,
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+// `len` bytes. the buffer grows as the data comes in, so a corrupt length
+// runs into the end of the input instead of allocating up to 4 GiB.
+fn read_exactly<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(bad_data("truncated snapshot"));
+    }
+    Ok(bytes)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    read_exactly(r, len)
+}
+
+fn read_words<R: Read>(r: &mut R) -> io::Result<Vec<u16>> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    let bytes = read_exactly(r, len * 2)?;
+    Ok(bytes.chunks(2).map(LittleEndian::read_u16).collect())
+}
+
+fn bad_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Snapshot {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u32::<LittleEndian>(VERSION)?;
+
+        w.write_u32::<LittleEndian>(self.pc)?;
+        w.write_u32::<LittleEndian>(self.call_stack.len() as u32)?;
+        for frame in &self.call_stack {
+            w.write_u16::<LittleEndian>(frame.sp)?;
+            w.write_u32::<LittleEndian>(frame.from)?;
+            w.write_u32::<LittleEndian>(frame.to)?;
+            w.write_u32::<LittleEndian>(frame.ret_addr)?;
+            // 0xffff for calls, or the vector
+            w.write_u16::<LittleEndian>(match frame.kind {
+                FrameKind::Call => 0xffff,
+                FrameKind::Interrupt(vector) => vector as u16,
+            })?;
+            // 0xffffffff if it's not a tail call
+            w.write_u32::<LittleEndian>(frame.tail_call_from.unwrap_or(0xffffffff))?;
+        }
+        w.write_u8(self.skip_next_insn as u8)?;
+        w.write_u8(self.sleeping as u8)?;
+        w.write_u64::<LittleEndian>(self.insn_count)?;
+        w.write_u64::<LittleEndian>(self.cycle_count)?;
+
+        w.write_all(&self.regs)?;
+        w.write_u8(self.sreg)?;
+        write_bytes(w, &self.data_mem)?;
+
+        w.write_u32::<LittleEndian>(self.flash.len() as u32)?;
+        for &word in &self.flash {
+            w.write_u16::<LittleEndian>(word)?;
+        }
+
+        write_bytes(w, &self.usart_input)?;
+        write_bytes(w, &self.usart_output_log)?;
+
+        w.write_u32::<LittleEndian>(self.nvm.addr)?;
+        w.write_all(&self.nvm.data)?;
+        w.write_u8(self.nvm.cmd)?;
+        w.write_u8(self.nvm.ctrlb)?;
+        w.write_u8(self.nvm.intctrl)?;
+        w.write_u32::<LittleEndian>(self.nvm.page_buffer.len() as u32)?;
+        for &word in &self.nvm.page_buffer {
+            w.write_u16::<LittleEndian>(word)?;
+        }
+        w.write_u8(self.nvm.buffer_loaded as u8)?;
+        w.write_u8(self.nvm.lock_bits)?;
+
+        w.write_u32::<LittleEndian>(self.peripheral_state.len() as u32)?;
+        for (name, state) in &self.peripheral_state {
+            write_bytes(w, name.as_bytes())?;
+            write_bytes(w, state)?;
+        }
+        write_bytes(w, &self.injected_interrupts)?;
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(bad_data("not a yaavre snapshot"));
+        }
+
+        if r.read_u32::<LittleEndian>()? != VERSION {
+            return Err(bad_data("unsupported snapshot version"));
+        }
+
+        let pc = r.read_u32::<LittleEndian>()?;
+
+        let call_stack_len = r.read_u32::<LittleEndian>()?;
+        let mut call_stack = vec![];
+        for _ in 0..call_stack_len {
+            let sp = r.read_u16::<LittleEndian>()?;
+            let from = r.read_u32::<LittleEndian>()?;
+            let to = r.read_u32::<LittleEndian>()?;
+            let ret_addr = r.read_u32::<LittleEndian>()?;
+            let kind = match r.read_u16::<LittleEndian>()? {
+                0xffff => FrameKind::Call,
+                vector => FrameKind::Interrupt(vector as u8),
+            };
+            let tail_call_from = match r.read_u32::<LittleEndian>()? {
+                0xffffffff => None,
+                func => Some(func),
+            };
+            call_stack.push(Frame { sp, from, to, ret_addr, kind, tail_call_from });
+        }
+
+        let skip_next_insn = r.read_u8()? != 0;
+        let sleeping = r.read_u8()? != 0;
+        let insn_count = r.read_u64::<LittleEndian>()?;
+        let cycle_count = r.read_u64::<LittleEndian>()?;
+
+        let mut regs = [0; 32];
+        r.read_exact(&mut regs)?;
+        let sreg = r.read_u8()?;
+        let data_mem = read_bytes(r)?;
+
+        let flash = read_words(r)?;
+
+        let usart_input = read_bytes(r)?;
+        let usart_output_log = read_bytes(r)?;
+
+        let mut nvm = NvmController::new();
+        nvm.addr = r.read_u32::<LittleEndian>()?;
+        r.read_exact(&mut nvm.data)?;
+        nvm.cmd = r.read_u8()?;
+        nvm.ctrlb = r.read_u8()?;
+        nvm.intctrl = r.read_u8()?;
+        nvm.page_buffer = read_words(r)?;
+        nvm.buffer_loaded = r.read_u8()? != 0;
+        nvm.lock_bits = r.read_u8()?;
+
+        let num_peripherals = r.read_u32::<LittleEndian>()?;
+        let mut peripheral_state = vec![];
+        for _ in 0..num_peripherals {
+            let name = String::from_utf8(read_bytes(r)?)
+                .map_err(|_| bad_data("bad peripheral name"))?;
+            let state = read_bytes(r)?;
+            peripheral_state.push((name, state));
+        }
+        let injected_interrupts = read_bytes(r)?;
+
+        Ok(Snapshot {
+            pc,
+            call_stack,
+            skip_next_insn,
+            sleeping,
+            insn_count,
+            cycle_count,
+
+            regs,
+            sreg,
+            data_mem,
+            flash,
+
+            usart_input,
+            usart_output_log,
+            nvm,
+            peripheral_state,
+            injected_interrupts,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load(path: &str) -> io::Result<Snapshot> {
+        let mut r = BufReader::new(File::open(path)?);
+        Snapshot::read_from(&mut r)
+    }
+}
@@ -0,0 +1,333 @@
+// XMEGA SPI, in master mode, with virtual slave devices
+
+use std::any::Any;
+use std::mem;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const CTRL : u32 = 0x00;
+const INTCTRL : u32 = 0x01;
+const STATUS : u32 = 0x02;
+const DATA : u32 = 0x03;
+
+// CTRL bits
+const CLK2X : u8 = 0x80;
+const ENABLE : u8 = 0x40;
+const MASTER : u8 = 0x10;
+
+// STATUS bits
+const IF : u8 = 0x80;
+const WRCOL : u8 = 0x40;
+
+
+/// a device on the SPI bus
+pub trait SpiSlave: Send {
+    /// shift in a byte from the master, and return the byte shifted out at
+    /// the same time
+    fn exchange(&mut self, mosi: u8) -> u8;
+
+    /// chip select was released
+    fn deselect(&mut self) {}
+
+    /// internal state, for snapshots
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _state: &[u8]) {}
+
+    fn box_clone(&self) -> Box<dyn SpiSlave>;
+}
+
+impl Clone for Box<dyn SpiSlave> {
+    fn clone(&self) -> Box<dyn SpiSlave> {
+        self.box_clone()
+    }
+}
+
+/// MISO wired to MOSI
+#[derive(Clone, Default)]
+pub struct LoopbackSlave;
+
+impl SpiSlave for LoopbackSlave {
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        mosi
+    }
+
+    fn box_clone(&self) -> Box<dyn SpiSlave> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct AttachedSlave {
+    pub device: Box<dyn SpiSlave>,
+    /// active-low chip select pin as (port, pin); if None, the slave is
+    /// always selected
+    pub chip_select: Option<(String, u8)>,
+    pub selected: bool,
+}
+
+#[derive(Clone)]
+pub struct Spi {
+    name: String,
+    base: u32,
+    vector: u8,
+    dma_trigger: u8,
+
+    pub ctrl: u8,
+    pub intctrl: u8,
+    pub status: u8,
+    /// last received byte
+    pub data: u8,
+
+    pub slaves: Vec<AttachedSlave>,
+
+    /// byte being sent, and the cycle at which the transfer completes
+    transfer: Option<(u8, u64)>,
+    now: u64,
+}
+
+impl Spi {
+    pub fn new(name: &str, base: u32, vector: u8, dma_trigger: u8) -> Spi {
+        Spi {
+            name: name.to_string(),
+            base,
+            vector,
+            dma_trigger,
+
+            ctrl: 0,
+            intctrl: 0,
+            status: 0,
+            data: 0,
+
+            slaves: vec![],
+
+            transfer: None,
+            now: 0,
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn SpiSlave>,
+                  chip_select: Option<(&str, u8)>) {
+
+        self.slaves.push(AttachedSlave {
+            device,
+            chip_select: chip_select.map(|(port, pin)| (port.to_string(), pin)),
+            selected: chip_select.is_none(),
+        });
+    }
+
+    /// update a slave's chip select; called by IOMemory as port pins change
+    pub fn set_selected(&mut self, slave: usize, selected: bool) {
+        let s = &mut self.slaves[slave];
+        if s.selected && !selected {
+            s.device.deselect();
+        }
+        s.selected = selected;
+    }
+
+    /// CPU cycles per byte
+    fn byte_cycles(&self) -> u64 {
+        let div = match self.ctrl & 0x3 {
+            0 => 4,
+            1 => 16,
+            2 => 64,
+            _ => 128,
+        };
+        let div = if (self.ctrl & CLK2X) != 0 { div / 2 } else { div };
+        8 * div
+    }
+
+    fn complete_transfer(&mut self, mosi: u8) {
+        // undriven MISO reads as 1s; several drivers wired-AND
+        let mut miso = 0xff;
+        for s in self.slaves.iter_mut().filter(|s| s.selected) {
+            miso &= s.device.exchange(mosi);
+        }
+
+        self.data = miso;
+        self.status |= IF;
+        self.transfer = None;
+    }
+}
+
+impl Peripheral for Spi {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x08)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            INTCTRL => self.intctrl,
+            STATUS => self.status,
+            DATA => {
+                self.status &= !(IF | WRCOL);
+                self.data
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL => self.ctrl = val,
+            INTCTRL => self.intctrl = val,
+            STATUS => {},
+            DATA => {
+                if self.transfer.is_some() {
+                    self.status |= WRCOL;
+                } else if (self.ctrl & (ENABLE | MASTER)) == ENABLE | MASTER {
+                    self.status &= !IF;
+                    self.transfer = Some((val, self.now + self.byte_cycles()));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut spi = Spi::new(&self.name, self.base, self.vector, self.dma_trigger);
+        spi.slaves = mem::take(&mut self.slaves);
+        spi.now = self.now;
+
+        *self = spi;
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.now = now;
+
+        if let Some((mosi, done_at)) = self.transfer {
+            if done_at <= now {
+                self.complete_transfer(mosi);
+            }
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        let slept = now.saturating_sub(self.now);
+        self.now = now;
+        if let Some((_, ref mut done_at)) = self.transfer {
+            *done_at += slept;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.transfer.map(|(_, done_at)| done_at)
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let level = self.intctrl & 0x3;
+        if (self.status & IF) != 0 && level != 0 {
+            Some((self.vector, level))
+        } else {
+            None
+        }
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        if (self.intctrl & 0x3) != 0 {
+            out.push(self.vector);
+        }
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        if vector == self.vector {
+            self.status &= !IF;
+        }
+    }
+
+    // DMA reads of DATA clear IF, so there's nothing to ack
+    fn dma_request(&self, trigsrc: u8) -> bool {
+        trigsrc == self.dma_trigger && (self.status & IF) != 0
+    }
+
+    fn powered(&self) -> bool {
+        (self.ctrl & ENABLE) != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.ctrl, self.intctrl, self.status, self.data];
+
+        let (mosi, done_at) = self.transfer.unwrap_or((0, u64::MAX));
+        state.push(mosi);
+        state.write_u64::<LittleEndian>(done_at).unwrap();
+        state.write_u64::<LittleEndian>(self.now).unwrap();
+
+        for s in &self.slaves {
+            state.push(s.selected as u8);
+            let slave_state = s.device.save_state();
+            state.write_u32::<LittleEndian>(slave_state.len() as u32).unwrap();
+            state.extend_from_slice(&slave_state);
+        }
+
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 21 {
+            return;
+        }
+
+        self.ctrl = state[0];
+        self.intctrl = state[1];
+        self.status = state[2];
+        self.data = state[3];
+
+        let mosi = state[4];
+        let mut r = &state[5..];
+        let done_at = r.read_u64::<LittleEndian>().unwrap();
+        self.transfer =
+            if done_at == u64::MAX { None } else { Some((mosi, done_at)) };
+        self.now = r.read_u64::<LittleEndian>().unwrap();
+
+        // slaves are attached by the host, so only restore the ones that are
+        // still there
+        for s in self.slaves.iter_mut() {
+            if r.len() < 5 {
+                break;
+            }
+
+            s.selected = r[0] != 0;
+            r = &r[1..];
+            let len = r.read_u32::<LittleEndian>().unwrap() as usize;
+            if r.len() < len {
+                break;
+            }
+            s.device.load_state(&r[..len]);
+            r = &r[len..];
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the atxmega128a4u's SPI modules
+pub fn default_spis() -> Vec<Spi> {
+    vec![
+        Spi::new("SPIC", 0x08C0, 24, 0x4A),
+        Spi::new("SPID", 0x09C0, 87, 0x6A),
+    ]
+}
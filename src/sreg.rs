@@ -1,5 +1,7 @@
 // AVR Status Register
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SReg {
     pub c : bool,
     pub z : bool,
@@ -11,6 +13,12 @@ pub struct SReg {
     pub i : bool,
 }
 
+impl Default for SReg {
+    fn default() -> SReg {
+        SReg::new()
+    }
+}
+
 impl SReg {
     pub fn new() -> SReg {
         SReg {
@@ -47,3 +55,19 @@ impl SReg {
         self.i = (val & (1 << 7)) != 0;
     }
 }
+
+const FLAG_CHARS : &str = "cznvshti";
+
+/// the SREG value with the flags named in `flags` set, e.g. "zc" or "ZC"
+/// for Z and C. '.' is ignored, so fmt_sreg()'s output parses back.
+pub fn parse_flags(flags: &str) -> Option<u8> {
+    let mut val = 0;
+    for c in flags.chars() {
+        if c == '.' {
+            continue;
+        }
+        let bit = FLAG_CHARS.find(c.to_ascii_lowercase())?;
+        val |= 1 << bit;
+    }
+    Some(val)
+}
@@ -0,0 +1,101 @@
+// Stack usage analysis: the SP low-water mark, the deepest SP per function,
+// and an optional guard region that SP must never enter
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use symbols::SymbolTable;
+use callstack::Frame;
+
+
+#[derive(Clone)]
+pub struct StackMonitor {
+    /// start of internal SRAM. SP below this hasn't been set up yet.
+    pub sram_start: u16,
+    /// where the stack starts
+    pub ramend: u16,
+    /// the lowest SP seen, or ramend
+    pub low_water: u16,
+    /// call targets at the low-water mark, outermost first
+    pub low_water_path: Vec<u32>,
+    /// function (call target, or None outside of any call) -> lowest SP
+    pub deepest: HashMap<Option<u32>, u16>,
+    /// [start, end) addresses that SP mustn't reach, e.g. the end of .bss
+    pub guard: Option<(u16, u16)>,
+}
+
+impl StackMonitor {
+    pub fn new(sram_start: u16, ramend: u16) -> StackMonitor {
+        StackMonitor {
+            sram_start,
+            ramend,
+            low_water: ramend,
+            low_water_path: vec![],
+            deepest: HashMap::new(),
+            guard: None,
+        }
+    }
+
+    /// note the current SP; returns false if it's in the guard region
+    pub fn check(&mut self, sp: u16, call_stack: &[Frame]) -> bool {
+        if sp < self.sram_start {
+            return true;
+        }
+
+        if sp < self.low_water {
+            self.low_water = sp;
+            self.low_water_path = call_stack.iter().map(|f| f.to).collect();
+        }
+
+        let func = call_stack.last().map(|f| f.to);
+        let deepest = self.deepest.entry(func).or_insert(self.ramend);
+        if sp < *deepest {
+            *deepest = sp;
+        }
+
+        match self.guard {
+            Some((start, end)) => sp < start || sp >= end,
+            None => true,
+        }
+    }
+
+    /// maximum depth in bytes
+    pub fn max_depth(&self) -> u16 {
+        self.ramend - self.low_water
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let name = |func: Option<u32>| match func {
+            Some(addr) => symbols.fmt_addr(addr),
+            None => "[top]".to_string(),
+        };
+
+        let mut out = String::new();
+        writeln!(out, "max stack depth: {} bytes (SP {:#06x})",
+            self.max_depth(), self.low_water).unwrap();
+
+        let path: Vec<String> = self.low_water_path
+            .iter()
+            .map(|&addr| name(Some(addr)))
+            .collect();
+        writeln!(out, "deepest call path: [top]{}{}",
+            if path.is_empty() { "" } else { " -> " },
+            path.join(" -> ")).unwrap();
+
+        if let Some((start, end)) = self.guard {
+            writeln!(out, "guard region: {:#06x}-{:#06x}, {} bytes to spare",
+                start, end,
+                self.low_water.saturating_sub(end)).unwrap();
+        }
+
+        let mut funcs: Vec<_> = self.deepest.iter().collect();
+        funcs.sort_by_key(|&(&func, &sp)| (sp, func));
+
+        writeln!(out).unwrap();
+        writeln!(out, "{:<32} {:>10}", "function", "max depth").unwrap();
+        for (&func, &sp) in funcs {
+            writeln!(out, "{:<32} {:>10}", name(func), self.ramend - sp).unwrap();
+        }
+
+        out
+    }
+}
@@ -0,0 +1,185 @@
+// Stable hashes of the CPU state, for checking that runs are deterministic
+//
+// Hashes are FNV-1a, so they come out the same on every platform and Rust
+// version. A HashLog records one every N instructions; comparing the logs of
+// two runs (or checking a run against an earlier run's log) narrows down
+// where they first diverged to within N instructions.
+//
+// Data space is hashed a page at a time and the page hashes are hashed
+// together, so only the pages that changed since the last hash need
+// hashing again. Finding those by comparing against a copy is much cheaper
+// than hashing everything, and unlike tracking writes it can't miss any,
+// e.g. from DMA, the host or a restored snapshot.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+
+const FNV_OFFSET : u64 = 0xcbf29ce484222325;
+const FNV_PRIME : u64 = 0x100000001b3;
+
+const PAGE_SIZE : usize = 256;
+
+
+pub struct StateHasher(u64);
+
+impl Default for StateHasher {
+    fn default() -> StateHasher {
+        StateHasher::new()
+    }
+}
+
+impl StateHasher {
+    pub fn new() -> StateHasher {
+        StateHasher(FNV_OFFSET)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+
+/// hashes data space, rehashing only the pages that changed since the
+/// last time
+#[derive(Clone, Default)]
+pub struct MemHasher {
+    /// the memory as of the last hash
+    copy: Vec<u8>,
+    page_hashes: Vec<u64>,
+}
+
+impl MemHasher {
+    pub fn new() -> MemHasher {
+        MemHasher::default()
+    }
+
+    pub fn hash(&mut self, mem: &[u8]) -> u64 {
+        if mem.len() != self.copy.len() {
+            self.copy = mem.to_vec();
+            self.page_hashes = mem.chunks(PAGE_SIZE).map(hash_page).collect();
+        } else {
+            let pages = mem.chunks(PAGE_SIZE)
+                .zip(self.copy.chunks_mut(PAGE_SIZE))
+                .zip(&mut self.page_hashes);
+            for ((page, copy), hash) in pages {
+                if page != &copy[..] {
+                    copy.copy_from_slice(page);
+                    *hash = hash_page(page);
+                }
+            }
+        }
+
+        let mut hasher = StateHasher::new();
+        for hash in &self.page_hashes {
+            hasher.write(&hash.to_le_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+fn hash_page(page: &[u8]) -> u64 {
+    let mut hasher = StateHasher::new();
+    hasher.write(page);
+    hasher.finish()
+}
+
+
+#[derive(Clone)]
+pub struct HashLog {
+    pub interval: u64,
+    /// instruction count of the next hash
+    next: u64,
+    /// (instruction count, hash)
+    pub hashes: Vec<(u64, u64)>,
+    /// hashes from an earlier run to compare against
+    reference: Option<BTreeMap<u64, u64>>,
+    pub mem_hasher: MemHasher,
+}
+
+impl HashLog {
+    pub fn new(interval: u64) -> HashLog {
+        let interval = interval.max(1);
+        HashLog {
+            interval,
+            next: interval,
+            hashes: vec![],
+            reference: None,
+            mem_hasher: MemHasher::new(),
+        }
+    }
+
+    /// compare hashes against `hashes` as they are recorded
+    pub fn set_reference(&mut self, hashes: &[(u64, u64)]) {
+        self.reference = Some(hashes.iter().cloned().collect());
+    }
+
+    pub fn due(&self, insn_count: u64) -> bool {
+        insn_count >= self.next
+    }
+
+    /// add a hash. returns false if the reference has a different one for
+    /// the same point.
+    pub fn record(&mut self, insn_count: u64, hash: u64) -> bool {
+        self.hashes.push((insn_count, hash));
+        // warp mode can skip over multiples of the interval
+        self.next = (insn_count / self.interval + 1) * self.interval;
+
+        match self.reference {
+            Some(ref reference) =>
+                reference.get(&insn_count).is_none_or(|&h| h == hash),
+            None => true,
+        }
+    }
+
+    /// the instruction count of the last matching hash before `insn_count`
+    pub fn last_match_before(&self, insn_count: u64) -> u64 {
+        self.hashes.iter().rev()
+            .map(|&(insns, _)| insns)
+            .find(|&insns| insns < insn_count)
+            .unwrap_or(0)
+    }
+
+    /// one "instruction-count hash" line per hash, in hex
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let text: String = self.hashes.iter()
+            .map(|&(insns, hash)| format!("{} {:016x}\n", insns, hash))
+            .collect();
+        fs::write(path, text)
+    }
+
+    pub fn load(path: &str) -> io::Result<Vec<(u64, u64)>> {
+        let text = fs::read_to_string(path)?;
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                let mut parts = line.split_whitespace();
+                let insns = parts.next().and_then(|s| s.parse().ok());
+                let hash = parts.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+                match (insns, hash) {
+                    (Some(insns), Some(hash)) => Ok((insns, hash)),
+                    _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("bad hash log line {}", i + 1))),
+                }
+            })
+            .collect()
+    }
+}
+
+/// where two hash logs first disagree: the instruction count of the
+/// previous hash in `a`, and the first one that differs
+pub fn first_divergence(a: &[(u64, u64)], b: &[(u64, u64)]) -> Option<(u64, u64)> {
+    let b: BTreeMap<u64, u64> = b.iter().cloned().collect();
+    let i = a.iter()
+        .position(|&(insns, hash)| b.get(&insns).is_some_and(|&h| h != hash))?;
+    let last_match = if i > 0 { a[i - 1].0 } else { 0 };
+    Some((last_match, a[i].0))
+}
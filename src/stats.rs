@@ -0,0 +1,120 @@
+// Runtime statistics: instructions per mnemonic, speed, memory traffic and
+// stack depth
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem;
+use std::mem::Discriminant;
+use std::time::{Duration, Instant};
+use disa::AvrInsn;
+
+
+#[derive(Clone)]
+pub struct Stats {
+    /// mnemonic, count
+    opcodes: HashMap<Discriminant<AvrInsn>, (String, u64)>,
+
+    started: Instant,
+    start_insns: u64,
+    start_cycles: u64,
+    start_reads: u64,
+    start_writes: u64,
+
+    pub min_sp: u16,
+    /// last byte of internal SRAM, where the stack starts
+    pub ramend: u16,
+    pub max_call_depth: usize,
+}
+
+/// the values the report is made of, as of one point in time
+pub struct Counters {
+    pub insns: u64,
+    pub cycles: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub emulated_time: Duration,
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+impl Stats {
+    pub fn new(now: &Counters, sp: u16, ramend: u16) -> Stats {
+        Stats {
+            opcodes: HashMap::new(),
+
+            started: Instant::now(),
+            start_insns: now.insns,
+            start_cycles: now.cycles,
+            start_reads: now.reads,
+            start_writes: now.writes,
+
+            min_sp: sp,
+            ramend,
+            max_call_depth: 0,
+        }
+    }
+
+    pub fn count_insn(&mut self, insn: &AvrInsn) {
+        let entry = self.opcodes
+            .entry(mem::discriminant(insn))
+            .or_insert_with(|| {
+                // the variant name, e.g. "Ldi" out of "Ldi(24, 66)"
+                let name = format!("{:?}", insn);
+                let len = name.find(|c: char| !c.is_alphanumeric())
+                    .unwrap_or(name.len());
+                (name[..len].to_string(), 0)
+            });
+        entry.1 += 1;
+    }
+
+    pub fn note_stack(&mut self, sp: u16, call_depth: usize) {
+        if sp < self.min_sp {
+            self.min_sp = sp;
+        }
+        if call_depth > self.max_call_depth {
+            self.max_call_depth = call_depth;
+        }
+    }
+
+    /// (mnemonic, count), most frequent first
+    pub fn histogram(&self) -> Vec<(&str, u64)> {
+        let mut hist: Vec<_> = self.opcodes
+            .values()
+            .map(|&(ref name, count)| (name.as_str(), count))
+            .collect();
+        hist.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        hist
+    }
+
+    pub fn report(&self, now: &Counters) -> String {
+        let mut out = String::new();
+
+        let insns = now.insns.saturating_sub(self.start_insns);
+        let cycles = now.cycles.saturating_sub(self.start_cycles);
+        let wall = secs(self.started.elapsed());
+        let emulated = secs(now.emulated_time);
+
+        writeln!(out, "instructions: {}", insns).unwrap();
+        writeln!(out, "cycles: {}", cycles).unwrap();
+        writeln!(out, "emulated time: {:.6}s, wall time: {:.6}s ({:.2}x real time)",
+            emulated, wall,
+            if wall > 0.0 { emulated / wall } else { 0.0 }).unwrap();
+        writeln!(out, "speed: {:.2} MIPS",
+            if wall > 0.0 { insns as f64 / wall / 1e6 } else { 0.0 }).unwrap();
+        writeln!(out, "data reads: {}, writes: {}",
+            now.reads.saturating_sub(self.start_reads),
+            now.writes.saturating_sub(self.start_writes)).unwrap();
+        writeln!(out, "max stack depth: {} bytes, {} calls",
+            self.ramend.saturating_sub(self.min_sp), self.max_call_depth).unwrap();
+
+        writeln!(out).unwrap();
+        for (name, count) in self.histogram() {
+            let percent = if insns == 0 { 0.0 } else { 100.0 * count as f64 / insns as f64 };
+            writeln!(out, "{:<10} {:>12} {:>6.2}%", name, count, percent).unwrap();
+        }
+
+        out
+    }
+}
@@ -0,0 +1,145 @@
+// Pin stimulus playback
+//
+// Drives input pins from a file, at exact cycles, so that waveforms from
+// sensors or encoders can be replayed the same way every run. Pin changes go
+// through the ports like any other outside signal, so they also set port
+// interrupt flags and generate pin events.
+//
+// The stimulus file is CSV with one pin change per line:
+//   <cycle>,<port>.<pin>,<0|1|z>
+// where z stops driving the pin.
+
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use gpio::PinState;
+use iomem::IOMemory;
+use wiring::PinDevice;
+
+
+pub const PIN_STIMULUS_NAME : &str = "pin stimulus";
+
+/// one pin change: the pin's port and number, and what it's driven to
+#[derive(Clone, Debug, PartialEq)]
+pub struct PinChange {
+    pub cycle: u64,
+    pub port: String,
+    pub pin: u8,
+    pub state: PinState,
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad pin stimulus line {}", line_num + 1))
+}
+
+/// read a stimulus file, see above
+pub fn load_stimulus(path: &str) -> io::Result<Vec<PinChange>> {
+    let r = BufReader::new(File::open(path)?);
+    let mut stimulus = vec![];
+
+    for (line_num, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        if parts.len() != 3 {
+            return Err(bad_line(line_num));
+        }
+
+        let cycle = u64::from_str(parts[0]).map_err(|_| bad_line(line_num))?;
+        let mut pin_parts = parts[1].splitn(2, '.');
+        let port = pin_parts.next().unwrap();
+        let pin = pin_parts.next()
+            .and_then(|s| u8::from_str(s).ok())
+            .ok_or_else(|| bad_line(line_num))?;
+        let state = match parts[2] {
+            "0" => PinState::Low,
+            "1" => PinState::High,
+            "z" | "Z" => PinState::Floating,
+            _ => return Err(bad_line(line_num)),
+        };
+        if pin >= 8 {
+            return Err(bad_line(line_num));
+        }
+
+        stimulus.push(PinChange { cycle, port: port.to_string(), pin, state });
+    }
+
+    stimulus.sort_by_key(|change| change.cycle);
+    Ok(stimulus)
+}
+
+#[derive(Clone)]
+pub struct PinStimulus {
+    stimulus: Vec<PinChange>,
+    next: usize,
+}
+
+impl PinStimulus {
+    pub fn new(stimulus: Vec<PinChange>) -> PinStimulus {
+        PinStimulus { stimulus, next: 0 }
+    }
+
+    /// ports that the stimulus drives but `io_mem` doesn't have
+    pub fn missing_ports(&self, io_mem: &IOMemory) -> Vec<&str> {
+        let mut missing: Vec<&str> = self.stimulus.iter()
+            .map(|change| change.port.as_str())
+            .filter(|port| io_mem.port(port).is_none())
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+}
+
+impl PinDevice for PinStimulus {
+    fn name(&self) -> &str {
+        PIN_STIMULUS_NAME
+    }
+
+    fn update(&mut self, io_mem: &mut IOMemory, now: u64) {
+        while self.next < self.stimulus.len() && self.stimulus[self.next].cycle <= now {
+            let change = &self.stimulus[self.next];
+            if let Some(port) = io_mem.port_mut(&change.port) {
+                port.drive_pin(change.pin, change.state);
+            }
+            self.next += 1;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.stimulus.get(self.next).map(|change| change.cycle)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        state.write_u64::<LittleEndian>(self.next as u64).unwrap();
+        state
+    }
+
+    fn load_state(&mut self, mut state: &[u8]) {
+        if let Ok(next) = state.read_u64::<LittleEndian>() {
+            self.next = (next as usize).min(self.stimulus.len());
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn PinDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
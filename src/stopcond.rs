@@ -0,0 +1,43 @@
+// Conditions for stopping a run, for Emulator::run_until_any
+//
+// Limits count from the start of the run. Symbols are resolved when the run
+// starts.
+
+use std::fmt;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopCondition {
+    /// execution reached this byte address
+    Pc(u32),
+    /// execution reached this code symbol
+    Symbol(String),
+    /// this many instructions were executed
+    InsnLimit(u64),
+    /// this many cycles passed
+    CycleLimit(u64),
+    /// the USART transmitted something
+    UartOutput,
+}
+
+impl fmt::Display for StopCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopCondition::Pc(addr) => write!(f, "pc {:#x}", addr),
+            StopCondition::Symbol(ref name) => write!(f, "{}", name),
+            StopCondition::InsnLimit(n) => write!(f, "{} instructions", n),
+            StopCondition::CycleLimit(n) => write!(f, "{} cycles", n),
+            StopCondition::UartOutput => write!(f, "USART output"),
+        }
+    }
+}
+
+/// a StopCondition with symbols resolved and limits made absolute
+#[derive(Clone, Copy, Debug)]
+pub enum ResolvedCondition {
+    Pc(u32),
+    InsnCount(u64),
+    CycleCount(u64),
+    /// output log longer than this
+    UartOutput(usize),
+}
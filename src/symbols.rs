@@ -0,0 +1,153 @@
+// Symbol table, for naming code addresses
+//
+// Loaded from `avr-nm` output (ideally `avr-nm -S`, for sizes), one symbol
+// per line:
+//   <addr> [<size>] <type> <name>
+// Data symbols (at 0x800000 and up in AVR ELF files) are kept too, but
+// lookups only consider code.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+
+/// where the AVR toolchain puts data space addresses in ELF files
+pub const DATA_OFFSET : u32 = 0x800000;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// byte address
+    pub addr: u32,
+    /// 0 if unknown
+    pub size: u32,
+    /// nm's type letter, e.g. 'T' for global code
+    pub kind: char,
+}
+
+impl Symbol {
+    pub fn is_code(&self) -> bool {
+        match self.kind {
+            'T' | 't' | 'W' | 'w' => self.addr < DATA_OFFSET,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// sorted by address
+    symbols: Vec<Symbol>,
+}
+
+fn bad_line(line_num: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad symbol line {}", line_num + 1))
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn add(&mut self, symbol: Symbol) {
+        let i = self.symbols
+            .iter()
+            .position(|s| s.addr > symbol.addr)
+            .unwrap_or(self.symbols.len());
+        self.symbols.insert(i, symbol);
+    }
+
+    pub fn load_nm(path: &str) -> io::Result<SymbolTable> {
+        let r = BufReader::new(File::open(path)?);
+        let mut symbols = vec![];
+
+        for (line_num, line) in r.lines().enumerate() {
+            let line = line?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+
+            let (addr, size, kind, name) = match parts[..] {
+                [] => continue,
+                // undefined symbols have no address
+                [_, _] => continue,
+                [addr, kind, name] => (addr, "0", kind, name),
+                [addr, size, kind, name] => (addr, size, kind, name),
+                _ => return Err(bad_line(line_num)),
+            };
+
+            let addr = u32::from_str_radix(addr, 16);
+            let size = u32::from_str_radix(size, 16);
+            let kind = kind.chars().next();
+            match (addr, size, kind) {
+                (Ok(addr), Ok(size), Some(kind)) => symbols.push(Symbol {
+                    name: name.to_string(),
+                    addr,
+                    size,
+                    kind,
+                }),
+                _ => return Err(bad_line(line_num)),
+            }
+        }
+
+        symbols.sort_by_key(|s| s.addr);
+        Ok(SymbolTable { symbols })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// the code symbol containing byte address `addr`, and the offset into
+    /// it. without a size, a symbol extends up to the next one.
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let code = |s: &&Symbol| s.is_code();
+        let i = match self.symbols.binary_search_by_key(&addr, |s| s.addr) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+
+        let sym = self.symbols[..i].iter().rev().find(code)?;
+        let ofs = addr - sym.addr;
+        if sym.size != 0 && ofs >= sym.size {
+            return None;
+        }
+
+        Some((sym, ofs))
+    }
+
+    /// byte address range [start, end) of the code symbol `name`
+    pub fn range(&self, name: &str) -> Option<(u32, u32)> {
+        let i = self.symbols.iter().position(|s| s.name == name && s.is_code())?;
+        let sym = &self.symbols[i];
+
+        let end =
+            if sym.size != 0 {
+                sym.addr + sym.size
+            } else {
+                self.symbols[i + 1..]
+                    .iter()
+                    .find(|s| s.is_code() && s.addr > sym.addr)
+                    .map_or(sym.addr + 2, |s| s.addr)
+            };
+        Some((sym.addr, end))
+    }
+
+    /// e.g. "main+0x12", or the bare address if there's no symbol
+    pub fn fmt_addr(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, ofs)) => format!("{}+{:#x}", sym.name, ofs),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
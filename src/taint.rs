@@ -0,0 +1,262 @@
+// Taint tracking from inputs, for finding where input can take over control
+//
+// Bytes the firmware reads from a source address (by default, the USARTs'
+// DATA registers) are tainted, and taint follows them through registers and
+// data memory, one instruction at a time. Whatever is computed from a
+// tainted byte is tainted too; loading a constant or clearing a register
+// removes taint. Flags aren't tracked, so branching on input doesn't spread
+// it.
+//
+// A report is made when tainted data decides where execution goes (Z for an
+// indirect jump or call, or a return address popped off the stack) or where
+// SPM writes to flash.
+
+use std::collections::HashSet;
+use std::fmt;
+use disa::{AvrInsn, Reg, RegPair, MemAccess, MemRegUpdate};
+use iomem::{IOMemory, PtrReg};
+
+
+/// where tainted data ended up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaintSink {
+    /// IJMP/ICALL/EIJMP/EICALL through a tainted Z
+    IndirectJump,
+    /// RET/RETI to a tainted return address
+    Return,
+    /// SPM with a tainted address in Z
+    FlashWrite,
+}
+
+impl fmt::Display for TaintSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TaintSink::IndirectJump => write!(f, "indirect jump through tainted Z"),
+            TaintSink::Return => write!(f, "return to tainted address"),
+            TaintSink::FlashWrite => write!(f, "flash write to tainted address"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TaintReport {
+    pub sink: TaintSink,
+    pub pc: u32,
+    pub insn_count: u64,
+}
+
+
+#[derive(Clone)]
+pub struct Taint {
+    /// reads from these data space addresses are tainted
+    pub sources: Vec<u32>,
+    regs: [bool; 32],
+    mem: HashSet<u32>,
+    pub reports: Vec<TaintReport>,
+    /// stop with an error on the first report
+    pub trap: bool,
+    /// bytes a call or interrupt pushes, see Device::ret_addr_size
+    pub ret_addr_size: u16,
+}
+
+/// the data space address a LD/ST accesses, before any pre-decrement is
+/// applied to the register. None if it's not through X, Y or Z.
+fn mem_access_addr(io: &IOMemory, mema: &MemAccess) -> Option<u32> {
+    let reg = PtrReg::from_reg(mema.reg_pair.0)?;
+    let mut addr = io.get_full_reg(reg);
+    if mema.update == MemRegUpdate::PreDec {
+        addr = addr.wrapping_sub(1);
+    }
+    Some(addr.wrapping_add(mema.ofs as u32) & io.full_reg_mask(reg))
+}
+
+impl Taint {
+    pub fn new(sources: Vec<u32>) -> Taint {
+        Taint {
+            sources,
+            regs: [false; 32],
+            mem: HashSet::new(),
+            reports: vec![],
+            trap: false,
+            ret_addr_size: 3,
+        }
+    }
+
+    pub fn reg(&self, r: u8) -> bool {
+        self.regs[r as usize]
+    }
+
+    pub fn set_reg(&mut self, r: u8, tainted: bool) {
+        self.regs[r as usize] = tainted;
+    }
+
+    pub fn mem(&self, addr: u32) -> bool {
+        self.sources.contains(&addr) || self.mem.contains(&addr)
+    }
+
+    pub fn set_mem(&mut self, addr: u32, tainted: bool) {
+        if tainted {
+            self.mem.insert(addr);
+        } else {
+            self.mem.remove(&addr);
+        }
+    }
+
+    /// forget all taint, but keep the sources and reports
+    pub fn clear(&mut self) {
+        self.regs = [false; 32];
+        self.mem.clear();
+    }
+
+    fn pair(&self, r: u8) -> bool {
+        self.reg(r) || self.reg(r + 1)
+    }
+
+    fn set_pair(&mut self, r: u8, tainted: bool) {
+        self.set_reg(r, tainted);
+        self.set_reg(r + 1, tainted);
+    }
+
+    /// a return address is being pushed at SP and below; it's the CPU's,
+    /// not the input's
+    pub fn push_ret_addr(&mut self, sp: u16) {
+        for i in 0..self.ret_addr_size {
+            self.set_mem(sp.wrapping_sub(i) as u32, false);
+        }
+    }
+
+    /// carry taint across `insn`, which is about to be executed with the
+    /// state in `io`. returns where tainted data is about to be used, if
+    /// anywhere.
+    pub fn propagate(&mut self, insn: &AvrInsn, io: &IOMemory) -> Option<TaintSink> {
+        let sp = io.get_sp();
+
+        match *insn {
+            AvrInsn::Ldi(Reg(rd), _) => self.set_reg(rd, false),
+
+            // the usual ways of clearing a register
+            AvrInsn::Eor(Reg(rd), Reg(rr)) | AvrInsn::Sub(Reg(rd), Reg(rr))
+                    if rd == rr => self.set_reg(rd, false),
+
+            AvrInsn::Mov(Reg(rd), Reg(rr)) => {
+                let t = self.reg(rr);
+                self.set_reg(rd, t);
+            },
+
+            AvrInsn::Movw(RegPair(rd), RegPair(rr)) => {
+                let (lo, hi) = (self.reg(rr), self.reg(rr + 1));
+                self.set_reg(rd, lo);
+                self.set_reg(rd + 1, hi);
+            },
+
+            AvrInsn::Add(Reg(rd), Reg(rr)) | AvrInsn::Adc(Reg(rd), Reg(rr))
+            | AvrInsn::Sub(Reg(rd), Reg(rr)) | AvrInsn::Sbc(Reg(rd), Reg(rr))
+            | AvrInsn::And(Reg(rd), Reg(rr)) | AvrInsn::Or(Reg(rd), Reg(rr))
+            | AvrInsn::Eor(Reg(rd), Reg(rr)) => {
+                let t = self.reg(rd) || self.reg(rr);
+                self.set_reg(rd, t);
+            },
+
+            AvrInsn::Adiw(RegPair(rd), _) | AvrInsn::Sbiw(RegPair(rd), _) => {
+                let t = self.pair(rd);
+                self.set_pair(rd, t);
+            },
+
+            AvrInsn::Mul(Reg(rd), Reg(rr)) | AvrInsn::Muls(Reg(rd), Reg(rr))
+            | AvrInsn::Mulsu(Reg(rd), Reg(rr)) | AvrInsn::Fmul(Reg(rd), Reg(rr))
+            | AvrInsn::Fmuls(Reg(rd), Reg(rr)) | AvrInsn::Fmulsu(Reg(rd), Reg(rr)) => {
+                let t = self.reg(rd) || self.reg(rr);
+                self.set_pair(0, t);
+            },
+
+            AvrInsn::In(Reg(rd), port) => {
+                let t = self.mem(io.port_addr(port as u32));
+                self.set_reg(rd, t);
+            },
+
+            AvrInsn::Lds(Reg(rd), k) => {
+                let t = self.mem(k as u32);
+                self.set_reg(rd, t);
+            },
+
+            AvrInsn::Ld(Reg(rd), mema) | AvrInsn::Ldd(Reg(rd), mema) => {
+                // the emulator fails the instruction if there's no address
+                if let Some(addr) = mem_access_addr(io, &mema) {
+                    let t = self.mem(addr);
+                    self.set_reg(rd, t);
+                }
+            },
+
+            AvrInsn::Pop(Reg(rd)) => {
+                let t = self.mem(sp.wrapping_add(1) as u32);
+                self.set_reg(rd, t);
+            },
+
+            // flash isn't tracked
+            AvrInsn::LpmZ(Reg(rd), _) | AvrInsn::ElpmZ(Reg(rd), _) =>
+                self.set_reg(rd, false),
+
+            AvrInsn::Out(port, Reg(rr)) => {
+                let t = self.reg(rr);
+                self.set_mem(io.port_addr(port as u32), t);
+            },
+
+            AvrInsn::Sts(k, Reg(rr)) => {
+                let t = self.reg(rr);
+                self.set_mem(k as u32, t);
+            },
+
+            AvrInsn::St(mema, Reg(rr)) | AvrInsn::Std(mema, Reg(rr)) => {
+                if let Some(addr) = mem_access_addr(io, &mema) {
+                    let t = self.reg(rr);
+                    self.set_mem(addr, t);
+                }
+            },
+
+            AvrInsn::Push(Reg(rr)) => {
+                let t = self.reg(rr);
+                self.set_mem(sp as u32, t);
+            },
+
+            AvrInsn::Xch(Reg(rd)) | AvrInsn::Las(Reg(rd))
+            | AvrInsn::Lac(Reg(rd)) | AvrInsn::Lat(Reg(rd)) => {
+                let z = io.get_full_z();
+                let (t_mem, t_reg) = (self.mem(z), self.reg(rd));
+                let t_new = match *insn {
+                    AvrInsn::Xch(_) => t_reg,
+                    _ => t_mem || t_reg,
+                };
+                self.set_mem(z, t_new);
+                self.set_reg(rd, t_mem);
+            },
+
+            AvrInsn::Ijmp | AvrInsn::Eijmp if self.pair(30) =>
+                return Some(TaintSink::IndirectJump),
+
+            AvrInsn::Icall | AvrInsn::Eicall => {
+                if self.pair(30) {
+                    return Some(TaintSink::IndirectJump);
+                }
+                self.push_ret_addr(sp);
+            },
+
+            AvrInsn::Call(_) | AvrInsn::Rcall(_) => self.push_ret_addr(sp),
+
+            AvrInsn::Ret | AvrInsn::Reti => {
+                let ret_addr = (1..self.ret_addr_size + 1)
+                    .any(|i| self.mem(sp.wrapping_add(i) as u32));
+                if ret_addr {
+                    return Some(TaintSink::Return);
+                }
+            },
+
+            AvrInsn::Spm | AvrInsn::SpmZ if self.pair(30) =>
+                return Some(TaintSink::FlashWrite),
+
+            // the rest only change flags, or change registers in place
+            _ => {},
+        }
+
+        None
+    }
+}
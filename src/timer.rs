@@ -0,0 +1,517 @@
+// XMEGA 16-bit Timer/Counters (TCxn)
+
+use std::any::Any;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const CTRLA : u32 = 0x00;
+const CTRLB : u32 = 0x01;
+const CTRLC : u32 = 0x02;
+const CTRLD : u32 = 0x03;
+const CTRLE : u32 = 0x04;
+const INTCTRLA : u32 = 0x06;
+const INTCTRLB : u32 = 0x07;
+const CTRLFCLR : u32 = 0x08;
+const CTRLFSET : u32 = 0x09;
+const CTRLGCLR : u32 = 0x0A;
+const CTRLGSET : u32 = 0x0B;
+const INTFLAGS : u32 = 0x0C;
+const TEMP : u32 = 0x0F;
+const CNT : u32 = 0x20;
+const CNT_H : u32 = 0x21;
+const PER : u32 = 0x26;
+const PER_H : u32 = 0x27;
+const CCA : u32 = 0x28;
+const CCD_H : u32 = 0x2F;
+const PERBUF : u32 = 0x36;
+const PERBUF_H : u32 = 0x37;
+const CCABUF : u32 = 0x38;
+const CCDBUF_H : u32 = 0x3F;
+
+// INTFLAGS bits
+const OVFIF : u8 = 0x01;
+const ERRIF : u8 = 0x02;
+const CCAIF : u8 = 0x10;
+
+// CTRLD event actions
+const EVACT_CAPT : u8 = 1;
+const EVACT_RESTART : u8 = 4;
+
+// CTRLFSET commands
+const CMD_MASK : u8 = 0x0C;
+const CMD_RESTART : u8 = 0x08;
+const CMD_RESET : u8 = 0x0C;
+
+
+#[derive(Clone)]
+pub struct TimerCounter {
+    name: String,
+    base: u32,
+    /// vector of OVF; then ERR, CCA, CCB...
+    ovf_vector: u8,
+    /// 4 for type 0 timers, 2 for type 1
+    num_channels: usize,
+    /// DMA trigger source of OVF; then ERR, CCA, CCB...
+    dma_trigger: u8,
+    /// EVSYS source of OVF; then ERR, and CCA at +4
+    ev_source: u8,
+
+    pub ctrla: u8,
+    pub ctrlb: u8,
+    pub ctrlc: u8,
+    pub ctrld: u8,
+    pub ctrle: u8,
+    pub intctrla: u8,
+    pub intctrlb: u8,
+    pub ctrlf: u8,
+    pub ctrlg: u8,
+    pub intflags: u8,
+    /// high byte latch for 16-bit accesses
+    pub temp: u8,
+    pub cnt: u16,
+    pub per: u16,
+    pub cc: [u16; 4],
+    pub perbuf: u16,
+    pub ccbuf: [u16; 4],
+
+    /// cycle count at the last tick
+    last_tick: u64,
+    /// cycles not yet counted because of the prescaler
+    prescaler_acc: u64,
+
+    /// events not yet routed
+    events: Vec<u8>,
+}
+
+impl TimerCounter {
+    pub fn new(name: &str, base: u32, ovf_vector: u8, num_channels: usize,
+               dma_trigger: u8, ev_source: u8) -> TimerCounter {
+
+        TimerCounter {
+            name: name.to_string(),
+            base,
+            ovf_vector,
+            num_channels,
+            dma_trigger,
+            ev_source,
+
+            ctrla: 0,
+            ctrlb: 0,
+            ctrlc: 0,
+            ctrld: 0,
+            ctrle: 0,
+            intctrla: 0,
+            intctrlb: 0,
+            ctrlf: 0,
+            ctrlg: 0,
+            intflags: 0,
+            temp: 0,
+            cnt: 0,
+            per: 0xffff,
+            cc: [0; 4],
+            perbuf: 0xffff,
+            ccbuf: [0; 4],
+
+            last_tick: 0,
+            prescaler_acc: 0,
+
+            events: vec![],
+        }
+    }
+
+    /// clock divider selected by CTRLA, or None if the timer is stopped or
+    /// clocked by the event system
+    pub fn prescaler(&self) -> Option<u64> {
+        match self.ctrla & 0x0f {
+            1 => Some(1),
+            2 => Some(2),
+            3 => Some(4),
+            4 => Some(8),
+            5 => Some(64),
+            6 => Some(256),
+            7 => Some(1024),
+            _ => None,
+        }
+    }
+
+    /// count `counts` timer clocks, setting OVF and compare match flags
+    pub fn advance(&mut self, mut counts: u64) {
+        while counts > 0 {
+            let cnt = self.cnt as u64;
+            let top = if cnt <= self.per as u64 { self.per as u64 } else { 0xffff };
+            let to_ovf = top - cnt + 1;
+            let step = counts.min(to_ovf);
+            let wrapped = step == to_ovf;
+
+            // values the counter passes through, excluding the current one
+            let last = if wrapped { top } else { cnt + step };
+            for ch in 0..self.num_channels {
+                let cc = self.cc[ch] as u64;
+                if (cc > cnt && cc <= last) || (wrapped && cc == 0) {
+                    self.intflags |= CCAIF << ch;
+                    self.events.push(self.ev_source + 4 + ch as u8);
+                }
+            }
+
+            if wrapped {
+                self.intflags |= OVFIF;
+                self.events.push(self.ev_source);
+                self.cnt = 0;
+            } else {
+                self.cnt = last as u16;
+            }
+
+            counts -= step;
+        }
+    }
+
+    /// (INTFLAGS bit, vector, level) for each interrupt source
+    fn interrupt_sources(&self) -> Vec<(u8, u8, u8)> {
+        let mut sources = vec![
+            (OVFIF, self.ovf_vector, self.intctrla & 0x3),
+            (ERRIF, self.ovf_vector + 1, (self.intctrla >> 2) & 0x3),
+        ];
+
+        for ch in 0..self.num_channels {
+            sources.push((
+                CCAIF << ch,
+                self.ovf_vector + 2 + ch as u8,
+                (self.intctrlb >> (2 * ch)) & 0x3));
+        }
+
+        sources
+    }
+
+    /// the INTFLAGS bit for DMA trigger source `trigsrc`, if it's ours
+    fn dma_trigger_flag(&self, trigsrc: u8) -> Option<u8> {
+        if trigsrc < self.dma_trigger {
+            return None;
+        }
+
+        match trigsrc - self.dma_trigger {
+            0 => Some(OVFIF),
+            1 => Some(ERRIF),
+            n if ((n - 2) as usize) < self.num_channels => Some(CCAIF << (n - 2)),
+            _ => None,
+        }
+    }
+
+    fn read16(&mut self, ofs: u32, val: u16) -> u8 {
+        if (ofs & 1) == 0 {
+            self.temp = (val >> 8) as u8;
+            val as u8
+        } else {
+            self.temp
+        }
+    }
+
+    /// returns the new value once the high byte is written
+    fn write16(&mut self, ofs: u32, val: u8) -> Option<u16> {
+        if (ofs & 1) == 0 {
+            self.temp = val;
+            None
+        } else {
+            Some(((val as u16) << 8) | (self.temp as u16))
+        }
+    }
+}
+
+impl Peripheral for TimerCounter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x40)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRLA => self.ctrla,
+            CTRLB => self.ctrlb,
+            CTRLC => self.ctrlc,
+            CTRLD => self.ctrld,
+            CTRLE => self.ctrle,
+            INTCTRLA => self.intctrla,
+            INTCTRLB => self.intctrlb,
+            CTRLFCLR | CTRLFSET => self.ctrlf,
+            CTRLGCLR | CTRLGSET => self.ctrlg,
+            INTFLAGS => self.intflags,
+            TEMP => self.temp,
+            CNT..=CNT_H => {
+                let cnt = self.cnt;
+                self.read16(ofs, cnt)
+            },
+            PER..=PER_H => {
+                let per = self.per;
+                self.read16(ofs, per)
+            },
+            CCA..=CCD_H => {
+                let cc = self.cc[((ofs - CCA) / 2) as usize];
+                self.read16(ofs, cc)
+            },
+            PERBUF..=PERBUF_H => {
+                let perbuf = self.perbuf;
+                self.read16(ofs, perbuf)
+            },
+            CCABUF..=CCDBUF_H => {
+                let ccbuf = self.ccbuf[((ofs - CCABUF) / 2) as usize];
+                self.read16(ofs, ccbuf)
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRLA => self.ctrla = val,
+            CTRLB => self.ctrlb = val,
+            CTRLC => self.ctrlc = val,
+            CTRLD => self.ctrld = val,
+            CTRLE => self.ctrle = val,
+            INTCTRLA => self.intctrla = val,
+            INTCTRLB => self.intctrlb = val,
+            CTRLFCLR => self.ctrlf &= !val,
+            CTRLFSET => {
+                match val & CMD_MASK {
+                    CMD_RESTART => {
+                        self.cnt = 0;
+                        self.prescaler_acc = 0;
+                    },
+                    CMD_RESET => {
+                        self.reset();
+                        return;
+                    },
+                    _ => {},
+                }
+
+                self.ctrlf |= val & !CMD_MASK;
+            },
+            CTRLGCLR => self.ctrlg &= !val,
+            CTRLGSET => self.ctrlg |= val,
+            // write 1 to clear
+            INTFLAGS => self.intflags &= !val,
+            TEMP => self.temp = val,
+            CNT..=CNT_H => {
+                if let Some(cnt) = self.write16(ofs, val) {
+                    self.cnt = cnt;
+                }
+            },
+            PER..=PER_H => {
+                if let Some(per) = self.write16(ofs, val) {
+                    self.per = per;
+                }
+            },
+            CCA..=CCD_H => {
+                if let Some(cc) = self.write16(ofs, val) {
+                    self.cc[((ofs - CCA) / 2) as usize] = cc;
+                }
+            },
+            PERBUF..=PERBUF_H => {
+                if let Some(perbuf) = self.write16(ofs, val) {
+                    self.perbuf = perbuf;
+                }
+            },
+            CCABUF..=CCDBUF_H => {
+                if let Some(ccbuf) = self.write16(ofs, val) {
+                    self.ccbuf[((ofs - CCABUF) / 2) as usize] = ccbuf;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let last_tick = self.last_tick;
+        *self = TimerCounter::new(
+            &self.name, self.base, self.ovf_vector, self.num_channels,
+            self.dma_trigger, self.ev_source);
+        self.last_tick = last_tick;
+    }
+
+    fn tick(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.last_tick);
+        self.last_tick = now;
+
+        if let Some(div) = self.prescaler() {
+            let total = self.prescaler_acc + elapsed;
+            self.prescaler_acc = total % div;
+            self.advance(total / div);
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        self.last_tick = now;
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let div = self.prescaler()?;
+
+        // the next overflow or compare match
+        let cnt = self.cnt as u64;
+        let top = if cnt <= self.per as u64 { self.per as u64 } else { 0xffff };
+        let mut counts = top - cnt + 1;
+        for ch in 0..self.num_channels {
+            let cc = self.cc[ch] as u64;
+            if cc > cnt && cc <= top {
+                counts = counts.min(cc - cnt);
+            }
+        }
+
+        Some(self.last_tick + counts * div - self.prescaler_acc)
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        // highest level, then lowest vector
+        self.interrupt_sources()
+            .into_iter()
+            .filter(|&(flag, _, level)| (self.intflags & flag) != 0 && level != 0)
+            .map(|(_, vector, level)| (vector, level))
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        out.extend(self.interrupt_sources()
+            .into_iter()
+            .filter(|&(_, _, level)| level != 0)
+            .map(|(_, vector, _)| vector));
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        for (flag, v, _) in self.interrupt_sources() {
+            if v == vector {
+                self.intflags &= !flag;
+            }
+        }
+    }
+
+    fn poll_events(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.events);
+    }
+
+    fn event(&mut self, channel: u8) {
+        // CLKSEL 8-15 counts event channels 0-7
+        if (self.ctrla & 0x0f) == 8 + channel {
+            self.advance(1);
+        }
+
+        // EVSEL 8-15 selects event channels 0-7
+        let evsel = self.ctrld & 0x0f;
+        if evsel < 8 || channel < evsel - 8 {
+            return;
+        }
+
+        let evact = self.ctrld >> 5;
+        let ch = (channel - (evsel - 8)) as usize;
+        match evact {
+            // channel n captures from event channel EVSEL+n
+            EVACT_CAPT if ch < self.num_channels => {
+                self.cc[ch] = self.cnt;
+                self.intflags |= CCAIF << ch;
+            },
+            EVACT_RESTART if ch == 0 => {
+                self.cnt = 0;
+                self.prescaler_acc = 0;
+            },
+            _ => {},
+        }
+    }
+
+    fn dma_request(&self, trigsrc: u8) -> bool {
+        match self.dma_trigger_flag(trigsrc) {
+            Some(flag) => (self.intflags & flag) != 0,
+            None => false,
+        }
+    }
+
+    fn dma_ack(&mut self, trigsrc: u8) {
+        if let Some(flag) = self.dma_trigger_flag(trigsrc) {
+            self.intflags &= !flag;
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.prescaler().is_some()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.ctrla, self.ctrlb, self.ctrlc, self.ctrld, self.ctrle,
+            self.intctrla, self.intctrlb, self.ctrlf, self.ctrlg,
+            self.intflags, self.temp,
+        ];
+
+        let words = [self.cnt, self.per, self.perbuf];
+        for &w in words.iter().chain(self.cc.iter()).chain(self.ccbuf.iter()) {
+            state.write_u16::<LittleEndian>(w).unwrap();
+        }
+
+        state.write_u64::<LittleEndian>(self.last_tick).unwrap();
+        state.write_u64::<LittleEndian>(self.prescaler_acc).unwrap();
+        state.extend_from_slice(&self.events);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        // 11 bytes, 11 words, 2 u64s, then events
+        if state.len() < 11 + 22 + 16 {
+            return;
+        }
+
+        self.ctrla = state[0];
+        self.ctrlb = state[1];
+        self.ctrlc = state[2];
+        self.ctrld = state[3];
+        self.ctrle = state[4];
+        self.intctrla = state[5];
+        self.intctrlb = state[6];
+        self.ctrlf = state[7];
+        self.ctrlg = state[8];
+        self.intflags = state[9];
+        self.temp = state[10];
+
+        let mut r = &state[11..];
+        self.cnt = r.read_u16::<LittleEndian>().unwrap();
+        self.per = r.read_u16::<LittleEndian>().unwrap();
+        self.perbuf = r.read_u16::<LittleEndian>().unwrap();
+        for i in 0..4 {
+            self.cc[i] = r.read_u16::<LittleEndian>().unwrap();
+        }
+        for i in 0..4 {
+            self.ccbuf[i] = r.read_u16::<LittleEndian>().unwrap();
+        }
+        self.last_tick = r.read_u64::<LittleEndian>().unwrap();
+        self.prescaler_acc = r.read_u64::<LittleEndian>().unwrap();
+        self.events = r.to_vec();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the atxmega128a4u's timers
+pub fn default_timers() -> Vec<TimerCounter> {
+    vec![
+        TimerCounter::new("TCC0", 0x0800, 14, 4, 0x40, 0xC0),
+        TimerCounter::new("TCC1", 0x0840, 20, 2, 0x46, 0xC8),
+        TimerCounter::new("TCD0", 0x0900, 77, 4, 0x60, 0xD0),
+        TimerCounter::new("TCD1", 0x0940, 83, 2, 0x66, 0xD8),
+        TimerCounter::new("TCE0", 0x0A00, 47, 4, 0x80, 0xE0),
+    ]
+}
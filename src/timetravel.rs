@@ -0,0 +1,66 @@
+// Periodic checkpoints for reverse execution, see Emulator::step_back()
+
+use std::collections::VecDeque;
+use snapshot::Snapshot;
+
+
+#[derive(Clone)]
+pub struct TimeTravel {
+    /// take a checkpoint every `interval` instructions
+    pub interval: u64,
+    /// drop the oldest checkpoints past this many
+    pub max_checkpoints: usize,
+
+    /// oldest first
+    pub checkpoints: VecDeque<Snapshot>,
+    /// the instruction count to take the next checkpoint at
+    next: u64,
+}
+
+impl TimeTravel {
+    pub fn new(interval: u64, max_checkpoints: usize) -> TimeTravel {
+        TimeTravel {
+            interval,
+            max_checkpoints,
+            checkpoints: VecDeque::new(),
+            next: 0,
+        }
+    }
+
+    pub fn needs_checkpoint(&self, insn_count: u64) -> bool {
+        insn_count >= self.next
+    }
+
+    // the first multiple of the interval after `insn_count`. warp mode and
+    // stubs can skip over multiples.
+    fn next_after(&self, insn_count: u64) -> u64 {
+        (insn_count / self.interval + 1) * self.interval
+    }
+
+    pub fn add_checkpoint(&mut self, snap: Snapshot) {
+        self.next = self.next_after(snap.insn_count);
+        self.checkpoints.push_back(snap);
+        while self.checkpoints.len() > self.max_checkpoints {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /// the latest checkpoint taken at or before `insn_count`, or the oldest
+    /// one if they're all later
+    pub fn checkpoint_before(&self, insn_count: u64) -> Option<&Snapshot> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|snap| snap.insn_count <= insn_count)
+            .or(self.checkpoints.front())
+    }
+
+    /// execution went back to `snap`, and may go differently from there on,
+    /// e.g. if the host changes something. forget the later checkpoints.
+    pub fn rewind_to(&mut self, snap: &Snapshot) {
+        while self.checkpoints.back().is_some_and(|c| c.insn_count > snap.insn_count) {
+            self.checkpoints.pop_back();
+        }
+        self.next = self.next_after(snap.insn_count);
+    }
+}
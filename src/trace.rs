@@ -0,0 +1,196 @@
+// Instruction tracing
+//
+// With a tracer set, the emulator reports every executed instruction (in
+// the filter's ranges) to it.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use disa::AvrInsn;
+use symbols::SymbolTable;
+use json;
+
+
+pub struct TraceEntry<'a> {
+    /// byte address
+    pub pc: u32,
+    pub insn: AvrInsn,
+    /// the instruction was skipped by a preceding skip instruction
+    pub skipped: bool,
+    /// counters before the instruction
+    pub cycle: u64,
+    pub insn_count: u64,
+    /// SP after the instruction
+    pub sp: u16,
+    /// (register, new value) for each register the instruction changed
+    pub reg_changes: Vec<(u8, u8)>,
+    pub sreg_before: u8,
+    pub sreg_after: u8,
+    /// the function containing pc, and the offset into it
+    pub symbol: Option<(&'a str, u32)>,
+}
+
+pub trait Tracer: Send {
+    fn trace(&mut self, entry: &TraceEntry);
+
+    /// called when the tracer is removed
+    fn flush(&mut self) {}
+}
+
+/// which instructions to trace
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// byte address ranges [start, end). empty means everything.
+    pub ranges: Vec<(u32, u32)>,
+}
+
+impl TraceFilter {
+    pub fn all() -> TraceFilter {
+        TraceFilter { ranges: vec![] }
+    }
+
+    pub fn add_range(&mut self, start: u32, end: u32) {
+        self.ranges.push((start, end));
+    }
+
+    /// trace only inside function `name`; returns false if there's no such
+    /// symbol
+    pub fn add_symbol(&mut self, symbols: &SymbolTable, name: &str) -> bool {
+        match symbols.range(name) {
+            Some((start, end)) => {
+                self.add_range(start, end);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn matches(&self, pc: u32) -> bool {
+        self.ranges.is_empty()
+            || self.ranges.iter().any(|&(start, end)| pc >= start && pc < end)
+    }
+}
+
+const SREG_CHARS : &[u8] = b"CZNVSHTI";
+
+pub(crate) fn fmt_sreg(sreg: u8) -> String {
+    SREG_CHARS.iter()
+        .enumerate()
+        .map(|(bit, &c)| if (sreg & (1 << bit)) != 0 { c as char } else { '.' })
+        .collect()
+}
+
+/// one line per instruction:
+///   <insn count> <cycle> <pc> <symbol+ofs> <insn> [changes]
+pub struct TextTracer<W: Write + Send> {
+    out: W,
+    /// also show changed registers and flags
+    pub show_changes: bool,
+}
+
+impl<W: Write + Send> TextTracer<W> {
+    pub fn new(out: W, show_changes: bool) -> TextTracer<W> {
+        TextTracer { out, show_changes }
+    }
+
+    fn write_entry(&mut self, e: &TraceEntry) -> io::Result<()> {
+        let location = match e.symbol {
+            Some((name, 0)) => name.to_string(),
+            Some((name, ofs)) => format!("{}+{:#x}", name, ofs),
+            None => String::new(),
+        };
+
+        write!(self.out, "{:>10} {:>12} {:#07x} {:<24} {:?}",
+            e.insn_count, e.cycle, e.pc, location, e.insn)?;
+        if e.skipped {
+            write!(self.out, " (skipped)")?;
+        }
+
+        if self.show_changes {
+            for &(r, val) in e.reg_changes.iter() {
+                write!(self.out, " r{}={:02x}", r, val)?;
+            }
+            if e.sreg_before != e.sreg_after {
+                write!(self.out, " sreg={}", fmt_sreg(e.sreg_after))?;
+            }
+        }
+
+        writeln!(self.out)
+    }
+}
+
+/// one JSON object per line, e.g.
+///   {"n":12,"cycle":20,"pc":256,"insn":"Ldi(24, 66)","sp":16383,
+///    "regs":{"24":66},"sreg":0,"sym":"main+0x4"}
+/// "regs" has the changed registers only; "skipped" is only there for
+/// skipped instructions, and "sym" only with symbols.
+pub struct JsonTracer<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> JsonTracer<W> {
+    pub fn new(out: W) -> JsonTracer<W> {
+        JsonTracer { out }
+    }
+
+    fn write_entry(&mut self, e: &TraceEntry) -> io::Result<()> {
+        let regs: Vec<String> = e.reg_changes
+            .iter()
+            .map(|&(r, val)| format!("\"{}\":{}", r, val))
+            .collect();
+
+        write!(self.out,
+            "{{\"n\":{},\"cycle\":{},\"pc\":{},\"insn\":{},\"sp\":{},\
+             \"regs\":{{{}}},\"sreg\":{}",
+            e.insn_count, e.cycle, e.pc, json::string(&format!("{:?}", e.insn)),
+            e.sp, regs.join(","), e.sreg_after)?;
+
+        if e.skipped {
+            write!(self.out, ",\"skipped\":true")?;
+        }
+
+        match e.symbol {
+            Some((name, 0)) =>
+                write!(self.out, ",\"sym\":{}", json::string(name))?,
+            Some((name, ofs)) =>
+                write!(self.out, ",\"sym\":{}",
+                    json::string(&format!("{}+{:#x}", name, ofs)))?,
+            None => {},
+        }
+
+        writeln!(self.out, "}}")
+    }
+}
+
+impl JsonTracer<BufWriter<File>> {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(JsonTracer::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write + Send> Tracer for JsonTracer<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        let _ = self.write_entry(entry);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+impl TextTracer<BufWriter<File>> {
+    pub fn create(path: &str, show_changes: bool) -> io::Result<Self> {
+        Ok(TextTracer::new(BufWriter::new(File::create(path)?), show_changes))
+    }
+}
+
+impl<W: Write + Send> Tracer for TextTracer<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        // a trace with holes is still useful, so carry on
+        let _ = self.write_entry(entry);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
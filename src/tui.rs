@@ -0,0 +1,527 @@
+// Full-screen debugger front-end, with the "tui" feature
+//
+// Takes the same commands as the line-oriented debugger, but keeps the code
+// around PC, the registers, the stack, watches, attached LCDs, the board and
+// USART output on screen, and updates them as the program steps or runs. F5 continues, F10 steps
+// over calls and F11 steps; Enter on an empty line repeats the last command.
+// Alt+1 to Alt+9 press or release the board's buttons, even while running.
+// Ctrl-C or Esc stops a running program, and Ctrl-C quits otherwise.
+
+use std::io;
+use std::io::Stdout;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crossterm::ExecutableCommand;
+use crossterm::event;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen,
+                          LeaveAlternateScreen};
+use ratatui::{Frame, Terminal};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use board::{Board, BOARD_NAME};
+use debugger::Debugger;
+use diag::DiagnosticsSink;
+use disasm::branch_target;
+use emulator::{Emulator, StopReason};
+use lcd;
+use trace::fmt_sreg;
+use wiring::PinDevice;
+
+
+/// cycles to run between screen updates while the program runs
+const RUN_CHUNK : u64 = 100_000;
+/// lines of command and USART output kept for scrolling back
+const MAX_LINES : usize = 1000;
+/// stack bytes shown per line
+const STACK_BYTES_PER_LINE : usize = 8;
+/// width of the board pane
+const BOARD_WIDTH : u16 = 20;
+
+/// collects USART output and warnings, which would mess up the screen if
+/// printed
+#[derive(Default)]
+struct ConsoleSink {
+    uart: Mutex<Vec<u8>>,
+    warnings: Mutex<Vec<String>>,
+}
+
+impl DiagnosticsSink for ConsoleSink {
+    fn warning(&self, msg: &str) {
+        self.warnings.lock().unwrap().push(msg.to_string());
+    }
+
+    fn uart_output(&self, val: u8) {
+        self.uart.lock().unwrap().push(val);
+    }
+}
+
+/// the terminal in raw mode on the alternate screen, until dropped
+struct Screen {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Screen {
+    fn new() -> io::Result<Screen> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        stdout.execute(EnterAlternateScreen)?;
+        Ok(Screen { terminal: Terminal::new(CrosstermBackend::new(stdout))? })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = self.terminal.backend_mut().execute(LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+fn push_line(lines: &mut Vec<String>, line: String) {
+    if lines.len() == MAX_LINES {
+        lines.remove(0);
+    }
+    lines.push(line);
+}
+
+/// the last lines of `lines` that fit in a bordered pane `height` rows high
+fn tail(lines: &[String], height: u16) -> Vec<Line<'_>> {
+    let n = height.saturating_sub(2) as usize;
+    lines[lines.len().saturating_sub(n)..]
+        .iter()
+        .map(|s| Line::from(s.as_str()))
+        .collect()
+}
+
+fn pane(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+/// `count` instructions around pc, with a few before it
+fn code_lines(emu: &Emulator, count: usize) -> Vec<Line<'static>> {
+    let pc = emu.pc;
+
+    // start decoding at the function start if it's close, so that
+    // two-word instructions line up
+    let start = emu.symbols.lookup(pc)
+        .map(|(_, ofs)| pc - ofs)
+        .filter(|&start| pc - start <= 4 * count as u32)
+        .unwrap_or_else(|| pc.saturating_sub(2 * count as u32));
+
+    let mut insns = vec![];
+    let mut addr = start;
+    while addr < pc {
+        match emu.prog_mem.get_insn_at(addr) {
+            Some(insn) => {
+                insns.push((addr, insn));
+                addr += insn.byte_size() as u32;
+            },
+            None => addr += 2,
+        }
+    }
+    // decoding started in the middle of an instruction
+    if addr != pc {
+        insns.clear();
+    }
+
+    let before = count / 3;
+    if insns.len() > before {
+        let extra = insns.len() - before;
+        insns.drain(..extra);
+    }
+
+    addr = pc;
+    while insns.len() < count {
+        match emu.prog_mem.get_insn_at(addr) {
+            Some(insn) => {
+                insns.push((addr, insn));
+                addr += insn.byte_size() as u32;
+            },
+            None => break,
+        }
+    }
+
+    let mut lines = vec![];
+    for (addr, insn) in insns {
+        if let Some((sym, 0)) = emu.symbols.lookup(addr) {
+            lines.push(Line::styled(format!("<{}>:", sym.name),
+                                    Style::default().fg(Color::Cyan)));
+        }
+
+        let bp = emu.breakpoints.iter().any(|bp| bp.addr == addr);
+        let mut text = format!("{}{} {:>6x}:  {:?}",
+            if addr == pc { "=>" } else { "  " },
+            if bp { "*" } else { " " },
+            addr, insn);
+        if let Some(tgt) = branch_target(addr, &insn) {
+            text.push_str(&format!("  ; {:#x}", tgt));
+            if emu.symbols.lookup(tgt).is_some() {
+                text.push_str(&format!(" <{}>", emu.symbols.fmt_addr(tgt)));
+            }
+        }
+
+        let style =
+            if addr == pc {
+                Style::default().fg(Color::Black).bg(Color::Yellow)
+            } else if bp {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default()
+            };
+        lines.push(Line::styled(text, style));
+    }
+
+    lines
+}
+
+pub struct Tui<'a> {
+    debugger: Debugger<'a>,
+    console: Arc<ConsoleSink>,
+    /// USART output, split into lines; the last one is still open
+    uart: Vec<String>,
+    /// command output and warnings
+    output: Vec<String>,
+    /// the watches as of the last command, with changes marked
+    watches: String,
+    /// the registers from before the last command, to point out changes
+    prev_regs: [u8; 32],
+    input: String,
+    last_command: String,
+    running: bool,
+}
+
+impl<'a> Tui<'a> {
+    pub fn new(emu: &'a mut Emulator) -> Tui<'a> {
+        let mut debugger = Debugger::new(emu);
+        debugger.show_state = false;
+
+        let mut prev_regs = [0; 32];
+        for (r, prev) in prev_regs.iter_mut().enumerate() {
+            *prev = debugger.emu.get_reg8(r as u8);
+        }
+
+        Tui {
+            debugger,
+            console: Arc::new(ConsoleSink::default()),
+            uart: vec![String::new()],
+            output: vec![],
+            watches: String::new(),
+            prev_regs,
+            input: String::new(),
+            last_command: String::new(),
+            running: false,
+        }
+    }
+
+    /// take over the terminal until the user quits. warnings and USART
+    /// output show up in the TUI meanwhile, instead of going to the
+    /// emulator's diagnostics sink.
+    pub fn run(&mut self) -> io::Result<()> {
+        let old_sink = self.debugger.emu.io_mem.diag.clone();
+        self.debugger.emu.set_diagnostics_sink(self.console.clone());
+        let result = self.event_loop();
+        self.debugger.emu.set_diagnostics_sink(old_sink);
+        result
+    }
+
+    fn event_loop(&mut self) -> io::Result<()> {
+        let mut screen = Screen::new()?;
+
+        loop {
+            self.collect_console();
+            screen.terminal.draw(|f| self.draw(f))?;
+
+            if self.running {
+                if event::poll(Duration::from_millis(0))? {
+                    if let Event::Key(key) = event::read()? {
+                        if !self.toggle_button(&key) && is_stop_key(&key) {
+                            self.running = false;
+                            let pc = self.debugger.emu.pc;
+                            self.log(format!("interrupted @ {:#x}", pc));
+                            self.command_done();
+                        }
+                    }
+                }
+                if self.running {
+                    self.run_chunk();
+                }
+                continue;
+            }
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle_key(key)? {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Alt+N presses button N on the board, or releases it if it's pressed.
+    /// returns whether the key was one of those.
+    fn toggle_button(&mut self, key: &KeyEvent) -> bool {
+        if key.kind != KeyEventKind::Press || !key.modifiers.contains(KeyModifiers::ALT) {
+            return false;
+        }
+        let n = match key.code {
+            KeyCode::Char(c @ '1'..='9') => c as usize - '1' as usize,
+            _ => return false,
+        };
+
+        if let Some(board) = self.debugger.emu.io_mem.pin_device_mut::<Board>(BOARD_NAME) {
+            let button = board.buttons().get(n).map(|&(name, pressed)| (name.to_string(), pressed));
+            if let Some((name, pressed)) = button {
+                board.set_button(&name, !pressed);
+            }
+        }
+        true
+    }
+
+    /// returns false to quit
+    fn handle_key(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if self.toggle_button(&key) {
+            return Ok(true);
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                return Ok(false),
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => { self.input.pop(); },
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Enter => {
+                let line = if self.input.trim().is_empty() {
+                    self.last_command.clone()
+                } else {
+                    self.input.clone()
+                };
+                self.input.clear();
+                return self.run_line(&line);
+            },
+            KeyCode::F(5) => return self.run_line("c"),
+            KeyCode::F(10) => return self.run_line("n"),
+            KeyCode::F(11) => return self.run_line("s"),
+            _ => {},
+        }
+        Ok(true)
+    }
+
+    fn run_line(&mut self, line: &str) -> io::Result<bool> {
+        if line.trim().is_empty() {
+            return Ok(true);
+        }
+        self.log(format!("(yaavre) {}", line));
+        self.last_command = line.to_string();
+        for r in 0..32 {
+            self.prev_regs[r] = self.debugger.emu.get_reg8(r as u8);
+        }
+
+        // run in chunks, so that the screen keeps updating
+        match line.split_whitespace().next() {
+            Some("c") | Some("continue") => {
+                self.debugger.emu.discard_signals();
+                self.running = true;
+                return Ok(true);
+            },
+            _ => {},
+        }
+
+        let mut out = vec![];
+        let keep_going = self.debugger.run_command(line, &mut out)?;
+        for line in String::from_utf8_lossy(&out).lines() {
+            self.log(line.to_string());
+        }
+        self.command_done();
+        Ok(keep_going)
+    }
+
+    fn run_chunk(&mut self) {
+        let end = self.debugger.emu.cycle_count + RUN_CHUNK;
+        let result = self.debugger.emu.run_until_cycle(end);
+        match result {
+            Ok(StopReason::CycleLimit) => return,
+            Ok(reason) => self.log(format!("stopped: {:?}", reason)),
+            Err(e) => self.log(format!("error: {}", e)),
+        }
+        self.running = false;
+        self.command_done();
+    }
+
+    fn command_done(&mut self) {
+        self.watches = self.debugger.fmt_watches();
+    }
+
+    fn log(&mut self, line: String) {
+        push_line(&mut self.output, line);
+    }
+
+    // move what the sink collected into the panes
+    fn collect_console(&mut self) {
+        let warnings: Vec<String> = self.console.warnings.lock().unwrap().drain(..).collect();
+        for warning in warnings {
+            self.log(warning);
+        }
+
+        let bytes: Vec<u8> = self.console.uart.lock().unwrap().drain(..).collect();
+        for b in bytes {
+            match b {
+                b'\n' => push_line(&mut self.uart, String::new()),
+                b'\r' => {},
+                b if b.is_ascii_graphic() || b == b' ' => self.uart.last_mut().unwrap().push(b as char),
+                _ => {},
+            }
+        }
+    }
+
+    fn draw(&self, f: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(12),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Length(1),
+            ])
+            .split(f.size());
+
+        let top = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+            .split(rows[0]);
+        let right = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(10), Constraint::Min(3)])
+            .split(top[1]);
+        // LCDs and the board go between the watches and the USART, at their
+        // own size
+        let lcds = lcd::attached(&self.debugger.emu.io_mem);
+        let board = self.debugger.emu.io_mem.pin_device::<Board>(BOARD_NAME);
+        let mut columns = vec![Constraint::Percentage(40)];
+        columns.extend(lcds.iter().map(|lcd| Constraint::Length(lcd.cols as u16 + 2)));
+        if board.is_some() {
+            columns.push(Constraint::Length(BOARD_WIDTH));
+        }
+        columns.push(Constraint::Min(10));
+        let middle = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(columns)
+            .split(rows[1]);
+        let uart_area = middle[middle.len() - 1];
+
+        let emu = &*self.debugger.emu;
+        let title = if self.running { "code (running)" } else { "code" };
+        let code = code_lines(emu, top[0].height.saturating_sub(2) as usize);
+        f.render_widget(Paragraph::new(code).block(pane(title)), top[0]);
+
+        f.render_widget(Paragraph::new(self.register_lines()).block(pane("registers")),
+                        right[0]);
+        f.render_widget(Paragraph::new(self.stack_lines(right[1])).block(pane("stack")),
+                        right[1]);
+
+        let watches: Vec<Line> = self.watches.lines().map(Line::from).collect();
+        f.render_widget(Paragraph::new(watches).block(pane("watches")), middle[0]);
+        for (i, lcd) in lcds.iter().enumerate() {
+            let lines: Vec<Line> = lcd.lines().into_iter().map(Line::from).collect();
+            f.render_widget(Paragraph::new(lines).block(pane(lcd.name())), middle[i + 1]);
+        }
+        if let Some(board) = board {
+            f.render_widget(Paragraph::new(board_lines(board)).block(pane("board")),
+                            middle[lcds.len() + 1]);
+        }
+        f.render_widget(Paragraph::new(tail(&self.uart, uart_area.height)).block(pane("USART")),
+                        uart_area);
+
+        f.render_widget(Paragraph::new(tail(&self.output, rows[2].height)).block(pane("output")),
+                        rows[2]);
+
+        let prompt = format!("(yaavre) {}", self.input);
+        f.set_cursor(rows[3].x + prompt.len() as u16, rows[3].y);
+        f.render_widget(Paragraph::new(prompt), rows[3]);
+    }
+
+    fn register_lines(&self) -> Vec<Line<'_>> {
+        let emu = &*self.debugger.emu;
+        let changed = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+
+        let mut lines = vec![];
+        for row in 0..4 {
+            let mut spans = vec![Span::raw(format!("{:>3}:", format!("r{}", row * 8)))];
+            for r in row * 8..row * 8 + 8 {
+                let val = emu.get_reg8(r as u8);
+                let text = format!(" {:02x}", val);
+                spans.push(if val != self.prev_regs[r] {
+                    Span::styled(text, changed)
+                } else {
+                    Span::raw(text)
+                });
+            }
+            lines.push(Line::from(spans));
+        }
+
+        lines.push(Line::from(format!("X: {:06x} Y: {:06x} Z: {:06x}",
+            emu.io_mem.get_full_x(), emu.io_mem.get_full_y(), emu.io_mem.get_full_z())));
+        lines.push(Line::from(format!("SP: {:#06x}  SREG: {}",
+            emu.io_mem.get_sp(), fmt_sreg(emu.get_sreg()))));
+        let t = emu.emulated_time();
+        lines.push(Line::from(format!("cycles: {}, time: {}.{:06}s",
+            emu.cycle_count, t.as_secs(), t.subsec_micros())));
+        lines
+    }
+
+    /// the call stack, innermost first, then the bytes on the stack
+    fn stack_lines(&self, area: Rect) -> Vec<Line<'_>> {
+        let emu = &*self.debugger.emu;
+        let mut lines = vec![Line::from(format!("#0 {}", emu.fmt_location(emu.pc)))];
+        for (i, frame) in emu.call_stack.iter().rev().enumerate() {
+            lines.push(Line::from(format!("#{} {}", i + 1, emu.fmt_location(frame.ret_addr))));
+        }
+
+        let rows = (area.height.saturating_sub(2) as usize).saturating_sub(lines.len() + 1);
+        let mut bytes = vec![0; rows * STACK_BYTES_PER_LINE];
+        let start = emu.io_mem.get_sp() as u32 + 1;
+        // near the end of memory, show whatever's left
+        while !bytes.is_empty() && emu.read_data(start, &mut bytes).is_err() {
+            let len = bytes.len() - 1;
+            bytes.truncate(len);
+        }
+
+        if !bytes.is_empty() {
+            lines.push(Line::from(""));
+        }
+        for (i, chunk) in bytes.chunks(STACK_BYTES_PER_LINE).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            lines.push(Line::from(format!("{:#06x}: {}",
+                start as usize + i * STACK_BYTES_PER_LINE, hex.join(" "))));
+        }
+        lines
+    }
+}
+
+/// lit LEDs in red, then buttons with the Alt key that toggles them
+fn board_lines(board: &Board) -> Vec<Line<'_>> {
+    let lit = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+    let mut lines: Vec<Line> = board.leds().into_iter()
+        .map(|(name, on)| if on {
+            Line::from(Span::styled(format!("* {}", name), lit))
+        } else {
+            Line::from(format!("  {}", name))
+        })
+        .collect();
+    lines.extend(board.buttons().into_iter().enumerate().map(|(i, (name, pressed))| {
+        let key = if i < 9 { format!("M-{}", i + 1) } else { "   ".to_string() };
+        Line::from(format!("{} [{}] {}", key, if pressed { "x" } else { " " }, name))
+    }));
+    lines
+}
+
+fn is_stop_key(key: &KeyEvent) -> bool {
+    key.kind == KeyEventKind::Press && match key.code {
+        KeyCode::Esc => true,
+        KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
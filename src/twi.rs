@@ -0,0 +1,578 @@
+// XMEGA TWI (I2C), in master mode, with virtual slave devices
+
+use std::any::Any;
+use std::mem;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const CTRL : u32 = 0x00;
+const MASTER_CTRLA : u32 = 0x01;
+const MASTER_CTRLB : u32 = 0x02;
+const MASTER_CTRLC : u32 = 0x03;
+const MASTER_STATUS : u32 = 0x04;
+const MASTER_BAUD : u32 = 0x05;
+const MASTER_ADDR : u32 = 0x06;
+const MASTER_DATA : u32 = 0x07;
+
+// MASTER.CTRLA bits
+const ENABLE : u8 = 0x08;
+
+// MASTER.CTRLB bits
+const SMEN : u8 = 0x01;
+
+// MASTER.CTRLC bits
+const ACKACT : u8 = 0x04;
+const CMD_MASK : u8 = 0x03;
+const CMD_REPSTART : u8 = 1;
+const CMD_BYTEREC : u8 = 2;
+const CMD_STOP : u8 = 3;
+
+// MASTER.STATUS bits
+const RIF : u8 = 0x80;
+const WIF : u8 = 0x40;
+const CLKHOLD : u8 = 0x20;
+const RXACK : u8 = 0x10;
+const ARBLOST : u8 = 0x08;
+const BUSERR : u8 = 0x04;
+const BUSSTATE_MASK : u8 = 0x03;
+const BUSSTATE_IDLE : u8 = 1;
+const BUSSTATE_OWNER : u8 = 2;
+
+
+/// a device on the I2C bus
+pub trait I2cDevice: Send {
+    /// whether the device answers to 7-bit address `address`
+    fn responds_to(&self, address: u8) -> bool;
+
+    /// the device was addressed; returns whether it ACKs
+    fn start(&mut self, _address: u8, _read: bool) -> bool {
+        true
+    }
+
+    /// receive a byte from the master; returns whether it ACKs
+    fn write(&mut self, val: u8) -> bool;
+
+    /// send a byte to the master
+    fn read(&mut self) -> u8;
+
+    fn stop(&mut self) {}
+
+    /// internal state, for snapshots
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _state: &[u8]) {}
+
+    fn box_clone(&self) -> Box<dyn I2cDevice>;
+}
+
+impl Clone for Box<dyn I2cDevice> {
+    fn clone(&self) -> Box<dyn I2cDevice> {
+        self.box_clone()
+    }
+}
+
+/// a 24xx-series serial EEPROM, e.g. 24C02 or 24LC256
+#[derive(Clone)]
+pub struct Eeprom24xx {
+    /// base 7-bit address, usually 0x50 plus the A0-A2 pins
+    address: u8,
+    page_size: usize,
+    pub data: Vec<u8>,
+
+    /// current word address
+    pointer: usize,
+    /// address bytes still expected in the current write
+    addr_bytes_left: usize,
+    /// (address, value) written since the start condition, committed on stop
+    pending_writes: Vec<(usize, u8)>,
+}
+
+impl Eeprom24xx {
+    pub fn new(address: u8, size: usize, page_size: usize) -> Eeprom24xx {
+        Eeprom24xx {
+            address,
+            page_size,
+            data: vec![0xff; size],
+
+            pointer: 0,
+            addr_bytes_left: 0,
+            pending_writes: vec![],
+        }
+    }
+
+    /// devices up to 2KB take a single address byte, with the high address
+    /// bits in the device address
+    fn addr_bytes(&self) -> usize {
+        if self.data.len() > 2048 { 2 } else { 1 }
+    }
+
+    /// the low device address bits that select a 256-byte block
+    fn block_mask(&self) -> u8 {
+        if self.addr_bytes() == 1 {
+            ((self.data.len() / 256).max(1) - 1) as u8
+        } else {
+            0
+        }
+    }
+}
+
+impl I2cDevice for Eeprom24xx {
+    fn responds_to(&self, address: u8) -> bool {
+        (address & !self.block_mask()) == self.address
+    }
+
+    fn start(&mut self, address: u8, read: bool) -> bool {
+        if !read {
+            self.addr_bytes_left = self.addr_bytes();
+            if self.addr_bytes() == 1 {
+                // block select bits
+                self.pointer = ((address & self.block_mask()) as usize) << 8;
+            } else {
+                self.pointer = 0;
+            }
+        }
+        true
+    }
+
+    fn write(&mut self, val: u8) -> bool {
+        let size = self.data.len();
+
+        if self.addr_bytes_left > 0 {
+            self.addr_bytes_left -= 1;
+            if self.addr_bytes() == 2 && self.addr_bytes_left == 1 {
+                self.pointer = (val as usize) << 8;
+            } else {
+                self.pointer = (self.pointer & !0xff) | (val as usize);
+            }
+            self.pointer %= size;
+        } else {
+            self.pending_writes.push((self.pointer, val));
+
+            // wrap around within the page
+            let page_start = self.pointer - self.pointer % self.page_size;
+            self.pointer = page_start + (self.pointer + 1) % self.page_size;
+        }
+
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let val = self.data[self.pointer];
+        self.pointer = (self.pointer + 1) % self.data.len();
+        val
+    }
+
+    fn stop(&mut self) {
+        for (addr, val) in self.pending_writes.drain(..) {
+            self.data[addr] = val;
+        }
+        self.addr_bytes_left = 0;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        state.write_u32::<LittleEndian>(self.pointer as u32).unwrap();
+        state.write_u32::<LittleEndian>(self.addr_bytes_left as u32).unwrap();
+        state.write_u32::<LittleEndian>(self.pending_writes.len() as u32).unwrap();
+        for &(addr, val) in &self.pending_writes {
+            state.write_u32::<LittleEndian>(addr as u32).unwrap();
+            state.push(val);
+        }
+        state.extend_from_slice(&self.data);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        let mut r = state;
+        if r.len() < 12 {
+            return;
+        }
+
+        let pointer = r.read_u32::<LittleEndian>().unwrap() as usize;
+        let addr_bytes_left = r.read_u32::<LittleEndian>().unwrap() as usize;
+        let num_writes = r.read_u32::<LittleEndian>().unwrap() as usize;
+        if r.len() != num_writes * 5 + self.data.len() {
+            return;
+        }
+
+        self.pointer = pointer;
+        self.addr_bytes_left = addr_bytes_left;
+        self.pending_writes.clear();
+        for _ in 0..num_writes {
+            let addr = r.read_u32::<LittleEndian>().unwrap() as usize;
+            let val = r.read_u8().unwrap();
+            self.pending_writes.push((addr, val));
+        }
+        self.data.copy_from_slice(r);
+    }
+
+    fn box_clone(&self) -> Box<dyn I2cDevice> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct Twi {
+    name: String,
+    base: u32,
+    /// master interrupt vector
+    vector: u8,
+
+    pub ctrl: u8,
+    pub master_ctrla: u8,
+    pub master_ctrlb: u8,
+    pub master_ctrlc: u8,
+    pub master_status: u8,
+    pub master_baud: u8,
+    pub master_addr: u8,
+    pub master_data: u8,
+
+    pub devices: Vec<Box<dyn I2cDevice>>,
+
+    /// index of the addressed device, and whether we're reading from it
+    active: Option<(usize, bool)>,
+    /// STATUS bits to set once the current byte is done, and when
+    pending: Option<(u8, u64)>,
+    now: u64,
+}
+
+impl Twi {
+    pub fn new(name: &str, base: u32, vector: u8) -> Twi {
+        Twi {
+            name: name.to_string(),
+            base,
+            vector,
+
+            ctrl: 0,
+            master_ctrla: 0,
+            master_ctrlb: 0,
+            master_ctrlc: 0,
+            master_status: 0,
+            master_baud: 0,
+            master_addr: 0,
+            master_data: 0,
+
+            devices: vec![],
+
+            active: None,
+            pending: None,
+            now: 0,
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn I2cDevice>) {
+        self.devices.push(device);
+    }
+
+    /// CPU cycles per byte (9 SCL periods)
+    fn byte_cycles(&self) -> u64 {
+        9 * 2 * (5 + self.master_baud as u64)
+    }
+
+    fn finish_byte(&mut self, status: u8) {
+        self.master_status &= !(RIF | WIF | RXACK);
+        self.pending = Some((status, self.now + self.byte_cycles()));
+    }
+
+    fn send_address(&mut self) {
+        if (self.master_ctrla & ENABLE) == 0 {
+            return;
+        }
+
+        let address = self.master_addr >> 1;
+        let read = (self.master_addr & 1) != 0;
+
+        self.active = None;
+        self.master_status =
+            (self.master_status & !BUSSTATE_MASK) | BUSSTATE_OWNER;
+
+        let idx = self.devices.iter().position(|d| d.responds_to(address));
+        let ack = match idx {
+            Some(i) => self.devices[i].start(address, read),
+            None => false,
+        };
+
+        if !ack {
+            self.finish_byte(WIF | RXACK | CLKHOLD);
+            return;
+        }
+
+        self.active = idx.map(|i| (i, read));
+        if read {
+            self.receive_byte();
+        } else {
+            self.finish_byte(WIF | CLKHOLD);
+        }
+    }
+
+    fn receive_byte(&mut self) {
+        match self.active {
+            Some((i, true)) => {
+                self.master_data = self.devices[i].read();
+                self.finish_byte(RIF | CLKHOLD);
+            },
+            _ => self.master_status |= BUSERR,
+        }
+    }
+
+    fn send_byte(&mut self, val: u8) {
+        match self.active {
+            Some((i, false)) => {
+                let ack = self.devices[i].write(val);
+                self.finish_byte(WIF | CLKHOLD | (if ack { 0 } else { RXACK }));
+            },
+            _ => self.finish_byte(WIF | CLKHOLD | RXACK),
+        }
+    }
+
+    fn send_stop(&mut self) {
+        if let Some((i, _)) = self.active.take() {
+            self.devices[i].stop();
+        }
+
+        self.pending = None;
+        self.master_status &= !(CLKHOLD | BUSSTATE_MASK);
+        self.master_status |= BUSSTATE_IDLE;
+    }
+
+    fn do_command(&mut self, cmd: u8) {
+        match cmd {
+            CMD_REPSTART => self.send_address(),
+            // ACKACT set means NACK, which ends the read
+            CMD_BYTEREC if (self.master_ctrlc & ACKACT) == 0 =>
+                self.receive_byte(),
+            CMD_STOP => self.send_stop(),
+            _ => {},
+        }
+    }
+}
+
+impl Peripheral for Twi {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x10)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRL => self.ctrl,
+            MASTER_CTRLA => self.master_ctrla,
+            MASTER_CTRLB => self.master_ctrlb,
+            MASTER_CTRLC => self.master_ctrlc,
+            MASTER_STATUS => self.master_status,
+            MASTER_BAUD => self.master_baud,
+            MASTER_ADDR => self.master_addr,
+            MASTER_DATA => {
+                let val = self.master_data;
+                self.master_status &= !(RIF | CLKHOLD);
+
+                // smart mode acks and starts the next byte on reads
+                if (self.master_ctrlb & SMEN) != 0 {
+                    self.do_command(CMD_BYTEREC);
+                }
+                val
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRL => self.ctrl = val,
+            MASTER_CTRLA => self.master_ctrla = val,
+            MASTER_CTRLB => self.master_ctrlb = val,
+            MASTER_CTRLC => {
+                self.master_ctrlc = val & !CMD_MASK;
+                self.do_command(val & CMD_MASK);
+            },
+            MASTER_STATUS => {
+                // write 1 to clear
+                self.master_status &= !(val & (RIF | WIF | ARBLOST | BUSERR));
+                if (val & BUSSTATE_MASK) == BUSSTATE_IDLE {
+                    self.master_status =
+                        (self.master_status & !BUSSTATE_MASK) | BUSSTATE_IDLE;
+                }
+            },
+            MASTER_BAUD => self.master_baud = val,
+            MASTER_ADDR => {
+                self.master_addr = val;
+                self.send_address();
+            },
+            MASTER_DATA => {
+                self.master_data = val;
+                self.master_status &= !(WIF | CLKHOLD);
+                self.send_byte(val);
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut twi = Twi::new(&self.name, self.base, self.vector);
+        twi.devices = mem::take(&mut self.devices);
+        twi.now = self.now;
+
+        *self = twi;
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.now = now;
+
+        if let Some((status, done_at)) = self.pending {
+            if done_at <= now {
+                self.master_status |= status;
+                self.pending = None;
+            }
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        let slept = now.saturating_sub(self.now);
+        self.now = now;
+        if let Some((_, ref mut done_at)) = self.pending {
+            *done_at += slept;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.pending.map(|(_, done_at)| done_at)
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let level = self.master_ctrla >> 6;
+        let rien = (self.master_ctrla & 0x20) != 0;
+        let wien = (self.master_ctrla & 0x10) != 0;
+
+        let flagged =
+            (rien && (self.master_status & RIF) != 0)
+            || (wien && (self.master_status & WIF) != 0);
+        if flagged && level != 0 {
+            Some((self.vector, level))
+        } else {
+            None
+        }
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        let level = self.master_ctrla >> 6;
+        if level != 0 && (self.master_ctrla & 0x30) != 0 {
+            out.push(self.vector);
+        }
+    }
+
+    // the flags are cleared by accessing DATA or ADDR, not by taking the
+    // interrupt
+
+    fn powered(&self) -> bool {
+        (self.master_ctrla & ENABLE) != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.ctrl, self.master_ctrla, self.master_ctrlb, self.master_ctrlc,
+            self.master_status, self.master_baud, self.master_addr,
+            self.master_data,
+        ];
+
+        match self.active {
+            Some((i, read)) => {
+                state.push(1 + read as u8);
+                state.write_u32::<LittleEndian>(i as u32).unwrap();
+            },
+            None => {
+                state.push(0);
+                state.write_u32::<LittleEndian>(0).unwrap();
+            },
+        }
+
+        let (status, done_at) = self.pending.unwrap_or((0, u64::MAX));
+        state.push(status);
+        state.write_u64::<LittleEndian>(done_at).unwrap();
+        state.write_u64::<LittleEndian>(self.now).unwrap();
+
+        for d in &self.devices {
+            let device_state = d.save_state();
+            state.write_u32::<LittleEndian>(device_state.len() as u32).unwrap();
+            state.extend_from_slice(&device_state);
+        }
+
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 30 {
+            return;
+        }
+
+        self.ctrl = state[0];
+        self.master_ctrla = state[1];
+        self.master_ctrlb = state[2];
+        self.master_ctrlc = state[3];
+        self.master_status = state[4];
+        self.master_baud = state[5];
+        self.master_addr = state[6];
+        self.master_data = state[7];
+
+        let active = state[8];
+        let mut r = &state[9..];
+        let i = r.read_u32::<LittleEndian>().unwrap() as usize;
+        self.active = match active {
+            0 => None,
+            _ if i >= self.devices.len() => None,
+            _ => Some((i, active == 2)),
+        };
+
+        let status = r.read_u8().unwrap();
+        let done_at = r.read_u64::<LittleEndian>().unwrap();
+        self.pending =
+            if done_at == u64::MAX { None } else { Some((status, done_at)) };
+        self.now = r.read_u64::<LittleEndian>().unwrap();
+
+        // devices are attached by the host, so only restore the ones that
+        // are still there
+        for d in self.devices.iter_mut() {
+            if r.len() < 4 {
+                break;
+            }
+
+            let len = r.read_u32::<LittleEndian>().unwrap() as usize;
+            if r.len() < len {
+                break;
+            }
+            d.load_state(&r[..len]);
+            r = &r[len..];
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the atxmega128a4u's TWI modules
+pub fn default_twis() -> Vec<Twi> {
+    vec![
+        Twi::new("TWIC", 0x0480, 13),
+        Twi::new("TWIE", 0x04A0, 46),
+    ]
+}
@@ -0,0 +1,495 @@
+// XMEGA USART, in asynchronous mode
+//
+// Frames take as many cycles as the baud rate settings say. Received bytes
+// go through a 2-byte receive buffer, and transmitted bytes through the
+// data register buffer and the transmit shift register, with the status
+// flags and interrupts following along.
+//
+// The USART only sees single bytes; IOMemory feeds it from the host input
+// queue and collects what it transmits.
+//
+// A classic AVR's USART (UCSRnA-C, UBRRn, UDRn) works the same way with the
+// registers shuffled, so it's the same model behind a translation of the
+// register layout. Its interrupt enables act as low level.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use peripheral::Peripheral;
+use sleep::SleepMode;
+
+
+// register offsets
+const DATA : u32 = 0x00;
+const STATUS : u32 = 0x01;
+const CTRLA : u32 = 0x03;
+const CTRLB : u32 = 0x04;
+const CTRLC : u32 = 0x05;
+const BAUDCTRLA : u32 = 0x06;
+const BAUDCTRLB : u32 = 0x07;
+
+// STATUS bits
+const RXCIF : u8 = 0x80;
+const TXCIF : u8 = 0x40;
+const DREIF : u8 = 0x20;
+const BUFOVF : u8 = 0x08;
+
+// CTRLB bits
+const RXEN : u8 = 0x10;
+const TXEN : u8 = 0x08;
+const CLK2X : u8 = 0x04;
+
+// CTRLC bits
+const PMODE_MASK : u8 = 0x30;
+const SBMODE : u8 = 0x08;
+const CHSIZE_MASK : u8 = 0x07;
+
+// classic register offsets
+const UCSRA : u32 = 0x00;
+const UCSRB : u32 = 0x01;
+const UCSRC : u32 = 0x02;
+const UBRRL : u32 = 0x04;
+const UBRRH : u32 = 0x05;
+const UDR : u32 = 0x06;
+
+// classic UCSRA bits; the flags are where STATUS has them
+const U2X : u8 = 0x02;
+
+// classic UCSRB bits; RXEN and TXEN are where CTRLB has them
+const RXCIE : u8 = 0x80;
+const TXCIE : u8 = 0x40;
+const UDRIE : u8 = 0x20;
+const UCSZ2 : u8 = 0x04;
+const TXB8 : u8 = 0x01;
+
+const RX_BUFFER_SIZE : usize = 2;
+
+
+#[derive(Clone)]
+pub struct Usart {
+    name: String,
+    base: u32,
+    /// RXC vector; DRE and TXC follow it
+    vector: u8,
+    /// RXC DMA trigger; DRE follows it
+    dma_trigger: u8,
+    /// classic register layout
+    classic: bool,
+
+    pub status: u8,
+    pub ctrla: u8,
+    pub ctrlb: u8,
+    pub ctrlc: u8,
+    pub baudctrla: u8,
+    pub baudctrlb: u8,
+
+    rx_buffer: VecDeque<u8>,
+    /// byte being received, and the cycle it arrives at
+    rx_shift: Option<(u8, u64)>,
+    /// byte waiting in the data register buffer
+    tx_buffer: Option<u8>,
+    /// byte being sent, and the cycle the frame ends at
+    tx_shift: Option<(u8, u64)>,
+    /// sent bytes, for IOMemory to collect
+    transmitted: Vec<u8>,
+    now: u64,
+}
+
+impl Usart {
+    pub fn new(name: &str, base: u32, vector: u8, dma_trigger: u8) -> Usart {
+        Usart {
+            name: name.to_string(),
+            base,
+            vector,
+            dma_trigger,
+            classic: false,
+
+            status: DREIF,
+            ctrla: 0,
+            // 8N1
+            ctrlb: 0,
+            ctrlc: 0x03,
+            baudctrla: 0,
+            baudctrlb: 0,
+
+            rx_buffer: VecDeque::new(),
+            rx_shift: None,
+            tx_buffer: None,
+            tx_shift: None,
+            transmitted: vec![],
+            now: 0,
+        }
+    }
+
+    /// a classic AVR USART, e.g. USART0 with UCSR0A at `base`
+    pub fn classic(name: &str, base: u32, vector: u8) -> Usart {
+        let mut usart = Usart::new(name, base, vector, 0);
+        usart.classic = true;
+        usart
+    }
+
+    /// the data space address of the data register
+    pub fn data_addr(&self) -> u32 {
+        self.base + if self.classic { UDR } else { DATA }
+    }
+
+    /// CPU cycles per bit, from BSEL, BSCALE and CLK2X
+    pub fn bit_cycles(&self) -> u64 {
+        let bsel = (((self.baudctrlb & 0x0f) as u64) << 8) | (self.baudctrla as u64);
+        // 4-bit two's complement
+        let bscale = ((self.baudctrlb as i8) >> 4) as i32;
+        let samples = if (self.ctrlb & CLK2X) != 0 { 8 } else { 16 };
+
+        if bscale >= 0 {
+            (samples << bscale) * (bsel + 1)
+        } else {
+            // samples * (BSEL * 2^BSCALE + 1) has a fractional part;
+            // round it rather than truncating BSEL
+            let shift = -bscale;
+            (samples * (bsel + (1 << shift)) + (1 << (shift - 1))) >> shift
+        }
+    }
+
+    /// CPU cycles per frame: start bit, data bits, parity and stop bits
+    pub fn frame_cycles(&self) -> u64 {
+        let data_bits = match self.ctrlc & CHSIZE_MASK {
+            size @ 0..=3 => 5 + size as u64,
+            _ => 9,
+        };
+        let parity_bits = if (self.ctrlc & PMODE_MASK) != 0 { 1 } else { 0 };
+        let stop_bits = if (self.ctrlc & SBMODE) != 0 { 2 } else { 1 };
+
+        (1 + data_bits + parity_bits + stop_bits) * self.bit_cycles()
+    }
+
+    /// whether a new byte can start arriving
+    pub fn ready_to_receive(&self) -> bool {
+        (self.ctrlb & RXEN) != 0 && self.rx_shift.is_none()
+    }
+
+    /// start receiving `val`; it arrives a frame later
+    pub fn receive(&mut self, val: u8) {
+        self.rx_shift = Some((val, self.now + self.frame_cycles()));
+    }
+
+    /// bytes whose frames finished since the last call
+    pub fn take_transmitted(&mut self) -> Vec<u8> {
+        let mut out = vec![];
+        out.append(&mut self.transmitted);
+        out
+    }
+
+    fn start_transmit(&mut self) {
+        if self.tx_shift.is_none() {
+            if let Some(val) = self.tx_buffer.take() {
+                self.tx_shift = Some((val, self.now + self.frame_cycles()));
+                self.status |= DREIF;
+            }
+        }
+    }
+
+    fn update_rx_flag(&mut self) {
+        if self.rx_buffer.is_empty() {
+            self.status &= !RXCIF;
+        } else {
+            self.status |= RXCIF;
+        }
+    }
+
+    fn level(&self, shift: u8) -> u8 {
+        (self.ctrla >> shift) & 0x3
+    }
+
+    fn read_reg(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            DATA => {
+                let val = self.rx_buffer.pop_front().unwrap_or(0);
+                self.update_rx_flag();
+                if self.rx_buffer.is_empty() {
+                    self.status &= !BUFOVF;
+                }
+                val
+            },
+            STATUS => self.status,
+            CTRLA => self.ctrla,
+            CTRLB => self.ctrlb,
+            CTRLC => self.ctrlc,
+            BAUDCTRLA => self.baudctrla,
+            BAUDCTRLB => self.baudctrlb,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            // writes while the buffer is full are lost
+            DATA if (self.ctrlb & TXEN) != 0 && self.tx_buffer.is_none() => {
+                self.tx_buffer = Some(val);
+                self.status &= !DREIF;
+                self.start_transmit();
+            },
+            // TXCIF is cleared by writing a one; the rest is read-only
+            STATUS => self.status &= !(val & TXCIF),
+            CTRLA => self.ctrla = val,
+            CTRLB => {
+                self.ctrlb = val;
+                if (val & RXEN) == 0 {
+                    // disabling the receiver flushes it
+                    self.rx_buffer.clear();
+                    self.rx_shift = None;
+                    self.status &= !(RXCIF | BUFOVF);
+                }
+            },
+            CTRLC => self.ctrlc = val,
+            BAUDCTRLA => self.baudctrla = val,
+            BAUDCTRLB => self.baudctrlb = val,
+            _ => {},
+        }
+    }
+
+    fn read_classic(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            UCSRA => self.status | if (self.ctrlb & CLK2X) != 0 { U2X } else { 0 },
+            UCSRB => {
+                let enables = [(RXCIE, 4), (TXCIE, 2), (UDRIE, 0)];
+                let ie = enables.iter()
+                    .filter(|&&(_, shift)| self.level(shift) != 0)
+                    .fold(0, |ie, &(bit, _)| ie | bit);
+                ie | (self.ctrlb & (RXEN | TXEN | TXB8)) | (self.ctrlc & UCSZ2)
+            },
+            // UCSZ1:0 are a bit higher than CHSIZE1:0
+            UCSRC => (self.ctrlc & !CHSIZE_MASK) | ((self.ctrlc & 0x03) << 1),
+            UBRRL => self.baudctrla,
+            UBRRH => self.baudctrlb & 0x0f,
+            UDR => self.read_reg(DATA),
+            _ => 0,
+        }
+    }
+
+    fn write_classic(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            UCSRA => {
+                self.ctrlb = (self.ctrlb & !CLK2X) | if (val & U2X) != 0 { CLK2X } else { 0 };
+                self.write_reg(STATUS, val);
+            },
+            UCSRB => {
+                let enables = [(RXCIE, 4), (TXCIE, 2), (UDRIE, 0)];
+                self.ctrla = enables.iter()
+                    .filter(|&&(bit, _)| (val & bit) != 0)
+                    .fold(0, |ctrla, &(_, shift)| ctrla | (1 << shift));
+                self.ctrlc = (self.ctrlc & !UCSZ2) | (val & UCSZ2);
+                let ctrlb = (self.ctrlb & CLK2X) | (val & (RXEN | TXEN | TXB8));
+                self.write_reg(CTRLB, ctrlb);
+            },
+            UCSRC => self.ctrlc = (val & !CHSIZE_MASK) | (self.ctrlc & UCSZ2) | ((val >> 1) & 0x03),
+            UBRRL => self.baudctrla = val,
+            // BSCALE is 0, which makes it the classic UBRR formula
+            UBRRH => self.baudctrlb = val & 0x0f,
+            UDR => self.write_reg(DATA, val),
+            _ => {},
+        }
+    }
+}
+
+impl Peripheral for Usart {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, if self.classic { 0x07 } else { 0x08 })
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        if self.classic {
+            self.read_classic(ofs)
+        } else {
+            self.read_reg(ofs)
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        if self.classic {
+            self.write_classic(ofs, val);
+        } else {
+            self.write_reg(ofs, val);
+        }
+    }
+
+    fn reset(&mut self) {
+        let now = self.now;
+        let classic = self.classic;
+        *self = Usart::new(&self.name, self.base, self.vector, self.dma_trigger);
+        self.classic = classic;
+        self.now = now;
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.now = now;
+
+        if let Some((val, done_at)) = self.rx_shift {
+            if done_at <= now {
+                self.rx_shift = None;
+                if self.rx_buffer.len() < RX_BUFFER_SIZE {
+                    self.rx_buffer.push_back(val);
+                } else {
+                    self.status |= BUFOVF;
+                }
+                self.update_rx_flag();
+            }
+        }
+
+        if let Some((val, done_at)) = self.tx_shift {
+            if done_at <= now {
+                self.tx_shift = None;
+                self.transmitted.push(val);
+                if self.tx_buffer.is_some() {
+                    self.start_transmit();
+                } else {
+                    self.status |= TXCIF;
+                }
+            }
+        }
+    }
+
+    fn stops_in_sleep(&self, mode: SleepMode) -> bool {
+        !mode.peripheral_clock()
+    }
+
+    fn resume(&mut self, now: u64) {
+        let slept = now.saturating_sub(self.now);
+        self.now = now;
+        if let Some((_, ref mut done_at)) = self.rx_shift {
+            *done_at += slept;
+        }
+        if let Some((_, ref mut done_at)) = self.tx_shift {
+            *done_at += slept;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let rx = self.rx_shift.map(|(_, done_at)| done_at);
+        let tx = self.tx_shift.map(|(_, done_at)| done_at);
+        rx.into_iter().chain(tx).min()
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let candidates = [
+            (RXCIF, self.vector, self.level(4)),
+            (DREIF, self.vector + 1, self.level(0)),
+            (TXCIF, self.vector + 2, self.level(2)),
+        ];
+
+        candidates.iter()
+            .filter(|&&(flag, _, level)| (self.status & flag) != 0 && level != 0)
+            .map(|&(_, vector, level)| (vector, level))
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        for &(vector, shift) in &[(self.vector, 4), (self.vector + 1, 0), (self.vector + 2, 2)] {
+            if self.level(shift) != 0 {
+                out.push(vector);
+            }
+        }
+    }
+
+    fn interrupt_taken(&mut self, vector: u8) {
+        // RXCIF and DREIF stay set until the data register is read/written
+        if vector == self.vector + 2 {
+            self.status &= !TXCIF;
+        }
+    }
+
+    // reading/writing DATA clears the flags, so there's nothing to ack
+    fn dma_request(&self, trigsrc: u8) -> bool {
+        if trigsrc == self.dma_trigger {
+            (self.status & RXCIF) != 0
+        } else if trigsrc == self.dma_trigger + 1 {
+            (self.ctrlb & TXEN) != 0 && (self.status & DREIF) != 0
+        } else {
+            false
+        }
+    }
+
+    fn powered(&self) -> bool {
+        (self.ctrlb & (RXEN | TXEN)) != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.status, self.ctrla, self.ctrlb, self.ctrlc,
+            self.baudctrla, self.baudctrlb,
+        ];
+
+        let (rx_val, rx_done) = self.rx_shift.unwrap_or((0, u64::MAX));
+        state.push(rx_val);
+        state.write_u64::<LittleEndian>(rx_done).unwrap();
+        let (tx_val, tx_done) = self.tx_shift.unwrap_or((0, u64::MAX));
+        state.push(tx_val);
+        state.write_u64::<LittleEndian>(tx_done).unwrap();
+        state.write_u64::<LittleEndian>(self.now).unwrap();
+
+        state.push(self.tx_buffer.is_some() as u8);
+        state.push(self.tx_buffer.unwrap_or(0));
+        state.push(self.rx_buffer.len() as u8);
+        state.extend(self.rx_buffer.iter());
+
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 35 {
+            return;
+        }
+
+        self.status = state[0];
+        self.ctrla = state[1];
+        self.ctrlb = state[2];
+        self.ctrlc = state[3];
+        self.baudctrla = state[4];
+        self.baudctrlb = state[5];
+
+        let rx_val = state[6];
+        let mut r = &state[7..];
+        let rx_done = r.read_u64::<LittleEndian>().unwrap();
+        self.rx_shift =
+            if rx_done == u64::MAX { None } else { Some((rx_val, rx_done)) };
+        let tx_val = r.read_u8().unwrap();
+        let tx_done = r.read_u64::<LittleEndian>().unwrap();
+        self.tx_shift =
+            if tx_done == u64::MAX { None } else { Some((tx_val, tx_done)) };
+        self.now = r.read_u64::<LittleEndian>().unwrap();
+
+        self.tx_buffer = if r[0] != 0 { Some(r[1]) } else { None };
+        let rx_len = r[2] as usize;
+        self.rx_buffer = r[3..].iter().take(rx_len).cloned().collect();
+        self.transmitted.clear();
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the atxmega128a4u's USART that the host talks to
+pub fn default_usarts() -> Vec<Usart> {
+    vec![
+        Usart::new("USARTC0", 0x08A0, 25, 0x4B),
+    ]
+}
+
+/// the atmega328p's USART
+pub fn atmega328p_usarts() -> Vec<Usart> {
+    vec![
+        Usart::classic("USART0", 0x00C0, 18),
+    ]
+}
@@ -0,0 +1,909 @@
+// XMEGA USB device module, and a USB host that talks to it
+//
+// The emulator plays the host. Once the firmware enables the module and
+// attaches, the host resets the bus and enumerates the device with standard
+// control transfers: it reads the device and configuration descriptors, sets
+// address 1 and the first configuration, and if there's a CDC-ACM interface,
+// sets the line coding and raises DTR and RTS. From then on it moves bytes
+// between the CDC bulk endpoints and a byte stream, which the host
+// application can connect to a pty or a TCP socket.
+//
+// Transactions go through the endpoint table in SRAM at EPPTR, like on the
+// chip: an endpoint whose BUSNACK0 is clear takes or gives one packet (or a
+// whole transfer, with MULTIPKT), and then gets TRNCOMPL0 and BUSNACK0 set
+// and raises TRNIF. SETUP packets land in endpoint 0's OUT buffer whether
+// it's armed or not. The host waits TRANSACTION_CYCLES between transactions,
+// and retries ones that are NAKed or get no answer.
+//
+// Not emulated: ping-pong buffers, the transaction complete FIFO, start of
+// frame, suspend and resume, and isochronous endpoints. The host's state
+// isn't part of snapshots.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use peripheral::Peripheral;
+use iomem::IOMemory;
+#[cfg(unix)]
+use pty::Pty;
+
+
+// register offsets
+const CTRLA : u32 = 0x00;
+const CTRLB : u32 = 0x01;
+const STATUS : u32 = 0x02;
+const ADDR : u32 = 0x03;
+const FIFOWP : u32 = 0x04;
+const FIFORP : u32 = 0x05;
+const EPPTRL : u32 = 0x06;
+const EPPTRH : u32 = 0x07;
+const INTCTRLA : u32 = 0x08;
+const INTCTRLB : u32 = 0x09;
+const INTFLAGSACLR : u32 = 0x0A;
+const INTFLAGSASET : u32 = 0x0B;
+const INTFLAGSBCLR : u32 = 0x0C;
+const INTFLAGSBSET : u32 = 0x0D;
+const CAL0 : u32 = 0x3A;
+const CAL1 : u32 = 0x3B;
+
+// CTRLA bits
+const ENABLE : u8 = 0x80;
+const MAXEP_MASK : u8 = 0x0F;
+
+// CTRLB bits
+const ATTACH : u8 = 0x01;
+
+// STATUS bits
+const BUSRST : u8 = 0x01;
+
+// INTCTRLA bits
+const SOFIE : u8 = 0x80;
+const BUSEVIE : u8 = 0x40;
+const BUSERRIE : u8 = 0x20;
+const STALLIE : u8 = 0x10;
+const INTLVL_MASK : u8 = 0x03;
+
+// INTCTRLB and INTFLAGSB bits
+const TRNIE : u8 = 0x02;
+const SETUPIE : u8 = 0x01;
+const TRNIF : u8 = 0x02;
+const SETUPIF : u8 = 0x01;
+
+// INTFLAGSA bits
+const SOFIF : u8 = 0x80;
+const BUS_EVENT_FLAGS : u8 = 0x70;
+const BUS_ERROR_FLAGS : u8 = 0x0E;
+const RSTIF : u8 = 0x10;
+const STALLIF : u8 = 0x01;
+
+// endpoint table entry offsets; each endpoint has an OUT entry and then an
+// IN entry
+const EP_STATUS : u32 = 0;
+const EP_CTRL : u32 = 1;
+const EP_CNT : u32 = 2;
+const EP_DATAPTR : u32 = 4;
+const EP_AUXDATA : u32 = 6;
+const EP_ENTRY_SIZE : u32 = 8;
+
+// endpoint STATUS bits
+const EP_STALLF : u8 = 0x80;
+const EP_TRNCOMPL0 : u8 = 0x20;
+const EP_SETUP : u8 = 0x10;
+const EP_BUSNACK0 : u8 = 0x02;
+
+// endpoint CTRL bits
+const EP_TYPE_MASK : u8 = 0xC0;
+const EP_MULTIPKT : u8 = 0x20;
+const EP_INTDSBL : u8 = 0x08;
+const EP_STALL : u8 = 0x04;
+const EP_BUFSIZE_MASK : u8 = 0x07;
+
+// endpoint CNT
+const CNT_MASK : u16 = 0x03FF;
+const CNT_ZLP : u16 = 0x8000;
+
+/// cycles between transactions, about what a 64-byte packet takes at full
+/// speed with a 32 MHz CPU
+const TRANSACTION_CYCLES : u64 = 1500;
+/// how long the host holds the bus in reset
+const RESET_CYCLES : u64 = 32_000;
+/// the address the host gives the device
+const DEVICE_ADDR : u8 = 1;
+
+// descriptor types and class codes
+const DESC_DEVICE : u8 = 1;
+const DESC_CONFIGURATION : u8 = 2;
+const DESC_INTERFACE : u8 = 4;
+const DESC_ENDPOINT : u8 = 5;
+const CLASS_CDC : u8 = 0x02;
+const CLASS_CDC_DATA : u8 = 0x0A;
+
+/// 115200 baud, 1 stop bit, no parity, 8 data bits
+const LINE_CODING : [u8; 7] = [0x00, 0xC2, 0x01, 0x00, 0, 0, 8];
+
+
+/// where the host side of the CDC-ACM port goes
+pub enum CdcPort {
+    #[cfg(unix)]
+    Pty(Pty),
+    /// non-blocking
+    Tcp(TcpStream),
+}
+
+impl CdcPort {
+    fn read(&mut self, max: usize) -> Vec<u8> {
+        let mut data = vec![];
+        match *self {
+            #[cfg(unix)]
+            CdcPort::Pty(ref mut pty) => {
+                while data.len() < max {
+                    match pty.try_read_byte() {
+                        Some(val) => data.push(val),
+                        None => break,
+                    }
+                }
+            },
+            CdcPort::Tcp(ref mut stream) => {
+                let mut buf = vec![0; max];
+                // nothing to read, or the connection is gone
+                if let Ok(n) = stream.read(&mut buf) {
+                    data.extend_from_slice(&buf[..n]);
+                }
+            },
+        }
+        data
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        match *self {
+            #[cfg(unix)]
+            CdcPort::Pty(ref mut pty) => {
+                for &val in data {
+                    pty.write_byte(val);
+                }
+            },
+            CdcPort::Tcp(ref mut stream) => {
+                let _ = stream.write_all(data);
+            },
+        }
+    }
+}
+
+/// how the device answered a transaction
+#[derive(Clone, Debug, PartialEq)]
+enum Handshake {
+    /// with the data, for IN transactions
+    Ack(Vec<u8>),
+    Nak,
+    Stall,
+    /// the endpoint doesn't exist or isn't enabled
+    NoResponse,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Setup,
+    DataIn,
+    /// with how much was sent so far
+    DataOut(usize),
+    StatusIn,
+    StatusOut,
+}
+
+#[derive(Clone, Debug)]
+struct Control {
+    setup: [u8; 8],
+    /// what to send, or what was received
+    data: Vec<u8>,
+    phase: Phase,
+}
+
+impl Control {
+    fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16,
+           data: Vec<u8>) -> Control {
+
+        let setup = [
+            request_type, request,
+            value as u8, (value >> 8) as u8,
+            index as u8, (index >> 8) as u8,
+            length as u8, (length >> 8) as u8,
+        ];
+        Control { setup, data, phase: Phase::Setup }
+    }
+
+    fn is_in(&self) -> bool {
+        (self.setup[0] & 0x80) != 0
+    }
+
+    fn length(&self) -> usize {
+        self.setup[6] as usize | (self.setup[7] as usize) << 8
+    }
+}
+
+/// the steps of enumeration, in order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    DeviceDescriptor,
+    SetAddress,
+    ConfigHeader,
+    /// with wTotalLength
+    Config(u16),
+    SetConfiguration,
+    SetLineCoding,
+    SetControlLineState,
+}
+
+/// the CDC-ACM interface, from the configuration descriptor
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CdcInterface {
+    pub comm_interface: u8,
+    pub in_ep: u8,
+    pub in_size: u16,
+    pub out_ep: u8,
+    pub out_size: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostState {
+    /// waiting for the firmware to attach
+    Detached,
+    /// the bus reset ends at this cycle
+    Reset(u64),
+    Enumerating,
+    /// enumerated, and moving CDC data if there's an interface for it
+    Configured,
+    /// enumeration failed, for this reason
+    Failed(String),
+}
+
+#[derive(Clone)]
+pub struct Usb {
+    base: u32,
+    /// BUSEVENT vector; TRNCOMPL is the next one
+    vector: u8,
+
+    pub ctrla: u8,
+    pub ctrlb: u8,
+    pub status: u8,
+    pub addr: u8,
+    pub fifowp: u8,
+    pub fiforp: u8,
+    pub epptr: u16,
+    pub intctrla: u8,
+    pub intctrlb: u8,
+    pub intflagsa: u8,
+    pub intflagsb: u8,
+    pub cal: [u8; 2],
+
+    pub host_state: HostState,
+    step: Step,
+    control: Option<Control>,
+    /// the address the host talks to
+    host_addr: u8,
+    ep0_size: u16,
+    /// bConfigurationValue
+    config: u8,
+    pub cdc: Option<CdcInterface>,
+    /// alternates between polling IN and sending OUT data
+    poll_in: bool,
+    next_transaction: u64,
+
+    /// bytes for the firmware to receive on the CDC port
+    pub cdc_input: VecDeque<u8>,
+    /// bytes the firmware sent on the CDC port, when there's no port
+    pub cdc_output: Vec<u8>,
+    /// shared with clones
+    port: Option<Arc<Mutex<CdcPort>>>,
+}
+
+fn read8(io: &IOMemory, addr: u32) -> Option<u8> {
+    io.data_mem.get(addr as usize).cloned()
+}
+
+fn read16(io: &IOMemory, addr: u32) -> Option<u16> {
+    Some(read8(io, addr)? as u16 | (read8(io, addr + 1)? as u16) << 8)
+}
+
+fn write8(io: &mut IOMemory, addr: u32, val: u8) {
+    if let Some(p) = io.data_mem.get_mut(addr as usize) {
+        *p = val;
+    }
+}
+
+fn write16(io: &mut IOMemory, addr: u32, val: u16) {
+    write8(io, addr, val as u8);
+    write8(io, addr + 1, (val >> 8) as u8);
+}
+
+fn buffer_size(ctrl: u8) -> usize {
+    [8, 16, 32, 64, 128, 256, 512, 1023][(ctrl & EP_BUFSIZE_MASK) as usize]
+}
+
+/// the CDC-ACM interface in a configuration descriptor, if there is one
+fn find_cdc(config: &[u8]) -> Option<CdcInterface> {
+    let mut cdc = CdcInterface::default();
+    let mut class = 0;
+    let mut have_comm = false;
+
+    let mut rest = config;
+    while rest.len() >= 2 && rest[0] >= 2 && rest[0] as usize <= rest.len() {
+        let desc = &rest[..rest[0] as usize];
+        match desc[1] {
+            DESC_INTERFACE if desc.len() >= 9 => {
+                class = desc[5];
+                if class == CLASS_CDC && !have_comm {
+                    cdc.comm_interface = desc[2];
+                    have_comm = true;
+                }
+            },
+            // bulk endpoints of the data interface
+            DESC_ENDPOINT if desc.len() >= 7 && class == CLASS_CDC_DATA
+                    && (desc[3] & 0x03) == 2 => {
+                let size = desc[4] as u16 | (desc[5] as u16) << 8;
+                if (desc[2] & 0x80) != 0 {
+                    cdc.in_ep = desc[2] & 0x0F;
+                    cdc.in_size = size;
+                } else {
+                    cdc.out_ep = desc[2] & 0x0F;
+                    cdc.out_size = size;
+                }
+            },
+            _ => {},
+        }
+        rest = &rest[desc.len()..];
+    }
+
+    if have_comm && cdc.in_ep != 0 && cdc.out_ep != 0 {
+        Some(cdc)
+    } else {
+        None
+    }
+}
+
+impl Usb {
+    pub fn new(base: u32, vector: u8) -> Usb {
+        Usb {
+            base,
+            vector,
+
+            ctrla: 0,
+            ctrlb: 0,
+            status: 0,
+            addr: 0,
+            fifowp: 0,
+            fiforp: 0,
+            epptr: 0,
+            intctrla: 0,
+            intctrlb: 0,
+            intflagsa: 0,
+            intflagsb: 0,
+            cal: [0; 2],
+
+            host_state: HostState::Detached,
+            step: Step::DeviceDescriptor,
+            control: None,
+            host_addr: 0,
+            ep0_size: 8,
+            config: 0,
+            cdc: None,
+            poll_in: true,
+            next_transaction: 0,
+
+            cdc_input: VecDeque::new(),
+            cdc_output: vec![],
+            port: None,
+        }
+    }
+
+    /// connect the CDC-ACM port to `port` instead of cdc_input and
+    /// cdc_output
+    pub fn set_cdc_port(&mut self, port: CdcPort) {
+        self.port = Some(Arc::new(Mutex::new(port)));
+    }
+
+    fn attached(&self) -> bool {
+        (self.ctrla & ENABLE) != 0 && (self.ctrlb & ATTACH) != 0
+    }
+
+    /// whether the host has a transaction to do at `now`
+    pub fn due(&self, now: u64) -> bool {
+        match self.host_state {
+            HostState::Enumerating => now >= self.next_transaction,
+            HostState::Configured => self.cdc.is_some() && now >= self.next_transaction,
+            HostState::Reset(end) => now >= end,
+            _ => false,
+        }
+    }
+
+    /// how enumeration went, for people
+    pub fn fmt_status(&self) -> String {
+        let state = match self.host_state {
+            HostState::Detached => "detached".to_string(),
+            HostState::Reset(_) => "bus reset".to_string(),
+            HostState::Enumerating => format!("enumerating: {:?}", self.step),
+            HostState::Configured => "configured".to_string(),
+            HostState::Failed(ref why) => format!("enumeration failed: {}", why),
+        };
+        match self.cdc {
+            Some(cdc) => format!("USB {}, CDC-ACM on endpoints {} IN and {} OUT\n",
+                                 state, cdc.in_ep, cdc.out_ep),
+            None => format!("USB {}\n", state),
+        }
+    }
+
+    /// data space address of endpoint `ep`'s OUT or IN table entry
+    fn entry(&self, ep: u8, is_in: bool) -> u32 {
+        self.epptr as u32 + ep as u32 * 2 * EP_ENTRY_SIZE
+            + if is_in { EP_ENTRY_SIZE } else { 0 }
+    }
+
+    fn responds(&self, ep: u8) -> bool {
+        self.addr == self.host_addr && ep <= (self.ctrla & MAXEP_MASK)
+    }
+
+    /// the transaction finished on an endpoint with control `ctrl`
+    fn complete(&mut self, ctrl: u8) {
+        if (ctrl & EP_INTDSBL) == 0 {
+            self.intflagsb |= TRNIF;
+        }
+    }
+
+    /// the endpoint's status, control and whether it takes a packet now
+    fn check_endpoint(&mut self, io: &mut IOMemory, e: u32)
+            -> Result<(u8, u8), Handshake> {
+
+        let (status, ctrl) = match (read8(io, e + EP_STATUS), read8(io, e + EP_CTRL)) {
+            (Some(status), Some(ctrl)) => (status, ctrl),
+            _ => return Err(Handshake::NoResponse),
+        };
+
+        if (ctrl & EP_TYPE_MASK) == 0 {
+            return Err(Handshake::NoResponse);
+        }
+        if (ctrl & EP_STALL) != 0 {
+            write8(io, e + EP_STATUS, status | EP_STALLF);
+            self.intflagsa |= STALLIF;
+            return Err(Handshake::Stall);
+        }
+        if (status & EP_BUSNACK0) != 0 {
+            return Err(Handshake::Nak);
+        }
+        Ok((status, ctrl))
+    }
+
+    fn setup_transaction(&mut self, io: &mut IOMemory, setup: &[u8; 8]) -> Handshake {
+        if !self.responds(0) {
+            return Handshake::NoResponse;
+        }
+
+        let e = self.entry(0, false);
+        let dataptr = match read16(io, e + EP_DATAPTR) {
+            Some(ptr) if (read8(io, e + EP_CTRL).unwrap_or(0) & EP_TYPE_MASK) != 0 => ptr,
+            _ => return Handshake::NoResponse,
+        };
+
+        for (i, &val) in setup.iter().enumerate() {
+            write8(io, dataptr as u32 + i as u32, val);
+        }
+        write16(io, e + EP_CNT, setup.len() as u16);
+
+        // a SETUP clears stalls on endpoint 0, and leaves both directions
+        // waiting for the firmware
+        for &(entry, flags) in &[(e, EP_SETUP | EP_BUSNACK0),
+                                 (self.entry(0, true), EP_BUSNACK0)] {
+            let status = read8(io, entry + EP_STATUS).unwrap_or(0);
+            write8(io, entry + EP_STATUS, status | flags);
+            let ctrl = read8(io, entry + EP_CTRL).unwrap_or(0);
+            write8(io, entry + EP_CTRL, ctrl & !EP_STALL);
+        }
+
+        self.intflagsb |= SETUPIF;
+        Handshake::Ack(vec![])
+    }
+
+    fn out_transaction(&mut self, io: &mut IOMemory, ep: u8, data: &[u8]) -> Handshake {
+        if !self.responds(ep) {
+            return Handshake::NoResponse;
+        }
+
+        let e = self.entry(ep, false);
+        let (status, ctrl) = match self.check_endpoint(io, e) {
+            Ok(endpoint) => endpoint,
+            Err(handshake) => return handshake,
+        };
+        let dataptr = read16(io, e + EP_DATAPTR).unwrap_or(0) as u32;
+        let size = buffer_size(ctrl);
+        let data = &data[..data.len().min(size)];
+
+        let done = if (ctrl & EP_MULTIPKT) != 0 {
+            // CNT counts what arrived, up to AUXDATA
+            let cnt = read16(io, e + EP_CNT).unwrap_or(0) & CNT_MASK;
+            let total = read16(io, e + EP_AUXDATA).unwrap_or(0);
+            for (i, &val) in data.iter().enumerate() {
+                write8(io, dataptr + cnt as u32 + i as u32, val);
+            }
+            let cnt = cnt + data.len() as u16;
+            write16(io, e + EP_CNT, cnt);
+            data.len() < size || cnt >= total
+        } else {
+            for (i, &val) in data.iter().enumerate() {
+                write8(io, dataptr + i as u32, val);
+            }
+            write16(io, e + EP_CNT, data.len() as u16);
+            true
+        };
+
+        if done {
+            write8(io, e + EP_STATUS, status | EP_TRNCOMPL0 | EP_BUSNACK0);
+            self.complete(ctrl);
+        }
+        Handshake::Ack(vec![])
+    }
+
+    fn in_transaction(&mut self, io: &mut IOMemory, ep: u8) -> Handshake {
+        if !self.responds(ep) {
+            return Handshake::NoResponse;
+        }
+
+        let e = self.entry(ep, true);
+        let (status, ctrl) = match self.check_endpoint(io, e) {
+            Ok(endpoint) => endpoint,
+            Err(handshake) => return handshake,
+        };
+        let dataptr = read16(io, e + EP_DATAPTR).unwrap_or(0) as u32;
+        let size = buffer_size(ctrl);
+        let cnt = read16(io, e + EP_CNT).unwrap_or(0);
+        let total = (cnt & CNT_MASK) as usize;
+
+        let (start, len, done) = if (ctrl & EP_MULTIPKT) != 0 {
+            // AUXDATA counts what was sent, up to CNT, and a transfer that
+            // fills the last packet ends with an empty one if ZLP is set
+            let sent = read16(io, e + EP_AUXDATA).unwrap_or(0) as usize;
+            let len = total.saturating_sub(sent).min(size);
+            write16(io, e + EP_AUXDATA, (sent + len) as u16);
+            let done = sent + len >= total && (len < size || (cnt & CNT_ZLP) == 0);
+            (sent, len, done)
+        } else {
+            (0, total.min(size), true)
+        };
+
+        let data = (0..len)
+            .map(|i| read8(io, dataptr + (start + i) as u32).unwrap_or(0))
+            .collect();
+        if done {
+            write8(io, e + EP_STATUS, status | EP_TRNCOMPL0 | EP_BUSNACK0);
+            self.complete(ctrl);
+        }
+        Handshake::Ack(data)
+    }
+
+    fn start_control(&mut self, step: Step) {
+        let cdc = self.cdc.unwrap_or_default();
+        self.step = step;
+        self.control = Some(match step {
+            Step::DeviceDescriptor =>
+                Control::new(0x80, 6, (DESC_DEVICE as u16) << 8, 0, 18, vec![]),
+            Step::SetAddress =>
+                Control::new(0x00, 5, DEVICE_ADDR as u16, 0, 0, vec![]),
+            Step::ConfigHeader =>
+                Control::new(0x80, 6, (DESC_CONFIGURATION as u16) << 8, 0, 9, vec![]),
+            Step::Config(total) =>
+                Control::new(0x80, 6, (DESC_CONFIGURATION as u16) << 8, 0, total, vec![]),
+            Step::SetConfiguration =>
+                Control::new(0x00, 9, self.config as u16, 0, 0, vec![]),
+            Step::SetLineCoding =>
+                Control::new(0x21, 0x20, 0, cdc.comm_interface as u16, 7, LINE_CODING.to_vec()),
+            // DTR and RTS
+            Step::SetControlLineState =>
+                Control::new(0x21, 0x22, 3, cdc.comm_interface as u16, 0, vec![]),
+        });
+    }
+
+    fn fail(&mut self, why: String) {
+        self.host_state = HostState::Failed(why);
+        self.control = None;
+    }
+
+    /// the control transfer for the current step finished, with the data
+    /// the device sent
+    fn control_done(&mut self, data: Vec<u8>) {
+        match self.step {
+            Step::DeviceDescriptor => {
+                if data.len() < 8 || data[1] != DESC_DEVICE {
+                    return self.fail(format!("bad device descriptor {:02x?}", data));
+                }
+                self.ep0_size = data[7] as u16;
+                self.start_control(Step::SetAddress);
+            },
+            Step::SetAddress => {
+                self.host_addr = DEVICE_ADDR;
+                self.start_control(Step::ConfigHeader);
+            },
+            Step::ConfigHeader => {
+                if data.len() < 9 || data[1] != DESC_CONFIGURATION {
+                    return self.fail(format!("bad configuration descriptor {:02x?}", data));
+                }
+                let total = data[2] as u16 | (data[3] as u16) << 8;
+                self.config = data[5];
+                self.start_control(Step::Config(total));
+            },
+            Step::Config(_) => {
+                // without a CDC-ACM interface, the device is configured
+                // but there's nothing to talk to
+                self.cdc = find_cdc(&data);
+                self.start_control(Step::SetConfiguration);
+            },
+            Step::SetConfiguration if self.cdc.is_some() =>
+                self.start_control(Step::SetLineCoding),
+            Step::SetConfiguration | Step::SetControlLineState => {
+                self.control = None;
+                self.host_state = HostState::Configured;
+            },
+            Step::SetLineCoding => self.start_control(Step::SetControlLineState),
+        }
+    }
+
+    /// the next transaction of the current control transfer
+    fn control_transaction(&mut self, io: &mut IOMemory) {
+        let mut control = match self.control.take() {
+            Some(control) => control,
+            None => return,
+        };
+        let ep0_size = self.ep0_size.max(8) as usize;
+
+        let handshake = match control.phase {
+            Phase::Setup => self.setup_transaction(io, &control.setup),
+            Phase::DataIn | Phase::StatusIn => self.in_transaction(io, 0),
+            Phase::DataOut(sent) => {
+                let end = control.data.len().min(sent + ep0_size);
+                let packet = control.data[sent..end].to_vec();
+                self.out_transaction(io, 0, &packet)
+            },
+            Phase::StatusOut => self.out_transaction(io, 0, &[]),
+        };
+
+        let received = match handshake {
+            Handshake::Ack(received) => received,
+            Handshake::Stall =>
+                return self.fail(format!("device stalled {:?}", self.step)),
+            Handshake::Nak | Handshake::NoResponse => {
+                self.control = Some(control);
+                return;
+            },
+        };
+
+        let length = control.length();
+        control.phase = match control.phase {
+            Phase::Setup if length == 0 => Phase::StatusIn,
+            Phase::Setup if control.is_in() => Phase::DataIn,
+            Phase::Setup => Phase::DataOut(0),
+            Phase::DataIn => {
+                let short = received.len() < ep0_size;
+                control.data.extend(received);
+                if short || control.data.len() >= length {
+                    control.data.truncate(length);
+                    Phase::StatusOut
+                } else {
+                    Phase::DataIn
+                }
+            },
+            Phase::DataOut(sent) => {
+                let sent = (sent + ep0_size).min(control.data.len());
+                if sent >= control.data.len() { Phase::StatusIn } else { Phase::DataOut(sent) }
+            },
+            Phase::StatusIn | Phase::StatusOut => return self.control_done(control.data),
+        };
+        self.control = Some(control);
+    }
+
+    /// poll the CDC IN endpoint, or send it input, taking turns
+    fn cdc_transaction(&mut self, io: &mut IOMemory) {
+        let cdc = match self.cdc {
+            Some(cdc) => cdc,
+            None => return,
+        };
+
+        if let Some(ref port) = self.port {
+            let room = (cdc.out_size as usize).saturating_sub(self.cdc_input.len());
+            let data = port.lock().unwrap().read(room);
+            self.cdc_input.extend(data);
+        }
+
+        self.poll_in = !self.poll_in;
+        if self.poll_in || self.cdc_input.is_empty() {
+            if let Handshake::Ack(data) = self.in_transaction(io, cdc.in_ep) {
+                match self.port {
+                    Some(ref port) => port.lock().unwrap().write(&data),
+                    None => self.cdc_output.extend(data),
+                }
+            }
+        } else {
+            let len = self.cdc_input.len().min(cdc.out_size as usize);
+            let packet: Vec<u8> = self.cdc_input.iter().take(len).cloned().collect();
+            if let Handshake::Ack(_) = self.out_transaction(io, cdc.out_ep, &packet) {
+                self.cdc_input.drain(..len);
+            }
+        }
+    }
+
+    /// do the host's next transaction; called by IOMemory when due()
+    pub fn run(&mut self, io: &mut IOMemory, now: u64) {
+        match self.host_state {
+            HostState::Reset(_) => {
+                self.status &= !BUSRST;
+                self.host_state = HostState::Enumerating;
+                self.host_addr = 0;
+                self.ep0_size = 8;
+                self.cdc = None;
+                self.start_control(Step::DeviceDescriptor);
+            },
+            HostState::Enumerating => self.control_transaction(io),
+            HostState::Configured => self.cdc_transaction(io),
+            _ => {},
+        }
+        self.next_transaction = now + TRANSACTION_CYCLES;
+    }
+}
+
+impl Peripheral for Usb {
+    fn name(&self) -> &str {
+        "USB"
+    }
+
+    fn addr_range(&self) -> (u32, u32) {
+        (self.base, 0x40)
+    }
+
+    fn read(&mut self, ofs: u32) -> u8 {
+        match ofs {
+            CTRLA => self.ctrla,
+            CTRLB => self.ctrlb,
+            STATUS => self.status,
+            ADDR => self.addr,
+            FIFOWP => self.fifowp,
+            FIFORP => self.fiforp,
+            EPPTRL => self.epptr as u8,
+            EPPTRH => (self.epptr >> 8) as u8,
+            INTCTRLA => self.intctrla,
+            INTCTRLB => self.intctrlb,
+            INTFLAGSACLR | INTFLAGSASET => self.intflagsa,
+            INTFLAGSBCLR | INTFLAGSBSET => self.intflagsb,
+            CAL0 => self.cal[0],
+            CAL1 => self.cal[1],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, ofs: u32, val: u8) {
+        match ofs {
+            CTRLA => self.ctrla = val,
+            CTRLB => self.ctrlb = val,
+            ADDR => self.addr = val & 0x7F,
+            FIFOWP => self.fifowp = val,
+            FIFORP => self.fiforp = val,
+            EPPTRL => self.epptr = (self.epptr & 0xFF00) | val as u16,
+            EPPTRH => self.epptr = (self.epptr & 0x00FF) | (val as u16) << 8,
+            INTCTRLA => self.intctrla = val,
+            INTCTRLB => self.intctrlb = val,
+            INTFLAGSACLR => self.intflagsa &= !val,
+            INTFLAGSASET => self.intflagsa |= val,
+            INTFLAGSBCLR => self.intflagsb &= !val,
+            INTFLAGSBSET => self.intflagsb |= val,
+            CAL0 => self.cal[0] = val,
+            CAL1 => self.cal[1] = val,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        let (base, vector, port) = (self.base, self.vector, self.port.take());
+        *self = Usb::new(base, vector);
+        self.port = port;
+    }
+
+    fn tick(&mut self, now: u64) {
+        // attaching resets the bus, and detaching ends everything
+        match (self.attached(), &self.host_state) {
+            (true, &HostState::Detached) => {
+                self.status |= BUSRST;
+                self.intflagsa |= RSTIF;
+                self.host_state = HostState::Reset(now + RESET_CYCLES);
+            },
+            (false, &HostState::Detached) => {},
+            (false, _) => {
+                self.host_state = HostState::Detached;
+                self.control = None;
+            },
+            _ => {},
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        match self.host_state {
+            HostState::Reset(end) => Some(end),
+            HostState::Enumerating => Some(self.next_transaction),
+            HostState::Configured if self.cdc.is_some() => Some(self.next_transaction),
+            _ => None,
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<(u8, u8)> {
+        let level = self.intctrla & INTLVL_MASK;
+        if level == 0 {
+            return None;
+        }
+
+        let bus_event = ((self.intflagsa & SOFIF) != 0 && (self.intctrla & SOFIE) != 0)
+            || ((self.intflagsa & BUS_EVENT_FLAGS) != 0 && (self.intctrla & BUSEVIE) != 0)
+            || ((self.intflagsa & BUS_ERROR_FLAGS) != 0 && (self.intctrla & BUSERRIE) != 0)
+            || ((self.intflagsa & STALLIF) != 0 && (self.intctrla & STALLIE) != 0);
+        let transaction = ((self.intflagsb & TRNIF) != 0 && (self.intctrlb & TRNIE) != 0)
+            || ((self.intflagsb & SETUPIF) != 0 && (self.intctrlb & SETUPIE) != 0);
+
+        if bus_event {
+            Some((self.vector, level))
+        } else if transaction {
+            Some((self.vector + 1, level))
+        } else {
+            None
+        }
+    }
+
+    fn enabled_interrupts(&self, out: &mut Vec<u8>) {
+        if (self.intctrla & INTLVL_MASK) == 0 {
+            return;
+        }
+        if (self.intctrla & (SOFIE | BUSEVIE | BUSERRIE | STALLIE)) != 0 {
+            out.push(self.vector);
+        }
+        if (self.intctrlb & (TRNIE | SETUPIE)) != 0 {
+            out.push(self.vector + 1);
+        }
+    }
+
+    fn powered(&self) -> bool {
+        (self.ctrla & ENABLE) != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![
+            self.ctrla, self.ctrlb, self.status, self.addr, self.fifowp,
+            self.fiforp, self.epptr as u8, (self.epptr >> 8) as u8,
+            self.intctrla, self.intctrlb, self.intflagsa, self.intflagsb,
+            self.cal[0], self.cal[1],
+        ]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        if state.len() < 14 {
+            return;
+        }
+
+        self.ctrla = state[0];
+        self.ctrlb = state[1];
+        self.status = state[2];
+        self.addr = state[3];
+        self.fifowp = state[4];
+        self.fiforp = state[5];
+        self.epptr = state[6] as u16 | (state[7] as u16) << 8;
+        self.intctrla = state[8];
+        self.intctrlb = state[9];
+        self.intflagsa = state[10];
+        self.intflagsb = state[11];
+        self.cal = [state[12], state[13]];
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
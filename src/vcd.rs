@@ -0,0 +1,198 @@
+// Value change dumps of selected signals, for GTKWave
+//
+// A logic analyzer capture shows what the pins did; a VCD from the emulator
+// shows the same pins next to what the firmware was doing, e.g. SP and the
+// global interrupt flag. Signals are sampled after every step. IO registers
+// take the values the CPU reads and writes, since reading some of them
+// behind the firmware's back has side effects.
+//
+// Timestamps are emulated nanoseconds rather than cycles, so that they line
+// up with captures even when the firmware changes the CPU clock.
+
+use std::io::Write;
+use gpio::PinState;
+use iomem::IOMemory;
+use regmap;
+use regmap::IoBlock;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    /// a GPIO pin's level, or z if nothing drives it
+    Pin { port: String, pin: u8 },
+    /// all of a port's pins
+    Port(String),
+    /// SREG.I
+    InterruptFlag,
+    Sp,
+    /// the IO register at this address
+    IoReg(u32),
+}
+
+impl Source {
+    /// "PORTA.3", "PORTA", "sreg.i", "sp", or an IO register name like
+    /// "TCC0.CNTL"
+    pub fn parse(spec: &str, io_mem: &IOMemory, io_regs: &[IoBlock]) -> Option<Source> {
+        match spec.to_lowercase().as_str() {
+            "sreg.i" => return Some(Source::InterruptFlag),
+            "sp" => return Some(Source::Sp),
+            _ => {},
+        }
+
+        if io_mem.port(spec).is_some() {
+            return Some(Source::Port(spec.to_string()));
+        }
+
+        let mut parts = spec.splitn(2, '.');
+        let port = parts.next().unwrap_or("");
+        if io_mem.port(port).is_some() {
+            if let Some(pin) = parts.next().and_then(|s| s.parse().ok()).filter(|&pin| pin < 8) {
+                return Some(Source::Pin { port: port.to_string(), pin });
+            }
+        }
+
+        regmap::find(io_regs, spec).map(Source::IoReg)
+    }
+
+    pub fn width(&self) -> u8 {
+        match *self {
+            Source::Pin { .. } | Source::InterruptFlag => 1,
+            Source::Port(_) | Source::IoReg(_) => 8,
+            Source::Sp => 16,
+        }
+    }
+
+    /// the value in VCD notation, or None for IO registers, which aren't
+    /// sampled
+    fn sample(&self, io_mem: &IOMemory) -> Option<String> {
+        match *self {
+            Source::Pin { ref port, pin } =>
+                io_mem.port(port).map(|p| pin_char(p.pin_state(pin)).to_string()),
+            Source::Port(ref port) =>
+                io_mem.port(port).map(|p| (0..8).rev().map(|pin| pin_char(p.pin_state(pin))).collect()),
+            Source::InterruptFlag =>
+                Some(if io_mem.sreg.i { "1" } else { "0" }.to_string()),
+            Source::Sp => Some(format!("{:016b}", io_mem.get_sp())),
+            Source::IoReg(_) => None,
+        }
+    }
+}
+
+fn pin_char(state: PinState) -> char {
+    match state {
+        PinState::Low => '0',
+        PinState::High => '1',
+        PinState::Floating => 'z',
+    }
+}
+
+struct Signal {
+    name: String,
+    source: Source,
+    /// identifier code in the dump
+    id: String,
+    /// the last value written, in VCD notation
+    last: Option<String>,
+}
+
+/// short printable identifiers: "!", "\"", ..., "~", "!!", ...
+fn id_code(mut i: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (i % 94) as u8) as char);
+        i /= 94;
+        if i == 0 {
+            return id;
+        }
+        i -= 1;
+    }
+}
+
+pub struct Vcd {
+    out: Box<dyn Write + Send>,
+    /// the module the signals are in
+    scope: String,
+    signals: Vec<Signal>,
+    header_done: bool,
+    /// the last timestamp written
+    time: Option<u64>,
+}
+
+impl Vcd {
+    /// `signals` are (name, source) pairs; `scope` is e.g. the device name
+    pub fn new(out: Box<dyn Write + Send>, scope: &str, signals: Vec<(String, Source)>) -> Vcd {
+        let signals = signals.into_iter()
+            .enumerate()
+            .map(|(i, (name, source))| Signal { name, source, id: id_code(i), last: None })
+            .collect();
+
+        Vcd {
+            out,
+            scope: scope.to_string(),
+            signals,
+            header_done: false,
+            time: None,
+        }
+    }
+
+    /// whether any signal needs IO register accesses passed to io_access()
+    pub fn has_io_regs(&self) -> bool {
+        self.signals.iter().any(|s| matches!(s.source, Source::IoReg(_)))
+    }
+
+    fn write_header(&mut self) {
+        let _ = writeln!(self.out, "$version yaavre $end");
+        let _ = writeln!(self.out, "$timescale 1ns $end");
+        let _ = writeln!(self.out, "$scope module {} $end", self.scope);
+        for s in &self.signals {
+            let _ = writeln!(self.out, "$var wire {} {} {} $end",
+                             s.source.width(), s.id, s.name.replace(' ', "_"));
+        }
+        let _ = writeln!(self.out, "$upscope $end");
+        let _ = writeln!(self.out, "$enddefinitions $end");
+        self.header_done = true;
+    }
+
+    fn change(&mut self, i: usize, time: u64, val: String) {
+        if self.signals[i].last.as_ref() == Some(&val) {
+            return;
+        }
+        if !self.header_done {
+            self.write_header();
+        }
+        if self.time != Some(time) {
+            let _ = writeln!(self.out, "#{}", time);
+            self.time = Some(time);
+        }
+
+        let s = &self.signals[i];
+        let _ = if s.source.width() == 1 {
+            writeln!(self.out, "{}{}", val, s.id)
+        } else {
+            writeln!(self.out, "b{} {}", val, s.id)
+        };
+        self.signals[i].last = Some(val);
+    }
+
+    /// note the signals' values at `time` (in ns)
+    pub fn sample(&mut self, time: u64, io_mem: &IOMemory) {
+        for i in 0..self.signals.len() {
+            if let Some(val) = self.signals[i].source.sample(io_mem) {
+                self.change(i, time, val);
+            }
+        }
+    }
+
+    /// the CPU read or wrote `val` at `addr`
+    pub fn io_access(&mut self, time: u64, addr: u32, val: u8) {
+        for i in 0..self.signals.len() {
+            if self.signals[i].source == Source::IoReg(addr) {
+                self.change(i, time, format!("{:08b}", val));
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
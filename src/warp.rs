@@ -0,0 +1,197 @@
+// Warp mode: fast-forwarding busy-wait loops
+//
+// Two kinds of loops are skipped over instead of executed:
+//
+// - delay loops like the ones avr-libc's _delay_ms and _delay_loop_1/2 expand
+//   to: a counter decremented by 1 (DEC, SBIW, or SUBI followed by SBCIs) and
+//   a BRNE back. Iterations are skipped by lowering the counter and counting
+//   the skipped cycles. The last iteration always runs normally so the flags
+//   come out right.
+//
+// - polling loops: once an iteration writes nothing and ends with the
+//   registers exactly as it started, only peripherals can end the loop, so
+//   iterations are skipped in growing batches.
+//
+// Neither skips past the next scheduled peripheral event (see sched.rs), so
+// interrupts and flag changes are seen within an iteration of when they
+// happen. Changes nothing schedules, like host input, may be seen up to a
+// batch late.
+
+use disa::{AvrInsn, Reg, RegPair};
+use progmem::ProgramMemory;
+use device::Device;
+use disasm::branch_target;
+use cycles::insn_cycles;
+
+
+/// delay loops are short
+const MAX_DELAY_LOOP_INSNS : usize = 5;
+
+/// the most polling loop iterations to skip at once
+const MAX_POLL_BATCH : u64 = 256;
+
+
+/// a counter loop found by delay_loop()
+pub struct DelayLoop {
+    /// counter registers, least significant first
+    pub counter: Vec<usize>,
+    /// per iteration, with the branch taken
+    pub cycles: u64,
+    pub insns: u64,
+}
+
+impl DelayLoop {
+    /// how many more times the loop runs, given the registers at its start
+    pub fn iterations(&self, regs: &[u8; 32]) -> u64 {
+        let val = self.counter.iter().rev()
+            .fold(0u64, |acc, &r| (acc << 8) | regs[r] as u64);
+        // a counter of 0 wraps around
+        if val == 0 { 1 << (8 * self.counter.len()) } else { val }
+    }
+
+    /// make the loop run `n` more times
+    pub fn set_iterations(&self, regs: &mut [u8; 32], n: u64) {
+        for (i, &r) in self.counter.iter().enumerate() {
+            regs[r] = (n >> (8 * i)) as u8;
+        }
+    }
+}
+
+/// the delay loop starting at `head`, if there is one
+pub fn delay_loop(prog_mem: &ProgramMemory, head: u32, device: &Device)
+        -> Option<DelayLoop> {
+    let mut body = vec![];
+    let mut pc = head;
+    loop {
+        if body.len() == MAX_DELAY_LOOP_INSNS {
+            return None;
+        }
+
+        let insn = prog_mem.get_insn_at(pc)?;
+        body.push(insn);
+        if let AvrInsn::Brne(_) = insn {
+            if branch_target(pc, &insn) != Some(head) {
+                return None;
+            }
+            break;
+        }
+        pc += insn.byte_size() as u32;
+    }
+
+    let counter = match body[0] {
+        AvrInsn::Dec(Reg(r)) if body.len() == 2 => vec![r as usize],
+        AvrInsn::Sbiw(RegPair(r), 1) if body.len() == 2 =>
+            vec![r as usize, r as usize + 1],
+        AvrInsn::Subi(Reg(r), 1) => {
+            let mut counter = vec![r as usize];
+            for insn in &body[1..body.len() - 1] {
+                match *insn {
+                    AvrInsn::Sbci(Reg(r), 0) => counter.push(r as usize),
+                    _ => return None,
+                }
+            }
+            counter
+        },
+        _ => return None,
+    };
+
+    // each byte only once
+    if (1..counter.len()).any(|i| counter[..i].contains(&counter[i])) {
+        return None;
+    }
+
+    Some(DelayLoop {
+        counter,
+        cycles: body.iter().map(|insn| insn_cycles(insn, true, device)).sum(),
+        insns: body.len() as u64,
+    })
+}
+
+
+/// CPU state at the start of a polling loop iteration
+#[derive(Clone, PartialEq)]
+pub struct LoopState {
+    pub head: u32,
+    pub regs: [u8; 32],
+    pub sreg: u8,
+    pub sp: u16,
+    pub depth: usize,
+    /// data space writes so far
+    pub data_writes: u64,
+}
+
+#[derive(Clone)]
+pub struct Warp {
+    /// the last backward jump's target, and the cycle and instruction
+    /// counts then
+    last: Option<(LoopState, u64, u64)>,
+    /// iterations to skip next time, while the loop stays idle
+    batch: u64,
+
+    pub delay_loops: u64,
+    pub poll_batches: u64,
+    pub skipped_cycles: u64,
+}
+
+impl Default for Warp {
+    fn default() -> Warp {
+        Warp::new()
+    }
+}
+
+impl Warp {
+    pub fn new() -> Warp {
+        Warp {
+            last: None,
+            batch: 1,
+            delay_loops: 0,
+            poll_batches: 0,
+            skipped_cycles: 0,
+        }
+    }
+
+    /// execution moved somewhere other than by running, e.g. back in time
+    /// or to a new entry point; the next iteration has nothing to compare
+    /// with. the counters stay.
+    pub fn forget(&mut self) {
+        self.last = None;
+        self.batch = 1;
+    }
+
+    /// execution jumped back to `state.head`. if the last iteration did
+    /// nothing, returns how many iterations to skip and their (cycles,
+    /// instructions) each. iterations that would end after cycle `limit`
+    /// aren't skipped.
+    pub fn poll_loop(&mut self, state: LoopState, cycle: u64, insn_count: u64,
+                     limit: Option<u64>) -> Option<(u64, u64, u64)> {
+
+        let skip = match self.last {
+            Some((ref last, last_cycle, last_insns)) if *last == state => {
+                let (cycles, insns) = (cycle - last_cycle, insn_count - last_insns);
+                let n = match limit {
+                    Some(limit) if cycles > 0 =>
+                        self.batch.min(limit.saturating_sub(cycle) / cycles),
+                    _ => self.batch,
+                };
+                if n > 0 { Some((n, cycles, insns)) } else { None }
+            },
+            _ => None,
+        };
+
+        match skip {
+            Some((n, cycles, _)) => {
+                self.batch = (self.batch * 2).min(MAX_POLL_BATCH);
+                self.poll_batches += 1;
+                self.skipped_cycles += n * cycles;
+            },
+            None => self.batch = 1,
+        }
+
+        // the skipped iterations shouldn't count towards the next one
+        let (skipped_cycles, skipped_insns) = skip
+            .map_or((0, 0), |(n, cycles, insns)| (n * cycles, n * insns));
+        self.last = Some((state, cycle + skipped_cycles, insn_count + skipped_insns));
+
+        skip
+    }
+}
@@ -0,0 +1,44 @@
+// Devices outside the chip, wired to GPIO pins
+//
+// Unlike SPI slaves or I2C devices, these don't talk through a peripheral;
+// they watch pin levels, and may drive pins themselves, like a character LCD
+// or a push button would. The host attaches them to IOMemory, and they stay
+// attached, with their state, across resets of the chip.
+
+use std::any::Any;
+use iomem::IOMemory;
+
+
+pub trait PinDevice: Send {
+    /// e.g. "LCD"
+    fn name(&self) -> &str;
+
+    /// called before every instruction with the current cycle count. look
+    /// at pins, and drive them, through `io_mem`'s ports.
+    fn update(&mut self, io_mem: &mut IOMemory, now: u64);
+
+    /// the cycle at which the device next changes pins by itself, if it's
+    /// going to, so that idle time isn't skipped past it
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// internal state, for snapshots
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _state: &[u8]) {}
+
+    fn box_clone(&self) -> Box<dyn PinDevice>;
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl Clone for Box<dyn PinDevice> {
+    fn clone(&self) -> Box<dyn PinDevice> {
+        self.box_clone()
+    }
+}
@@ -0,0 +1,158 @@
+// Random operands through ADD/ADC/SUB/SBC/CP/CPC, checking the results and
+// flags against a reference that works them out from the actual arithmetic
+// rather than the instruction set manual's bit equations
+
+extern crate quickcheck;
+extern crate yaavre;
+
+use quickcheck::{quickcheck, TestResult};
+use yaavre::Emulator;
+
+
+// SREG bits
+const C : u8 = 0x01;
+const Z : u8 = 0x02;
+const N : u8 = 0x04;
+const V : u8 = 0x08;
+const S : u8 = 0x10;
+const H : u8 = 0x20;
+
+/// the "<op> r24, r25" opcodes
+const ADD : u16 = 0x0f89;
+const ADC : u16 = 0x1f89;
+const SUB : u16 = 0x1b89;
+const SBC : u16 = 0x0b89;
+const CP : u16 = 0x1789;
+const CPC : u16 = 0x0789;
+
+
+/// run `opcode` with r24 = `rd`, r25 = `rr` and SREG = `sreg`; returns r24
+/// and SREG afterwards
+fn run(opcode: u16, rd: u8, rr: u8, sreg: u8) -> (u8, u8) {
+    let mut emu = Emulator::new();
+    emu.prog_mem.set_words(vec![opcode]);
+    emu.reset();
+
+    emu.set_reg8(24, rd);
+    emu.set_reg8(25, rr);
+    emu.io_mem.sreg.set_u8(sreg);
+
+    let res = emu.step();
+    assert!(res.fault.is_none(), "{:?}", res.fault);
+    (emu.get_reg8(24), emu.io_mem.sreg.as_u8())
+}
+
+/// the flags for an 8-bit result, given the unsigned and signed results
+/// computed in wider types
+fn flags(unsigned: i32, signed: i32, half: bool, zero: bool, sreg: u8) -> u8 {
+    let r = unsigned as u8;
+    let mut flags = sreg & !(C | Z | N | V | S | H);
+
+    if !(0..=0xff).contains(&unsigned) {
+        flags |= C;
+    }
+    if zero {
+        flags |= Z;
+    }
+    if (r & 0x80) != 0 {
+        flags |= N;
+    }
+    if !(-128..=127).contains(&signed) {
+        flags |= V;
+    }
+    // the sign of the true result
+    if signed < 0 {
+        flags |= S;
+    }
+    if half {
+        flags |= H;
+    }
+
+    flags
+}
+
+/// (result, SREG) for adding, with the carry if `with_carry`
+fn reference_add(rd: u8, rr: u8, sreg: u8, with_carry: bool) -> (u8, u8) {
+    let cin = if with_carry { (sreg & C) as i32 } else { 0 };
+    let unsigned = rd as i32 + rr as i32 + cin;
+    let signed = rd as i8 as i32 + rr as i8 as i32 + cin;
+    let half = (rd & 0xf) as i32 + (rr & 0xf) as i32 + cin > 0xf;
+    let zero = unsigned as u8 == 0;
+
+    (unsigned as u8, flags(unsigned, signed, half, zero, sreg))
+}
+
+/// (result, SREG) for subtracting, with the borrow if `with_carry`. the
+/// with-carry variants only ever clear Z, so that multi-byte comparisons
+/// work.
+fn reference_sub(rd: u8, rr: u8, sreg: u8, with_carry: bool) -> (u8, u8) {
+    let cin = if with_carry { (sreg & C) as i32 } else { 0 };
+    let unsigned = rd as i32 - rr as i32 - cin;
+    let signed = rd as i8 as i32 - rr as i8 as i32 - cin;
+    let half = ((rd & 0xf) as i32) < (rr & 0xf) as i32 + cin;
+    let zero = unsigned as u8 == 0 && (!with_carry || (sreg & Z) != 0);
+
+    (unsigned as u8, flags(unsigned, signed, half, zero, sreg))
+}
+
+fn check(opcode: u16, rd: u8, rr: u8, sreg: u8, expected: (u8, u8)) -> TestResult {
+    let actual = run(opcode, rd, rr, sreg);
+    if actual == expected {
+        TestResult::passed()
+    } else {
+        TestResult::error(format!(
+            "{:04x} with r24={:02x} r25={:02x} sreg={:02x}: got {:02x?}, expected {:02x?}",
+            opcode, rd, rr, sreg, actual, expected))
+    }
+}
+
+#[test]
+fn add_flags() {
+    fn prop(rd: u8, rr: u8, sreg: u8) -> TestResult {
+        check(ADD, rd, rr, sreg, reference_add(rd, rr, sreg, false))
+    }
+    quickcheck(prop as fn(u8, u8, u8) -> TestResult);
+}
+
+#[test]
+fn adc_flags() {
+    fn prop(rd: u8, rr: u8, sreg: u8) -> TestResult {
+        check(ADC, rd, rr, sreg, reference_add(rd, rr, sreg, true))
+    }
+    quickcheck(prop as fn(u8, u8, u8) -> TestResult);
+}
+
+#[test]
+fn sub_flags() {
+    fn prop(rd: u8, rr: u8, sreg: u8) -> TestResult {
+        check(SUB, rd, rr, sreg, reference_sub(rd, rr, sreg, false))
+    }
+    quickcheck(prop as fn(u8, u8, u8) -> TestResult);
+}
+
+#[test]
+fn sbc_flags() {
+    fn prop(rd: u8, rr: u8, sreg: u8) -> TestResult {
+        check(SBC, rd, rr, sreg, reference_sub(rd, rr, sreg, true))
+    }
+    quickcheck(prop as fn(u8, u8, u8) -> TestResult);
+}
+
+#[test]
+fn cp_flags() {
+    fn prop(rd: u8, rr: u8, sreg: u8) -> TestResult {
+        // CP is SUB without storing the result
+        let (_, flags) = reference_sub(rd, rr, sreg, false);
+        check(CP, rd, rr, sreg, (rd, flags))
+    }
+    quickcheck(prop as fn(u8, u8, u8) -> TestResult);
+}
+
+#[test]
+fn cpc_flags() {
+    fn prop(rd: u8, rr: u8, sreg: u8) -> TestResult {
+        let (_, flags) = reference_sub(rd, rr, sreg, true);
+        check(CPC, rd, rr, sreg, (rd, flags))
+    }
+    quickcheck(prop as fn(u8, u8, u8) -> TestResult);
+}
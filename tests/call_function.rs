@@ -0,0 +1,52 @@
+// Calling guest functions from the host, and what's left after a failed call
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::device::ATMEGA328P;
+
+
+/// 0: rjmp .+0; nop; nop
+/// 6: inc r24; ret
+/// 10: inc r24; break
+const FUNCS : [u16; 7] = [0xc000, 0x0000, 0x0000, 0x9583, 0x9508, 0x9583, 0x9598];
+
+
+fn setup() -> Emulator {
+    let mut emu = Emulator::for_device(&ATMEGA328P);
+    emu.prog_mem.set_words(FUNCS.to_vec());
+    emu.reset();
+    emu.io_mem.set_sp(ATMEGA328P.ramend() as u16);
+    // somewhere in the middle of the program
+    emu.step();
+    emu
+}
+
+#[test]
+fn call_returns_value() {
+    let mut emu = setup();
+    let pc = emu.pc;
+
+    let res = emu.call_function("6", &[&[41]]).unwrap();
+    assert_eq!(res.ret & 0xff, 42);
+    assert_eq!(emu.pc, pc);
+    assert_eq!(emu.io_mem.get_sp() as u32, ATMEGA328P.ramend());
+    assert!(emu.call_stack.is_empty());
+}
+
+#[test]
+fn failed_call_leaves_pc_and_stack() {
+    let mut emu = setup();
+    let pc = emu.pc;
+
+    assert!(emu.call_function("a", &[&[41]]).is_err());
+    assert_eq!(emu.pc, pc);
+    assert_eq!(emu.io_mem.get_sp() as u32, ATMEGA328P.ramend());
+    assert!(emu.call_stack.is_empty());
+    // but the call's changes stay
+    assert_eq!(emu.get_reg8(24), 42);
+
+    // and the program carries on from where it was
+    emu.step();
+    assert_eq!(emu.pc, pc + 2);
+}
@@ -0,0 +1,77 @@
+// ICALL and IJMP through Z, with 2- and 3-byte return addresses
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::device::{Device, ATMEGA328P, ATXMEGA128A4U};
+
+
+/// ldi r30, 8; ldi r31, 0; icall; mov r20, r24; nop;
+/// ldi r30, 10; ijmp; nop;
+/// 8: ldi r24, 0x42; ret;
+/// 10: inc r25; nop
+const FUNC_PTR : [u16; 12] = [
+    0xe0e8, 0xe0f0, 0x9509, 0x2f48, 0x0000,
+    0xe0ea, 0x9409, 0x0000,
+    0xe482, 0x9508,
+    0x9593, 0x0000,
+];
+
+
+fn setup(device: &Device) -> Emulator {
+    let mut emu = Emulator::for_device(device);
+    emu.prog_mem.set_words(FUNC_PTR.to_vec());
+    emu.reset();
+    emu.io_mem.set_sp(device.ramend() as u16);
+    emu
+}
+
+fn step(emu: &mut Emulator) {
+    let res = emu.step();
+    assert!(res.fault.is_none(), "{}", res.fault.unwrap());
+}
+
+fn call_through_pointer(device: &Device) {
+    let mut emu = setup(device);
+    let sp = emu.io_mem.get_sp();
+
+    for _ in 0..3 {
+        step(&mut emu);
+    }
+    assert_eq!(emu.pc, 8 * 2);
+    let size = device.ret_addr_size();
+    assert_eq!(emu.io_mem.get_sp(), sp - size);
+    // big-endian word address of the MOV after the ICALL
+    let start = (sp - size + 1) as usize;
+    let pushed = &emu.io_mem.data_mem[start..start + size as usize];
+    assert_eq!(pushed.last(), Some(&3));
+    assert!(pushed[..pushed.len() - 1].iter().all(|&b| b == 0));
+
+    // the function, then back
+    step(&mut emu);
+    step(&mut emu);
+    assert_eq!(emu.pc, 3 * 2);
+    assert_eq!(emu.io_mem.get_sp(), sp);
+
+    step(&mut emu);
+    assert_eq!(emu.get_reg8(20), 0x42);
+
+    // IJMP pushes nothing
+    for _ in 0..3 {
+        step(&mut emu);
+    }
+    assert_eq!(emu.pc, 10 * 2);
+    assert_eq!(emu.io_mem.get_sp(), sp);
+    step(&mut emu);
+    assert_eq!(emu.get_reg8(25), 1);
+}
+
+#[test]
+fn icall_and_ijmp_on_classic_avr() {
+    call_through_pointer(&ATMEGA328P);
+}
+
+#[test]
+fn icall_pushes_3_bytes_on_xmega() {
+    call_through_pointer(&ATXMEGA128A4U);
+}
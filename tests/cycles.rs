@@ -0,0 +1,65 @@
+// Cycle counts for calls, stack accesses and interrupts on classic cores
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::device::{Device, ATMEGA328P, ATTINY85};
+
+
+/// sei; rcall 4; nop; rjmp .-2
+/// 4: push r16; pop r16; ret; nop
+/// 8 and 16: reti, for vector 8 with 2- and 4-byte vectors
+const PROGRAM : [u16; 17] = [
+    0x9478, 0xd002, 0x0000, 0xcfff,
+    0x930f, 0x910f, 0x9508, 0x0000,
+    0x9518, 0, 0, 0, 0, 0, 0, 0,
+    0x9518,
+];
+
+const VECTOR : u8 = 8;
+
+
+fn setup(device: &Device) -> Emulator {
+    let mut emu = Emulator::for_device(device);
+    emu.prog_mem.set_words(PROGRAM.to_vec());
+    emu.reset();
+    emu.io_mem.set_sp(device.ramend() as u16);
+    emu
+}
+
+fn step(emu: &mut Emulator) {
+    let res = emu.step();
+    assert!(res.fault.is_none(), "{}", res.fault.unwrap());
+}
+
+/// the cycle count after the call and return, after taking the interrupt and
+/// after returning from it
+fn run(device: &Device) -> (u64, u64, u64) {
+    let mut emu = setup(device);
+    for _ in 0..6 {
+        step(&mut emu);
+    }
+    assert_eq!(emu.pc, 3 * 2);
+    let call = emu.cycle_count;
+
+    emu.raise_interrupt(VECTOR);
+    step(&mut emu);
+    assert_eq!(emu.pc, VECTOR as u32 * device.vector_size);
+    let interrupt = emu.cycle_count;
+
+    step(&mut emu);
+    assert_eq!(emu.pc, 3 * 2);
+    (call, interrupt, emu.cycle_count)
+}
+
+#[test]
+fn mega_timings() {
+    // sei 1, rcall 3, push 2, pop 2, ret 4, nop 1; interrupt 4; reti 4
+    assert_eq!(run(&ATMEGA328P), (13, 17, 21));
+}
+
+#[test]
+fn tiny_timings() {
+    // the same as the mega, but with 2-byte vectors
+    assert_eq!(run(&ATTINY85), (13, 17, 21));
+}
@@ -0,0 +1,57 @@
+// The DES instruction, run for all 16 rounds, against known answers
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+
+
+/// des 0; des 1; ... des 15
+fn des_rounds() -> Vec<u16> {
+    (0..16).map(|k| 0x940b | (k << 4)).collect()
+}
+
+/// data in r0-r7 and the key in r8-r15, least significant byte first;
+/// H set means decrypt
+fn run_des(data: u64, key: u64, decrypt: bool) -> u64 {
+    let mut emu = Emulator::new();
+    emu.prog_mem.set_words(des_rounds());
+    emu.reset();
+
+    for i in 0..8 {
+        emu.set_reg8(i, (data >> (8 * i)) as u8);
+        emu.set_reg8(i + 8, (key >> (8 * i)) as u8);
+    }
+    emu.io_mem.sreg.h = decrypt;
+
+    for _ in 0..16 {
+        let res = emu.step();
+        assert!(res.fault.is_none(), "{}", res.fault.unwrap());
+    }
+
+    (0..8).fold(0, |out, i| out | ((emu.get_reg8(i) as u64) << (8 * i)))
+}
+
+// (key, plaintext, ciphertext)
+const VECTORS : [(u64, u64, u64); 3] = [
+    // NIST SP 800-17, variable plaintext known answer test
+    (0x0101010101010101, 0x8000000000000000, 0x95f8a5e5dd31d900),
+    // NIST SP 800-17, variable key known answer test
+    (0x8001010101010101, 0x0000000000000000, 0x95a8d72813daa94d),
+    // the worked example from "The DES Algorithm Illustrated"
+    (0x133457799bbcdff1, 0x0123456789abcdef, 0x85e813540f0ab405),
+];
+
+
+#[test]
+fn encrypts_with_h_clear() {
+    for &(key, plain, cipher) in &VECTORS {
+        assert_eq!(run_des(plain, key, false), cipher, "key {:016x}", key);
+    }
+}
+
+#[test]
+fn decrypts_with_h_set() {
+    for &(key, plain, cipher) in &VECTORS {
+        assert_eq!(run_des(cipher, key, true), plain, "key {:016x}", key);
+    }
+}
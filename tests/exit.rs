@@ -0,0 +1,67 @@
+// Exit codes: from avr-libc's _exit, or r24 when halting at __stop_program
+
+extern crate yaavre;
+
+use yaavre::{Emulator, StopReason};
+use yaavre::device::ATMEGA328P;
+use yaavre::symbols::Symbol;
+
+
+/// ldi r24, CODE; rcall _exit; nop
+/// 6: _exit: cli; rjmp .-4
+fn program(code: u8) -> Vec<u16> {
+    let ldi = 0xe080 | ((code as u16 & 0xf0) << 4) | (code as u16 & 0xf);
+    vec![ldi, 0xd001, 0x0000, 0x94f8, 0xcffe]
+}
+
+const EXIT : u32 = 6;
+
+
+fn setup(code: u8) -> Emulator {
+    let mut emu = Emulator::for_device(&ATMEGA328P);
+    emu.prog_mem.set_words(program(code));
+    emu.reset();
+    emu.io_mem.set_sp(ATMEGA328P.ramend() as u16);
+    emu
+}
+
+fn add_exit_symbol(emu: &mut Emulator) {
+    emu.symbols.add(Symbol { name: "_exit".to_string(), addr: EXIT, size: 4, kind: 'T' });
+}
+
+#[test]
+fn exits_at_the_exit_symbol() {
+    let mut emu = setup(3);
+    add_exit_symbol(&mut emu);
+    assert!(emu.exit_at_exit_symbol());
+    assert_eq!(emu.exit_pc, Some(EXIT));
+
+    assert_eq!(emu.run_until_cycle(1000).unwrap(), StopReason::Exit(3));
+    assert_eq!(emu.exit_code(), Some(3));
+    assert_eq!(emu.pc, EXIT);
+}
+
+#[test]
+fn halting_takes_the_code_from_r24() {
+    let mut emu = setup(3);
+    assert!(!emu.exit_at_exit_symbol());
+
+    assert_eq!(emu.run_until_cycle(1000).unwrap(), StopReason::Halted);
+    assert_eq!(emu.exit_code(), Some(3));
+}
+
+#[test]
+fn zero_exit_code() {
+    let mut emu = setup(0);
+    add_exit_symbol(&mut emu);
+    emu.exit_at_exit_symbol();
+    assert_eq!(emu.run_until_cycle(1000).unwrap(), StopReason::Exit(0));
+    assert_eq!(emu.exit_code(), Some(0));
+}
+
+#[test]
+fn no_exit_code_without_exiting() {
+    let mut emu = setup(3);
+    assert_eq!(emu.run_until_cycle(2).unwrap(), StopReason::CycleLimit);
+    assert_eq!(emu.exit_code(), None);
+}
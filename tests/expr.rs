@@ -0,0 +1,100 @@
+// Breakpoint condition expressions: parsing and evaluating against the
+// machine state
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::expr::{BinOp, Expr, Var};
+use yaavre::symbols::{Symbol, SymbolTable, DATA_OFFSET};
+
+
+fn eval(s: &str, emu: &Emulator) -> i64 {
+    Expr::parse(s).unwrap().eval(emu)
+}
+
+fn num(val: i64) -> Box<Expr> {
+    Box::new(Expr::Num(val))
+}
+
+fn var(var: Var) -> Box<Expr> {
+    Box::new(Expr::Var(var))
+}
+
+fn symbols() -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    symbols.add(Symbol {
+        name: "rx_buf".to_string(), addr: DATA_OFFSET + 0x2100, size: 16, kind: 'B',
+    });
+    symbols.add(Symbol { name: "main".to_string(), addr: 0x1f4, size: 0, kind: 'T' });
+    symbols
+}
+
+
+#[test]
+fn multiplication_binds_tighter_than_addition() {
+    assert_eq!(Expr::parse("1 + 2 * 3").unwrap(),
+               Expr::Binary(BinOp::Add, num(1),
+                            Box::new(Expr::Binary(BinOp::Mul, num(2), num(3)))));
+    assert_eq!(eval("1 + 2 * 3", &Emulator::new()), 7);
+    assert_eq!(eval("(1 + 2) * 3", &Emulator::new()), 9);
+}
+
+#[test]
+fn comparisons_bind_tighter_than_equality() {
+    // (a < b) == c
+    let lt = Expr::Binary(BinOp::Lt, var(Var::Reg(1)), var(Var::Reg(2)));
+    assert_eq!(Expr::parse("r1 < r2 == r3").unwrap(),
+               Expr::Binary(BinOp::Eq, Box::new(lt), var(Var::Reg(3))));
+
+    let mut emu = Emulator::new();
+    emu.set_reg8(1, 5);
+    emu.set_reg8(2, 6);
+    emu.set_reg8(3, 1);
+    assert_eq!(eval("r1 < r2 == r3", &emu), 1);
+    emu.set_reg8(3, 0);
+    assert_eq!(eval("r1 < r2 == r3", &emu), 0);
+}
+
+#[test]
+fn mem16_is_little_endian() {
+    let mut emu = Emulator::new();
+    emu.io_mem.data_mem[0x2100] = 0x34;
+    emu.io_mem.data_mem[0x2101] = 0x12;
+    assert_eq!(eval("mem16[0x2100]", &emu), 0x1234);
+    assert_eq!(eval("mem[0x2100 + 1]", &emu), 0x12);
+    assert_eq!(eval("MEM16[0x2100] == 0x1234", &emu), 1);
+    // outside of data space
+    assert_eq!(eval("mem[-1]", &emu), 0);
+}
+
+#[test]
+fn sreg_flags() {
+    let mut emu = Emulator::new();
+    emu.io_mem.sreg.z = true;
+    assert_eq!(Expr::parse("sreg.z").unwrap(), Expr::Var(Var::Flag(1)));
+    assert_eq!(eval("sreg.z", &emu), 1);
+    assert_eq!(eval("sreg.c", &emu), 0);
+    assert_eq!(eval("sreg", &emu), 2);
+    assert!(Expr::parse("sreg.q").is_err());
+}
+
+#[test]
+fn r_prefixed_names_can_be_symbols() {
+    let symbols = symbols();
+    assert_eq!(Expr::parse_with_symbols("rx_buf", &symbols).unwrap(), Expr::Num(0x2100));
+    assert_eq!(Expr::parse_with_symbols("main", &symbols).unwrap(), Expr::Num(0x1f4));
+    assert_eq!(Expr::parse_with_symbols("r24", &symbols).unwrap(), Expr::Var(Var::Reg(24)));
+
+    // not registers, and unknown without symbols
+    assert!(Expr::parse("rx_buf").is_err());
+    assert!(Expr::parse("r32").is_err());
+}
+
+#[test]
+fn division_by_zero_gives_zero() {
+    let emu = Emulator::new();
+    assert_eq!(eval("7 / 0", &emu), 0);
+    assert_eq!(eval("7 % 0", &emu), 0);
+    assert_eq!(eval("7 / r5 == 0", &emu), 1);
+    assert_eq!(eval("7 / 2", &emu), 3);
+}
@@ -0,0 +1,51 @@
+// Flash size and page size come from the device, not the xmega defaults
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::device::{ATMEGA328P, ATTINY85, ATXMEGA128A4U};
+use yaavre::progmem::ProgramMemory;
+
+
+#[test]
+fn writes_stop_at_the_end_of_flash() {
+    let mut flash = ProgramMemory::for_device(&ATMEGA328P);
+    assert!(flash.write_word(0x7ffe, 0x1234));
+    assert!(!flash.write_word(0x8000, 0x1234));
+    assert_eq!(flash.read_word(0x7ffe), Some(0x1234));
+
+    let mut flash = ProgramMemory::for_device(&ATXMEGA128A4U);
+    assert!(flash.write_word(0x8000, 0x1234));
+}
+
+#[test]
+fn erase_page_erases_one_device_page() {
+    let mut flash = ProgramMemory::for_device(&ATTINY85);
+    flash.set_words(vec![0; 0x100]);
+    assert!(flash.erase_page(0x48));
+
+    let erased: Vec<u32> = (0..0x200).step_by(2)
+        .filter(|&addr| flash.read_word(addr) == Some(0xffff))
+        .collect();
+    assert_eq!(erased, (0x40..0x80).step_by(2).collect::<Vec<u32>>());
+}
+
+#[test]
+fn nvm_page_buffer_is_one_device_page() {
+    let emu = Emulator::for_device(&ATMEGA328P);
+    assert_eq!(emu.io_mem.nvm.page_buffer.len(), 64);
+    assert_eq!(emu.prog_mem.size(), 0x8000);
+
+    let emu = Emulator::new();
+    assert_eq!(emu.io_mem.nvm.page_buffer.len(), 0x100);
+}
+
+#[test]
+fn lpm_past_the_image_reads_erased_flash() {
+    let mut flash = ProgramMemory::for_device(&ATMEGA328P);
+    flash.set_words(vec![0x1234]);
+    assert_eq!(flash.get_prog_mem_byte(0, &"", 0), 0x34);
+    assert_eq!(flash.get_prog_mem_byte(1, &"", 0), 0x12);
+    assert_eq!(flash.get_prog_mem_byte(2, &"", 0), 0xff);
+    assert_eq!(flash.get_prog_mem_byte(0x7fff, &"", 0), 0xff);
+}
@@ -0,0 +1,103 @@
+// LD/ST through X, Y and Z across the 64 KiB boundary, on devices with and
+// without the RAMP registers
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::device::{Device, ATXMEGA128A4U};
+use yaavre::iomem::EIND;
+
+
+/// room for buffers on both sides of 64 KiB
+const BIG_RAM : Device = Device { sram_size: 0x20000, ..ATXMEGA128A4U };
+/// the same, but with 16-bit pointers like a classic AVR
+const NO_RAMP : Device = Device { ramp_regs: &[EIND], ..BIG_RAM };
+
+/// opcodes, all with r24
+const LD_X_INC : u16 = 0x918d;
+const LD_DEC_X : u16 = 0x918e;
+const ST_DEC_Y : u16 = 0x938a;
+const LDD_Y_1 : u16 = 0x8189;
+const ST_Z_INC : u16 = 0x9381;
+
+
+fn setup(device: &Device, opcode: u16) -> Emulator {
+    let mut emu = Emulator::for_device(device);
+    emu.prog_mem.set_words(vec![opcode]);
+    emu.reset();
+    emu
+}
+
+fn step(emu: &mut Emulator) {
+    let res = emu.step();
+    assert!(res.fault.is_none(), "{}", res.fault.unwrap());
+}
+
+#[test]
+fn post_inc_carries_into_rampx() {
+    let mut emu = setup(&BIG_RAM, LD_X_INC);
+    emu.io_mem.data_mem[0xffff] = 0x42;
+    emu.io_mem.set_full_x(0xffff);
+
+    step(&mut emu);
+    assert_eq!(emu.get_reg8(24), 0x42);
+    assert_eq!(emu.io_mem.get_full_x(), 0x10000);
+    assert_eq!(emu.io_mem.get_rampx(), 1);
+}
+
+#[test]
+fn pre_dec_borrows_from_rampy() {
+    let mut emu = setup(&BIG_RAM, ST_DEC_Y);
+    emu.set_reg8(24, 0x42);
+    emu.io_mem.set_full_y(0x10000);
+
+    step(&mut emu);
+    assert_eq!(emu.io_mem.data_mem[0xffff], 0x42);
+    assert_eq!(emu.io_mem.get_full_y(), 0xffff);
+    assert_eq!(emu.io_mem.get_rampy(), 0);
+}
+
+#[test]
+fn displacement_crosses_64k() {
+    let mut emu = setup(&BIG_RAM, LDD_Y_1);
+    emu.io_mem.data_mem[0x10000] = 0x42;
+    emu.io_mem.set_full_y(0xffff);
+
+    step(&mut emu);
+    assert_eq!(emu.get_reg8(24), 0x42);
+    assert_eq!(emu.io_mem.get_full_y(), 0xffff);
+}
+
+#[test]
+fn post_inc_crosses_rampz() {
+    let mut emu = setup(&BIG_RAM, ST_Z_INC);
+    emu.set_reg8(24, 0x42);
+    emu.io_mem.set_full_z(0x1ffff);
+
+    step(&mut emu);
+    assert_eq!(emu.io_mem.data_mem[0x1ffff], 0x42);
+    assert_eq!(emu.io_mem.get_full_z(), 0x20000);
+}
+
+#[test]
+fn post_inc_wraps_without_rampx() {
+    let mut emu = setup(&NO_RAMP, LD_X_INC);
+    emu.io_mem.data_mem[0xffff] = 0x42;
+    emu.io_mem.set_full_x(0xffff);
+
+    step(&mut emu);
+    assert_eq!(emu.get_reg8(24), 0x42);
+    assert_eq!(emu.io_mem.get_full_x(), 0);
+    assert_eq!(emu.io_mem.get_rampx(), 0);
+}
+
+#[test]
+fn pre_dec_wraps_without_rampx() {
+    let mut emu = setup(&NO_RAMP, LD_DEC_X);
+    emu.io_mem.data_mem[0xffff] = 0x42;
+    emu.io_mem.set_full_x(0);
+
+    step(&mut emu);
+    assert_eq!(emu.get_reg8(24), 0x42);
+    assert_eq!(emu.io_mem.get_full_x(), 0xffff);
+}
@@ -0,0 +1,49 @@
+// Reading snapshots back, and rejecting corrupt ones
+
+extern crate yaavre;
+
+use std::io::ErrorKind;
+use yaavre::Emulator;
+use yaavre::snapshot::Snapshot;
+
+
+// magic, version, PC, an empty call stack, skip and sleeping flags, counts,
+// registers and SREG come before data space's length
+const DATA_MEM_LEN_OFS : usize = 8 + 4 + 4 + 4 + 1 + 1 + 8 + 8 + 32 + 1;
+
+
+fn saved() -> Vec<u8> {
+    let mut emu = Emulator::new();
+    emu.reset();
+    emu.set_reg8(5, 0x42);
+    let mut out = vec![];
+    emu.snapshot().write_to(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn round_trip() {
+    let data = saved();
+    let snap = Snapshot::read_from(&mut &data[..]).unwrap();
+    assert_eq!(snap.regs[5], 0x42);
+
+    let mut emu = Emulator::new();
+    emu.restore(&snap);
+    assert_eq!(emu.get_reg8(5), 0x42);
+}
+
+#[test]
+fn huge_length_is_an_error() {
+    let mut data = saved();
+    data[DATA_MEM_LEN_OFS..DATA_MEM_LEN_OFS + 4].copy_from_slice(&[0xff; 4]);
+    let err = Snapshot::read_from(&mut &data[..]).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn truncated_snapshot_is_an_error() {
+    let data = saved();
+    for &len in &[4, DATA_MEM_LEN_OFS + 100, data.len() - 1] {
+        assert!(Snapshot::read_from(&mut &data[..len]).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+// Runs the single-instruction cases in fixtures/sreg.txt
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+
+
+const FIXTURES : &str = include_str!("fixtures/sreg.txt");
+const SREG_CHARS : &str = "CZNVSHTI";
+
+
+fn parse_sreg(flags: &str) -> u8 {
+    if flags == "-" {
+        return 0;
+    }
+
+    flags.chars()
+        .map(|c| 1 << SREG_CHARS.find(c).expect("bad SREG flag"))
+        .fold(0, |acc, bit| acc | bit)
+}
+
+fn fmt_sreg(sreg: u8) -> String {
+    let s: String = "ITHSVNZC".chars()
+        .filter(|&c| (sreg & (1 << SREG_CHARS.find(c).unwrap())) != 0)
+        .collect();
+    if s.is_empty() { "-".to_string() } else { s }
+}
+
+/// "r24=7f sreg=C" as (register or None for SREG, value) pairs
+fn parse_settings(s: &str) -> Vec<(Option<u8>, u8)> {
+    s.split_whitespace()
+        .map(|setting| {
+            let mut parts = setting.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let val = parts.next().expect("missing value");
+            if name == "sreg" {
+                (None, parse_sreg(val))
+            } else {
+                let r = name[1..].parse().expect("bad register");
+                (Some(r), u8::from_str_radix(val, 16).expect("bad value"))
+            }
+        })
+        .collect()
+}
+
+/// run one case; returns a description of what went wrong, if anything
+fn run_case(line: &str) -> Option<String> {
+    let fields: Vec<&str> = line.split('|').map(|s| s.trim()).collect();
+    assert_eq!(fields.len(), 4, "bad fixture line {:?}", line);
+    let opcode = u16::from_str_radix(fields[1], 16).expect("bad opcode");
+
+    let mut emu = Emulator::new();
+    emu.prog_mem.set_words(vec![opcode]);
+    emu.reset();
+
+    for (reg, val) in parse_settings(fields[2]) {
+        match reg {
+            Some(r) => emu.set_reg8(r, val),
+            None => emu.io_mem.sreg.set_u8(val),
+        }
+    }
+
+    let res = emu.step();
+    if let Some(e) = res.fault {
+        return Some(format!("{}: {}", fields[0], e));
+    }
+
+    let mut errors = vec![];
+    for (reg, expected) in parse_settings(fields[3]) {
+        match reg {
+            Some(r) if emu.get_reg8(r) != expected =>
+                errors.push(format!("r{} = {:02x}, expected {:02x}",
+                                    r, emu.get_reg8(r), expected)),
+            None if emu.io_mem.sreg.as_u8() != expected =>
+                errors.push(format!("SREG = {}, expected {}",
+                                    fmt_sreg(emu.io_mem.sreg.as_u8()),
+                                    fmt_sreg(expected))),
+            _ => {},
+        }
+    }
+
+    if errors.is_empty() {
+        None
+    } else {
+        Some(format!("{} with {}: {}", fields[0], fields[2], errors.join(", ")))
+    }
+}
+
+#[test]
+fn sreg_golden() {
+    let failures: Vec<String> = FIXTURES.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(run_case)
+        .collect();
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,49 @@
+// State hashes only depend on the state, however it was reached
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+use yaavre::statehash::MemHasher;
+
+
+/// inc r16; add r17, r16; sts 0x2100, r17; rjmp .-10
+const COUNT_LOOP : [u16; 5] = [0x9503, 0x0f10, 0x9310, 0x2100, 0xcffb];
+
+
+#[test]
+fn page_hashes_follow_changes() {
+    let mut mem = vec![0; 0x1000];
+    let mut hasher = MemHasher::new();
+    let empty = hasher.hash(&mem);
+
+    mem[0x345] = 1;
+    let changed = hasher.hash(&mem);
+    assert!(changed != empty);
+    assert_eq!(changed, MemHasher::new().hash(&mem));
+
+    mem[0x345] = 0;
+    assert_eq!(hasher.hash(&mem), empty);
+
+    // a different size starts over
+    mem.push(0);
+    assert_eq!(hasher.hash(&mem), MemHasher::new().hash(&mem));
+}
+
+#[test]
+fn recorded_hashes_match_state_hash() {
+    let mut emu = Emulator::new();
+    emu.prog_mem.set_words(COUNT_LOOP.to_vec());
+    emu.reset();
+    emu.enable_state_hashing(10);
+
+    for _ in 0..20 {
+        while emu.insn_count % 10 != 9 {
+            assert!(emu.step().fault.is_none());
+        }
+        assert!(emu.step().fault.is_none());
+
+        let &(insns, hash) = emu.state_hashes.as_ref().unwrap().hashes.last().unwrap();
+        assert_eq!(insns, emu.insn_count);
+        assert_eq!(hash, emu.state_hash());
+    }
+}
@@ -0,0 +1,158 @@
+// step_back(): landing on the right instruction, and re-executing with the
+// same inputs
+
+extern crate yaavre;
+
+use yaavre::Emulator;
+
+
+/// inc r16; add r17, r16; sts 0x2100, r17; rjmp .-10
+const COUNT_LOOP : [u16; 5] = [0x9503, 0x0f10, 0x9310, 0x2100, 0xcffb];
+
+/// ldi r16, RXEN; sts USARTC0.CTRLB, r16
+/// wait: lds r18, USARTC0.STATUS; sbrs r18, RXCIF; rjmp wait
+/// lds r24, USARTC0.DATA; add r25, r24; sts 0x2100, r25; rjmp wait
+const ECHO_SUM : [u16; 13] = [
+    0xe100, 0x9300, 0x08a4,
+    0x9120, 0x08a1, 0xff27, 0xcffc,
+    0x9180, 0x08a0, 0x0f98, 0x9390, 0x2100, 0xcff6,
+];
+
+/// ldi r24, 200; loop: dec r24; brne loop; rjmp .-8
+const DELAY_LOOP : [u16; 4] = [0xec88, 0x958a, 0xf7f1, 0xcffc];
+
+
+#[derive(Debug, PartialEq)]
+struct State {
+    pc: u32,
+    insn_count: u64,
+    cycle_count: u64,
+    regs: [u8; 32],
+    sreg: u8,
+    data_mem: Vec<u8>,
+}
+
+fn state(emu: &Emulator) -> State {
+    State {
+        pc: emu.pc,
+        insn_count: emu.insn_count,
+        cycle_count: emu.cycle_count,
+        regs: emu.io_mem.regs.r,
+        sreg: emu.io_mem.sreg.as_u8(),
+        data_mem: emu.io_mem.data_mem.clone(),
+    }
+}
+
+fn setup(words: &[u16]) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.prog_mem.set_words(words.to_vec());
+    emu.reset();
+    emu.enable_time_travel(10, 100);
+    emu
+}
+
+fn step(emu: &mut Emulator) {
+    let res = emu.step();
+    assert!(res.fault.is_none(), "{}", res.fault.unwrap());
+}
+
+/// the state before each instruction, for `n` instructions
+fn run_recording(emu: &mut Emulator, n: usize) -> Vec<State> {
+    let mut history = vec![];
+    for _ in 0..n {
+        history.push(state(emu));
+        step(emu);
+    }
+    history.push(state(emu));
+    history
+}
+
+#[test]
+fn step_back_lands_n_insns_earlier() {
+    let mut emu = setup(&COUNT_LOOP);
+    let history = run_recording(&mut emu, 57);
+
+    assert_eq!(emu.step_back(23).unwrap(), 23);
+    assert_eq!(state(&emu), history[34]);
+
+    // and again, from in between checkpoints
+    assert_eq!(emu.step_back(29).unwrap(), 29);
+    assert_eq!(state(&emu), history[5]);
+}
+
+#[test]
+fn step_back_then_forward_repeats_execution() {
+    let mut emu = setup(&COUNT_LOOP);
+    let history = run_recording(&mut emu, 40);
+
+    emu.step_back(35).unwrap();
+    for expected in &history[5..] {
+        assert_eq!(&state(&emu), expected);
+        step(&mut emu);
+    }
+}
+
+#[test]
+fn step_back_past_start() {
+    let mut emu = setup(&COUNT_LOOP);
+    let history = run_recording(&mut emu, 12);
+
+    assert_eq!(emu.step_back(100).unwrap(), 12);
+    assert_eq!(state(&emu), history[0]);
+}
+
+#[test]
+fn step_back_lands_exactly_in_warp_mode() {
+    // warp mode skips iterations in batches
+    let mut emu = setup(&DELAY_LOOP);
+    emu.enable_warp();
+    while emu.insn_count < 1000 {
+        step(&mut emu);
+    }
+    let start = emu.insn_count;
+
+    assert_eq!(emu.step_back(150).unwrap(), 150);
+
+    let mut expected = setup(&DELAY_LOOP);
+    while expected.insn_count < start - 150 {
+        step(&mut expected);
+    }
+    assert_eq!(state(&emu), state(&expected));
+}
+
+#[test]
+fn step_back_replays_uart_input() {
+    let mut emu = setup(&ECHO_SUM);
+    emu.queue_uart_input(b"ab");
+
+    let mut history = vec![];
+    while emu.io_mem.data_mem[0x2100] != b'a'.wrapping_add(b'b') {
+        assert!(history.len() < 10000, "input never arrived");
+        history.push(state(&emu));
+        step(&mut emu);
+    }
+    history.push(state(&emu));
+    let end = history.len() - 1;
+
+    // back to before either byte arrived, then forward again
+    emu.step_back(end as u64 - 3).unwrap();
+    assert_eq!(state(&emu), history[3]);
+    for expected in &history[3..] {
+        assert_eq!(&state(&emu), expected);
+        step(&mut emu);
+    }
+}
+
+#[test]
+fn changes_after_step_back_drop_later_checkpoints() {
+    let mut emu = setup(&COUNT_LOOP);
+    run_recording(&mut emu, 40);
+
+    // a different future: r16 starts over from 0x80
+    emu.step_back(35).unwrap();
+    emu.set_reg8(16, 0x80);
+    let history = run_recording(&mut emu, 30);
+
+    assert_eq!(emu.step_back(10).unwrap(), 10);
+    assert_eq!(state(&emu), history[20]);
+}
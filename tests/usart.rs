@@ -0,0 +1,229 @@
+// USART timing, status flags and interrupts, in the XMEGA and classic
+// register layouts
+
+extern crate yaavre;
+
+use yaavre::peripheral::Peripheral;
+use yaavre::usart::Usart;
+
+
+// XMEGA register offsets
+const DATA : u32 = 0;
+const STATUS : u32 = 1;
+const CTRLA : u32 = 3;
+const CTRLB : u32 = 4;
+const CTRLC : u32 = 5;
+const BAUDCTRLA : u32 = 6;
+const BAUDCTRLB : u32 = 7;
+
+// classic register offsets
+const UCSRA : u32 = 0;
+const UCSRB : u32 = 1;
+const UCSRC : u32 = 2;
+const UBRRL : u32 = 4;
+const UDR : u32 = 6;
+
+const RXCIF : u8 = 0x80;
+const TXCIF : u8 = 0x40;
+const DREIF : u8 = 0x20;
+const BUFOVF : u8 = 0x08;
+
+// CTRLB
+const RXEN : u8 = 0x10;
+const TXEN : u8 = 0x08;
+
+// classic UCSRB
+const RXCIE : u8 = 0x80;
+const TXCIE : u8 = 0x40;
+const UDRIE : u8 = 0x20;
+
+const VECTOR : u8 = 25;
+const CLASSIC_VECTOR : u8 = 18;
+
+// BSEL 0, BSCALE 0: 16 cycles per bit, 160 per 8N1 frame
+const FRAME : u64 = 160;
+
+
+/// an XMEGA USART at the fastest baud rate, with RXC, DRE and TXC at low
+/// level
+fn xmega() -> Usart {
+    let mut usart = Usart::new("USARTC0", 0x08A0, VECTOR, 0x4B);
+    usart.tick(0);
+    usart.write(CTRLB, RXEN | TXEN);
+    usart.write(CTRLA, 0x15);
+    usart
+}
+
+fn classic() -> Usart {
+    let mut usart = Usart::classic("USART0", 0x00C0, CLASSIC_VECTOR);
+    usart.tick(0);
+    usart.write(UCSRB, RXCIE | TXCIE | UDRIE | RXEN | TXEN);
+    usart
+}
+
+fn vector(usart: &Usart) -> Option<u8> {
+    usart.pending_interrupt().map(|(vector, _)| vector)
+}
+
+
+#[test]
+fn frame_ends_after_frame_cycles() {
+    let mut usart = xmega();
+    assert_eq!(usart.frame_cycles(), FRAME);
+    usart.write(DATA, b'a');
+    // straight on to the shift register
+    assert_ne!(usart.read(STATUS) & DREIF, 0);
+
+    usart.tick(FRAME - 1);
+    assert!(usart.take_transmitted().is_empty());
+    assert_eq!(usart.read(STATUS) & TXCIF, 0);
+    assert_eq!(usart.next_event(), Some(FRAME));
+
+    usart.tick(FRAME);
+    assert_eq!(usart.take_transmitted(), b"a");
+    assert_ne!(usart.read(STATUS) & TXCIF, 0);
+}
+
+#[test]
+fn frame_cycles_follow_the_frame_format() {
+    let mut usart = xmega();
+    // 7 data bits, even parity, 2 stop bits
+    usart.write(CTRLC, 0x20 | 0x08 | 0x02);
+    assert_eq!(usart.frame_cycles(), 11 * 16);
+    // 9 data bits
+    usart.write(CTRLC, 0x07);
+    assert_eq!(usart.frame_cycles(), 11 * 16);
+}
+
+/// DRE, TXC and RXC fire as the frames go by. `no_dre` is the register
+/// write that leaves only RXC and TXC enabled.
+fn interrupts_follow_the_frames(mut usart: Usart, data: u32, no_dre: (u32, u8),
+                                vector_base: u8) {
+    let (rxc, dre, txc) = (vector_base, vector_base + 1, vector_base + 2);
+
+    // the data register is empty
+    assert_eq!(vector(&usart), Some(dre));
+
+    // the first byte goes straight to the shift register, the second waits
+    usart.write(data, b'a');
+    assert_eq!(vector(&usart), Some(dre));
+    usart.write(data, b'b');
+    assert_eq!(vector(&usart), None);
+
+    // and moves on when the first is sent
+    usart.tick(FRAME);
+    assert_eq!(vector(&usart), Some(dre));
+
+    // TXC once both are out
+    usart.write(no_dre.0, no_dre.1);
+    assert_eq!(vector(&usart), None);
+    usart.tick(2 * FRAME - 1);
+    assert_eq!(vector(&usart), None);
+    usart.tick(2 * FRAME);
+    assert_eq!(vector(&usart), Some(txc));
+    assert_eq!(usart.take_transmitted(), b"ab");
+    usart.interrupt_taken(txc);
+    assert_eq!(vector(&usart), None);
+
+    // RXC when a received byte arrives, until it's read
+    usart.receive(b'x');
+    usart.tick(3 * FRAME - 1);
+    assert_eq!(vector(&usart), None);
+    usart.tick(3 * FRAME);
+    assert_eq!(vector(&usart), Some(rxc));
+    assert_eq!(usart.read(data), b'x');
+    assert_eq!(vector(&usart), None);
+}
+
+#[test]
+fn xmega_interrupts() {
+    interrupts_follow_the_frames(xmega(), DATA, (CTRLA, 0x14), VECTOR);
+}
+
+#[test]
+fn classic_interrupts() {
+    interrupts_follow_the_frames(classic(), UDR, (UCSRB, RXCIE | TXCIE | RXEN | TXEN),
+                                 CLASSIC_VECTOR);
+}
+
+fn overrun(mut usart: Usart, data: u32, status: u32) {
+    for (i, &val) in b"xyz".iter().enumerate() {
+        usart.receive(val);
+        usart.tick((i as u64 + 1) * FRAME);
+    }
+    // the buffer holds two bytes; the third is lost
+    assert_ne!(usart.read(status) & BUFOVF, 0);
+    assert_eq!(usart.read(data), b'x');
+    assert_eq!(usart.read(data), b'y');
+    assert_eq!(usart.read(status) & (BUFOVF | RXCIF), 0);
+}
+
+#[test]
+fn overrun_sets_bufovf() {
+    overrun(xmega(), DATA, STATUS);
+    overrun(classic(), UDR, UCSRA);
+}
+
+#[test]
+fn xmega_baud_rate() {
+    let mut usart = xmega();
+    // BSCALE 1, BSEL 12: 16 * 2 * 13
+    usart.write(BAUDCTRLA, 12);
+    usart.write(BAUDCTRLB, 0x10);
+    assert_eq!(usart.bit_cycles(), 416);
+
+    // BSCALE -7, BSEL 2094: 115200 baud at 32 MHz, 16 * (2094 / 128 + 1)
+    // = 277.75 cycles
+    usart.write(BAUDCTRLA, 0x2e);
+    usart.write(BAUDCTRLB, 0x98);
+    assert_eq!(usart.bit_cycles(), 278);
+
+    // with CLK2X, 8 * (2094 / 128 + 1) = 138.875
+    usart.write(CTRLB, RXEN | TXEN | 0x04);
+    assert_eq!(usart.bit_cycles(), 139);
+}
+
+#[test]
+fn classic_registers_map_onto_the_xmega_ones() {
+    let mut usart = classic();
+    // 9600 baud at 16 MHz
+    usart.write(UBRRL, 103);
+    assert_eq!(usart.bit_cycles(), 16 * 104);
+    usart.write(UCSRA, 0x02);
+    assert_eq!(usart.bit_cycles(), 8 * 104);
+    assert_eq!(usart.read(UCSRA) & 0x02, 0x02);
+    usart.write(UCSRA, 0);
+
+    // 8E2
+    usart.write(UCSRC, 0x2e);
+    assert_eq!(usart.read(UCSRC), 0x2e);
+    assert_eq!(usart.frame_cycles(), 12 * 16 * 104);
+
+    // UCSZ2 in UCSRB makes it 9 data bits
+    usart.write(UCSRB, RXEN | TXEN | 0x04);
+    assert_eq!(usart.read(UCSRB), RXEN | TXEN | 0x04);
+    assert_eq!(usart.read(UCSRC), 0x2e);
+    assert_eq!(usart.frame_cycles(), 13 * 16 * 104);
+}
+
+#[test]
+fn load_state_carries_on_mid_frame() {
+    let mut usart = xmega();
+    usart.write(DATA, b'a');
+    usart.write(DATA, b'b');
+    usart.tick(FRAME / 2);
+    usart.receive(b'x');
+    let state = usart.save_state();
+
+    let mut copy = Usart::new("USARTC0", 0x08A0, VECTOR, 0x4B);
+    copy.load_state(&state);
+    for usart in &mut [usart, copy] {
+        usart.tick(FRAME);
+        assert_eq!(usart.take_transmitted(), b"a");
+        usart.tick(FRAME / 2 + FRAME);
+        assert_eq!(usart.read(DATA), b'x');
+        usart.tick(2 * FRAME);
+        assert_eq!(usart.take_transmitted(), b"b");
+        assert_eq!(vector(usart), Some(VECTOR + 1));
+    }
+}